@@ -1,5 +1,5 @@
 use core::time;
-use log4rs;
+use mods::cli;
 use mods::rsbk::RSBK;
 use std::thread;
 pub mod mods;
@@ -7,6 +7,10 @@ use log::error;
 
 fn main() {
     log_init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args));
+    }
     loop {
     let rsbk = RSBK::create();
         log::info!("备份开始运行.");
@@ -2,5 +2,31 @@ pub mod bk_config;
 pub mod rsbk;
 pub mod base_bk_option;
 pub mod version_mode;
+pub mod retention;
+pub mod version_index;
+pub mod quota;
+pub mod prune;
+pub mod trash;
+pub mod storage;
+pub mod remote;
+pub mod sftp;
+pub mod s3;
+pub mod smb;
+pub mod webdav;
+pub mod database;
+pub mod command_source;
 pub mod incremental_mode;
-// pub mod network_interface_operate;
+pub mod run_stats;
+pub mod catalog;
+pub mod file_metadata;
+pub mod restore;
+pub mod cli;
+pub mod watch_mode;
+pub mod replicate_mode;
+pub mod hooks;
+pub mod global_config;
+pub mod network_interface_operate;
+pub mod snapshot;
+pub mod notify;
+pub mod smtp;
+pub mod webhook;
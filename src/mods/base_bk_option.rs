@@ -1,240 +1,783 @@
+use super::bk_config::{Compression, SymlinkPolicy};
+use super::catalog::Catalog;
+use super::database;
+use super::file_metadata::{EntryKind, Manifest};
+use super::prune::{PruneReport, PruneRule};
+use super::remote::{self, SpoolFile};
+use super::retention::RetentionPolicy;
+use super::run_stats::RunStats;
+use super::storage::{Link, StorageBackend, StorageKind, StorageMeta};
+use super::trash::TrashRecord;
+use super::version_index::VersionIndex;
 use chrono::{DateTime, Duration, Local};
-use std::ffi::OsStr;
-use std::fs::{self, metadata, read_dir};
-use std::io::Error;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, metadata, read_dir, symlink_metadata, File, Metadata};
+use std::io::{self, Error, ErrorKind, Read};
 use std::path::{Path, PathBuf};
-use std::vec;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+/// 同时写入多个目的地时, 每次从源文件读取的大小
+const FAN_OUT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// 为错误附加发生错误的步骤说明
+pub fn context(msg: &'static str) -> impl FnOnce(Error) -> Error {
+    move |e| Error::new(e.kind(), format!("{}:{}", msg, e))
+}
+
+/// 源目录中的路径按符号链接策略判断后的类型
+pub enum SourceKind {
+    Dir,
+    /// 普通文件, 跟随符号链接时为链接指向的文件
+    File(SourceFile),
+    /// 保留为符号链接, 以及跟随策略下指向不存在路径的链接
+    Symlink(PathBuf),
+    /// 命名管道、设备文件和套接字, 只记录不读取
+    Special(EntryKind),
+    /// 按策略忽略的符号链接
+    Skipped,
+}
+
+/// 源目录中普通文件的信息
+pub struct SourceFile {
+    pub len: u64,
+    pub modified: SystemTime,
+    /// 有多个链接的文件的标识, 用于识别硬链接; 远程源文件没有此项
+    pub link_id: Option<(u64, u64)>,
+}
+
+/// 按符号链接策略判断本地源路径的类型
+pub fn classify(path: &Path, policy: SymlinkPolicy) -> Result<SourceKind, Error> {
+    let meta = symlink_metadata(path)?;
+    if !meta.file_type().is_symlink() {
+        return Ok(classify_metadata(meta));
+    }
+    match policy {
+        SymlinkPolicy::Skip => Ok(SourceKind::Skipped),
+        SymlinkPolicy::Preserve => Ok(SourceKind::Symlink(fs::read_link(path)?)),
+        SymlinkPolicy::Follow => match metadata(path) {
+            Ok(meta) => Ok(classify_metadata(meta)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Ok(SourceKind::Symlink(fs::read_link(path)?))
+            }
+            Err(e) => Err(e),
+        },
+    }
+}
+
+fn classify_metadata(meta: Metadata) -> SourceKind {
+    if meta.is_dir() {
+        return SourceKind::Dir;
+    }
+    if meta.is_file() {
+        return SourceKind::File(SourceFile {
+            len: meta.len(),
+            modified: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            link_id: hard_link_id(&meta),
+        });
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let file_type = meta.file_type();
+        if file_type.is_fifo() {
+            return SourceKind::Special(EntryKind::Fifo);
+        } else if file_type.is_char_device() {
+            return SourceKind::Special(EntryKind::CharDevice { rdev: meta.rdev() });
+        } else if file_type.is_block_device() {
+            return SourceKind::Special(EntryKind::BlockDevice { rdev: meta.rdev() });
+        }
+    }
+    SourceKind::Special(EntryKind::Socket)
+}
+
+/// 源目录内有多个链接的文件的标识, 用于识别硬链接
+fn hard_link_id(meta: &Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if meta.nlink() > 1 {
+            return Some((meta.dev(), meta.ino()));
+        }
+    }
+    let _ = meta;
+    None
+}
+
+/// 按远程存储返回的信息判断远程源路径的类型
+/// 远程主机上的符号链接无法跟随, 除 Skip 策略外都保留为符号链接; 存储不支持读取的符号链接忽略
+fn classify_remote(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    meta: &StorageMeta,
+    policy: SymlinkPolicy,
+) -> Result<SourceKind, Error> {
+    Ok(match meta.kind {
+        StorageKind::Dir => SourceKind::Dir,
+        StorageKind::File => SourceKind::File(SourceFile {
+            len: meta.len,
+            modified: meta.modified,
+            link_id: None,
+        }),
+        StorageKind::Symlink if policy == SymlinkPolicy::Skip => SourceKind::Skipped,
+        StorageKind::Symlink => match storage.read_link(path) {
+            Ok(target) => SourceKind::Symlink(PathBuf::from(target)),
+            Err(e) if e.kind() == ErrorKind::Unsupported => SourceKind::Skipped,
+            Err(e) => return Err(e),
+        },
+    })
+}
+
+/// 按符号链接策略遍历源目录(不包括根目录本身), 对每个路径调用 visit
+/// 跟随符号链接时, 已访问过的目录会被跳过以避免循环
+/// 远程源目录通过其存储列出, 变动检测只需要列出的大小和修改时间, 不读取文件内容
+pub fn walk_source(
+    root: &SourceRoot,
+    policy: SymlinkPolicy,
+    mut visit: impl FnMut(&Path, &SourceKind) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let root_path = &root.path;
+    if let Some(storage) = &root.storage {
+        let mut directories = vec![root_path.clone()];
+        while let Some(dir) = directories.pop() {
+            for entry in storage.list(&dir)? {
+                let path = dir.join(&entry.name);
+                let kind = classify_remote(storage.as_ref(), &path, &entry.meta, policy)?;
+                if let SourceKind::Dir = kind {
+                    directories.push(path.clone());
+                }
+                visit(&path, &kind)?;
+            }
+        }
+        return Ok(());
+    }
+
+    let mut visited = HashSet::new();
+    if policy == SymlinkPolicy::Follow {
+        visited.insert(fs::canonicalize(root_path)?);
+    }
+
+    let mut directories = vec![root_path.to_path_buf()];
+    while let Some(dir) = directories.pop() {
+        for entry in read_dir(&dir)? {
+            let path = entry?.path();
+            let kind = classify(&path, policy)?;
+            if let SourceKind::Dir = kind {
+                if policy == SymlinkPolicy::Follow && !visited.insert(fs::canonicalize(&path)?) {
+                    warn!("检测到符号链接循环或重复目录, 跳过 {}", path.display());
+                    continue;
+                }
+                directories.push(path.clone());
+            }
+            visit(&path, &kind)?;
+        }
+    }
+    Ok(())
+}
+
+/// 一个源目录及其在备份中的位置
+pub struct SourceRoot {
+    /// 读取源文件的目录, 数据库及命令源为其本地缓存目录, 其他远程地址原样作为路径
+    pub path: PathBuf,
+    /// 在备份目录中的文件夹, 只有一个源目录时为空, 内容直接位于备份目录中
+    pub folder: PathBuf,
+    /// 远程源目录的存储, 以 path 下的路径直接访问; 本地源目录为 None
+    pub storage: Option<Arc<dyn StorageBackend>>,
+}
+
+/// 一次备份读取的所有源目录, 由 BackupConfig::source_set 生成
+/// 源路径按所在的源目录映射为备份目录中的相对路径
+pub struct SourceSet {
+    pub roots: Vec<SourceRoot>,
+}
+
+impl SourceSet {
+    /// 路径所在的源目录, 源目录互相包含时取最深的一个
+    fn root_of(&self, path: &Path) -> Option<&SourceRoot> {
+        self.roots
+            .iter()
+            .filter(|root| path.starts_with(&root.path))
+            .max_by_key(|root| root.path.components().count())
+    }
+
+    /// 路径所在的远程源目录的存储, 本地路径返回 None
+    pub fn storage(&self, path: &Path) -> Option<&dyn StorageBackend> {
+        self.root_of(path)?.storage.as_deref()
+    }
+
+    /// 按符号链接策略判断源路径的类型, 远程路径从其存储读取
+    pub fn classify(&self, path: &Path, policy: SymlinkPolicy) -> Result<SourceKind, Error> {
+        let Some(storage) = self.storage(path) else {
+            return classify(path, policy);
+        };
+        let meta = storage.stat(path)?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("远程源路径 {} 不存在", path.display()),
+            )
+        })?;
+        classify_remote(storage, path, &meta, policy)
+    }
+
+    /// 源文件内容的 sha256, 远程文件由其存储计算
+    pub fn digest(&self, path: &Path) -> Result<String, Error> {
+        match self.storage(path) {
+            Some(storage) => storage.digest(path),
+            None => sha256::try_digest(path),
+        }
+    }
+
+    /// 远程源文件下载到本地临时文件后返回, 离开作用域时删除; 本地源文件返回 None, 直接读取
+    /// 只有需要备份的文件才会下载
+    pub fn fetch(&self, path: &Path) -> Result<Option<SpoolFile>, Error> {
+        let Some(storage) = self.storage(path) else {
+            return Ok(None);
+        };
+        let spool = SpoolFile::create()?;
+        storage.copy_to(path, spool.path())?;
+        Ok(Some(spool))
+    }
+
+    /// 将源目录中的路径映射为备份目录中的相对路径, 不在任何源目录内时返回 None
+    pub fn relative(&self, path: &Path) -> Option<PathBuf> {
+        let root = self.root_of(path)?;
+        Some(root.folder.join(path.strip_prefix(&root.path).ok()?))
+    }
+
+    /// 路径在备份目录中的相对路径, 用作文件目录及清单的键
+    pub fn relative_key(&self, path: &Path) -> String {
+        self.relative(path)
+            .unwrap_or_else(|| path.to_path_buf())
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// 在变动的路径中补全位于源目录内的上级目录, 保证目标目录存在
+    /// 有多个源目录时源目录本身对应备份中的文件夹, 也一并补全
+    pub fn with_ancestors(&self, changed: &[String]) -> Vec<String> {
+        let mut path_list: Vec<String> = Vec::new();
+        for path in changed {
+            let Some(root) = self.root_of(Path::new(path)) else {
+                continue;
+            };
+            for ancestor in Path::new(path).ancestors() {
+                if !ancestor.starts_with(&root.path)
+                    || (ancestor == root.path && root.folder.as_os_str().is_empty())
+                {
+                    break;
+                }
+                path_list.push(ancestor.to_string_lossy().to_string());
+            }
+        }
+        path_list.sort();
+        path_list.dedup();
+        path_list
+    }
+}
 
-#[allow(unused)]
 /// 根据目标位置的源目录在目标位置创建所有目录
 /// 会将所有目录一一对应保留
-/// 不会复制权限
+/// 不会复制权限, 开启 preserve_metadata 时由清单(Manifest)记录并应用
 pub fn create_all_dir(
-    from_dir_list: &Vec<String>,
-    to_path_name: &PathBuf,
-    backup_title: &String,
+    storage: &dyn StorageBackend,
+    from_dir_list: &[String],
+    to_path_name: &Path,
+    sources: &SourceSet,
+    policy: SymlinkPolicy,
+    stats: &mut RunStats,
 ) -> Result<(), Error> {
     for path in from_dir_list.iter() {
-        if metadata(path)?.is_dir() {
-            let mut path_buf = to_path_name.clone();
-            let mut flag = false;
-            for component in Path::new(path).components() {
-                if flag {
-                    path_buf.push(component.as_os_str());
-                }
-                if component.as_os_str() == OsStr::new(backup_title) {
-                    flag = true;
-                }
+        if let SourceKind::Dir = sources.classify(Path::new(path), policy)? {
+            let Some(relative) = sources.relative(Path::new(path)) else {
+                continue;
+            };
+            let path_buf = to_path_name.join(relative);
+            if !storage.stat(&path_buf)?.is_some_and(|meta| meta.is_dir()) {
+                storage.mkdir(&path_buf)?;
+                stats.dirs_created += 1;
             }
-            fs::create_dir_all(&path_buf)?;
         }
     }
     Ok(())
 }
 
-#[allow(unused)]
-/// 将文件从源目录复制到目标位置
-/// 如果原文件不存在Result是文件不存在的err
+/// 复制文件时的一个备份目的地
+/// 复制成功的文件、清单及统计信息按目的地分别记录
+pub struct CopyTarget<'a> {
+    pub storage: &'a dyn StorageBackend,
+    /// 备份目录
+    pub backup_path: PathBuf,
+    pub compression: Compression,
+    /// 需要复制到此目的地的路径, 不填写时复制所有路径
+    pub paths: Option<HashSet<String>>,
+    pub manifest: Manifest,
+    pub stats: RunStats,
+    /// 复制成功的路径
+    pub copied: Vec<String>,
+    /// 已复制的有多个链接的文件, 用于在目的地中重建硬链接
+    hard_links: HashMap<(u64, u64), (PathBuf, String)>,
+}
+
+impl<'a> CopyTarget<'a> {
+    pub fn new(
+        storage: &'a dyn StorageBackend,
+        backup_path: PathBuf,
+        compression: Compression,
+        manifest: Manifest,
+        stats: RunStats,
+    ) -> Self {
+        CopyTarget {
+            storage,
+            backup_path,
+            compression,
+            paths: None,
+            manifest,
+            stats,
+            copied: Vec::new(),
+            hard_links: HashMap::new(),
+        }
+    }
+
+    fn wants(&self, path: &str) -> bool {
+        self.paths.as_ref().is_none_or(|paths| paths.contains(path))
+    }
+}
+
+/// 将文件从源目录复制到所有目标位置
+/// 每个源文件只读取一次, 同时写入所有需要该文件的目的地, 各目的地得到同一时刻的内容
+/// 单个文件复制失败时记录到对应目的地的统计中并继续复制其余文件
 /// 如果目标文件不存在会直接创建
 /// 目标文件存在会被直接覆盖
-/// 成功的Result是成功拷贝的MB
+/// 符号链接按策略处理; 同一批文件中的硬链接在目标位置也建立为硬链接;
+/// 命名管道和设备文件不读取内容, 只记录在清单中。这些路径的类型都会记录在清单中,
+/// 目标文件系统不支持链接时退回为复制, 还原时按清单重建
+/// 从源目录读取的字节数记录在 stats 中, 复制成功的文件及写入的字节数记录在各目的地中
 pub fn copy_file(
-    from_dir_list: &Vec<String>,
-    to_path_name: &PathBuf,
-    backup_title: &String,
-) -> Result<u64, Error> {
-    let mut size = 0;
+    from_dir_list: &[String],
+    targets: &mut [CopyTarget],
+    sources: &SourceSet,
+    policy: SymlinkPolicy,
+    stats: &mut RunStats,
+) -> Result<(), Error> {
     for path in from_dir_list.iter() {
-        if metadata(path)?.is_file() {
-            let mut path_buf = to_path_name.clone();
-            let mut flag = false;
-            for component in Path::new(path).components() {
-                if flag {
-                    path_buf.push(component.as_os_str());
+        let wanted: Vec<usize> = (0..targets.len())
+            .filter(|&i| targets[i].wants(path))
+            .collect();
+        if wanted.is_empty() {
+            continue;
+        }
+        let kind = match sources.classify(Path::new(path), policy) {
+            Ok(kind) => kind,
+            Err(e) => {
+                for &i in &wanted {
+                    targets[i].stats.record_failure(path, &e);
+                }
+                continue;
+            }
+        };
+        let Some(relative_path) = sources.relative(Path::new(path)) else {
+            continue;
+        };
+        let relative = relative_path.to_string_lossy().to_string();
+
+        match kind {
+            SourceKind::Dir | SourceKind::Skipped => {}
+            SourceKind::File(file) => {
+                let link_id = file.link_id;
+                let mut writes = Vec::new();
+                for &i in &wanted {
+                    let target = &mut targets[i];
+                    let path_buf = target.backup_path.join(&relative_path);
+                    let linked = link_id.and_then(|id| target.hard_links.get(&id));
+                    if let Some((first_path, first_relative)) = linked {
+                        target.manifest.record_kind(
+                            &relative,
+                            EntryKind::HardLink {
+                                target: first_relative.clone(),
+                            },
+                        );
+                        let _ = target.storage.delete(&path_buf);
+                        if target
+                            .storage
+                            .link(Link::Hard(first_path), &path_buf)
+                            .is_ok()
+                        {
+                            target.copied.push(path.clone());
+                            target.stats.files_copied += 1;
+                            continue;
+                        }
+                    }
+                    // 无法在备份目录中创建硬链接时复制内容, 清单中仍记录为硬链接, 还原时重建;
+                    // 只有源文件已不再是硬链接时才改为普通文件
+                    if linked.is_none() {
+                        if let Some(entry) = target.manifest.files.get_mut(&relative) {
+                            entry.kind = EntryKind::Regular;
+                        }
+                    }
+                    writes.push((i, path_buf));
                 }
-                if component.as_os_str() == OsStr::new(backup_title) {
-                    flag = true;
+                if writes.is_empty() {
+                    continue;
+                }
+                let outputs: Vec<(&dyn StorageBackend, &Path, Compression)> = writes
+                    .iter()
+                    .map(|(i, path_buf)| {
+                        (
+                            targets[*i].storage,
+                            path_buf.as_path(),
+                            targets[*i].compression,
+                        )
+                    })
+                    .collect();
+                let results = match sources.fetch(Path::new(path)).and_then(|spool| {
+                    let source = spool.as_ref().map_or(Path::new(path), |s| s.path());
+                    fan_out(source, &outputs)
+                }) {
+                    Ok((bytes, results)) => {
+                        stats.bytes_read += bytes;
+                        results
+                    }
+                    Err(e) => {
+                        for (i, _) in &writes {
+                            targets[*i].stats.record_failure(path, &e);
+                        }
+                        continue;
+                    }
+                };
+                for ((i, path_buf), result) in writes.into_iter().zip(results) {
+                    let target = &mut targets[i];
+                    match result {
+                        Ok(bytes) => {
+                            target.copied.push(path.clone());
+                            target.stats.files_copied += 1;
+                            target.stats.bytes_written += bytes;
+                            if let Some(id) = link_id {
+                                target
+                                    .hard_links
+                                    .entry(id)
+                                    .or_insert((path_buf, relative.clone()));
+                            }
+                        }
+                        Err(e) => target.stats.record_failure(path, &e),
+                    }
+                }
+            }
+            SourceKind::Symlink(link_to) => {
+                let link_to = link_to.to_string_lossy().to_string();
+                for &i in &wanted {
+                    let target = &mut targets[i];
+                    let path_buf = target.backup_path.join(&relative_path);
+                    if target
+                        .storage
+                        .stat(&path_buf)
+                        .is_ok_and(|meta| meta.is_some())
+                    {
+                        let _ = target.storage.delete(&path_buf);
+                    }
+                    if let Err(e) = target.storage.link(Link::Symbolic(&link_to), &path_buf) {
+                        log::debug!("无法在备份目录中创建符号链接 {}: {}", path_buf.display(), e);
+                    }
+                    target.manifest.record_kind(
+                        &relative,
+                        EntryKind::Symlink {
+                            target: link_to.clone(),
+                        },
+                    );
+                    target.copied.push(path.clone());
+                    target.stats.files_copied += 1;
+                }
+            }
+            SourceKind::Special(kind) => {
+                for &i in &wanted {
+                    targets[i].manifest.record_kind(&relative, kind.clone());
+                    targets[i].copied.push(path.clone());
                 }
             }
-            size += fs::copy(&path, &path_buf)?;
         }
     }
-    Ok(size / 1_048_576) // 将字节转换为MB
+    Ok(())
 }
 
-#[allow(unused)]
-/// 获取指定根目录内的所有路径（文件和目录）
-/// 返回整个目录的Vec<String>
-/// 包括所有文件夹及文件的名字
-pub fn get_all_path(root_path: &String) -> Result<Vec<String>, Error> {
-    let mut path_list = vec![root_path.clone()];
-    let mut start_index = 0;
-
-    loop {
-        let list_len = path_list.len();
-        for index in start_index..list_len {
-            let path = &path_list[index];
-            if metadata(path)?.is_dir() {
-                for child_dir in read_dir(path)? {
-                    path_list.push(child_dir?.path().to_string_lossy().to_string());
+/// 读取一次源文件并写入所有输出, 返回读取的字节数及每个输出写入的字节数
+/// 只有一个不压缩的输出时直接复制(同时复制权限位);
+/// 否则由每个输出的写入线程从通道中读取源文件的内容, 按需压缩后写入
+/// 读取源文件出错时返回错误, 某个输出写入出错不影响其他输出
+fn fan_out(
+    source: &Path,
+    outputs: &[(&dyn StorageBackend, &Path, Compression)],
+) -> Result<(u64, Vec<Result<u64, Error>>), Error> {
+    if let [(storage, path, Compression::None)] = outputs {
+        let bytes = storage.copy_from(source, path)?;
+        return Ok((bytes, vec![Ok(bytes)]));
+    }
+    let mut file = File::open(source)?;
+    thread::scope(|scope| {
+        let mut senders = Vec::new();
+        let mut handles = Vec::new();
+        for &(storage, path, compression) in outputs {
+            let (sender, receiver) = mpsc::sync_channel(4);
+            senders.push(sender);
+            handles.push(scope.spawn(move || {
+                let mut reader = compression.encoder(ChunkReader::new(receiver));
+                storage.write(path, &mut reader)
+            }));
+        }
+
+        let mut bytes_read = 0;
+        let mut read_result = Ok(());
+        loop {
+            let mut buf = vec![0; FAN_OUT_CHUNK_SIZE];
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    buf.truncate(n);
+                    bytes_read += n as u64;
+                    let chunk = Arc::new(buf);
+                    for sender in &senders {
+                        // 写入出错的线程已不再接收
+                        let _ = sender.send(Ok(chunk.clone()));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    for sender in &senders {
+                        let _ = sender.send(Err(Error::new(e.kind(), e.to_string())));
+                    }
+                    read_result = Err(e);
+                    break;
                 }
             }
         }
-        if list_len == start_index {
-            break;
+        drop(senders);
+        let results = handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(Error::other("写入备份目的地的线程异常退出")))
+            })
+            .collect();
+        read_result.map(|_| (bytes_read, results))
+    })
+}
+
+/// 从通道中依次读取源文件内容的 reader, 通道关闭即读取结束
+struct ChunkReader {
+    receiver: Receiver<Result<Arc<Vec<u8>>, Error>>,
+    chunk: Arc<Vec<u8>>,
+    offset: usize,
+}
+
+impl ChunkReader {
+    fn new(receiver: Receiver<Result<Arc<Vec<u8>>, Error>>) -> Self {
+        ChunkReader {
+            receiver,
+            chunk: Arc::new(Vec::new()),
+            offset: 0,
         }
-        start_index = list_len;
     }
-    Ok(path_list)
 }
 
-#[allow(unused)]
-/// 获取一定天数内修改的所有路径
-/// 根据日期返回整个目录的Vec<String>
-/// 包括所有文件夹及文件的名字
-pub fn get_all_path_by_day(
-    from_path: &String,
-    to_path: &String,
-    backup_title: &String,
-    day: usize,
-) -> Result<Vec<String>, Error> {
-    let save_day = Local::now() - Duration::days(day as i64);
-    let mut from_list = Vec::new();
-
-    let mut directories = vec![from_path.clone()];
-    while let Some(path) = directories.pop() {
-        for entry in read_dir(&path)? {
-            let entry = entry?;
-            let path_str = entry.path().to_string_lossy().to_string();
-            if entry.file_type()?.is_dir() {
-                directories.push(path_str.clone());
-                from_list.push(path_str);
-            } else if entry.file_type()?.is_file() {
-                let modified_time: DateTime<Local> = entry.metadata()?.modified()?.into();
-                if modified_time > save_day {
-                    from_list.push(path_str);
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.offset = 0;
                 }
+                Ok(Err(e)) => return Err(e),
+                Err(_) => return Ok(0),
             }
         }
+        let len = buf.len().min(self.chunk.len() - self.offset);
+        buf[..len].copy_from_slice(&self.chunk[self.offset..self.offset + len]);
+        self.offset += len;
+        Ok(len)
     }
+}
 
-    let mut to_list = Vec::new();
-    let mut directories = vec![to_path.clone()];
-    while let Some(path) = directories.pop() {
-        for entry in read_dir(&path)? {
-            let entry = entry?;
-            let path_str = entry.path().to_string_lossy().to_string();
-            if entry.file_type()?.is_dir() {
-                directories.push(path_str.clone());
-                to_list.push(path_str);
-            } else if entry.file_type()?.is_file() {
-                let modified_time: DateTime<Local> = entry.metadata()?.modified()?.into();
-                if modified_time > save_day {
-                    to_list.push(path_str);
+/// 获取所有源目录内的所有路径（文件和目录）
+/// 返回整个目录的Vec<String>
+/// 包括源目录本身及所有文件夹及文件的名字
+pub fn get_all_path(
+    sources: &SourceSet,
+    policy: SymlinkPolicy,
+    stats: &mut RunStats,
+) -> Result<Vec<String>, Error> {
+    let mut path_list = Vec::new();
+    for root in &sources.roots {
+        path_list.push(root.path.to_string_lossy().to_string());
+        walk_source(root, policy, |path, kind| {
+            match kind {
+                SourceKind::Dir => {}
+                SourceKind::Skipped => {
+                    stats.files_scanned += 1;
+                    stats.files_skipped += 1;
+                    return Ok(());
                 }
+                _ => stats.files_scanned += 1,
             }
-        }
+            path_list.push(path.to_string_lossy().to_string());
+            Ok(())
+        })?;
     }
+    Ok(path_list)
+}
 
-    let mut temp_list = Vec::new();
-    for path in &to_list {
-        let mut path_buf = PathBuf::from(from_path);
-        let mut flag = false;
-        for component in Path::new(path).components() {
-            if component.as_os_str() == OsStr::new(backup_title) || flag {
-                flag = true;
-                path_buf.push(component.as_os_str());
-            }
+/// 估算复制 path_list 需要写入的大小(普通文件大小之和)
+pub fn estimate_size(path_list: &[String], sources: &SourceSet, policy: SymlinkPolicy) -> u64 {
+    path_list
+        .iter()
+        .filter_map(|path| match sources.classify(Path::new(path), policy) {
+            Ok(SourceKind::File(file)) => Some(file.len),
+            _ => None,
+        })
+        .sum()
+}
+
+/// 按文件目录判断源路径是否有变动, 有变动时暂存新的记录等待 commit
+/// 普通文件只有在 since 之后修改的才会检查; 符号链接和特殊文件以其指向的路径或类型判断
+/// 目录总是返回 false
+pub fn check_changed(
+    storage: &dyn StorageBackend,
+    catalog: &mut Catalog,
+    sources: &SourceSet,
+    path: &Path,
+    kind: &SourceKind,
+    since: Option<DateTime<Local>>,
+) -> Result<bool, Error> {
+    let relative = sources.relative_key(path);
+    Ok(match kind {
+        SourceKind::Dir | SourceKind::Skipped => false,
+        SourceKind::File(file) => {
+            let modified_time: DateTime<Local> = file.modified.into();
+            since.is_none_or(|since| modified_time > since)
+                && catalog.check_file(storage, relative, file.len, modified_time, || {
+                    sources.digest(path)
+                })?
         }
-        temp_list.push(path_buf.to_string_lossy().to_string());
+        SourceKind::Symlink(target) => {
+            catalog.check_special(relative, &format!("Symlink:{}", target.display()))
+        }
+        SourceKind::Special(kind) => catalog.check_special(relative, &format!("{:?}", kind)),
+    })
+}
+
+/// 获取所有源目录中保存天数内修改、且相对文件目录有变动的文件及其上级目录
+/// catalogs 为各备份目的地的文件目录、保存天数及存储, 源目录只遍历一次, 按顺序返回各目的地的路径
+/// 符号链接和特殊文件以其指向的路径或类型判断是否变动
+/// 只遍历源目录, 不读取目标目录
+pub fn get_changed_paths(
+    sources: &SourceSet,
+    policy: SymlinkPolicy,
+    catalogs: &mut [(&mut Catalog, usize, &dyn StorageBackend)],
+    stats: &mut RunStats,
+) -> Result<Vec<Vec<String>>, Error> {
+    let save_days: Vec<DateTime<Local>> = catalogs
+        .iter()
+        .map(|(_, day, _)| Local::now() - Duration::days(*day as i64))
+        .collect();
+    let mut changed = vec![Vec::new(); catalogs.len()];
+
+    for root in &sources.roots {
+        walk_source(root, policy, |path, kind| {
+            if let SourceKind::Dir = kind {
+                return Ok(());
+            }
+            let mut is_changed = false;
+            for (i, (catalog, _, storage)) in catalogs.iter_mut().enumerate() {
+                if check_changed(*storage, catalog, sources, path, kind, Some(save_days[i]))? {
+                    changed[i].push(path.to_string_lossy().to_string());
+                    is_changed = true;
+                }
+            }
+            stats.files_scanned += 1;
+            if !is_changed {
+                stats.files_skipped += 1;
+            }
+            Ok(())
+        })?;
     }
 
-    let result_list = from_list
-        .into_iter()
-        .filter(|x| !temp_list.contains(x))
-        .collect::<Vec<String>>();
-    Ok(result_list)
+    Ok(changed
+        .iter()
+        .map(|changed| sources.with_ancestors(changed))
+        .collect())
 }
 
-#[allow(unused)]
 /// 获取或创建备份路径,返回可用的备份目录
 /// backup_name取config,
 /// current_version取hashs.len()
-pub fn get_backup_path(root_name: &String, backup_name: &String) -> Result<PathBuf, Error> {
+pub fn get_backup_path(
+    storage: &dyn StorageBackend,
+    root_name: &str,
+    backup_name: &str,
+) -> Result<PathBuf, Error> {
     let mut backup_path = get_backup_base_path(root_name);
     backup_path.push(backup_name);
 
-    fs::create_dir_all(&backup_path)?;
+    storage.mkdir(&backup_path)?;
     // println!("index  : {:#?}", index);
     // println!("get_backup_path 创建dir : {:#?}", backup_path);
     Ok(backup_path)
 }
 
-pub fn get_backup_path_by_version(
-    root_name: &String,
-    backup_name: &String,
-    current_version: &usize,
-    total_version: &usize,
-) -> Result<PathBuf, Error> {
-    let mut index = *current_version;
-    let mut backup_path = get_backup_base_path(root_name);
-    backup_path.push(backup_name);
+/// 删除保留策略之外的版本并更新版本索引, 返回剩余的版本数
+/// 预演时只记录到报告中, 不删除目录也不写入索引
+pub fn prune_versions(
+    storage: &dyn StorageBackend,
+    backup_root: &Path,
+    index: &mut VersionIndex,
+    policy: &RetentionPolicy,
+    preserve_version: usize,
+    report: &mut PruneReport,
+) -> Result<usize, Error> {
+    let times: Vec<DateTime<Local>> = index.versions.iter().map(|v| v.created_at).collect();
+    let reasons = policy.evaluate(&times, preserve_version);
 
-    loop {
-        backup_path.push(format!("bk_version_{}", index));
-        if !backup_path.is_dir() {
-            break;
+    let mut kept = Vec::new();
+    let mut result = Ok(());
+    for (entry, reason) in std::mem::take(&mut index.versions).into_iter().zip(reasons) {
+        if !reason.is_empty() || result.is_err() {
+            log::debug!("保留版本 {}:{:?}", entry.id, reason);
+            kept.push(entry);
+            continue;
         }
-        if index >= *total_version {
-            remove_first_version(root_name, backup_name, total_version)?;
-            index = *total_version;
-        } else {
-            index += 1;
+        let detail = format!(
+            "版本时间 {}, 不在保留策略内",
+            entry.created_at.format("%Y-%m-%d %T")
+        );
+        if let Err(e) = report.remove_recorded(
+            storage,
+            &VersionIndex::version_path(backup_root, &entry),
+            PruneRule::Retention,
+            detail,
+            TrashRecord::Version(entry.clone()),
+        ) {
+            kept.push(entry);
+            result = Err(e);
         }
-        backup_path.pop();
     }
-
-    fs::create_dir_all(&backup_path)?;
-
-    Ok(backup_path)
-}
-
-/// 删除最旧的备份版本并重命名后续版本
-/// backup_name：存储文件夹名
-/// version：应当保留版本数量
-/// 返回备份dir
-pub fn remove_first_version(
-    root_name: &String,
-    backup_name: &String,
-    version: &usize,
-) -> Result<PathBuf, Error> {
-    let mut path1 = get_backup_base_path(root_name);
-    path1.push(backup_name);
-
-    let old_version_path = path1.join(format!("bk_version_0"));
-    if old_version_path.is_dir() {
-        fs::remove_dir_all(&old_version_path)?;
+    index.versions = kept;
+    if !report.dry_run {
+        index.save(storage, backup_root)?;
     }
-
-    for index in 1..=*version {
-        let old_path = path1.join(format!("bk_version_{}", index));
-        let new_path = path1.join(format!("bk_version_{}", index - 1));
-        if old_path.is_dir() {
-            fs::rename(&old_path, &new_path)?;
-        }
-    }
-
-    Ok(path1.join(format!("bk_version_{}", *version - 1)))
+    result.map(|_| index.versions.len())
 }
 
 /// 删除指定根目录内的所有空目录
-pub fn delete_all_empty_dir(root_path: &String) -> Result<bool, Error> {
+/// 不跟随符号链接
+pub fn delete_all_empty_dir(storage: &dyn StorageBackend, root_path: &Path) -> Result<bool, Error> {
     let mut is_empty = true;
-    for entry in read_dir(root_path)? {
-        let path = entry?.path();
-        if metadata(&path)?.is_dir() {
-            if delete_all_empty_dir(&path.to_string_lossy().to_string())? {
-                fs::remove_dir_all(&path)?;
+    for entry in storage.list(root_path)? {
+        let path = root_path.join(&entry.name);
+        if entry.meta.is_dir() {
+            if delete_all_empty_dir(storage, &path)? {
+                storage.delete(&path)?;
             } else {
                 is_empty = false;
             }
@@ -245,46 +788,160 @@ pub fn delete_all_empty_dir(root_path: &String) -> Result<bool, Error> {
     Ok(is_empty)
 }
 
-/// 删除指定天数内未修改的文件
-pub fn delete_expired_file(root_path: &String, day: usize) -> Result<bool, Error> {
-    let save_day = Local::now() - Duration::days(day as i64);
-    let mut path_list = vec![root_path.clone()];
-    let mut start_index = 0;
-
-    loop {
-        let list_len = path_list.len();
-        for index in start_index..list_len {
-            let path = &path_list[index];
-            if metadata(path)?.is_dir() {
-                for child_dir in read_dir(path)? {
-                    path_list.push(child_dir?.path().to_string_lossy().to_string());
-                }
+/// 备份目的地的根目录
+/// 已存在的目录直接使用, 否则位于程序的 BackupConfig 目录下
+/// 远程地址原样作为路径, 由 remote::destination_storage 返回的存储转换为远程主机上的路径
+pub fn get_backup_base_path(root_name: &str) -> PathBuf {
+    if remote::is_remote(root_name) {
+        return PathBuf::from(root_name.trim_end_matches('/'));
+    }
+    let path = Path::new(root_name);
+    if path.is_dir() {
+        return path.to_path_buf();
+    }
+    let mut base_path = PathBuf::from("BackupConfig");
+    base_path.push(root_name);
+    base_path
+}
+
+/// 两个文件的内容是否相同, a 不存在时视为不同
+/// 用于数据库及命令输出等每次重新生成的源文件, 内容未变时保留原文件, 避免产生新的版本
+pub fn same_content(a: &Path, b: &Path) -> Result<bool, Error> {
+    if !a.exists() || fs::metadata(a)?.len() != fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    Ok(sha256::try_digest(a)? == sha256::try_digest(b)?)
+}
+
+/// 读取源文件的根目录
+/// 数据库地址使用其本地缓存目录, 由 remote::pull_source 在备份前导出;
+/// 其他远程地址原样作为路径, 由 remote::source_storage 返回的存储直接读取
+pub fn get_source_path(root_name: &str) -> String {
+    if database::is_database(root_name) {
+        return remote::cache_path(root_name).to_string_lossy().to_string();
+    }
+    if remote::is_remote(root_name) {
+        return root_name.trim_end_matches('/').to_string();
+    }
+    root_name.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::storage::{MemoryStorage, StorageEntry};
+
+    /// 不支持硬链接的存储, 其余操作交给内存存储
+    #[derive(Debug, Default)]
+    struct NoHardLinks(MemoryStorage);
+
+    impl StorageBackend for NoHardLinks {
+        fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+            self.0.list(path)
+        }
+        fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+            self.0.stat(path)
+        }
+        fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+            self.0.read(path)
+        }
+        fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+            self.0.write(path, reader)
+        }
+        fn mkdir(&self, path: &Path) -> Result<(), Error> {
+            self.0.mkdir(path)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+            self.0.rename(from, to)
+        }
+        fn delete(&self, path: &Path) -> Result<(), Error> {
+            self.0.delete(path)
+        }
+        fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+            match link {
+                Link::Hard(_) => Err(Error::new(ErrorKind::Unsupported, "不支持硬链接")),
+                Link::Symbolic(_) => self.0.link(link, path),
             }
         }
-        if list_len == start_index {
-            break;
+        fn read_link(&self, path: &Path) -> Result<String, Error> {
+            self.0.read_link(path)
         }
-        start_index = list_len;
     }
 
-    for path in path_list.iter() {
-        if metadata(path)?.is_file() {
-            let modified_time: DateTime<Local> = metadata(path)?.modified()?.into();
-            if save_day > modified_time {
-                fs::remove_file(path)?;
-            }
-        }
+    fn copy(source: &Path, storage: &dyn StorageBackend, manifest: Manifest) -> Manifest {
+        let sources = SourceSet {
+            roots: vec![SourceRoot {
+                path: source.to_path_buf(),
+                folder: PathBuf::new(),
+                storage: None,
+            }],
+        };
+        let mut stats = RunStats::start("task");
+        let path_list = get_all_path(&sources, SymlinkPolicy::Preserve, &mut stats).unwrap();
+        let backup_path = PathBuf::from("/backup");
+        storage.mkdir(&backup_path).unwrap();
+        let mut targets = vec![CopyTarget::new(
+            storage,
+            backup_path,
+            Compression::None,
+            manifest,
+            RunStats::start("task"),
+        )];
+        copy_file(
+            &path_list,
+            &mut targets,
+            &sources,
+            SymlinkPolicy::Preserve,
+            &mut stats,
+        )
+        .unwrap();
+        assert_eq!(targets[0].stats.files_failed, 0);
+        targets.pop().unwrap().manifest
     }
 
-    Ok(true)
-}
+    #[test]
+    fn hard_link_stays_in_manifest_when_copied() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), b"shared").unwrap();
+        fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+        let storage = NoHardLinks::default();
 
-fn get_backup_base_path(root_name: &String) -> PathBuf {
-    let path = Path::new(root_name);
-    if path.is_dir() {
-        return path.to_path_buf();
+        // 上次备份已记录为硬链接, 本次无法创建硬链接时仍应保留记录
+        let mut manifest = Manifest::default();
+        manifest.record_kind(
+            "b",
+            EntryKind::HardLink {
+                target: "a".to_string(),
+            },
+        );
+        let manifest = copy(dir.path(), &storage, manifest);
+        // 先复制的文件为普通文件, 另一个记录为指向它的硬链接
+        let kind = |relative: &str| manifest.files.get(relative).map(|m| m.kind.clone());
+        let linked = [("a", "b"), ("b", "a")].iter().any(|(first, second)| {
+            kind(first).is_none_or(|k| k.is_regular())
+                && kind(second)
+                    == Some(EntryKind::HardLink {
+                        target: first.to_string(),
+                    })
+        });
+        assert!(linked, "{:?}", manifest.files);
+        assert_eq!(storage.read(Path::new("/backup/a")).unwrap(), b"shared");
+        assert_eq!(storage.read(Path::new("/backup/b")).unwrap(), b"shared");
+    }
+
+    #[test]
+    fn former_hard_link_becomes_regular() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), b"one").unwrap();
+        fs::write(dir.path().join("b"), b"two").unwrap();
+        let mut manifest = Manifest::default();
+        manifest.record_kind(
+            "b",
+            EntryKind::HardLink {
+                target: "a".to_string(),
+            },
+        );
+        let manifest = copy(dir.path(), &NoHardLinks::default(), manifest);
+        assert!(manifest.files.get("b").is_none_or(|m| m.kind.is_regular()));
     }
-    let mut base_path = PathBuf::from("BackupConfig");
-    base_path.push(root_name);
-    base_path
 }
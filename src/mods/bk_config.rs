@@ -1,14 +1,28 @@
+use super::base_bk_option::{self, SourceKind, SourceRoot, SourceSet};
+use super::command_source;
+use super::database::{self, DatabaseConfig};
+use super::hooks::HooksConfig;
+use super::network_interface_operate::AirGapConfig;
+use super::quota::QuotaConfig;
+use super::remote;
+use super::retention::RetentionPolicy;
+use super::s3::S3Config;
+use super::sftp::SftpConfig;
+use super::smb::SmbConfig;
+use super::snapshot::SnapshotConfig;
+use super::trash::TrashConfig;
+use super::webdav::WebDavConfig;
 use chrono::{DateTime, Local};
 use log::error;
 use serde::{Deserialize, Serialize};
 use sha256;
 use std::collections::HashMap;
-use std::io::{Error, Read};
+use std::io::{Error, ErrorKind, Read};
 use std::sync::Mutex;
 use std::{
-    fs::{metadata, read_dir, File, OpenOptions},
+    fs::{File, OpenOptions},
     io::Write,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 lazy_static::lazy_static! {
@@ -21,31 +35,281 @@ pub enum BackupMode {
     IncrementalMode {
         /// 表示一个备份任务应当保留几天
         save_days: usize,
+        /// 文件目录每隔几天与备份目录完整核对一次
+        #[serde(default = "default_catalog_reconcile_days")]
+        catalog_reconcile_days: usize,
     },
     VersionMode {
         /// # 初始值 backup_hashs: []
         backup_hashs: Vec<String>,
         /// 表示一个备份任务应当保留几个版本
+        /// 配置了 retention 时为保留最近几个版本的默认值
         preserve_version: usize,
+        /// 按版本时间的 GFS 保留策略, 不填写时只保留最近 preserve_version 个版本
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retention: Option<RetentionPolicy>,
     },
+    /// 复制任务: 将另一个版本控制模式任务已完成的版本复制到本任务的备份目的地, 不读取源目录
+    ReplicateMode {
+        /// 被复制的任务名(配置文件名)
+        task: String,
+        /// 被复制任务的第几个备份目的地, 从 0 开始, 顺序同 BackupConfig::destinations
+        #[serde(default)]
+        destination: usize,
+        /// 本任务的备份目的地保留几个版本
+        preserve_version: usize,
+        /// 本任务的备份目的地的保留策略, 不填写时只保留最近 preserve_version 个版本
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retention: Option<RetentionPolicy>,
+    },
+}
+
+/// 符号链接的处理策略
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// 将符号链接本身备份为符号链接, 不读取其指向的内容
+    #[default]
+    Preserve,
+    /// 备份符号链接指向的内容, 已访问过的目录会被跳过以避免循环
+    Follow,
+    /// 忽略符号链接
+    Skip,
+}
+
+/// 文件监听模式配置
+/// 仅支持 Linux 及本地源目录的增量备份任务, 由 inotify 收集变动的路径并在变动平息后备份这些路径
+/// 版本控制和复制模式每次备份都生成完整的副本, 由变动触发会在频繁修改时不断全量备份, 因此仍按间隔时间轮询
+/// 启用后不再按间隔时间轮询计算hash, 间隔时间仅用于定期完整扫描
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WatchConfig {
+    /// 最后一次变动后等待的秒数, 期间没有新的变动才开始备份
+    pub debounce_seconds: u64,
+}
+
+/// 备份目的地中文件内容的压缩方式
+/// 压缩后的文件与源文件同名, 压缩方式记录在备份目录的清单中, 还原时自动解压
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl Compression {
+    /// 读取时压缩的 reader, 不压缩时原样返回
+    pub fn encoder<'a>(&self, reader: impl Read + Send + 'a) -> Box<dyn Read + Send + 'a> {
+        match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::GzEncoder::new(
+                reader,
+                flate2::Compression::default(),
+            )),
+        }
+    }
+
+    /// 读取时解压的 reader, 不压缩时原样返回
+    pub fn decoder<'a>(&self, reader: impl Read + Send + 'a) -> Box<dyn Read + Send + 'a> {
+        match self {
+            Compression::None => Box::new(reader),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(reader)),
+        }
+    }
+}
+
+/// 一个备份目的地
+/// 同一任务的所有目的地共用一次源目录的读取, 各自保存备份并分别记录结果
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DestinationConfig {
+    /// 备份目的地, 格式与 backup_destination_path 相同
+    pub path: String,
+    /// 版本控制模式及复制任务的保留策略, 不填写时使用任务的 preserve_version 及 retention
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+    /// 增量备份模式的保存天数, 不填写时使用任务的 save_days
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub save_days: Option<usize>,
+    /// 文件内容的压缩方式, 默认不压缩
+    #[serde(default)]
+    pub compression: Compression,
+    /// 相对任务备份时间推迟的分钟数, 用于错开各目的地的写入时间
+    /// 到达时间的目的地一起备份, 未到达的等待下一次检查
+    #[serde(default)]
+    pub schedule_offset_minutes: usize,
+    /// 该目的地的空间限额, 不填写时使用任务的 quota
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaConfig>,
+}
+
+impl DestinationConfig {
+    fn new(path: &str) -> Self {
+        DestinationConfig {
+            path: path.to_string(),
+            retention: None,
+            save_days: None,
+            compression: Compression::None,
+            schedule_offset_minutes: 0,
+            quota: None,
+        }
+    }
+}
+
+/// 一个源目录
+/// 同一任务的所有源目录在一次备份中读取, 版本控制模式下共同组成一个版本
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SourceConfig {
+    /// 源目录, 格式与 backup_source_path 相同, 填写 command 时不填写
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    /// 命令源: 执行的命令及参数, 其标准输出作为一个文件备份
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// 命令源的输出在备份中的文件名, 不填写时为 {命令名}.out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    /// 命令源的超时秒数, 不填写时为 3600
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_seconds: Option<u64>,
+    /// 在备份中使用的文件夹名称, 不填写时取源目录的最后一个路径段, 命令源取命令名
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+impl SourceConfig {
+    fn new(path: &str) -> Self {
+        SourceConfig {
+            path: path.to_string(),
+            command: Vec::new(),
+            file_name: None,
+            timeout_seconds: None,
+            alias: None,
+        }
+    }
+
+    /// 是否为命令源
+    pub fn is_command(&self) -> bool {
+        !self.command.is_empty()
+    }
+
+    /// 用于日志及钩子命令的名称, 命令源为命令及参数, 地址中的密码替换为 ***
+    pub fn label(&self) -> String {
+        if self.is_command() {
+            self.command.join(" ")
+        } else {
+            database::redact(&self.path)
+        }
+    }
+
+    /// 命令名, 不含目录
+    fn program_name(&self) -> Option<String> {
+        self.command.first().and_then(|program| {
+            Path::new(program)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+        })
+    }
+
+    /// 命令源的输出在备份中的文件名
+    pub fn output_file_name(&self) -> String {
+        self.file_name
+            .clone()
+            .unwrap_or_else(|| format!("{}.out", self.program_name().unwrap_or_default()))
+    }
+
+    /// 自动识别 source_path 中的路径标题
+    pub fn detect_path_title(&self) -> Option<String> {
+        if self.is_command() {
+            return self.program_name();
+        }
+        // 通过分隔符 '/' 或 '\\' 获取最后一个路径段
+        self.path
+            .rfind('/')
+            .or_else(|| self.path.rfind('\\'))
+            .map(|sep_pos| self.path[(sep_pos + 1)..].to_string())
+    }
+
+    /// 在备份中使用的文件夹名称
+    pub fn folder(&self) -> String {
+        self.alias
+            .clone()
+            .unwrap_or_else(|| self.detect_path_title().unwrap_or_default())
+    }
+}
+
+fn default_catalog_reconcile_days() -> usize {
+    7
 }
 
 ///读取hash
 ///取 {path_name}_hash.yaml
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BackupConfig {
-    /// 备份目的地
+    /// 备份目的地, 填写 destinations 时可以不填写
     /// 输入相对路径则在程序目录下Backup目录
     /// 输入绝对路径则根据绝对目录
     /// 目录不存在时自动创建
+    /// 也可以填写 sftp://user@host:port/path、smb://domain;user@host/share/path、
+    /// webdav(s)://host:port/path 或 s3://bucket/prefix,
+    /// 备份直接写入远程主机或对象存储, 本地不保留副本
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub backup_destination_path: String,
-    /// 需备份根目录
+    /// 其他备份目的地, 与 backup_destination_path 一起使用同一次读取的源目录
+    /// 每个目的地可以单独设置保留策略、压缩方式及备份时间的偏移
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<DestinationConfig>,
+    /// 需备份根目录, 填写 sources 时可以不填写
+    /// 也可以填写 sftp://user@host:port/path 或 smb://domain;user@host/share/path,
+    /// 直接列出远程主机上的文件判断变动, 只读取需要备份的文件
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub backup_source_path: String,
+    /// 其他源目录, 与 backup_source_path 在同一次备份中读取
+    /// 有多个源目录时, 各源目录位于备份中以其别名命名的文件夹内
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceConfig>,
     /// 每次备份的间隔时间(分钟)
     pub backup_interval_minutes: usize,
     /// 首次备份的时间(mm:ss)
     pub initial_backup_time: String,
     pub is_effect: bool,
+    /// 是否保留文件元数据(权限、所有者、时间、扩展属性和 ACL)
+    /// 元数据记录在备份目录的清单中, 还原时重新应用
+    #[serde(default)]
+    pub preserve_metadata: bool,
+    /// 符号链接的处理策略, 默认保留为符号链接
+    #[serde(default)]
+    pub symlink_policy: SymlinkPolicy,
+    /// 文件监听模式, 不填写时按间隔时间轮询
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watch: Option<WatchConfig>,
+    /// 备份空间限额, 不填写时不限制
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<QuotaConfig>,
+    /// 回收站, 不填写时按保留策略删除的文件直接删除
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash: Option<TrashConfig>,
+    /// sftp:// 地址的连接配置, 不填写时使用 ssh-agent 及默认的重试设置
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sftp: Option<SftpConfig>,
+    /// s3:// 地址的连接配置, 使用 s3:// 地址时必须填写
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3: Option<S3Config>,
+    /// smb:// 地址的连接配置, 使用 smb:// 地址时必须填写
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smb: Option<SmbConfig>,
+    /// webdav:// 及 webdavs:// 地址的连接配置, 不填写时不认证
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webdav: Option<WebDavConfig>,
+    /// postgres://、mysql:// 及 sqlite:// 数据库源的配置, 不填写时使用默认的导出命令
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database: Option<DatabaseConfig>,
+    /// 备份前后执行的命令, 不填写时不执行
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hooks: Option<HooksConfig>,
+    /// 网络隔离模式, 仅在备份期间开启指定的网卡, 不填写时不改变网卡状态
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub air_gap: Option<AirGapConfig>,
+    /// 文件系统快照, 从 btrfs、LVM 或 zfs 快照中读取本地源目录, 不填写时直接读取源目录
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<SnapshotConfig>,
     /// 备份模式
     ///
     /// 1:增量备份模式
@@ -61,7 +325,7 @@ impl BackupConfig {
         file.read_to_string(&mut buf)?;
         serde_yaml::from_str(&buf).map_err(|e| {
             error!("读取配置文件时发生错误: {:?}", e);
-            Error::new(std::io::ErrorKind::Other, "读取配置文件时发生错误")
+            Error::other("读取配置文件时发生错误")
         })
     }
 
@@ -73,86 +337,393 @@ impl BackupConfig {
     }
 
     ///整个目录取Hash
-    pub fn get_hash(root_path: &str) -> Result<String, Error> {
-        let mut path_list = vec![root_path.to_string()];
+    ///符号链接按策略处理, 链接指向的路径及特殊文件的类型也计入Hash
+    ///有多个源目录时, 由各源目录的文件夹名称及Hash共同计算
+    pub fn get_hash(sources: &SourceSet, policy: SymlinkPolicy) -> Result<String, Error> {
+        if let [root] = sources.roots.as_slice() {
+            return Self::get_root_hash(root, policy);
+        }
+        let mut hashs = Vec::new();
+        for root in &sources.roots {
+            hashs.push(format!(
+                "{}:{}",
+                root.folder.display(),
+                Self::get_root_hash(root, policy)?
+            ));
+        }
+        Ok(sha256::digest(hashs.join("\n")))
+    }
+
+    fn get_root_hash(root: &SourceRoot, policy: SymlinkPolicy) -> Result<String, Error> {
         let mut modified_list = Vec::new();
 
-        while let Some(path) = path_list.pop() {
-            if let Ok(metadata) = metadata(&path) {
-                if metadata.is_dir() {
-                    for entry in read_dir(&path)? {
-                        let child_path = entry?.path().to_string_lossy().to_string();
-                        path_list.push(child_path.clone());
-                    }
-                } else if metadata.is_file() {
-                    let modified_time: DateTime<Local> = metadata.modified()?.into();
+        base_bk_option::walk_source(root, policy, |_, kind| {
+            match kind {
+                SourceKind::File(file) => {
+                    let modified_time: DateTime<Local> = file.modified.into();
                     modified_list.push(modified_time.format("%Y-%m-%d %T").to_string());
                 }
+                SourceKind::Symlink(target) => {
+                    modified_list.push(format!("->{}", target.display()))
+                }
+                SourceKind::Special(kind) => modified_list.push(format!("{:?}", kind)),
+                SourceKind::Dir | SourceKind::Skipped => {}
             }
-        }
+            Ok(())
+        })?;
 
-        let mut hash_str = path_list.join("");
-        hash_str += &modified_list.join("");
-        Ok(sha256::digest(hash_str))
+        Ok(sha256::digest(modified_list.join("")))
     }
 
     //写入Hash
-    pub fn set_hash(&mut self, yaml_name: &str, hash: &str) -> Result<(), Error> {
+    //只保留最近 keep 个版本的hash, 与备份目录中现存的版本数一致
+    pub fn set_hash(&mut self, yaml_name: &str, hash: &str, keep: usize) -> Result<(), Error> {
         let hash_path = BackupConfig::get_hash_path(yaml_name);
 
-        // Open the file in append mode to avoid overwriting
-        let mut file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&hash_path)?;
-
-        if let BackupMode::VersionMode {
-            backup_hashs,
-            preserve_version,
-        } = &mut self.options
-        {
+        if let BackupMode::VersionMode { backup_hashs, .. } = &mut self.options {
             if !backup_hashs.contains(&hash.to_string()) {
-                if backup_hashs.len() == *preserve_version {
-                    backup_hashs.remove(0);
-                }
                 backup_hashs.push(hash.to_string());
+                let excess = backup_hashs.len().saturating_sub(keep.max(1));
+                backup_hashs.drain(..excess);
 
                 // Serialize the struct to YAML
                 let yaml_str = match serde_yaml::to_string(self) {
                     Ok(s) => s,
                     Err(e) => {
-                        return Err(Error::new(
-                            std::io::ErrorKind::Other,
-                            format!("Failed to serialize BackupConfig to YAML: {:?}", e),
-                        ));
+                        return Err(Error::other(format!(
+                            "Failed to serialize BackupConfig to YAML: {:?}",
+                            e
+                        )));
                     }
                 };
 
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .truncate(true)
+                    .open(&hash_path)?;
                 file.write_all(yaml_str.as_bytes())?;
                 Ok(())
             } else {
-                Err(Error::new(
-                    std::io::ErrorKind::Other,
-                    "已存在此Hash,无需进行写入",
-                ))
+                Err(Error::other("已存在此Hash,无需进行写入"))
             }
         } else {
-            Err(Error::new(
-                std::io::ErrorKind::Other,
-                "动态模式不需要写入Hash",
-            ))
+            Err(Error::other("动态模式不需要写入Hash"))
         }
     }
 
-    /// 自动识别 source_path 中的路径标题
-    pub fn detect_path_title(&self) -> Option<String> {
-        // 通过分隔符 '/' 或 '\\' 获取最后一个路径段
-        if let Some(sep_pos) = self.backup_source_path.rfind('/') {
-            Some(self.backup_source_path[(sep_pos + 1)..].to_string())
-        } else if let Some(sep_pos) = self.backup_source_path.rfind('\\') {
-            Some(self.backup_source_path[(sep_pos + 1)..].to_string())
+    /// 任务的所有备份目的地, backup_destination_path 在前, 其后为 destinations
+    pub fn destinations(&self) -> Vec<DestinationConfig> {
+        let mut destinations = Vec::new();
+        if !self.backup_destination_path.is_empty() {
+            destinations.push(DestinationConfig::new(&self.backup_destination_path));
+        }
+        destinations.extend(self.destinations.iter().cloned());
+        destinations
+    }
+
+    /// 单个备份目的地的任务配置
+    /// backup_destination_path 替换为该目的地, 并应用其保留策略或保存天数及空间限额,
+    /// 远程同步、回收站、空间限额等按目的地处理的功能都使用此配置
+    pub fn for_destination(&self, destination: &DestinationConfig) -> BackupConfig {
+        let mut config = self.clone();
+        config.backup_destination_path = destination.path.clone();
+        config.destinations = Vec::new();
+        if destination.quota.is_some() {
+            config.quota = destination.quota.clone();
+        }
+        match &mut config.options {
+            BackupMode::IncrementalMode { save_days, .. } => {
+                if let Some(days) = destination.save_days {
+                    *save_days = days;
+                }
+            }
+            BackupMode::VersionMode { retention, .. }
+            | BackupMode::ReplicateMode { retention, .. } => {
+                if destination.retention.is_some() {
+                    *retention = destination.retention.clone();
+                }
+            }
+        }
+        config
+    }
+
+    /// 所有备份目的地的任务配置, 没有填写任何目的地时返回错误
+    pub fn destination_configs(&self) -> Result<Vec<BackupConfig>, Error> {
+        let configs: Vec<BackupConfig> = self
+            .destinations()
+            .iter()
+            .map(|destination| self.for_destination(destination))
+            .collect();
+        if configs.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "未填写备份目的地"));
+        }
+        Ok(configs)
+    }
+
+    /// 第 index 个备份目的地的标识, 用于区分各目的地的文件目录及备份时间
+    /// 第一个目的地直接使用任务名, 与只有一个目的地时一致
+    pub fn destination_key(task_name: &str, index: usize) -> String {
+        if index == 0 {
+            task_name.to_string()
         } else {
-            None
+            format!("{}@{}", task_name, index)
+        }
+    }
+
+    /// 任务的所有源目录, backup_source_path 在前, 其后为 sources
+    pub fn sources(&self) -> Vec<SourceConfig> {
+        let mut sources = Vec::new();
+        if !self.backup_source_path.is_empty() {
+            sources.push(SourceConfig::new(&self.backup_source_path));
         }
+        sources.extend(self.sources.iter().cloned());
+        sources
+    }
+
+    /// 任务不支持文件监听模式的原因, 支持时返回 None
+    pub fn watch_unsupported(&self) -> Option<&'static str> {
+        match self.options {
+            BackupMode::IncrementalMode { .. } => {}
+            BackupMode::VersionMode { .. } => return Some("版本控制任务不支持文件监听模式"),
+            BackupMode::ReplicateMode { .. } => return Some("复制任务不支持文件监听模式"),
+        }
+        if self
+            .sources()
+            .iter()
+            .any(|s| remote::is_remote(&s.path) || s.is_command())
+        {
+            return Some("远程源目录、数据库及命令源不支持文件监听模式");
+        }
+        None
+    }
+
+    /// 备份目的地中本任务的备份目录名称
+    /// 只有一个源目录时取其文件夹名称(别名或最后一个路径段), 有多个源目录时取任务名
+    pub fn backup_title(&self, task_name: &str) -> String {
+        match self.sources().as_slice() {
+            [source] => source.folder(),
+            _ => task_name.to_string(),
+        }
+    }
+
+    /// 本次备份读取的源目录, 数据库及命令源使用其本地缓存目录, 其他远程地址通过其存储直接读取
+    /// 有多个源目录时, 文件夹名称不能为空或重复
+    pub fn source_set(&self) -> Result<SourceSet, Error> {
+        let sources = self.sources();
+        if sources.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "未填写源目录"));
+        }
+        let single = sources.len() == 1;
+        let mut roots: Vec<SourceRoot> = Vec::new();
+        for source in &sources {
+            if source.path.is_empty() == source.command.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("源 {} 需要填写 path 或 command 中的一个", source.label()),
+                ));
+            }
+            if source.is_command() {
+                let file_name = source.output_file_name();
+                if matches!(file_name.as_str(), "" | "." | ".." | ".out")
+                    || file_name.contains(['/', '\\'])
+                {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("命令 {} 的 file_name 无效", source.label()),
+                    ));
+                }
+            }
+            let folder = if single {
+                PathBuf::new()
+            } else {
+                let folder = source.folder();
+                let invalid = matches!(folder.as_str(), "" | "." | "..")
+                    || folder.contains(['/', '\\'])
+                    || roots.iter().any(|r| r.folder == Path::new(&folder));
+                if invalid {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "源目录 {} 的文件夹名称无效或重复, 请填写 alias",
+                            source.label()
+                        ),
+                    ));
+                }
+                PathBuf::from(folder)
+            };
+            let (path, storage) = if source.is_command() {
+                (command_source::cache_path(source), None)
+            } else if remote::is_remote(&source.path) && !database::is_database(&source.path) {
+                (
+                    PathBuf::from(base_bk_option::get_source_path(&source.path)),
+                    Some(remote::source_storage(&source.path, self)?),
+                )
+            } else {
+                (
+                    PathBuf::from(base_bk_option::get_source_path(&source.path)),
+                    None,
+                )
+            };
+            roots.push(SourceRoot {
+                path,
+                folder,
+                storage,
+            });
+        }
+        Ok(SourceSet { roots })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::quota::ByteSize;
+
+    fn config(yaml: &str) -> BackupConfig {
+        let base = "backup_destination_path: /backup\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\nwatch:\n  debounce_seconds: 5\n";
+        serde_yaml::from_str(&format!("{}{}", base, yaml)).unwrap()
+    }
+
+    #[test]
+    fn only_local_incremental_tasks_can_be_watched() {
+        let incremental = "options:\n  mode: IncrementalMode\n  save_days: 3\n";
+        let local = config(&format!("backup_source_path: /data\n{}", incremental));
+        assert_eq!(local.watch_unsupported(), None);
+
+        let version = config(
+            "backup_source_path: /data\noptions:\n  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 3\n",
+        );
+        assert!(version.watch_unsupported().is_some());
+        let replicate = config(
+            "backup_source_path: \"\"\noptions:\n  mode: ReplicateMode\n  task: other\n  preserve_version: 3\n",
+        );
+        assert!(replicate.watch_unsupported().is_some());
+
+        let remote = config(&format!(
+            "backup_source_path: sftp://host/data\n{}",
+            incremental
+        ));
+        assert!(remote.watch_unsupported().is_some());
+        let command = config(&format!(
+            "backup_source_path: /data\nsources:\n  - command: [date]\n{}",
+            incremental
+        ));
+        assert!(command.watch_unsupported().is_some());
+    }
+
+    #[test]
+    fn destination_quota_overrides_task_quota() {
+        let task = config(
+            "backup_source_path: /data
+quota:
+  max_task_size: 10GiB
+destinations:
+  - path: /small
+    quota:
+      min_free_space: 1GiB
+  - path: /other
+options:
+  mode: VersionMode
+  backup_hashs: []
+  preserve_version: 3
+",
+        );
+        let configs = task.destination_configs().unwrap();
+        let quota = |i: usize| configs[i].quota.clone().unwrap();
+        assert_eq!(quota(0).max_task_size, Some(ByteSize(10 << 30)));
+        assert_eq!(quota(1).max_task_size, None);
+        assert_eq!(quota(1).min_free_space, Some(ByteSize(1 << 30)));
+        assert_eq!(quota(2).max_task_size, Some(ByteSize(10 << 30)));
+    }
+
+    /// 有多个源目录时文件夹名称(别名或最后一个路径段)不能为空、包含分隔符或重复
+    #[test]
+    fn source_aliases_are_validated() {
+        let incremental = "options:\n  mode: IncrementalMode\n  save_days: 3\n";
+        let sources = |entries: &str| config(&format!("sources:\n{}{}", entries, incremental));
+
+        let duplicate = sources("  - path: /a/data\n  - path: /b/data\n");
+        let e = duplicate.source_set().err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("/b/data"), "{}", e);
+
+        let aliased = sources("  - path: /a/data\n  - path: /b/data\n    alias: other\n");
+        let set = aliased.source_set().unwrap();
+        let folders: Vec<&Path> = set.roots.iter().map(|r| r.folder.as_path()).collect();
+        assert_eq!(folders, vec![Path::new("data"), Path::new("other")]);
+        assert_eq!(aliased.backup_title("task"), "task");
+
+        for alias in ["\"\"", "..", "a/b", "\"a\\\\b\"", "data"] {
+            let invalid = sources(&format!(
+                "  - path: /a/data\n  - path: /b/data\n    alias: {}\n",
+                alias
+            ));
+            assert!(invalid.source_set().is_err(), "alias {}", alias);
+        }
+        // 别名与命令源的默认名称重复
+        let command = sources("  - path: /a/date\n  - command: [/bin/date]\n");
+        assert!(command.source_set().is_err());
+
+        // 只有一个源目录时直接使用备份目录, 备份目录名称取别名
+        let single = sources("  - path: /a/data\n    alias: renamed\n");
+        assert_eq!(single.source_set().unwrap().roots[0].folder, PathBuf::new());
+        assert_eq!(single.backup_title("task"), "renamed");
+        assert_eq!(
+            config(&format!("backup_source_path: /a/data\n{}", incremental)).backup_title("task"),
+            "data"
+        );
+    }
+
+    /// 目的地的保存天数及保留策略只作用于该目的地, 未填写的目的地使用任务的配置
+    #[test]
+    fn destination_overrides_apply_per_destination() {
+        let incremental = config(
+            "backup_source_path: /data
+destinations:
+  - path: /offsite
+    save_days: 30
+  - path: /other
+options:
+  mode: IncrementalMode
+  save_days: 3
+",
+        );
+        let configs = incremental.destination_configs().unwrap();
+        let save_days = |config: &BackupConfig| match config.options {
+            BackupMode::IncrementalMode { save_days, .. } => save_days,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            configs
+                .iter()
+                .map(|c| (c.backup_destination_path.as_str(), save_days(c)))
+                .collect::<Vec<_>>(),
+            vec![("/backup", 3), ("/offsite", 30), ("/other", 3)]
+        );
+        assert!(configs.iter().all(|c| c.destinations.is_empty()));
+
+        let version = config(
+            "backup_source_path: /data
+destinations:
+  - path: /offsite
+    retention:
+      daily: 7
+    compression: Gzip
+options:
+  mode: VersionMode
+  backup_hashs: []
+  preserve_version: 3
+",
+        );
+        let configs = version.destination_configs().unwrap();
+        let retention = |config: &BackupConfig| match &config.options {
+            BackupMode::VersionMode { retention, .. } => retention.clone(),
+            _ => unreachable!(),
+        };
+        assert!(retention(&configs[0]).is_none());
+        assert_eq!(retention(&configs[1]).unwrap().daily, 7);
+        let destinations = version.destinations();
+        assert_eq!(destinations[0].compression, Compression::None);
+        assert_eq!(destinations[1].compression, Compression::Gzip);
     }
 }
@@ -0,0 +1,512 @@
+use super::file_metadata::{Manifest, MANIFEST_FILE_NAME};
+use super::prune::{PruneReport, PruneRule};
+use super::storage::{StorageBackend, StorageKind};
+use super::trash::TrashRecord;
+use chrono::{DateTime, Duration, Local};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// 目录中一个已备份文件的记录
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CatalogEntry {
+    /// 文件大小(字节)
+    pub size: u64,
+    /// 备份时源文件的修改时间
+    /// 由核对目标目录得到的记录没有此项
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<DateTime<Local>>,
+    /// 文件内容的 sha256
+    /// 由核对目标目录得到的记录在首次需要时才计算
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// 备份(写入目标目录)的时间, 用于按保存天数删除
+    pub backed_up_at: DateTime<Local>,
+}
+
+/// 增量备份模式在两次运行之间保存的文件目录
+/// 记录每个已备份文件的大小、修改时间和摘要, 判断变动时只需对比源目录和目录,
+/// 不必每次重新遍历目标目录
+/// 每隔一定天数与目标目录完整核对一次, 以发现目标目录中被修改或删除的文件
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Catalog {
+    /// 目录对应的备份目录, 备份目录变动后目录作废
+    pub backup_root: String,
+    /// 上次完整核对的时间
+    pub last_reconcile: Option<DateTime<Local>>,
+    /// 以备份目录中的相对路径为键, 只有一个源目录时即源目录的相对路径
+    pub files: BTreeMap<String, CatalogEntry>,
+    /// 本次运行检查到变动、等待复制完成后写入的记录
+    #[serde(skip)]
+    pending: HashMap<String, CatalogEntry>,
+    /// 备份目录中的文件已压缩, 大小及摘要无法与源文件对比
+    #[serde(skip)]
+    pub compressed: bool,
+}
+
+impl Catalog {
+    /// 目录存放地址
+    /// 取 BackupConfig/catalog/{task_name}.yaml, 第二个及之后的备份目的地为 {task_name}@{n}.yaml
+    pub fn get_catalog_path(task_name: &str) -> PathBuf {
+        let mut catalog_path = PathBuf::from("BackupConfig");
+        catalog_path.push("catalog");
+        catalog_path.push(task_name.to_owned() + ".yaml");
+        catalog_path
+    }
+
+    /// 读取任务的目录, 不存在或备份目录已变动时返回空目录
+    pub fn load(task_name: &str, backup_root: &Path) -> Result<Catalog, Error> {
+        let backup_root = backup_root.to_string_lossy().to_string();
+        let catalog_path = Catalog::get_catalog_path(task_name);
+        let mut catalog = match File::open(&catalog_path) {
+            Ok(mut file) => {
+                let mut buf = String::new();
+                file.read_to_string(&mut buf)?;
+                serde_yaml::from_str(&buf).map_err(|e| {
+                    Error::other(format!(
+                        "读取文件目录 {:?} 时发生错误: {:?}",
+                        catalog_path, e
+                    ))
+                })?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Catalog::default(),
+            Err(e) => return Err(e),
+        };
+        if catalog.backup_root != backup_root {
+            catalog = Catalog {
+                backup_root,
+                ..Catalog::default()
+            };
+        }
+        Ok(catalog)
+    }
+
+    /// 写入临时文件后替换, 避免中途出错时损坏目录
+    pub fn save(&self, task_name: &str) -> Result<(), Error> {
+        let catalog_path = Catalog::get_catalog_path(task_name);
+        if let Some(parent) = catalog_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let yaml_str = serde_yaml::to_string(self)
+            .map_err(|e| Error::other(format!("Failed to serialize Catalog to YAML: {:?}", e)))?;
+        let tmp_path = catalog_path.with_extension("yaml.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(yaml_str.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &catalog_path)
+    }
+
+    /// 是否需要与目标目录完整核对
+    pub fn needs_reconcile(&self, reconcile_days: usize) -> bool {
+        match self.last_reconcile {
+            Some(time) => Local::now() - time >= Duration::days(reconcile_days as i64),
+            None => true,
+        }
+    }
+
+    /// 遍历目标目录, 使目录与目标目录中实际存在的文件一致
+    /// 目标目录中不存在或大小不一致的记录会被移除, 下次运行时重新备份
+    /// 目录中没有的文件以目标文件的信息加入目录
+    /// 备份目录中的文件已压缩时只核对是否存在
+    pub fn reconcile(&mut self, storage: &dyn StorageBackend) -> Result<(), Error> {
+        let backup_root = PathBuf::from(&self.backup_root);
+        let mut found = BTreeMap::new();
+        let mut directories = vec![backup_root.clone()];
+        while let Some(path) = directories.pop() {
+            for entry in storage.list(&path)? {
+                let entry_path = path.join(&entry.name);
+                match entry.meta.kind {
+                    StorageKind::Dir => directories.push(entry_path),
+                    StorageKind::File => {
+                        if path == backup_root && entry.name.starts_with(MANIFEST_FILE_NAME) {
+                            continue;
+                        }
+                        let relative = relative_key(&backup_root, &entry_path);
+                        let record = match self.files.remove(&relative) {
+                            Some(record) if self.compressed || record.size == entry.meta.len => {
+                                record
+                            }
+                            _ => CatalogEntry {
+                                size: entry.meta.len,
+                                mtime: None,
+                                digest: None,
+                                backed_up_at: entry.meta.modified.into(),
+                            },
+                        };
+                        found.insert(relative, record);
+                    }
+                    StorageKind::Symlink => {
+                        // 符号链接的记录以链接指向的路径为摘要, 目标目录中存在即保留
+                        let relative = relative_key(&backup_root, &entry_path);
+                        if let Some(record) = self.files.remove(&relative) {
+                            found.insert(relative, record);
+                        }
+                    }
+                }
+            }
+        }
+        let dropped = self.files.len();
+        self.files = found;
+        self.last_reconcile = Some(Local::now());
+        info!(
+            "文件目录与备份目录 {} 核对完成, 共[{}]个文件, 移除失效记录[{}]个",
+            self.backup_root,
+            self.files.len(),
+            dropped
+        );
+        Ok(())
+    }
+
+    /// 检查源文件是否需要备份
+    /// 大小和修改时间与记录一致时视为未变动;
+    /// 大小一致而修改时间不同时对比内容摘要, 内容未变时只更新记录的修改时间
+    /// 需要备份时返回 true, 并暂存新的记录等待 commit
+    /// relative 为路径在备份目录中的相对路径(SourceSet::relative_key)
+    /// source_digest 计算源文件的摘要, 只在需要对比内容时调用;
+    /// 新文件及大小变动的文件不计算摘要, 以后需要时由备份文件计算, 备份文件已压缩时无法计算, 仍读取源文件
+    pub fn check_file(
+        &mut self,
+        storage: &dyn StorageBackend,
+        relative: String,
+        size: u64,
+        mtime: DateTime<Local>,
+        source_digest: impl FnOnce() -> Result<String, Error>,
+    ) -> Result<bool, Error> {
+        let same_size = match self.files.get(&relative) {
+            Some(record) if record.size == size && record.mtime == Some(mtime) => return Ok(false),
+            Some(record) => record.size == size,
+            None => false,
+        };
+
+        let digest = if same_size || self.compressed {
+            Some(source_digest()?)
+        } else {
+            None
+        };
+        if let (Some(digest), Some(record)) = (&digest, self.files.get_mut(&relative)) {
+            if record.size == size {
+                if record.digest.is_none() && !self.compressed {
+                    let backup_file = Path::new(&self.backup_root).join(&relative);
+                    record.digest = storage.digest(&backup_file).ok();
+                }
+                if record.digest.as_deref() == Some(digest.as_str()) {
+                    record.mtime = Some(mtime);
+                    return Ok(false);
+                }
+            }
+        }
+
+        self.pending.insert(
+            relative,
+            CatalogEntry {
+                size,
+                mtime: Some(mtime),
+                digest,
+                backed_up_at: Local::now(),
+            },
+        );
+        Ok(true)
+    }
+
+    /// 检查符号链接或特殊文件是否需要备份
+    /// descriptor 描述链接指向的路径或特殊文件的类型, 与记录不一致时需要备份
+    pub fn check_special(&mut self, relative: String, descriptor: &str) -> bool {
+        let digest = sha256::digest(descriptor);
+        if let Some(record) = self.files.get(&relative) {
+            if record.digest.as_deref() == Some(digest.as_str()) {
+                return false;
+            }
+        }
+        self.pending.insert(
+            relative,
+            CatalogEntry {
+                size: 0,
+                mtime: None,
+                digest: Some(digest),
+                backed_up_at: Local::now(),
+            },
+        );
+        true
+    }
+
+    /// 将复制成功的文件写入目录
+    /// copied 为复制成功的文件在备份目录中的相对路径
+    pub fn commit(&mut self, copied: impl IntoIterator<Item = String>) {
+        for relative in copied {
+            if let Some(record) = self.pending.remove(&relative) {
+                self.files.insert(relative, record);
+            }
+        }
+        self.pending.clear();
+    }
+
+    /// 删除超过保存天数的已备份文件及其空的上级目录
+    /// key 为目录的名称, manifest 为备份目录的清单, 与文件的记录一起保存到回收站中, 还原时重新写入
+    /// 预演时只记录到报告中, 不修改目录
+    /// 返回删除的文件数
+    pub fn delete_expired(
+        &mut self,
+        storage: &dyn StorageBackend,
+        key: &str,
+        day: usize,
+        manifest: &Manifest,
+        report: &mut PruneReport,
+    ) -> Result<u64, Error> {
+        let save_day = Local::now() - Duration::days(day as i64);
+        let backup_root = PathBuf::from(&self.backup_root);
+        let expired: Vec<(String, CatalogEntry)> = self
+            .files
+            .iter()
+            .filter(|(_, record)| record.backed_up_at < save_day)
+            .map(|(relative, record)| (relative.clone(), record.clone()))
+            .collect();
+
+        let removed_before = report.items.len();
+        for (relative, entry) in expired {
+            let backup_file = backup_root.join(&relative);
+            let metadata = Path::new(&relative)
+                .ancestors()
+                .map(|ancestor| ancestor.to_string_lossy().to_string())
+                .filter_map(|ancestor| {
+                    let meta = manifest.files.get(&ancestor)?.clone();
+                    Some((ancestor, meta))
+                })
+                .collect();
+            let detail = format!(
+                "备份于 {}, 超过保存天数 {} 天",
+                entry.backed_up_at.format("%Y-%m-%d %T"),
+                day
+            );
+            report.remove_recorded(
+                storage,
+                &backup_file,
+                PruneRule::Expired,
+                detail,
+                TrashRecord::File {
+                    catalog: key.to_string(),
+                    relative: relative.clone(),
+                    entry,
+                    metadata,
+                },
+            )?;
+            if report.dry_run {
+                continue;
+            }
+            self.files.remove(&relative);
+
+            for parent in backup_file.ancestors().skip(1) {
+                if parent == backup_root || !parent.starts_with(&backup_root) {
+                    break;
+                }
+                if !storage.list(parent).is_ok_and(|entries| entries.is_empty())
+                    || storage.delete(parent).is_err()
+                {
+                    break;
+                }
+            }
+        }
+        Ok((report.items.len() - removed_before) as u64)
+    }
+}
+
+/// 取 path 相对 root 的路径作为目录的键
+pub fn relative_key(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::bk_config::BackupConfig;
+    use crate::mods::storage::MemoryStorage;
+    use crate::mods::trash;
+    use std::cell::Cell;
+
+    fn catalog(backup_root: &str) -> Catalog {
+        Catalog {
+            backup_root: backup_root.to_string(),
+            ..Catalog::default()
+        }
+    }
+
+    fn time(seconds: i64) -> DateTime<Local> {
+        DateTime::from_timestamp(seconds, 0).unwrap().into()
+    }
+
+    /// 大小和修改时间与记录一致时不计算摘要
+    #[test]
+    fn unchanged_size_and_mtime_skip_hashing() {
+        let storage = MemoryStorage::new();
+        let mut catalog = catalog("/backup");
+        let changed = catalog
+            .check_file(&storage, "a".to_string(), 3, time(100), || {
+                panic!("新文件不计算摘要")
+            })
+            .unwrap();
+        assert!(changed);
+        catalog.commit(["a".to_string()]);
+        assert!(catalog.files["a"].digest.is_none());
+
+        let changed = catalog
+            .check_file(&storage, "a".to_string(), 3, time(100), || {
+                panic!("未变动的文件不计算摘要")
+            })
+            .unwrap();
+        assert!(!changed);
+    }
+
+    /// 修改时间变动而内容相同时只更新记录的修改时间, 内容不同时重新备份
+    #[test]
+    fn mtime_change_with_same_content_is_not_copied() {
+        let storage = MemoryStorage::new();
+        storage.mkdir(Path::new("/backup")).unwrap();
+        storage
+            .write(Path::new("/backup/a"), &mut &b"one"[..])
+            .unwrap();
+        let mut catalog = catalog("/backup");
+        catalog
+            .check_file(&storage, "a".to_string(), 3, time(100), || unreachable!())
+            .unwrap();
+        catalog.commit(["a".to_string()]);
+
+        let hashed = Cell::new(0);
+        let digest = |content: &'static str| {
+            let hashed = &hashed;
+            move || {
+                hashed.set(hashed.get() + 1);
+                Ok(sha256::digest(content))
+            }
+        };
+        let changed = catalog
+            .check_file(&storage, "a".to_string(), 3, time(200), digest("one"))
+            .unwrap();
+        assert!(!changed);
+        assert_eq!(hashed.get(), 1);
+        assert_eq!(catalog.files["a"].mtime, Some(time(200)));
+        // 备份文件的摘要在首次需要时计算并记录
+        assert_eq!(
+            catalog.files["a"].digest.as_deref(),
+            Some(sha256::digest("one").as_str())
+        );
+
+        let changed = catalog
+            .check_file(&storage, "a".to_string(), 3, time(300), digest("two"))
+            .unwrap();
+        assert!(changed);
+        catalog.commit(["a".to_string()]);
+        assert_eq!(catalog.files["a"].mtime, Some(time(300)));
+        assert_eq!(
+            catalog.files["a"].digest.as_deref(),
+            Some(sha256::digest("two").as_str())
+        );
+    }
+
+    /// 目录文件丢失后由备份目录重建, 内容相同的文件不会重新备份
+    #[test]
+    fn reconcile_rebuilds_lost_catalog() {
+        let storage = MemoryStorage::new();
+        storage.mkdir(Path::new("/backup/sub")).unwrap();
+        storage
+            .write(Path::new("/backup/a"), &mut &b"one"[..])
+            .unwrap();
+        storage
+            .write(Path::new("/backup/sub/b"), &mut &b"two"[..])
+            .unwrap();
+        storage
+            .write(
+                &Path::new("/backup").join(MANIFEST_FILE_NAME),
+                &mut &b"files: {}"[..],
+            )
+            .unwrap();
+        let mut catalog = Catalog::load("catalog_lost_test", Path::new("/backup")).unwrap();
+        assert!(catalog.files.is_empty());
+        assert!(catalog.needs_reconcile(7));
+
+        catalog.reconcile(&storage).unwrap();
+        assert!(!catalog.needs_reconcile(7));
+        assert_eq!(catalog.files.keys().collect::<Vec<_>>(), vec!["a", "sub/b"]);
+        assert!(catalog.files["a"].mtime.is_none());
+
+        let same = catalog
+            .check_file(&storage, "a".to_string(), 3, time(100), || {
+                Ok(sha256::digest("one"))
+            })
+            .unwrap();
+        let changed = catalog
+            .check_file(&storage, "sub/b".to_string(), 3, time(100), || {
+                Ok(sha256::digest("TWO"))
+            })
+            .unwrap();
+        assert!(!same);
+        assert!(changed);
+
+        // 备份目录中被删除或大小变动的文件移除记录, 下次重新备份
+        storage.delete(Path::new("/backup/sub/b")).unwrap();
+        storage
+            .write(Path::new("/backup/a"), &mut &b"changed"[..])
+            .unwrap();
+        catalog.reconcile(&storage).unwrap();
+        assert_eq!(catalog.files.keys().collect::<Vec<_>>(), vec!["a"]);
+        assert_eq!(catalog.files["a"].size, 7);
+        assert!(catalog.files["a"].mtime.is_none());
+    }
+
+    /// 配置了回收站时过期文件移入回收站, 记录连同目录中的条目一起保存
+    #[test]
+    fn expired_files_go_to_trash() {
+        let storage = MemoryStorage::new();
+        storage.mkdir(Path::new("/memory/data/sub")).unwrap();
+        storage
+            .write(Path::new("/memory/data/sub/old"), &mut &b"old"[..])
+            .unwrap();
+        storage
+            .write(Path::new("/memory/data/new"), &mut &b"new"[..])
+            .unwrap();
+        let mut catalog = catalog("/memory/data");
+        for (relative, days) in [("sub/old", 10), ("new", 1)] {
+            catalog.files.insert(
+                relative.to_string(),
+                CatalogEntry {
+                    size: 3,
+                    mtime: Some(time(100)),
+                    digest: None,
+                    backed_up_at: Local::now() - Duration::days(days),
+                },
+            );
+        }
+        let config: BackupConfig = serde_yaml::from_str(
+            "backup_source_path: /data\nbackup_destination_path: /memory\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\ntrash:\n  grace_days: 7\noptions:\n  mode: IncrementalMode\n  save_days: 3\n",
+        )
+        .unwrap();
+        let mut report = PruneReport::for_task(&config, "task", false);
+        let removed = catalog
+            .delete_expired(&storage, "task", 3, &Manifest::default(), &mut report)
+            .unwrap();
+
+        assert_eq!(removed, 1);
+        assert_eq!(catalog.files.keys().collect::<Vec<_>>(), vec!["new"]);
+        assert!(report.items[0].trashed);
+        assert_eq!(report.bytes_freed(), 0);
+        // 空的上级目录一并删除
+        assert!(storage
+            .stat(Path::new("/memory/data/sub"))
+            .unwrap()
+            .is_none());
+        assert_eq!(storage.read(Path::new("/memory/data/new")).unwrap(), b"new");
+        let items = trash::list(&storage, "/memory").unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].original_path, "/memory/data/sub/old");
+        match &items[0].record {
+            Some(TrashRecord::File {
+                catalog, relative, ..
+            }) => assert_eq!((catalog.as_str(), relative.as_str()), ("task", "sub/old")),
+            record => panic!("回收站条目的记录不正确: {:?}", record),
+        }
+    }
+}
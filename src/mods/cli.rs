@@ -0,0 +1,131 @@
+use super::{bk_config::BackupConfig, prune, quota::ByteSize, remote, restore, storage, trash};
+use log::{error, info};
+use std::io::Error;
+use std::path::Path;
+
+const USAGE: &str = "用法:
+    rsbk                                     按 BackupConfig 中的备份计划持续运行
+    rsbk restore <任务名> <还原目录> [版本]    还原备份, 不填写版本时还原最新版本
+    rsbk prune <任务名> [--dry-run]           按保留策略、空间限额或保存天数删除旧备份并输出报告,
+                                             --dry-run 时只输出报告, 不删除
+    rsbk trash list <任务名>                  列出任务备份目的地回收站中的条目
+    rsbk trash undelete <任务名> <条目ID>     将回收站中的条目移回原位置";
+
+/// 执行命令行子命令, 返回进程退出码
+pub fn run(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["restore", task_name, restore_path, rest @ ..] if rest.len() <= 1 => {
+            match restore::restore(task_name, rest.first().copied(), Path::new(restore_path)) {
+                Ok(stats) => {
+                    info!("{}", stats);
+                    if stats.files_failed > 0 {
+                        1
+                    } else {
+                        0
+                    }
+                }
+                Err(e) => {
+                    error!("{}:还原时发生错误:{}", task_name, e);
+                    1
+                }
+            }
+        }
+        ["prune", task_name, rest @ ..] if rest.is_empty() || rest == ["--dry-run"] => {
+            match prune::prune(task_name, !rest.is_empty()) {
+                Ok(report) => {
+                    match serde_yaml::to_string(&report) {
+                        Ok(yaml_str) => println!("{}", yaml_str),
+                        Err(e) => error!("{}:输出报告时发生错误:{}", task_name, e),
+                    }
+                    0
+                }
+                Err(e) => {
+                    error!("{}:删除旧备份时发生错误:{}", task_name, e);
+                    1
+                }
+            }
+        }
+        ["trash", "list", task_name] => {
+            let listed = trash_configs(task_name).and_then(|configs| {
+                let mut items = Vec::new();
+                for config in configs {
+                    let storage = remote::destination_storage(&storage::local(), &config)?;
+                    items.extend(trash::list(
+                        storage.as_ref(),
+                        &config.backup_destination_path,
+                    )?);
+                }
+                Ok(items)
+            });
+            match listed {
+                Ok(items) => {
+                    for item in items {
+                        println!(
+                            "{}  {}  {}  {:?}:{}  {}  彻底删除于 {}",
+                            item.id,
+                            item.task_name,
+                            item.original_path,
+                            item.rule,
+                            item.detail,
+                            ByteSize(item.bytes),
+                            item.purge_after.format("%Y-%m-%d %T")
+                        );
+                    }
+                    0
+                }
+                Err(e) => {
+                    error!("{}:读取回收站时发生错误:{}", task_name, e);
+                    1
+                }
+            }
+        }
+        ["trash", "undelete", task_name, id] => {
+            let undeleted = trash_configs(task_name).and_then(|configs| {
+                let mut storages = Vec::new();
+                for config in &configs {
+                    storages.push(remote::destination_storage(&storage::local(), config)?);
+                }
+                // 条目所在的备份目的地, 都没有时按第一个目的地报告错误
+                let index = configs
+                    .iter()
+                    .zip(&storages)
+                    .position(|(c, storage)| {
+                        storage
+                            .stat(
+                                &trash::Trash::get_trash_path(&c.backup_destination_path).join(id),
+                            )
+                            .is_ok_and(|meta| meta.is_some())
+                    })
+                    .unwrap_or(0);
+                trash::undelete(
+                    storages[index].as_ref(),
+                    &configs[index].backup_destination_path,
+                    id,
+                )
+            });
+            match undeleted {
+                Ok(item) => {
+                    info!(
+                        "{}:已将 {} 还原到 {}",
+                        task_name, item.id, item.original_path
+                    );
+                    0
+                }
+                Err(e) => {
+                    error!("{}:从回收站还原时发生错误:{}", task_name, e);
+                    1
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+/// 读取任务各备份目的地的配置
+fn trash_configs(task_name: &str) -> Result<Vec<BackupConfig>, Error> {
+    BackupConfig::create(&BackupConfig::get_hash_path(task_name))?.destination_configs()
+}
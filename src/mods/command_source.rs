@@ -0,0 +1,201 @@
+use super::base_bk_option::{self, context};
+use super::bk_config::SourceConfig;
+use super::hooks;
+use super::quota::ByteSize;
+use log::info;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// 命令的默认超时秒数
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 3600;
+
+/// 命令输出在本地的缓存目录, 位于程序的 BackupConfig/command 目录下
+/// 按命令及参数区分, 备份时作为该源的根目录
+pub fn cache_path(source: &SourceConfig) -> PathBuf {
+    let mut path = PathBuf::from("BackupConfig");
+    path.push("command");
+    path.push(&sha256::digest(source.command.join("\0"))[..16]);
+    path
+}
+
+/// 执行命令源的命令, 将标准输出保存为缓存目录下的 file_name
+/// 命令直接执行而不经过 shell, 退出码不为 0 或超时均返回错误, 本次备份失败
+/// 输出与上次相同时保留上次的文件(修改时间不变), 不会产生新的版本
+pub fn run(task_name: &str, source: &SourceConfig) -> Result<(), Error> {
+    let Some((program, args)) = source.command.split_first() else {
+        return Err(Error::new(ErrorKind::InvalidInput, "command 不能为空"));
+    };
+    let cache_dir = cache_path(source);
+    fs::create_dir_all(&cache_dir).map_err(context("创建命令输出目录时发生错误"))?;
+    let target = cache_dir.join(source.output_file_name());
+    // 临时文件位于缓存目录之外, 中途失败时不会被当作源文件备份
+    let partial = PathBuf::from(format!("{}.partial", cache_dir.display()));
+
+    let timeout_seconds = source.timeout_seconds.unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+    let result = File::create(&partial).and_then(|output| {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(output)
+            .stderr(Stdio::piped());
+        hooks::isolate(&mut command);
+        let mut child = command
+            .spawn()
+            .map_err(|e| Error::new(e.kind(), format!("无法执行 {}:{}", program, e)))?;
+        let stderr_reader = child.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut output = String::new();
+                let _ = stderr.read_to_string(&mut output);
+                output
+            })
+        });
+        let status = hooks::wait_timeout(&mut child, Duration::from_secs(timeout_seconds))?;
+        let stderr = stderr_reader
+            .and_then(|reader| reader.join().ok())
+            .unwrap_or_default();
+        match status {
+            Some(status) if status.success() => Ok(()),
+            Some(status) => Err(Error::other(format!(
+                "退出状态为 {}: {}",
+                status,
+                stderr.trim()
+            ))),
+            None => Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("超过 {} 秒未结束, 已终止", timeout_seconds),
+            )),
+        }
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(Error::new(
+            e.kind(),
+            format!("执行命令 {} 时发生错误:{}", source.label(), e),
+        ));
+    }
+
+    if base_bk_option::same_content(&target, &partial)? {
+        fs::remove_file(&partial).map_err(context("删除临时输出文件时发生错误"))?;
+        info!("{}:命令 {} 的输出无变动", task_name, source.label());
+        return Ok(());
+    }
+    let size = fs::metadata(&partial)?.len();
+    fs::rename(&partial, &target).map_err(context("保存命令输出时发生错误"))?;
+    info!(
+        "{}:已保存命令 {} 的输出到 {}, 大小[{}]",
+        task_name,
+        source.label(),
+        target.display(),
+        ByteSize(size)
+    );
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+    /// 以 sh 执行 script 的命令源, 离开作用域时删除其缓存目录
+    struct TestSource(SourceConfig);
+
+    impl TestSource {
+        fn new(script: &str, timeout_seconds: u64) -> Self {
+            TestSource(SourceConfig {
+                path: String::new(),
+                command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+                file_name: Some("output".to_string()),
+                timeout_seconds: Some(timeout_seconds),
+                alias: None,
+            })
+        }
+
+        fn output(&self) -> PathBuf {
+            cache_path(&self.0).join("output")
+        }
+
+        fn partial(&self) -> PathBuf {
+            PathBuf::from(format!("{}.partial", cache_path(&self.0).display()))
+        }
+    }
+
+    impl Drop for TestSource {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(cache_path(&self.0));
+            let _ = fs::remove_file(self.partial());
+        }
+    }
+
+    #[test]
+    fn failed_command_keeps_previous_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let fail = dir.path().join("fail");
+        let source = TestSource::new(&format!("echo new; test ! -e {}", fail.display()), 10);
+        run("command_test", &source.0).unwrap();
+        assert_eq!(fs::read(source.output()).unwrap(), b"new\n");
+        fs::write(source.output(), b"previous").unwrap();
+
+        fs::write(&fail, b"").unwrap();
+        let e = run("command_test", &source.0).unwrap_err();
+        assert!(e.to_string().contains("退出状态"), "{}", e);
+        assert_eq!(fs::read(source.output()).unwrap(), b"previous");
+        assert!(!source.partial().exists());
+    }
+
+    #[test]
+    fn timeout_kills_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let source = TestSource::new(
+            &format!("sleep 30 & echo $! > {}; wait", pid_file.display()),
+            1,
+        );
+        let started = Instant::now();
+        let e = run("command_test", &source.0).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(!source.output().exists());
+        assert!(!source.partial().exists());
+
+        // 命令启动的后台进程同样被终止
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        let stat = Path::new("/proc").join(pid.trim()).join("stat");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs::read_to_string(&stat).is_ok_and(|s| !s.contains(") Z ")) {
+            assert!(Instant::now() < deadline, "命令启动的进程未被终止");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn identical_output_keeps_cached_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        fs::write(&input, b"same").unwrap();
+        let source = TestSource::new(&format!("cat {}", input.display()), 10);
+        run("command_test", &source.0).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        File::options()
+            .write(true)
+            .open(source.output())
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let mtime = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+
+        run("command_test", &source.0).unwrap();
+        assert_eq!(mtime(&source.output()), modified);
+        assert!(!source.partial().exists());
+
+        fs::write(&input, b"changed").unwrap();
+        run("command_test", &source.0).unwrap();
+        assert_eq!(fs::read(source.output()).unwrap(), b"changed");
+        assert!(mtime(&source.output()) > SystemTime::now() - Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,535 @@
+use super::base_bk_option::{self, context};
+use super::bk_config::BackupConfig;
+use super::hooks;
+use super::quota::ByteSize;
+use super::remote;
+use super::webdav::uri_decode;
+use log::info;
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// 数据库源的配置
+/// 源目录填写 postgres://、mysql:// 或 sqlite:// 地址时使用, 不填写时使用默认值
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DatabaseConfig {
+    /// 数据库密码, 地址中没有填写密码时使用, 通过环境变量传给导出命令
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// pg_dump 命令的路径
+    #[serde(default = "default_pg_dump")]
+    pub pg_dump: String,
+    /// mysqldump 命令的路径
+    #[serde(default = "default_mysqldump")]
+    pub mysqldump: String,
+    /// 追加到导出命令的参数
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_args: Vec<String>,
+    /// 导出的超时秒数, 超时后终止导出, 本次备份失败
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            password: None,
+            pg_dump: default_pg_dump(),
+            mysqldump: default_mysqldump(),
+            extra_args: Vec::new(),
+            timeout_seconds: default_timeout_seconds(),
+        }
+    }
+}
+
+fn default_pg_dump() -> String {
+    "pg_dump".to_string()
+}
+
+fn default_mysqldump() -> String {
+    "mysqldump".to_string()
+}
+
+fn default_timeout_seconds() -> u64 {
+    3600
+}
+
+/// 数据库源的类型
+enum Database {
+    /// postgres://user@host:port/dbname, 地址中的密码去掉后通过 PGPASSWORD 传给 pg_dump
+    Postgres(String),
+    /// mysql://user@host:port/dbname
+    MySql(MySqlUrl),
+    /// sqlite:///path/to/file.db
+    Sqlite(PathBuf),
+}
+
+/// mysql:// 地址的各部分, 用户名和密码已按百分号编码解码
+struct MySqlUrl {
+    user: Option<String>,
+    password: Option<String>,
+    host: String,
+    port: Option<String>,
+    database: String,
+}
+
+impl MySqlUrl {
+    fn parse(url: &str) -> Result<MySqlUrl, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "无法识别的 mysql 地址: {}, 格式为 mysql://user@host:port/dbname",
+                    redact(url)
+                ),
+            )
+        };
+        let rest = url.strip_prefix("mysql://").ok_or_else(invalid)?;
+        let rest = rest.split('?').next().unwrap_or(rest);
+        let (authority, database) = rest.split_once('/').ok_or_else(invalid)?;
+        if database.is_empty() || database.contains('/') {
+            return Err(invalid());
+        }
+        let (user_info, host_port) = match authority.rsplit_once('@') {
+            Some((user_info, host_port)) => (Some(user_info), host_port),
+            None => (None, authority),
+        };
+        let (user, password) = match user_info {
+            Some(user_info) => match user_info.split_once(':') {
+                Some((user, password)) => (Some(uri_decode(user)), Some(uri_decode(password))),
+                None => (Some(uri_decode(user_info)), None),
+            },
+            None => (None, None),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), Some(port.to_string())),
+            None => (host_port.to_string(), None),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(MySqlUrl {
+            user,
+            password,
+            host,
+            port,
+            database: database.to_string(),
+        })
+    }
+}
+
+impl Database {
+    fn parse(url: &str) -> Result<Database, Error> {
+        match url.split_once("://") {
+            Some(("postgres" | "postgresql", _)) => Ok(Database::Postgres(url.to_string())),
+            Some(("mysql", _)) => Ok(Database::MySql(MySqlUrl::parse(url)?)),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Database::Sqlite(PathBuf::from(path))),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("不支持的数据库地址: {}", redact(url)),
+            )),
+        }
+    }
+}
+
+/// 是否为数据库源地址
+pub fn is_database(path: &str) -> bool {
+    matches!(
+        path.split_once("://").map(|(scheme, _)| scheme),
+        Some("postgres" | "postgresql" | "mysql" | "sqlite")
+    )
+}
+
+/// 导出数据库到源目录的本地缓存目录, 备份时与其他源目录相同, 按文件进行版本控制、压缩及保留
+/// PostgreSQL 使用 pg_dump 导出为 {dbname}.sql, MySQL 使用 mysqldump --single-transaction 导出为 {dbname}.sql,
+/// SQLite 使用在线备份 API 复制为同名的数据库文件
+/// 先导出到临时文件, 内容与上次导出相同时保留上次的文件(修改时间不变), 不会产生新的版本
+pub fn dump(task_name: &str, url: &str, config: &BackupConfig) -> Result<(), Error> {
+    let db_config = config.database.clone().unwrap_or_default();
+    dump_into(task_name, url, &db_config, &remote::cache_path(url))
+}
+
+/// 导出数据库到 cache_dir
+fn dump_into(
+    task_name: &str,
+    url: &str,
+    db_config: &DatabaseConfig,
+    cache_dir: &Path,
+) -> Result<(), Error> {
+    let database = Database::parse(url)?;
+    fs::create_dir_all(cache_dir).map_err(context("创建数据库导出目录时发生错误"))?;
+
+    let file_name = match &database {
+        Database::Postgres(url) => {
+            let name = url
+                .split('?')
+                .next()
+                .and_then(|u| u.rsplit('/').next())
+                .filter(|name| !name.is_empty() && !name.contains(':'))
+                .unwrap_or("postgres");
+            format!("{}.sql", name)
+        }
+        Database::MySql(url) => format!("{}.sql", url.database),
+        Database::Sqlite(path) => path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("sqlite 地址中没有数据库文件: {}", url),
+                )
+            })?,
+    };
+    let target = cache_dir.join(&file_name);
+    // 临时文件位于缓存目录之外, 中途失败时不会被当作源文件备份
+    let partial = PathBuf::from(format!("{}.partial", cache_dir.display()));
+
+    let result = match &database {
+        Database::Postgres(url) => {
+            // 密码不出现在命令行中, 避免被其他用户通过进程列表看到
+            let (url, password) = split_password(url);
+            let mut command = Command::new(&db_config.pg_dump);
+            command
+                .arg("--format=plain")
+                .arg("--no-password")
+                .arg(format!("--file={}", partial.display()))
+                .args(&db_config.extra_args)
+                .arg(format!("--dbname={}", url));
+            if let Some(password) = password.as_ref().or(db_config.password.as_ref()) {
+                command.env("PGPASSWORD", password);
+            }
+            run_dump(command, db_config.timeout_seconds)
+        }
+        Database::MySql(url) => {
+            let mut command = Command::new(&db_config.mysqldump);
+            command
+                .arg("--single-transaction")
+                .arg("--routines")
+                .arg("--triggers")
+                .arg("--events")
+                .arg("--skip-dump-date")
+                .arg(format!("--host={}", url.host))
+                .arg(format!("--result-file={}", partial.display()));
+            if let Some(port) = &url.port {
+                command.arg(format!("--port={}", port));
+            }
+            if let Some(user) = &url.user {
+                command.arg(format!("--user={}", user));
+            }
+            if let Some(password) = url.password.as_ref().or(db_config.password.as_ref()) {
+                command.env("MYSQL_PWD", password);
+            }
+            command
+                .args(&db_config.extra_args)
+                .arg("--databases")
+                .arg(&url.database);
+            run_dump(command, db_config.timeout_seconds)
+        }
+        Database::Sqlite(path) => sqlite_backup(path, &partial),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(Error::new(
+            e.kind(),
+            format!("导出数据库 {} 时发生错误:{}", redact(url), e),
+        ));
+    }
+
+    let unchanged = match &database {
+        Database::Postgres(_) => same_sql(&target, &partial)?,
+        _ => base_bk_option::same_content(&target, &partial)?,
+    };
+    if unchanged {
+        fs::remove_file(&partial).map_err(context("删除临时导出文件时发生错误"))?;
+        info!("{}:数据库 {} 无变动", task_name, redact(url));
+        return Ok(());
+    }
+    let size = fs::metadata(&partial)?.len();
+    fs::rename(&partial, &target).map_err(context("保存数据库导出文件时发生错误"))?;
+    info!(
+        "{}:已导出数据库 {} 到 {}, 大小[{}]",
+        task_name,
+        redact(url),
+        target.display(),
+        ByteSize(size)
+    );
+    Ok(())
+}
+
+/// 执行导出命令, 退出码不为 0 或超时均返回错误, 错误信息中包含命令的错误输出
+fn run_dump(mut command: Command, timeout_seconds: u64) -> Result<(), Error> {
+    let program = command.get_program().to_string_lossy().to_string();
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    hooks::isolate(&mut command);
+    let mut child = command
+        .spawn()
+        .map_err(|e| Error::new(e.kind(), format!("无法执行 {}:{}", program, e)))?;
+    let stderr_reader = child.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stderr.read_to_string(&mut output);
+            output
+        })
+    });
+    let status = hooks::wait_timeout(&mut child, Duration::from_secs(timeout_seconds))?;
+    let stderr = stderr_reader
+        .and_then(|reader| reader.join().ok())
+        .unwrap_or_default();
+    match status {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => Err(Error::other(format!(
+            "{} 退出状态为 {}: {}",
+            program,
+            status,
+            stderr.trim()
+        ))),
+        None => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("{} 超过 {} 秒未结束, 已终止", program, timeout_seconds),
+        )),
+    }
+}
+
+/// 使用 SQLite 在线备份 API 复制数据库, 复制期间其他连接仍可读写
+fn sqlite_backup(source: &Path, target: &Path) -> Result<(), Error> {
+    let _ = fs::remove_file(target);
+    let connection = Connection::open_with_flags(
+        source,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| Error::other(format!("无法打开 {}:{}", source.display(), e)))?;
+    connection
+        .backup(DatabaseName::Main, target, None)
+        .map_err(|e| Error::other(format!("复制 {} 时发生错误:{}", source.display(), e)))
+}
+
+/// 比较两次 pg_dump 的导出内容
+/// 新版本的 pg_dump 每次导出时在 \restrict 及 \unrestrict 行中写入随机的密钥, 比较时忽略这两行
+fn same_sql(a: &Path, b: &Path) -> Result<bool, Error> {
+    if !a.exists() {
+        return Ok(false);
+    }
+    let lines = |path: &Path| -> Result<_, Error> {
+        Ok(BufReader::new(File::open(path)?)
+            .split(b'\n')
+            .filter(|line| {
+                !matches!(line, Ok(line) if line.starts_with(b"\\restrict ") || line.starts_with(b"\\unrestrict "))
+            }))
+    };
+    let (mut a, mut b) = (lines(a)?, lines(b)?);
+    loop {
+        match (a.next().transpose()?, b.next().transpose()?) {
+            (None, None) => return Ok(true),
+            (Some(x), Some(y)) if x == y => continue,
+            _ => return Ok(false),
+        }
+    }
+}
+
+/// 从 postgres 地址中取出密码(用户信息中的密码或 password 参数), 返回去掉密码后的地址及解码后的密码
+fn split_password(url: &str) -> (String, Option<String>) {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return (url.to_string(), None);
+    };
+    let (rest, query) = match rest.split_once('?') {
+        Some((rest, query)) => (rest, Some(query)),
+        None => (rest, None),
+    };
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+    let mut password = None;
+    let authority = match authority.rsplit_once('@') {
+        Some((user_info, host)) => match user_info.split_once(':') {
+            Some((user, secret)) => {
+                password = Some(uri_decode(secret));
+                format!("{}@{}", user, host)
+            }
+            None => authority.to_string(),
+        },
+        None => authority.to_string(),
+    };
+    let mut stripped = format!("{}://{}{}", scheme, authority, path);
+    if let Some(query) = query {
+        let params: Vec<&str> = query
+            .split('&')
+            .filter(|param| match param.strip_prefix("password=") {
+                Some(secret) => {
+                    password.get_or_insert_with(|| uri_decode(secret));
+                    false
+                }
+                None => true,
+            })
+            .collect();
+        if !params.is_empty() {
+            stripped.push('?');
+            stripped.push_str(&params.join("&"));
+        }
+    }
+    (stripped, password)
+}
+
+/// 隐藏地址中的密码, 用于日志及错误信息
+pub fn redact(url: &str) -> String {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return url.to_string();
+    };
+    let authority_end = rest.find('/').unwrap_or(rest.len());
+    match rest[..authority_end].rsplit_once('@') {
+        Some((user_info, _)) if user_info.contains(':') => {
+            let user = user_info.split(':').next().unwrap_or_default();
+            format!("{}://{}:***@{}", scheme, user, &rest[user_info.len() + 1..])
+        }
+        _ => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_is_removed_from_postgres_url() {
+        assert_eq!(
+            split_password("postgres://backup:p%40ss@db:5432/app?sslmode=require"),
+            (
+                "postgres://backup@db:5432/app?sslmode=require".to_string(),
+                Some("p@ss".to_string())
+            )
+        );
+        assert_eq!(
+            split_password("postgresql://backup@db/app?password=secret&sslmode=disable"),
+            (
+                "postgresql://backup@db/app?sslmode=disable".to_string(),
+                Some("secret".to_string())
+            )
+        );
+        assert_eq!(
+            split_password("postgres://backup@db/app"),
+            ("postgres://backup@db/app".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn mysql_user_and_password_are_decoded() {
+        let url = MySqlUrl::parse("mysql://back%40up:p%40ss%3Aw%2Fd@db:3306/app?ssl=true").unwrap();
+        assert_eq!(url.user.as_deref(), Some("back@up"));
+        assert_eq!(url.password.as_deref(), Some("p@ss:w/d"));
+        assert_eq!(url.host, "db");
+        assert_eq!(url.port.as_deref(), Some("3306"));
+        assert_eq!(url.database, "app");
+
+        let url = MySqlUrl::parse("mysql://backup@db/app").unwrap();
+        assert_eq!(url.user.as_deref(), Some("backup"));
+        assert_eq!(url.password, None);
+        assert_eq!(url.port, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pg_dump_gets_password_from_environment() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        // 代替 pg_dump 的脚本, 将 PGPASSWORD 及参数写入 --file 指定的文件
+        let script = dir.path().join("pg_dump");
+        fs::write(
+            &script,
+            "#!/bin/sh\nfor arg; do case $arg in --file=*) out=${arg#--file=};; esac; done\n\
+             echo \"$PGPASSWORD\" > \"$out\"\necho \"$@\" >> \"$out\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let db_config = DatabaseConfig {
+            pg_dump: script.to_string_lossy().to_string(),
+            ..DatabaseConfig::default()
+        };
+        let cache_dir = dir.path().join("cache");
+        dump_into(
+            "task",
+            "postgres://backup:secret@db/app",
+            &db_config,
+            &cache_dir,
+        )
+        .unwrap();
+        let output = fs::read_to_string(cache_dir.join("app.sql")).unwrap();
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("secret"));
+        let args = lines.next().unwrap();
+        assert!(args.contains("--dbname=postgres://backup@db/app"));
+        assert!(!args.contains("secret"));
+    }
+
+    #[test]
+    fn sqlite_dump_detects_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("app.db");
+        let connection = Connection::open(&db_path).unwrap();
+        connection
+            .execute_batch("CREATE TABLE t (v TEXT); INSERT INTO t VALUES ('one');")
+            .unwrap();
+        let url = format!("sqlite://{}", db_path.display());
+        let cache_dir = dir.path().join("cache");
+        let config = DatabaseConfig::default();
+        let dumped = cache_dir.join("app.db");
+
+        dump_into("task", &url, &config, &cache_dir).unwrap();
+        let first = fs::metadata(&dumped).unwrap().modified().unwrap();
+        let copy = Connection::open(&dumped).unwrap();
+        let value: String = copy
+            .query_row("SELECT v FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(value, "one");
+        drop(copy);
+
+        // 内容未变时保留上次的文件
+        thread::sleep(Duration::from_millis(20));
+        dump_into("task", &url, &config, &cache_dir).unwrap();
+        assert_eq!(fs::metadata(&dumped).unwrap().modified().unwrap(), first);
+
+        connection
+            .execute("INSERT INTO t VALUES ('two')", [])
+            .unwrap();
+        dump_into("task", &url, &config, &cache_dir).unwrap();
+        assert_ne!(fs::metadata(&dumped).unwrap().modified().unwrap(), first);
+        let copy = Connection::open(&dumped).unwrap();
+        let count: i64 = copy
+            .query_row("SELECT count(*) FROM t", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 2);
+        assert!(!PathBuf::from(format!("{}.partial", cache_dir.display())).exists());
+    }
+
+    #[test]
+    fn same_sql_ignores_restrict_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a.sql");
+        let b = dir.path().join("b.sql");
+        assert!(!same_sql(&a, &b).unwrap());
+        fs::write(
+            &a,
+            "\\restrict AAAA\nCREATE TABLE t ();\n\\unrestrict AAAA\n",
+        )
+        .unwrap();
+        fs::write(
+            &b,
+            "\\restrict BBBB\nCREATE TABLE t ();\n\\unrestrict BBBB\n",
+        )
+        .unwrap();
+        assert!(same_sql(&a, &b).unwrap());
+        fs::write(
+            &b,
+            "\\restrict BBBB\nCREATE TABLE u ();\n\\unrestrict BBBB\n",
+        )
+        .unwrap();
+        assert!(!same_sql(&a, &b).unwrap());
+        fs::write(&b, "CREATE TABLE t ();\n-- \\restrict CCCC\n").unwrap();
+        assert!(!same_sql(&a, &b).unwrap());
+    }
+}
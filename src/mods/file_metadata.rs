@@ -0,0 +1,440 @@
+use super::base_bk_option::SourceSet;
+use super::bk_config::Compression;
+use super::storage::StorageBackend;
+use chrono::{DateTime, Local};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File, FileTimes};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// 清单文件名, 保存在每个备份目录的根目录中
+/// 遍历备份目录时应当跳过此文件
+pub const MANIFEST_FILE_NAME: &str = ".rsbk_manifest.yaml";
+
+/// POSIX ACL 在扩展属性中的名称
+#[cfg(unix)]
+const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+#[cfg(unix)]
+const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// 清单中路径的类型
+/// 普通文件和目录直接保存在备份目录中, 其余类型以清单为准,
+/// 即使目标文件系统无法保存(例如不支持符号链接), 还原时也能按清单重建
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum EntryKind {
+    /// 普通文件或目录
+    #[default]
+    Regular,
+    /// 符号链接, target 为链接指向的路径
+    Symlink { target: String },
+    /// 与源目录内另一个文件的硬链接, target 为该文件的相对路径
+    HardLink { target: String },
+    /// 命名管道
+    Fifo,
+    /// 字符设备
+    CharDevice { rdev: u64 },
+    /// 块设备
+    BlockDevice { rdev: u64 },
+    /// 套接字, 还原时不重建
+    Socket,
+}
+
+impl EntryKind {
+    pub fn is_regular(&self) -> bool {
+        *self == EntryKind::Regular
+    }
+}
+
+/// 一个文件或目录的元数据
+/// 扩展属性和 ACL 的值以十六进制保存
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FileMetadata {
+    /// 路径的类型, 普通文件和目录不记录
+    #[serde(default, skip_serializing_if = "EntryKind::is_regular")]
+    pub kind: EntryKind,
+    /// 权限位
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<DateTime<Local>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<DateTime<Local>>,
+    /// 扩展属性, 不包括 ACL
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    /// POSIX 访问 ACL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acl_access: Option<String>,
+    /// POSIX 默认 ACL, 仅目录有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acl_default: Option<String>,
+}
+
+impl FileMetadata {
+    /// 读取源文件的元数据, 不跟随符号链接
+    /// 读取失败的扩展属性会被忽略(例如无权读取的 trusted.* 属性)
+    pub fn capture(path: &Path) -> Result<FileMetadata, Error> {
+        let meta = fs::symlink_metadata(path)?;
+        #[allow(unused_mut)]
+        let mut file_metadata = FileMetadata {
+            atime: meta.accessed().ok().map(DateTime::from),
+            mtime: meta.modified().ok().map(DateTime::from),
+            ..FileMetadata::default()
+        };
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            file_metadata.mode = Some(meta.mode() & 0o7777);
+            file_metadata.uid = Some(meta.uid());
+            file_metadata.gid = Some(meta.gid());
+
+            if let Ok(names) = xattr::list(path) {
+                for name in names {
+                    let name = name.to_string_lossy().to_string();
+                    let Ok(Some(value)) = xattr::get(path, &name) else {
+                        continue;
+                    };
+                    let value = hex::encode(value);
+                    match name.as_str() {
+                        ACL_ACCESS_XATTR => file_metadata.acl_access = Some(value),
+                        ACL_DEFAULT_XATTR => file_metadata.acl_default = Some(value),
+                        _ => {
+                            file_metadata.xattrs.insert(name, value);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(file_metadata)
+    }
+
+    /// 读取远程源路径的元数据, 远程存储只提供修改时间; 同时返回是否为目录
+    pub fn capture_remote(
+        storage: &dyn StorageBackend,
+        path: &Path,
+    ) -> Result<(FileMetadata, bool), Error> {
+        let meta = storage.stat(path)?.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("远程源路径 {} 不存在", path.display()),
+            )
+        })?;
+        let file_metadata = FileMetadata {
+            mtime: Some(DateTime::from(meta.modified)),
+            ..FileMetadata::default()
+        };
+        Ok((file_metadata, meta.is_dir()))
+    }
+
+    /// 将元数据应用到目标文件
+    /// 逐项应用, 某一项失败不影响其余项, 返回失败的项
+    /// 目标文件系统不支持或权限不足时会失败, 元数据仍保留在清单中
+    /// 符号链接只应用所有者, 设备等特殊文件不应用扩展属性和时间(打开命名管道会阻塞)
+    pub fn apply(&self, path: &Path) -> Vec<String> {
+        let mut failed = Vec::new();
+        let Ok(file_type) = fs::symlink_metadata(path).map(|m| m.file_type()) else {
+            failed.push("路径不存在".to_string());
+            return failed;
+        };
+        let is_regular = file_type.is_file() || file_type.is_dir();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if self.uid.is_some() || self.gid.is_some() {
+                if let Err(e) = std::os::unix::fs::lchown(path, self.uid, self.gid) {
+                    failed.push(format!("uid/gid: {}", e));
+                }
+            }
+            if file_type.is_symlink() {
+                return failed;
+            }
+            for (name, value) in self.xattrs.iter().filter(|_| is_regular) {
+                if let Err(e) = set_hex_xattr(path, name, value) {
+                    failed.push(format!("xattr {}: {}", name, e));
+                }
+            }
+            if let Some(value) = self.acl_access.as_ref().filter(|_| is_regular) {
+                if let Err(e) = set_hex_xattr(path, ACL_ACCESS_XATTR, value) {
+                    failed.push(format!("acl: {}", e));
+                }
+            }
+            if let Some(value) = self.acl_default.as_ref().filter(|_| is_regular) {
+                if let Err(e) = set_hex_xattr(path, ACL_DEFAULT_XATTR, value) {
+                    failed.push(format!("default acl: {}", e));
+                }
+            }
+            // 修改所有者会清除 setuid 位, 因此最后设置权限
+            if let Some(mode) = self.mode {
+                if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+                    failed.push(format!("mode: {}", e));
+                }
+            }
+        }
+
+        if is_regular && (self.atime.is_some() || self.mtime.is_some()) {
+            let mut times = FileTimes::new();
+            if let Some(atime) = self.atime {
+                times = times.set_accessed(atime.into());
+            }
+            if let Some(mtime) = self.mtime {
+                times = times.set_modified(mtime.into());
+            }
+            if let Err(e) = File::open(path).and_then(|f| f.set_times(times)) {
+                failed.push(format!("atime/mtime: {}", e));
+            }
+        }
+        failed
+    }
+}
+
+#[cfg(unix)]
+fn set_hex_xattr(path: &Path, name: &str, value: &str) -> Result<(), Error> {
+    let value = hex::decode(value).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    xattr::set(path, name, &value)
+}
+
+/// 备份目录的清单
+/// 以备份目录的相对路径为键, 记录源文件的元数据
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Manifest {
+    /// 清单最后更新的时间
+    pub updated_at: Option<DateTime<Local>>,
+    /// 备份目录中普通文件内容的压缩方式
+    #[serde(default, skip_serializing_if = "is_uncompressed")]
+    pub compression: Compression,
+    pub files: BTreeMap<String, FileMetadata>,
+}
+
+impl Manifest {
+    pub fn get_manifest_path(backup_path: &Path) -> PathBuf {
+        backup_path.join(MANIFEST_FILE_NAME)
+    }
+
+    /// 清单中有需要保存的内容: 无法直接保存的路径、元数据或压缩方式
+    pub fn needs_saving(&self) -> bool {
+        !self.files.is_empty() || self.compression != Compression::None
+    }
+
+    /// 读取备份目录中的清单, 不存在时返回空清单
+    pub fn load(storage: &dyn StorageBackend, backup_path: &Path) -> Result<Manifest, Error> {
+        let manifest_path = Manifest::get_manifest_path(backup_path);
+        match storage.read(&manifest_path) {
+            Ok(buf) => serde_yaml::from_slice(&buf).map_err(|e| {
+                Error::other(format!("读取清单 {:?} 时发生错误: {:?}", manifest_path, e))
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&mut self, storage: &dyn StorageBackend, backup_path: &Path) -> Result<(), Error> {
+        self.updated_at = Some(Local::now());
+        let yaml_str = serde_yaml::to_string(self)
+            .map_err(|e| Error::other(format!("Failed to serialize Manifest to YAML: {:?}", e)))?;
+        let manifest_path = Manifest::get_manifest_path(backup_path);
+        let tmp_path = manifest_path.with_extension("yaml.tmp");
+        storage.write(&tmp_path, &mut yaml_str.as_bytes())?;
+        storage.rename(&tmp_path, &manifest_path)
+    }
+
+    /// 记录源路径的元数据并应用到备份目录中对应的路径
+    /// path_list 中的路径位于 sources 的源目录内, 且已经复制到 backup_path 中
+    /// 备份目录中不存在的路径(如设备文件)只记录不应用
+    /// 目录在文件之后、由深到浅应用, 避免写入文件改变目录的修改时间
+    /// 返回应用失败的路径数
+    pub fn record_and_apply(
+        &mut self,
+        task_name: &str,
+        path_list: &[String],
+        sources: &SourceSet,
+        backup_path: &Path,
+    ) -> usize {
+        let mut entries: Vec<(String, PathBuf, FileMetadata, bool)> = Vec::new();
+        for path in path_list {
+            let source = Path::new(path);
+            let Some(relative) = sources.relative(source) else {
+                continue;
+            };
+            let target = backup_path.join(&relative);
+            let captured = match sources.storage(source) {
+                Some(storage) => FileMetadata::capture_remote(storage, source),
+                None => FileMetadata::capture(source).map(|m| (m, source.is_dir())),
+            };
+            match captured {
+                Ok((file_metadata, is_dir)) => entries.push((
+                    relative.to_string_lossy().to_string(),
+                    target,
+                    file_metadata,
+                    is_dir,
+                )),
+                Err(e) => warn!("{}:读取 {} 的元数据时发生错误:{}", task_name, path, e),
+            }
+        }
+        entries.sort_by(|a, b| {
+            a.3.cmp(&b.3)
+                .then_with(|| b.1.components().count().cmp(&a.1.components().count()))
+        });
+
+        let mut failed_count = 0;
+        for (relative, target, mut file_metadata, _) in entries {
+            // 保留复制时记录的路径类型
+            if let Some(existing) = self.files.get_mut(&relative) {
+                file_metadata.kind = std::mem::take(&mut existing.kind);
+            }
+            let failed = if fs::symlink_metadata(&target).is_ok() {
+                file_metadata.apply(&target)
+            } else {
+                Vec::new()
+            };
+            if !failed.is_empty() {
+                failed_count += 1;
+                log::debug!(
+                    "{}:应用 {} 的元数据时发生错误:{}",
+                    task_name,
+                    relative,
+                    failed.join("; ")
+                );
+            }
+            self.files.insert(relative, file_metadata);
+        }
+        if failed_count > 0 {
+            warn!(
+                "{}:[{}]个路径的元数据未能完整应用到备份目录(目标文件系统不支持或权限不足), 已保存在清单中",
+                task_name, failed_count
+            );
+        }
+        failed_count
+    }
+
+    /// 将清单中的元数据应用到还原目录
+    /// 目录在文件之后、由深到浅应用
+    /// 返回应用失败的路径数
+    pub fn apply_to(&self, task_name: &str, restore_path: &Path) -> usize {
+        let mut entries: Vec<(&String, &FileMetadata, PathBuf)> = self
+            .files
+            .iter()
+            .map(|(relative, file_metadata)| (relative, file_metadata, restore_path.join(relative)))
+            .filter(|(_, _, target)| fs::symlink_metadata(target).is_ok())
+            .collect();
+        entries.sort_by(|a, b| {
+            a.2.is_dir()
+                .cmp(&b.2.is_dir())
+                .then_with(|| b.2.components().count().cmp(&a.2.components().count()))
+        });
+
+        let mut failed_count = 0;
+        for (relative, file_metadata, target) in entries {
+            let failed = file_metadata.apply(&target);
+            if !failed.is_empty() {
+                failed_count += 1;
+                warn!(
+                    "{}:还原 {} 的元数据时发生错误:{}",
+                    task_name,
+                    relative,
+                    failed.join("; ")
+                );
+            }
+        }
+        failed_count
+    }
+
+    /// 记录一个非普通文件的路径类型
+    pub fn record_kind(&mut self, relative: &str, kind: EntryKind) {
+        self.files.entry(relative.to_string()).or_default().kind = kind;
+    }
+
+    /// 按清单重建备份目录中无法直接保存的路径: 符号链接、硬链接、命名管道和设备文件
+    /// 应当在复制完普通文件之后、应用元数据之前调用
+    /// 返回重建失败的路径数
+    pub fn restore_entries(&self, task_name: &str, restore_path: &Path) -> usize {
+        let mut failed_count = 0;
+        for (relative, file_metadata) in &self.files {
+            let target = restore_path.join(relative);
+            let result = match &file_metadata.kind {
+                EntryKind::Regular | EntryKind::Socket => continue,
+                EntryKind::HardLink { target: link_to } => {
+                    let _ = fs::remove_file(&target);
+                    fs::hard_link(restore_path.join(link_to), &target)
+                }
+                _ if fs::symlink_metadata(&target).is_ok() => continue,
+                EntryKind::Symlink { target: link_to } => create_symlink(link_to, &target),
+                kind => make_node(&target, kind, file_metadata.mode.unwrap_or(0o600)),
+            };
+            if let Err(e) = result {
+                failed_count += 1;
+                warn!("{}:重建 {} 时发生错误:{}", task_name, relative, e);
+            }
+        }
+        failed_count
+    }
+
+    /// 只保留 keep 返回 true 的记录
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        self.files.retain(|relative, _| keep(relative));
+    }
+}
+
+fn is_uncompressed(compression: &Compression) -> bool {
+    *compression == Compression::None
+}
+
+/// 创建符号链接
+pub fn create_symlink(link_to: &str, path: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(link_to, path)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (link_to, path);
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "当前系统不支持创建符号链接",
+        ))
+    }
+}
+
+/// 创建命名管道或设备文件, 设备文件需要 root 权限
+fn make_node(path: &Path, kind: &EntryKind, mode: u32) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let mode = mode as libc::mode_t;
+        let ret = match kind {
+            EntryKind::Fifo => unsafe { libc::mkfifo(c_path.as_ptr(), mode) },
+            EntryKind::CharDevice { rdev } => unsafe {
+                libc::mknod(c_path.as_ptr(), libc::S_IFCHR | mode, *rdev as libc::dev_t)
+            },
+            EntryKind::BlockDevice { rdev } => unsafe {
+                libc::mknod(c_path.as_ptr(), libc::S_IFBLK | mode, *rdev as libc::dev_t)
+            },
+            _ => return Ok(()),
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(Error::last_os_error())
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, kind, mode);
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "当前系统不支持创建特殊文件",
+        ))
+    }
+}
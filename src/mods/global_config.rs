@@ -0,0 +1,64 @@
+use super::hooks;
+use super::smtp::SmtpConfig;
+use super::webhook::WebhookConfig;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// 全局配置, 对所有备份任务生效
+/// 取程序目录下的 rsbk.yaml, 文件不存在时使用默认配置
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GlobalConfig {
+    /// 任意任务的运行结果为 Failed 或 Partial 时依次执行的命令
+    /// 与任务的 post_backup 命令使用相同的环境变量
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_failure: Vec<String>,
+    /// on_failure 中每条命令的超时秒数
+    #[serde(default = "hooks::default_timeout_seconds")]
+    pub hook_timeout_seconds: u64,
+    /// 邮件通知, 不填写时不发送邮件
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smtp: Option<SmtpConfig>,
+    /// webhook 通知, 每个地址按自己的格式及事件发送
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookConfig>,
+    /// 任务超过该小时数没有成功的备份(包括无更新)时发送 Stale 通知, 0 表示不检查
+    #[serde(default)]
+    pub stale_hours: u64,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        GlobalConfig {
+            on_failure: Vec::new(),
+            hook_timeout_seconds: hooks::default_timeout_seconds(),
+            smtp: None,
+            webhooks: Vec::new(),
+            stale_hours: 0,
+        }
+    }
+}
+
+impl GlobalConfig {
+    pub fn get_config_path() -> PathBuf {
+        PathBuf::from("rsbk.yaml")
+    }
+
+    /// 读取全局配置, 读取或解析失败时记录错误并使用默认配置
+    pub fn load() -> GlobalConfig {
+        let config_path = GlobalConfig::get_config_path();
+        match fs::read_to_string(&config_path) {
+            Ok(buf) => serde_yaml::from_str(&buf).unwrap_or_else(|e| {
+                error!("读取全局配置文件 {:?} 时发生错误: {:?}", config_path, e);
+                GlobalConfig::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => GlobalConfig::default(),
+            Err(e) => {
+                error!("读取全局配置文件 {:?} 时发生错误: {}", config_path, e);
+                GlobalConfig::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use super::bk_config::{BackupConfig, BackupMode};
+use super::database;
+use super::global_config::GlobalConfig;
+use super::run_stats::{RunStats, RunStatus};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 备份前后执行的命令
+/// 每条命令由 sh -c(Windows 下为 cmd /C)执行, 任务信息及运行结果通过 RSBK_ 开头的环境变量传入
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HooksConfig {
+    /// 备份前依次执行的命令
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre_backup: Vec<String>,
+    /// 备份后依次执行的命令, 备份失败或被中止时同样执行
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post_backup: Vec<String>,
+    /// 每条命令的超时秒数, 超时后终止命令并视为失败
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// 备份前的命令失败时是否中止本次备份, 默认中止
+    #[serde(default = "default_abort_on_failure")]
+    pub abort_on_failure: bool,
+}
+
+pub fn default_timeout_seconds() -> u64 {
+    300
+}
+
+fn default_abort_on_failure() -> bool {
+    true
+}
+
+/// 执行钩子的阶段, 通过环境变量 RSBK_HOOK 传入
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    PreBackup,
+    PostBackup,
+    OnFailure,
+}
+
+impl Stage {
+    fn name(&self) -> &'static str {
+        match self {
+            Stage::PreBackup => "pre_backup",
+            Stage::PostBackup => "post_backup",
+            Stage::OnFailure => "on_failure",
+        }
+    }
+}
+
+/// 在备份前后执行任务的钩子命令
+/// backup 为实际执行备份的函数, 备份前的命令失败且 abort_on_failure 时不执行备份, 结果记为 Failed
+/// 备份后的命令总会执行, 结果为 Failed 或 Partial 时再执行全局配置中的 on_failure 命令
+/// 钩子命令的失败只记录到日志, 不改变备份的结果
+pub fn run_with_hooks(
+    task_name: &str,
+    config: &BackupConfig,
+    backup: impl FnOnce() -> RunStats,
+) -> RunStats {
+    run_hooks(task_name, config, &GlobalConfig::load(), backup)
+}
+
+fn run_hooks(
+    task_name: &str,
+    config: &BackupConfig,
+    global: &GlobalConfig,
+    backup: impl FnOnce() -> RunStats,
+) -> RunStats {
+    let hooks = config.hooks.as_ref();
+
+    let stats = match hooks.filter(|h| !h.pre_backup.is_empty()) {
+        Some(hooks) => match run_commands(
+            task_name,
+            config,
+            Stage::PreBackup,
+            &hooks.pre_backup,
+            hooks.timeout_seconds,
+            None,
+        ) {
+            Err(e) if hooks.abort_on_failure => {
+                error!("{}:备份前的命令执行失败, 中止本次备份:{}", task_name, e);
+                let mut stats = RunStats::start(task_name);
+                stats.fail(&Error::new(e.kind(), format!("备份前的命令执行失败:{}", e)));
+                stats.finish();
+                stats
+            }
+            Err(e) => {
+                warn!("{}:备份前的命令执行失败, 继续备份:{}", task_name, e);
+                backup()
+            }
+            Ok(()) => backup(),
+        },
+        None => backup(),
+    };
+
+    if let Some(hooks) = hooks.filter(|h| !h.post_backup.is_empty()) {
+        if let Err(e) = run_commands(
+            task_name,
+            config,
+            Stage::PostBackup,
+            &hooks.post_backup,
+            hooks.timeout_seconds,
+            Some(&stats),
+        ) {
+            error!("{}:备份后的命令执行失败:{}", task_name, e);
+        }
+    }
+
+    if matches!(stats.status, RunStatus::Failed | RunStatus::Partial)
+        && !global.on_failure.is_empty()
+    {
+        if let Err(e) = run_commands(
+            task_name,
+            config,
+            Stage::OnFailure,
+            &global.on_failure,
+            global.hook_timeout_seconds,
+            Some(&stats),
+        ) {
+            error!("{}:备份失败时的命令执行失败:{}", task_name, e);
+        }
+    }
+
+    stats
+}
+
+/// 依次执行命令, 遇到失败的命令即停止并返回错误
+fn run_commands(
+    task_name: &str,
+    config: &BackupConfig,
+    stage: Stage,
+    commands: &[String],
+    timeout_seconds: u64,
+    stats: Option<&RunStats>,
+) -> Result<(), Error> {
+    let envs = hook_env(task_name, config, stage, stats);
+    for command in commands {
+        info!("{}:执行{}命令: {}", task_name, stage.name(), command);
+        run_command(
+            task_name,
+            command,
+            &envs,
+            Duration::from_secs(timeout_seconds),
+        )?;
+    }
+    Ok(())
+}
+
+/// 传给钩子命令的环境变量
+/// 所有阶段都有 RSBK_TASK、RSBK_HOOK、RSBK_MODE、RSBK_SOURCES、RSBK_DESTINATIONS,
+/// 多个源目录或备份目的地以换行分隔, 地址中的密码替换为 ***
+/// 备份后及失败时另有本次运行的结果 RSBK_STATUS、RSBK_FILES_COPIED、RSBK_FILES_FAILED、
+/// RSBK_BYTES_WRITTEN、RSBK_DURATION_SECONDS、RSBK_ERRORS
+fn hook_env(
+    task_name: &str,
+    config: &BackupConfig,
+    stage: Stage,
+    stats: Option<&RunStats>,
+) -> Vec<(&'static str, String)> {
+    let mode = match config.options {
+        BackupMode::IncrementalMode { .. } => "IncrementalMode",
+        BackupMode::VersionMode { .. } => "VersionMode",
+        BackupMode::ReplicateMode { .. } => "ReplicateMode",
+    };
+    let sources: Vec<String> = config.sources().iter().map(|s| s.label()).collect();
+    let destinations: Vec<String> = config
+        .destinations()
+        .iter()
+        .map(|d| database::redact(&d.path))
+        .collect();
+    let mut envs = vec![
+        ("RSBK_TASK", task_name.to_string()),
+        ("RSBK_HOOK", stage.name().to_string()),
+        ("RSBK_MODE", mode.to_string()),
+        ("RSBK_SOURCES", sources.join("\n")),
+        ("RSBK_DESTINATIONS", destinations.join("\n")),
+    ];
+    if let Some(stats) = stats {
+        envs.extend([
+            ("RSBK_STATUS", format!("{:?}", stats.status)),
+            ("RSBK_FILES_COPIED", stats.files_copied.to_string()),
+            ("RSBK_FILES_FAILED", stats.files_failed.to_string()),
+            ("RSBK_BYTES_WRITTEN", stats.bytes_written.to_string()),
+            (
+                "RSBK_DURATION_SECONDS",
+                format!("{:.3}", stats.duration_secs),
+            ),
+            ("RSBK_ERRORS", stats.errors.join("\n")),
+        ]);
+    }
+    envs
+}
+
+/// 执行一条命令并等待其结束, 输出逐行写入日志
+/// 超时后终止命令(Unix 下终止整个进程组), 退出码不为 0 或超时均返回错误
+fn run_command(
+    task_name: &str,
+    command: &str,
+    envs: &[(&'static str, String)],
+    timeout: Duration,
+) -> Result<(), Error> {
+    let mut child = shell(command)
+        .envs(envs.iter().map(|(k, v)| (*k, v)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::new(e.kind(), format!("无法执行命令 {}:{}", command, e)))?;
+
+    // 命令启动的后台进程可能继承输出管道, 因此不等待日志线程结束
+    if let Some(stdout) = child.stdout.take() {
+        log_output(task_name, stdout, false);
+    }
+    if let Some(stderr) = child.stderr.take() {
+        log_output(task_name, stderr, true);
+    }
+
+    match wait_timeout(&mut child, timeout)? {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => Err(Error::other(format!(
+            "命令 {} 退出状态为 {}",
+            command, status
+        ))),
+        None => Err(Error::new(
+            ErrorKind::TimedOut,
+            format!(
+                "命令 {} 超过 {} 秒未结束, 已终止",
+                command,
+                timeout.as_secs()
+            ),
+        )),
+    }
+}
+
+/// 等待命令结束, 超过 timeout 时终止命令并返回 None
+/// 由 isolate 设置过的命令连同其启动的子进程一起终止
+pub fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            kill(child);
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    isolate(&mut cmd);
+    cmd
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+/// 使命令在单独的进程组中运行, 超时时连同命令启动的子进程一起终止
+#[cfg(unix)]
+pub fn isolate(cmd: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    cmd.process_group(0);
+}
+
+#[cfg(not(unix))]
+pub fn isolate(_cmd: &mut Command) {}
+
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+fn log_output(task_name: &str, output: impl Read + Send + 'static, is_stderr: bool) {
+    let task_name = task_name.to_string();
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            if is_stderr {
+                warn!("{}:[hook] {}", task_name, line);
+            } else {
+                info!("{}:[hook] {}", task_name, line);
+            }
+        }
+    });
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::fs;
+    use std::path::Path;
+
+    fn config(hooks: &str) -> BackupConfig {
+        serde_yaml::from_str(&format!(
+            "backup_source_path: /data/app\nsources:\n  - path: postgres://backup:secret@db:5432/app\nbackup_destination_path: /backup\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: IncrementalMode\n  save_days: 3\nhooks:\n{}",
+            hooks
+        ))
+        .unwrap()
+    }
+
+    fn finished(status: RunStatus) -> RunStats {
+        let mut stats = RunStats::start("hooks_test");
+        stats.status = status;
+        stats.finish();
+        stats
+    }
+
+    fn global(on_failure: &str) -> GlobalConfig {
+        GlobalConfig {
+            on_failure: vec![on_failure.to_string()],
+            ..GlobalConfig::default()
+        }
+    }
+
+    #[test]
+    fn failed_pre_backup_aborts_backup() {
+        let config = config("  pre_backup: [\"exit 3\"]\n");
+        let ran = Cell::new(false);
+        let stats = run_hooks("hooks_test", &config, &GlobalConfig::default(), || {
+            ran.set(true);
+            finished(RunStatus::Success)
+        });
+        assert!(!ran.get());
+        assert_eq!(stats.status, RunStatus::Failed);
+        assert!(stats.errors[0].contains("备份前的命令执行失败"));
+    }
+
+    #[test]
+    fn failed_pre_backup_continues_without_abort_on_failure() {
+        let config = config("  pre_backup: [\"exit 3\"]\n  abort_on_failure: false\n");
+        let ran = Cell::new(false);
+        let stats = run_hooks("hooks_test", &config, &GlobalConfig::default(), || {
+            ran.set(true);
+            finished(RunStatus::NoChange)
+        });
+        assert!(ran.get());
+        assert_eq!(stats.status, RunStatus::NoChange);
+    }
+
+    #[test]
+    fn post_backup_runs_after_failed_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("post");
+        let config = config(&format!(
+            "  post_backup: [\"echo $RSBK_HOOK $RSBK_STATUS > {}\"]\n",
+            output.display()
+        ));
+        let stats = run_hooks("hooks_test", &config, &GlobalConfig::default(), || {
+            finished(RunStatus::Failed)
+        });
+        assert_eq!(stats.status, RunStatus::Failed);
+        assert_eq!(fs::read_to_string(&output).unwrap(), "post_backup Failed\n");
+    }
+
+    #[test]
+    fn on_failure_runs_for_failed_and_partial() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("on_failure");
+        let global = global(&format!(
+            "echo $RSBK_HOOK $RSBK_STATUS >> {}",
+            output.display()
+        ));
+        let config = config("  timeout_seconds: 10\n");
+        for status in [
+            RunStatus::Failed,
+            RunStatus::Success,
+            RunStatus::Partial,
+            RunStatus::NoChange,
+        ] {
+            run_hooks("hooks_test", &config, &global, || finished(status));
+        }
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "on_failure Failed\non_failure Partial\n"
+        );
+    }
+
+    #[test]
+    fn timeout_kills_process_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let started = Instant::now();
+        let e = run_command(
+            "hooks_test",
+            &format!("sleep 30 & echo $! > {}; wait", pid_file.display()),
+            &[],
+            Duration::from_secs(1),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // 命令启动的后台进程同样被终止
+        let pid = fs::read_to_string(&pid_file).unwrap();
+        let stat = Path::new("/proc").join(pid.trim()).join("stat");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while fs::read_to_string(&stat).is_ok_and(|s| !s.contains(") Z ")) {
+            assert!(Instant::now() < deadline, "后台进程未被终止");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn env_describes_task_and_hides_passwords() {
+        let config = config("  timeout_seconds: 10\n");
+        let mut stats = RunStats::start("hooks_test");
+        stats.files_copied = 3;
+        stats.files_failed = 1;
+        stats.bytes_written = 42;
+        stats.errors = vec!["a: 错误".to_string(), "b: 错误".to_string()];
+        stats.finish();
+        let envs = hook_env("hooks_test", &config, Stage::PostBackup, Some(&stats));
+        let env = |name: &str| {
+            envs.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap()
+        };
+        assert_eq!(env("RSBK_TASK"), "hooks_test");
+        assert_eq!(env("RSBK_HOOK"), "post_backup");
+        assert_eq!(env("RSBK_MODE"), "IncrementalMode");
+        assert_eq!(
+            env("RSBK_SOURCES"),
+            "/data/app\npostgres://backup:***@db:5432/app"
+        );
+        assert_eq!(env("RSBK_DESTINATIONS"), "/backup");
+        assert_eq!(env("RSBK_STATUS"), "Partial");
+        assert_eq!(env("RSBK_FILES_COPIED"), "3");
+        assert_eq!(env("RSBK_FILES_FAILED"), "1");
+        assert_eq!(env("RSBK_BYTES_WRITTEN"), "42");
+        assert_eq!(env("RSBK_ERRORS"), "a: 错误\nb: 错误");
+        assert!(envs.iter().all(|(_, value)| !value.contains("secret")));
+
+        let envs = hook_env("hooks_test", &config, Stage::PreBackup, None);
+        assert!(envs.iter().all(|(key, _)| *key != "RSBK_STATUS"));
+    }
+}
@@ -1,161 +1,721 @@
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashSet};
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use super::{
-    base_bk_option,
-    bk_config::{BackupConfig, BackupMode},
+    base_bk_option::{self, context, CopyTarget, SourceSet},
+    bk_config::{BackupConfig, BackupMode, Compression},
+    catalog::Catalog,
+    file_metadata::Manifest,
+    prune::PruneReport,
+    quota::quota_error,
+    remote,
+    run_stats::{RunStats, RunStatus},
+    snapshot::SnapshotSet,
+    storage::{self, StorageBackend},
 };
-use log::{error, info};
+use log::{error, info, warn};
 
 ///基于增量备份模式，根据文件的更新情况进行备份，并根据保存天数删除过期文件
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IncrementalMode {
     pub task_config: BackupConfig,
+    /// 备份目的地的存储, 默认为本地磁盘
+    #[serde(skip, default = "storage::local")]
+    pub storage: Arc<dyn StorageBackend>,
 }
+
+/// 一个备份目的地在本次备份中的状态
+struct Destination {
+    /// 日志中使用的名称, 有多个目的地时附带目的地
+    label: String,
+    /// 文件目录的名称, 见 BackupConfig::destination_key
+    key: String,
+    /// 该目的地的任务配置
+    config: BackupConfig,
+    /// 该目的地的存储, 打开目的地时确定
+    storage: Arc<dyn StorageBackend>,
+    compression: Compression,
+    stats: RunStats,
+    result: Result<(), Error>,
+    /// 可以写入的目的地的备份目录及文件目录
+    pending: Option<(PathBuf, Catalog)>,
+    /// 需要复制到此目的地的路径
+    path_list: Vec<String>,
+}
+
+impl Destination {
+    /// 记录该目的地的错误, 不再向其复制文件
+    /// 文件目录仍会保存, 保留已删除的过期文件等变动
+    fn fail(&mut self, e: Error) {
+        error!("{}:{}", self.label, e);
+        self.path_list.clear();
+        self.result = Err(e);
+    }
+
+    fn save_days(&self) -> usize {
+        match self.config.options {
+            BackupMode::IncrementalMode { save_days, .. } => save_days,
+            BackupMode::VersionMode { .. } | BackupMode::ReplicateMode { .. } => 0,
+        }
+    }
+}
+
 impl IncrementalMode {
     /// 创建整个备份计划
     /// 应当只使用这个create生成计划
     pub fn create(task: BackupConfig) -> Self {
-        IncrementalMode { task_config: task }
+        IncrementalMode {
+            task_config: task,
+            storage: storage::local(),
+        }
     }
+
+    /// 备份到所有备份目的地
+    pub fn backup(&self, task_name: &str) -> RunStats {
+        let due: Vec<usize> = (0..self.task_config.destinations().len()).collect();
+        self.backup_to(task_name, &due)
+    }
+
     ///  用于执行备份计划，根据配置信息进行备份操作。
-    /// 在backup方法中，首先获取备份根路径。
-    /// 接着获取所有需要备份的文件路径，并判断是否有更新，如果有更新则进行备份操作。
-    /// 备份操作包括创建备份文件夹、将文件复制到备份目录中，并记录备份大小。
-    /// 备份完成后，根据保存天数删除过期文件，并删除空的备份文件夹。
-    pub fn backup(&self, task_name: &String) {
-        let backup_path: PathBuf;
-
-        match base_bk_option::get_backup_path(
-            &self.task_config.backup_destination_path,
-            &self.task_config.detect_path_title().unwrap_or_default(),
-        ) {
-            Ok(path) => backup_path = path,
-            Err(e) => {
-                error!(
+    /// due 为本次需要备份的目的地在 BackupConfig::destinations 中的序号。
+    /// 在backup方法中，首先获取各目的地的备份根路径及文件目录。
+    /// 接着遍历一次源目录, 按各目的地的文件目录判断需要备份的文件，如果有更新则进行备份操作。
+    /// 备份操作包括创建备份文件夹、读取一次源文件并复制到需要的目的地中，并记录备份大小。
+    /// 备份前先根据各目的地的保存天数删除过期文件，并检查空间限额。
+    /// 一个目的地出错不影响其他目的地, 返回本次运行的统计信息, 其中包含各目的地的结果
+    pub fn backup_to(&self, task_name: &str, due: &[usize]) -> RunStats {
+        let mut stats = RunStats::start(task_name);
+        let result = self
+            .destinations(task_name, due)
+            .and_then(|mut destinations| {
+                let result = self.backup_files(task_name, &mut destinations, &mut stats);
+                self.finish(destinations, &result, &mut stats);
+                result
+            });
+        if let Err(e) = result {
+            error!(
+                "{:#?}",
+                &(task_name.to_owned() + ":" + e.to_string().as_str())
+            );
+            stats.fail(&e);
+        }
+        stats.finish();
+        stats
+    }
+
+    /// 本次需要备份的目的地
+    fn destinations(&self, task_name: &str, due: &[usize]) -> Result<Vec<Destination>, Error> {
+        let configs = self.task_config.destination_configs()?;
+        let compressions: Vec<Compression> = self
+            .task_config
+            .destinations()
+            .iter()
+            .map(|d| d.compression)
+            .collect();
+        let multiple = configs.len() > 1;
+        Ok(due
+            .iter()
+            .filter_map(|&i| Some((i, configs.get(i)?.clone())))
+            .map(|(i, config)| Destination {
+                label: if multiple {
+                    format!("{}({})", task_name, config.backup_destination_path)
+                } else {
+                    task_name.to_string()
+                },
+                key: BackupConfig::destination_key(task_name, i),
+                config,
+                storage: self.storage.clone(),
+                compression: compressions[i],
+                stats: RunStats::start(task_name),
+                result: Ok(()),
+                pending: None,
+                path_list: Vec::new(),
+            })
+            .collect())
+    }
+
+    /// 保存各目的地的文件目录并汇总结果
+    fn finish(
+        &self,
+        destinations: Vec<Destination>,
+        result: &Result<(), Error>,
+        stats: &mut RunStats,
+    ) {
+        for mut destination in destinations {
+            // 源目录出错时错误只记录在任务中
+            if result.is_err() {
+                destination.stats.status = RunStatus::Failed;
+            }
+            if let Some((_, catalog)) = &destination.pending {
+                if let Err(e) = catalog
+                    .save(&destination.key)
+                    .map_err(context("保存文件目录时发生错误"))
+                {
+                    destination.fail(e);
+                }
+            }
+            if let Err(e) = std::mem::replace(&mut destination.result, Ok(())) {
+                destination.stats.fail(&e);
+            }
+            stats.merge_destination(
+                &destination.config.backup_destination_path,
+                destination.stats,
+            );
+        }
+    }
+
+    /// 打开目的地的存储, 获取备份目录并读取文件目录, 按需与备份目录完整核对
+    /// 已有备份的压缩方式与配置不一致时继续使用已有的压缩方式
+    fn open_destination(
+        &self,
+        destination: &mut Destination,
+        reconcile: bool,
+    ) -> Result<(PathBuf, Catalog), Error> {
+        destination.storage = remote::destination_storage(&self.storage, &destination.config)
+            .map_err(context("打开备份目的地时发生错误"))?;
+        let backup_title = self.task_config.backup_title(&destination.stats.task_name);
+        let storage = destination.storage.clone();
+        let storage = storage.as_ref();
+        let backup_path = base_bk_option::get_backup_path(
+            storage,
+            &destination.config.backup_destination_path,
+            &backup_title,
+        )
+        .map_err(context("获取备份路径时发生错误"))?;
+
+        let mut catalog = Catalog::load(&destination.key, &backup_path)
+            .map_err(context("读取文件目录时发生错误"))?;
+        let manifest =
+            Manifest::load(storage, &backup_path).map_err(context("读取清单时发生错误"))?;
+        if !catalog.files.is_empty() && manifest.compression != destination.compression {
+            warn!(
+                "{}:压缩方式与已有备份不一致, 继续使用 {:?}",
+                destination.label, manifest.compression
+            );
+            destination.compression = manifest.compression;
+        }
+        catalog.compressed = destination.compression != Compression::None;
+
+        let BackupMode::IncrementalMode {
+            catalog_reconcile_days,
+            ..
+        } = destination.config.options
+        else {
+            return Ok((backup_path, catalog));
+        };
+        if reconcile && catalog.needs_reconcile(catalog_reconcile_days) {
+            info!(
+                "{:#?}",
+                &(destination.label.clone() + ":开始与备份目录完整核对文件目录")
+            );
+            catalog
+                .reconcile(storage)
+                .map_err(context("核对文件目录时发生错误"))?;
+            base_bk_option::delete_all_empty_dir(storage, &backup_path)
+                .map_err(context("删除空目录时发生错误"))?;
+        }
+        Ok((backup_path, catalog))
+    }
+
+    fn backup_files(
+        &self,
+        task_name: &str,
+        destinations: &mut [Destination],
+        stats: &mut RunStats,
+    ) -> Result<(), Error> {
+        let sources = self.task_config.source_set()?;
+        remote::pull_source(task_name, &self.task_config)
+            .map_err(context("准备源目录时发生错误"))?;
+        let snapshots = SnapshotSet::create(task_name, &self.task_config, sources)
+            .map_err(context("创建快照时发生错误"))?;
+        let sources = &snapshots.sources;
+        for destination in destinations.iter_mut() {
+            match self.open_destination(destination, true) {
+                Ok(pending) => destination.pending = Some(pending),
+                Err(e) => destination.fail(e),
+            }
+        }
+
+        let mut catalogs: Vec<(&mut Catalog, usize, &dyn StorageBackend)> = Vec::new();
+        let mut indices = Vec::new();
+        for (i, destination) in destinations.iter_mut().enumerate() {
+            let save_days = destination.save_days();
+            if let Some((_, catalog)) = &mut destination.pending {
+                catalogs.push((catalog, save_days, destination.storage.as_ref()));
+                indices.push(i);
+            }
+        }
+        let path_lists = base_bk_option::get_changed_paths(
+            sources,
+            self.task_config.symlink_policy,
+            &mut catalogs,
+            stats,
+        )
+        .map_err(context("获取备份文件时发生错误"))?;
+        for (i, path_list) in indices.into_iter().zip(path_lists) {
+            destinations[i].path_list = path_list;
+        }
+
+        // 先删除过期文件, 为本次备份腾出空间
+        for destination in destinations.iter_mut() {
+            if let Err(e) = self.prepare(sources, destination) {
+                destination.fail(e);
+            }
+        }
+
+        if destinations
+            .iter()
+            .all(|d| d.pending.is_none() || d.path_list.is_empty())
+        {
+            info!(
+                "{:#?}",
+                &(task_name.to_owned() + ":检查到无更新,等待下一个备份任务"),
+            );
+        } else {
+            info!(
+                "{:#?}",
+                &(task_name.to_owned() + ":当前任务使用动态目录模式,检查到有更新,开始备份")
+            );
+            self.copy_to_backup(sources, destinations, stats)?;
+        }
+        for destination in destinations.iter_mut() {
+            if destination.result.is_err() {
+                continue;
+            }
+            if let Some((backup_path, _)) = &destination.pending {
+                if destination.path_list.is_empty() && destination.stats.files_deleted == 0 {
+                    destination.stats.status = RunStatus::NoChange;
+                }
+                info!(
                     "{:#?}",
-                    &(task_name.to_owned() + ":获取备份路径时发生错误:" + e.to_string().as_str()),
+                    &(destination.label.clone()
+                        + ":删除超出保存时效的文件及空目录完成,备份目录为 ："
+                        + &backup_path.to_string_lossy()
+                        + ",等待下一个备份任务")
                 );
-                return;
-            }
-        }
-
-        if let BackupMode::IncrementalMode { save_days } = self.task_config.options {
-            match base_bk_option::get_all_path_by_day(
-                &self.task_config.backup_source_path,
-                &String::from(backup_path.as_path().to_str().expect("")),
-                &self.task_config.detect_path_title().unwrap_or_default(),
-                save_days,
-            ) {
-                Ok(path_list) => {
-                    if path_list.len() > 0 {
-                        info!(
-                            "{:#?}",
-                            &(task_name.to_owned()
-                                + ":当前任务使用动态目录模式,检查到有更新,开始备份")
-                        );
-
-                        match base_bk_option::create_all_dir(
-                            &path_list,
-                            &backup_path,
-                            &self.task_config.detect_path_title().unwrap_or_default(),
-                        ) {
-                            Ok(()) => {
-                                match base_bk_option::copy_file(
-                                    &path_list,
-                                    &backup_path,
-                                    &self.task_config.detect_path_title().unwrap_or_default(),
-                                ) {
-                                    Ok(size) => {
-                                        info!(
-                                            "{:#?}",
-                                            &(task_name.to_owned()
-                                                + ":备份完成，备份大小为["
-                                                + &size.to_string()
-                                                + "]MB"),
-                                        );
-
-                                        match base_bk_option::delete_expired_file(
-                                            &String::from(
-                                                backup_path.as_path().to_str().expect(""),
-                                            ),
-                                            save_days,
-                                        ) {
-                                            Ok(_) => {
-                                                match base_bk_option::delete_all_empty_dir(
-                                                    &String::from(
-                                                        backup_path.as_path().to_str().expect(""),
-                                                    ),
-                                                ) {
-                                                    Ok(_) => {
-                                                        info!(
-                                                                "{:#?}",
-                                                                &(task_name.to_owned()
-                                                                    + ":删除超出保存时效的文件及空目录完成,备份目录为 ："
-                                                                    + &backup_path.as_os_str().to_str().unwrap()
-                                                                    + ",等待下一个备份任务")
-                                                            );
-                                                    }
-                                                    Err(e) => {
-                                                        error!(
-                                                            "{:#?}",
-                                                            &(task_name.to_owned()
-                                                                + ":删除空目录时发生错误:"
-                                                                + e.to_string().as_str()),
-                                                        );
-                                                        return;
-                                                    }
-                                                }
-                                            }
-                                            Err(e) => {
-                                                error!(
-                                                    "{:#?}",
-                                                    &(task_name.to_owned()
-                                                        + ":删除超出保存时效的文件时发生错误:"
-                                                        + e.to_string().as_str()),
-                                                );
-                                                return;
-                                            }
-                                        }
-                                    }
-                                    Err(e) => {
-                                        error!(
-                                            "{:#?}",
-                                            &(task_name.to_owned()
-                                                + ":备份文件时发生错误:"
-                                                + e.to_string().as_str()),
-                                        );
-                                        return;
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!(
-                                    "{:#?}",
-                                    &(task_name.to_owned()
-                                        + ":创建备份文件夹时发生错误:"
-                                        + e.to_string().as_str()),
-                                );
-                                return;
-                            }
-                        }
-                    } else {
-                        info!(
-                            "{:#?}",
-                            &(task_name.to_owned() + ":检查到无更新,等待下一个备份任务"),
-                        );
-                    }
+            }
+        }
+        Ok(())
+    }
+
+    /// 清除回收站, 删除过期文件并检查空间限额
+    fn prepare(&self, sources: &SourceSet, destination: &mut Destination) -> Result<(), Error> {
+        let save_days = destination.save_days();
+        let Some((backup_path, catalog)) = &mut destination.pending else {
+            return Ok(());
+        };
+        let mut report =
+            PruneReport::for_task(&destination.config, &destination.stats.task_name, false);
+        let storage = destination.storage.as_ref();
+        if let Err(e) = report.purge_trash(storage) {
+            warn!("{}:清除回收站时发生错误:{}", destination.label, e);
+        }
+        let deleted = self.delete_expired(
+            storage,
+            &destination.key,
+            backup_path,
+            catalog,
+            save_days,
+            &mut report,
+        );
+        destination.stats.bytes_freed += report.bytes_freed();
+        destination.stats.files_deleted += deleted?;
+        self.check_quota(
+            storage,
+            &destination.config,
+            backup_path,
+            &destination.path_list,
+            sources,
+        )
+    }
+
+    /// 删除超过保存天数的文件, 并从清单中移除对应的记录
+    /// key 为文件目录的名称(BackupConfig::destination_key)
+    /// 返回删除的文件数
+    fn delete_expired(
+        &self,
+        storage: &dyn StorageBackend,
+        key: &str,
+        backup_path: &Path,
+        catalog: &mut Catalog,
+        save_days: usize,
+        report: &mut PruneReport,
+    ) -> Result<u64, Error> {
+        let mut manifest =
+            Manifest::load(storage, backup_path).map_err(context("读取清单时发生错误"))?;
+        let deleted = catalog
+            .delete_expired(storage, key, save_days, &manifest, report)
+            .map_err(context("删除超出保存时效的文件时发生错误"))?;
+        if deleted > 0 && !report.dry_run {
+            let mut kept = HashSet::new();
+            for relative in catalog.files.keys() {
+                for ancestor in Path::new(relative).ancestors() {
+                    kept.insert(ancestor.to_string_lossy().to_string());
                 }
-                Err(e) => {
-                    error!(
-                        "{:#?}",
-                        &(task_name.to_owned()
-                            + ":获取备份文件时发生错误:"
-                            + e.to_string().as_str()),
+            }
+            let recorded = manifest.files.len();
+            manifest.retain(|relative| kept.contains(relative));
+            if manifest.files.len() != recorded {
+                manifest
+                    .save(storage, backup_path)
+                    .map_err(context("保存清单时发生错误"))?;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// 只删除超过保存天数的文件, 不进行备份, 供 prune 命令使用
+    /// task_config 应为单个备份目的地的任务配置(BackupConfig::for_destination),
+    /// task_name 为该目的地文件目录的名称(BackupConfig::destination_key)
+    pub fn prune(&self, task_name: &str, report: &mut PruneReport) -> Result<(), Error> {
+        let BackupMode::IncrementalMode { save_days, .. } = self.task_config.options else {
+            return Ok(());
+        };
+        let storage = remote::destination_storage(&self.storage, &self.task_config)?;
+        let mut backup_path =
+            base_bk_option::get_backup_base_path(&self.task_config.backup_destination_path);
+        backup_path.push(self.task_config.backup_title(&report.task_name));
+        if !storage
+            .stat(&backup_path)?
+            .is_some_and(|meta| meta.is_dir())
+        {
+            return Ok(());
+        }
+        let mut catalog =
+            Catalog::load(task_name, &backup_path).map_err(context("读取文件目录时发生错误"))?;
+        self.delete_expired(
+            storage.as_ref(),
+            task_name,
+            &backup_path,
+            &mut catalog,
+            save_days,
+            report,
+        )?;
+        if !report.dry_run {
+            catalog
+                .save(task_name)
+                .map_err(context("保存文件目录时发生错误"))?;
+        }
+        Ok(())
+    }
+
+    /// 估算本次写入的大小并检查空间限额, 不足时返回错误, 不开始备份
+    /// 增量备份模式只删除过期文件, 不会为满足限额删除仍在保存期内的文件
+    fn check_quota(
+        &self,
+        storage: &dyn StorageBackend,
+        config: &BackupConfig,
+        backup_path: &Path,
+        path_list: &[String],
+        sources: &SourceSet,
+    ) -> Result<(), Error> {
+        let Some(quota) = &config.quota else {
+            return Ok(());
+        };
+        let estimate = base_bk_option::estimate_size(path_list, sources, config.symlink_policy);
+        let usage = quota
+            .usage(
+                storage,
+                backup_path,
+                &base_bk_option::get_backup_base_path(&config.backup_destination_path),
+            )
+            .map_err(context("读取备份空间占用时发生错误"))?;
+        match quota.shortfall(&usage, estimate, 0) {
+            Some(reason) => Err(quota_error(reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// 创建目录并复制文件, 将复制成功的文件写入各目的地的文件目录
+    /// 各目的地需要的路径合并后只读取一次源文件
+    fn copy_to_backup(
+        &self,
+        sources: &SourceSet,
+        destinations: &mut [Destination],
+        stats: &mut RunStats,
+    ) -> Result<(), Error> {
+        let policy = self.task_config.symlink_policy;
+        let storages: Vec<Arc<dyn StorageBackend>> =
+            destinations.iter().map(|d| d.storage.clone()).collect();
+        let mut targets = Vec::new();
+        let mut active = Vec::new();
+        let mut union = BTreeSet::new();
+        for (i, destination) in destinations.iter_mut().enumerate() {
+            let Some((backup_path, _)) = &destination.pending else {
+                continue;
+            };
+            if destination.path_list.is_empty() {
+                continue;
+            }
+            let backup_path = backup_path.clone();
+            let storage = storages[i].as_ref();
+            let created = base_bk_option::create_all_dir(
+                storage,
+                &destination.path_list,
+                &backup_path,
+                sources,
+                policy,
+                &mut destination.stats,
+            )
+            .map_err(context("创建备份文件夹时发生错误"))
+            .and_then(|_| {
+                Manifest::load(storage, &backup_path).map_err(context("读取清单时发生错误"))
+            });
+            match created {
+                Ok(mut manifest) => {
+                    manifest.compression = destination.compression;
+                    let stats = std::mem::replace(
+                        &mut destination.stats,
+                        RunStats::start(&stats.task_name),
+                    );
+                    let mut target = CopyTarget::new(
+                        storage,
+                        backup_path,
+                        destination.compression,
+                        manifest,
+                        stats,
                     );
-                    return;
+                    union.extend(destination.path_list.iter().cloned());
+                    target.paths = Some(destination.path_list.iter().cloned().collect());
+                    targets.push(target);
+                    active.push(i);
+                }
+                Err(e) => destination.fail(e),
+            }
+        }
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let path_list: Vec<String> = union.into_iter().collect();
+        base_bk_option::copy_file(&path_list, &mut targets, sources, policy, stats)
+            .map_err(context("备份文件时发生错误"))?;
+
+        for (target, i) in targets.into_iter().zip(active) {
+            let destination = &mut destinations[i];
+            destination.stats = target.stats;
+            let mut manifest = target.manifest;
+            if let Some((_, catalog)) = &mut destination.pending {
+                catalog.commit(
+                    target
+                        .copied
+                        .iter()
+                        .map(|path| sources.relative_key(Path::new(path))),
+                );
+            }
+            if destination.config.preserve_metadata {
+                manifest.record_and_apply(
+                    &destination.label,
+                    &destination.path_list,
+                    sources,
+                    &target.backup_path,
+                );
+            }
+            if manifest.needs_saving() {
+                if let Err(e) = manifest
+                    .save(target.storage, &target.backup_path)
+                    .map_err(context("保存清单时发生错误"))
+                {
+                    // 清单未保存时不保存文件目录, 下次重新复制这些文件
+                    destination.fail(e);
+                    destination.pending = None;
+                    continue;
                 }
             }
+            info!(
+                "{:#?}",
+                &(destination.label.clone()
+                    + ":备份完成，备份大小为["
+                    + &destination.stats.bytes_written.to_string()
+                    + "]字节"),
+            );
         }
+        Ok(())
+    }
+
+    /// 仅备份指定的路径到所有备份目的地, 供监听模式使用
+    /// 路径应位于源目录内, 已被删除的路径会被跳过
+    /// 不执行过期文件的删除, 由定期的完整扫描负责
+    pub fn backup_paths(&self, task_name: &str, paths: &[String]) -> RunStats {
+        let mut stats = RunStats::start(task_name);
+        let due: Vec<usize> = (0..self.task_config.destinations().len()).collect();
+        let result = self
+            .destinations(task_name, &due)
+            .and_then(|mut destinations| {
+                let result =
+                    self.backup_changed_paths(task_name, paths, &mut destinations, &mut stats);
+                self.finish(destinations, &result, &mut stats);
+                result
+            });
+        if let Err(e) = result {
+            error!(
+                "{:#?}",
+                &(task_name.to_owned() + ":" + e.to_string().as_str())
+            );
+            stats.fail(&e);
+        }
+        stats.finish();
+        stats
+    }
+
+    fn backup_changed_paths(
+        &self,
+        task_name: &str,
+        paths: &[String],
+        destinations: &mut [Destination],
+        stats: &mut RunStats,
+    ) -> Result<(), Error> {
+        let snapshots =
+            SnapshotSet::create(task_name, &self.task_config, self.task_config.source_set()?)
+                .map_err(context("创建快照时发生错误"))?;
+        let sources = &snapshots.sources;
+        // 监听到的路径位于原源目录, 从快照中读取时转换为快照中的路径
+        let paths: Vec<String> = paths.iter().map(|p| snapshots.translate(p)).collect();
+        for destination in destinations.iter_mut() {
+            match self.open_destination(destination, false) {
+                Ok(pending) => destination.pending = Some(pending),
+                Err(e) => destination.fail(e),
+            }
+        }
+
+        // 连同上级目录一起备份, 保证目标目录存在
+        for path in &paths {
+            let path = Path::new(path);
+            let kind = match sources.classify(path, self.task_config.symlink_policy) {
+                // 目录及已被删除的路径
+                Ok(base_bk_option::SourceKind::Dir) | Err(_) => continue,
+                Ok(kind) => kind,
+            };
+            stats.files_scanned += 1;
+            let mut is_changed = false;
+            for destination in destinations.iter_mut() {
+                let Some((_, catalog)) = &mut destination.pending else {
+                    continue;
+                };
+                if base_bk_option::check_changed(
+                    destination.storage.as_ref(),
+                    catalog,
+                    sources,
+                    path,
+                    &kind,
+                    None,
+                )
+                .map_err(context("检查文件变动时发生错误"))?
+                {
+                    destination
+                        .path_list
+                        .push(path.to_string_lossy().to_string());
+                    is_changed = true;
+                }
+            }
+            if !is_changed {
+                stats.files_skipped += 1;
+            }
+        }
+        for destination in destinations.iter_mut() {
+            destination.path_list = sources.with_ancestors(&destination.path_list);
+            if destination.pending.is_none() {
+                continue;
+            }
+            if destination.path_list.is_empty() {
+                destination.stats.status = RunStatus::NoChange;
+                continue;
+            }
+            if let Some((backup_path, _)) = &destination.pending {
+                if let Err(e) = self.check_quota(
+                    destination.storage.as_ref(),
+                    &destination.config,
+                    backup_path,
+                    &destination.path_list,
+                    sources,
+                ) {
+                    destination.fail(e);
+                }
+            }
+        }
+        self.copy_to_backup(sources, destinations, stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::storage::MemoryStorage;
+    use std::fs;
+
+    #[test]
+    fn backs_up_to_storage_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a"), b"one").unwrap();
+        fs::write(source.join("sub/b"), b"two").unwrap();
+        let task_config: BackupConfig = serde_yaml::from_str(&format!(
+            "backup_source_path: {}\nbackup_destination_path: /memory\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: IncrementalMode\n  save_days: 3\n",
+            source.display()
+        ))
+        .unwrap();
+        let storage = Arc::new(MemoryStorage::new());
+        let mode = IncrementalMode {
+            task_config,
+            storage: storage.clone(),
+        };
+        let task_name = "incremental_memory_storage_test";
+        let first = mode.backup(task_name);
+        fs::write(source.join("c"), b"three").unwrap();
+        let second = mode.backup(task_name);
+        let third = mode.backup(task_name);
+        fs::remove_file(Catalog::get_catalog_path(task_name)).unwrap();
+
+        assert_eq!(first.status, RunStatus::Success);
+        assert_eq!(first.files_copied, 2);
+        assert_eq!(second.files_copied, 1);
+        assert_eq!(third.status, RunStatus::NoChange);
+        let read = |relative: &str| storage.read(&Path::new("/memory/data").join(relative));
+        assert_eq!(read("a").unwrap(), b"one");
+        assert_eq!(read("sub/b").unwrap(), b"two");
+        assert_eq!(read("c").unwrap(), b"three");
+        // 备份只写入存储, 不写入本地磁盘
+        assert!(!Path::new("/memory").exists());
+    }
+
+    /// 估算的大小超出目的地的限额时不复制任何文件, 其他目的地照常备份
+    #[test]
+    fn destination_over_quota_aborts_before_copying() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a"), b"one").unwrap();
+        let task_config: BackupConfig = serde_yaml::from_str(&format!(
+            "backup_source_path: {}\nbackup_destination_path: /memory/main\ndestinations:\n  - path: /memory/small\n    quota:\n      max_task_size: 2\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: IncrementalMode\n  save_days: 3\n",
+            source.display()
+        ))
+        .unwrap();
+        let storage = Arc::new(MemoryStorage::new());
+        let mode = IncrementalMode {
+            task_config,
+            storage: storage.clone(),
+        };
+        let task_name = "incremental_destination_quota_test";
+        let stats = mode.backup(task_name);
+        let _ = fs::remove_file(Catalog::get_catalog_path(task_name));
+        let _ = fs::remove_file(Catalog::get_catalog_path(&BackupConfig::destination_key(
+            task_name, 1,
+        )));
+
+        assert_eq!(stats.status, RunStatus::Partial);
+        assert_eq!(stats.destinations[0].status, RunStatus::Success);
+        assert_eq!(stats.destinations[1].status, RunStatus::Failed);
+        assert!(stats.destinations[1].errors[0].contains("空间限额不足"));
+        assert_eq!(
+            storage.read(Path::new("/memory/main/data/a")).unwrap(),
+            b"one"
+        );
+        assert!(storage
+            .stat(Path::new("/memory/small/data/a"))
+            .unwrap()
+            .is_none());
     }
 }
@@ -1,181 +1,692 @@
-// use std::io::{self, ErrorKind};
-// use std::process::{Command, Stdio};
-
-// /// 关闭当前系统的所有网络接口
-// pub fn shutdown_all_interfaces() -> io::Result<()> {
-//     match detect_os() {
-//         Ok(OsType::Linux) => shutdown_all_interfaces_linux(),
-//         Ok(OsType::Windows) => shutdown_all_interfaces_windows(),
-//         Err(err) => Err(io::Error::new(
-//             ErrorKind::Other,
-//             format!("Failed to detect OS: {}", err),
-//         )),
-//     }
-// }
-
-// /// 开启当前系统的所有网络接口
-// pub fn startup_all_interfaces() -> io::Result<()> {
-//     match detect_os() {
-//         Ok(OsType::Linux) => startup_all_interfaces_linux(),
-//         Ok(OsType::Windows) => startup_all_interfaces_windows(),
-//         Err(err) => Err(io::Error::new(
-//             ErrorKind::Other,
-//             format!("Failed to detect OS: {}", err),
-//         )),
-//     }
-// }
-
-// /// 枚举类型表示操作系统类型
-// enum OsType {
-//     Linux,
-//     Windows,
-// }
-
-// /// 检测当前操作系统类型
-// fn detect_os() -> Result<OsType, String> {
-//     let os = std::env::consts::OS;
-//     match os {
-//         "linux" => Ok(OsType::Linux),
-//         "windows" => Ok(OsType::Windows),
-//         _ => Err(format!("Unsupported OS: {}", os)),
-//     }
-// }
-
-// /// Linux 平台关闭所有网络接口
-// fn shutdown_all_interfaces_linux() -> io::Result<()> {
-//     let output = Command::new("sudo")
-//         .arg("ifconfig")
-//         .arg("-a")
-//         .arg("down")
-//         .stdout(Stdio::inherit()) // 将子进程的输出重定向到父进程，方便调试
-//         .stderr(Stdio::inherit())
-//         .output()?;
-
-//     if output.status.success() {
-//         println!("Successfully shut down all interfaces on Linux");
-//         Ok(())
-//     } else {
-//         let error_msg = String::from_utf8_lossy(&output.stderr);
-//         Err(io::Error::new(
-//             ErrorKind::Other,
-//             format!("Failed to shut down interfaces on Linux: {}", error_msg),
-//         ))
-//     }
-// }
-
-// /// Linux 平台开启所有网络接口
-// fn startup_all_interfaces_linux() -> io::Result<()> {
-//     // 获取所有接口名称
-//     let output = Command::new("ip")
-//         .arg("link")
-//         .arg("show")
-//         .output()?;
-    
-//     if !output.status.success() {
-//         let error_msg = String::from_utf8_lossy(&output.stderr);
-//         return Err(io::Error::new(
-//             ErrorKind::Other,
-//             format!("Failed to get interface list on Linux: {}", error_msg),
-//         ));
-//     }
-    
-//     // 解析接口名称
-//     let output_str = String::from_utf8_lossy(&output.stdout);
-//     let interfaces: Vec<&str> = output_str.lines()
-//         .filter_map(|line| extract_interface_name(line))
-//         .collect();
-
-//     // 逐个启用每个接口
-//     for iface in &interfaces {
-//         println!("Found interface: {}", iface);
-
-//         // 启用接口
-//         let result = Command::new("sudo")
-//             .arg("ip")
-//             .arg("link")
-//             .arg("set")
-//             .arg(iface)
-//             .arg("up")
-//             .stdout(Stdio::inherit())
-//             .stderr(Stdio::inherit())
-//             .output();
-
-//         match result {
-//             Ok(output) if output.status.success() => {
-//                 println!("Successfully started up interface {}", iface);
-//             }
-//             Ok(output) => {
-//                 let error_msg = String::from_utf8_lossy(&output.stderr);
-//                 return Err(io::Error::new(
-//                     ErrorKind::Other,
-//                     format!("Failed to start up interface {}: {}", iface, error_msg),
-//                 ));
-//             }
-//             Err(err) => {
-//                 return Err(io::Error::new(
-//                     ErrorKind::Other,
-//                     format!("Failed to start up interface {}: {}", iface, err),
-//                 ));
-//             }
-//         }
-//     }
-
-//     Ok(())
-// }
-
-
-
-// /// Windows 平台关闭所有网络接口
-// fn shutdown_all_interfaces_windows() -> io::Result<()> {
-//     let output = Command::new("netsh")
-//         .arg("interface")
-//         .arg("set")
-//         .arg("interface")
-//         .arg("name=*")
-//         .arg("admin=disable")
-//         .output()?;
-
-//     if output.status.success() {
-//         println!("Successfully shut down all interfaces on Windows");
-//         Ok(())
-//     } else {
-//         let error_msg = String::from_utf8_lossy(&output.stderr);
-//         Err(io::Error::new(
-//             ErrorKind::Other,
-//             format!("Failed to shut down interfaces on Windows: {}", error_msg),
-//         ))
-//     }
-// }
-
-// /// Windows 平台开启所有网络接口
-// fn startup_all_interfaces_windows() -> io::Result<()> {
-//     let output = Command::new("netsh")
-//         .arg("interface")
-//         .arg("set")
-//         .arg("interface")
-//         .arg("name=*")
-//         .arg("admin=enable")
-//         .output()?;
-
-//     if output.status.success() {
-//         println!("Successfully started up all interfaces on Windows");
-//         Ok(())
-//     } else {
-//         let error_msg = String::from_utf8_lossy(&output.stderr);
-//         Err(io::Error::new(
-//             ErrorKind::Other,
-//             format!("Failed to start up interfaces on Windows: {}", error_msg),
-//         ))
-//     }
-// }
-
-// /// 辅助函数：从 IP link show 命令输出中提取接口名称
-// fn extract_interface_name(line: &str) -> Option<&str> {
-//     if let Some(idx) = line.find(':') {
-//         let iface = &line[..idx].trim();
-//         if iface.ends_with(':') {
-//             return Some(&iface[..iface.len() - 1]);
-//         }
-//     }
-//     None
-// }
\ No newline at end of file
+use super::bk_config::BackupConfig;
+use super::run_stats::RunStats;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+lazy_static::lazy_static! {
+    /// 由隔离模式管理的网卡状态, 多个任务同时备份时共用同一张网卡
+    static ref INTERFACES: Mutex<HashMap<String, InterfaceState>> = Mutex::new(HashMap::new());
+}
+
+/// 查询及设置网卡的管理状态
+trait LinkControl: Sync {
+    fn is_up(&self, name: &str) -> Result<bool, Error>;
+    fn set_up(&self, name: &str, up: bool) -> Result<(), Error>;
+}
+
+/// 通过 netlink 操作系统中的网卡
+struct Netlink;
+
+impl LinkControl for Netlink {
+    fn is_up(&self, name: &str) -> Result<bool, Error> {
+        link_is_up(name)
+    }
+
+    fn set_up(&self, name: &str, up: bool) -> Result<(), Error> {
+        set_link_up(name, up)
+    }
+}
+
+/// 网络隔离模式配置
+/// 平时保持网卡关闭, 仅在任务备份期间开启 interfaces 中的网卡, 备份结束(包括出错或 panic)后重新关闭
+/// 仅支持 Linux, 通过 netlink 设置网卡状态, 需要 CAP_NET_ADMIN 权限
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AirGapConfig {
+    /// 备份期间开启的网卡名称
+    pub interfaces: Vec<String>,
+    /// 开启网卡后等待的秒数, 用于等待链路就绪或获取地址
+    #[serde(default)]
+    pub settle_seconds: u64,
+}
+
+#[derive(Debug, Default)]
+struct InterfaceState {
+    /// 正在使用该网卡的任务数
+    users: usize,
+    /// 网卡是否由隔离模式开启, 备份前已开启的网卡在备份后保持开启
+    raised: bool,
+    /// 上次未能恢复关闭状态, 恢复之前拒绝使用该网卡的任务运行
+    unrestored: bool,
+}
+
+/// 在网络隔离模式下执行备份
+/// 开启网卡失败或网卡之前未能恢复关闭状态时不执行备份, 结果记为 Failed
+/// 备份后未能关闭网卡同样记为 Failed, 并在下次备份前重试关闭
+pub fn run_with_air_gap(
+    task_name: &str,
+    config: &BackupConfig,
+    backup: impl FnOnce() -> RunStats,
+) -> RunStats {
+    let Some(air_gap) = config.air_gap.as_ref().filter(|a| !a.interfaces.is_empty()) else {
+        return backup();
+    };
+    let mut guard = match AirGapGuard::acquire(task_name, &air_gap.interfaces) {
+        Ok(guard) => guard,
+        Err(e) => {
+            error!("{}:网络隔离模式开启网卡失败, 拒绝运行:{}", task_name, e);
+            let mut stats = RunStats::start(task_name);
+            stats.fail(&Error::new(
+                e.kind(),
+                format!("网络隔离模式开启网卡失败:{}", e),
+            ));
+            stats.finish();
+            return stats;
+        }
+    };
+    if air_gap.settle_seconds > 0 {
+        thread::sleep(Duration::from_secs(air_gap.settle_seconds));
+    }
+
+    let mut stats = backup();
+    if let Err(e) = guard.release() {
+        error!("{}:网络隔离模式关闭网卡失败:{}", task_name, e);
+        stats.fail(&Error::new(
+            e.kind(),
+            format!("网络隔离模式关闭网卡失败:{}", e),
+        ));
+    }
+    stats
+}
+
+/// 开启网卡期间持有, 释放或 drop 时关闭由其开启且不再被其他任务使用的网卡
+pub struct AirGapGuard {
+    task_name: String,
+    interfaces: Vec<String>,
+    control: &'static dyn LinkControl,
+    states: &'static Mutex<HashMap<String, InterfaceState>>,
+}
+
+impl AirGapGuard {
+    /// 开启网卡, 任一网卡失败时关闭已开启的网卡并返回错误
+    pub fn acquire(task_name: &str, interfaces: &[String]) -> Result<AirGapGuard, Error> {
+        AirGapGuard::acquire_with(task_name, interfaces, &Netlink, &INTERFACES)
+    }
+
+    fn acquire_with(
+        task_name: &str,
+        interfaces: &[String],
+        control: &'static dyn LinkControl,
+        states: &'static Mutex<HashMap<String, InterfaceState>>,
+    ) -> Result<AirGapGuard, Error> {
+        let mut guard = AirGapGuard {
+            task_name: task_name.to_string(),
+            interfaces: Vec::new(),
+            control,
+            states,
+        };
+        // 出错返回时 states 先于 guard 释放, guard 的 drop 关闭已开启的网卡
+        let mut states = states.lock().unwrap_or_else(|e| e.into_inner());
+        for name in interfaces {
+            if guard.interfaces.contains(name) {
+                continue;
+            }
+            let state = states.entry(name.clone()).or_default();
+            if state.unrestored {
+                set_down(control, name).map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!("网卡 {} 之前未能恢复关闭状态且仍无法关闭:{}", name, e),
+                    )
+                })?;
+                info!("{}:网卡 {} 已恢复关闭状态", task_name, name);
+                state.unrestored = false;
+            }
+            if state.users == 0 {
+                if control.is_up(name)? {
+                    warn!(
+                        "{}:网卡 {} 在备份前已处于开启状态, 备份后保持开启",
+                        task_name, name
+                    );
+                    state.raised = false;
+                } else {
+                    control.set_up(name, true)?;
+                    info!("{}:已开启网卡 {}", task_name, name);
+                    state.raised = true;
+                }
+            }
+            state.users += 1;
+            guard.interfaces.push(name.clone());
+        }
+        drop(states);
+        Ok(guard)
+    }
+
+    /// 关闭由隔离模式开启的网卡, 返回遇到的第一个错误
+    /// 关闭失败的网卡被标记为未恢复, 之后使用该网卡的任务会先尝试关闭它
+    pub fn release(&mut self) -> Result<(), Error> {
+        let mut result = Ok(());
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        for name in self.interfaces.drain(..) {
+            let Some(state) = states.get_mut(&name) else {
+                continue;
+            };
+            state.users = state.users.saturating_sub(1);
+            if state.users > 0 || !state.raised {
+                continue;
+            }
+            state.raised = false;
+            match set_down(self.control, &name) {
+                Ok(()) => info!("{}:已关闭网卡 {}", self.task_name, name),
+                Err(e) => {
+                    state.unrestored = true;
+                    if result.is_ok() {
+                        result = Err(Error::new(e.kind(), format!("无法关闭网卡 {}:{}", name, e)));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Drop for AirGapGuard {
+    fn drop(&mut self) {
+        if self.interfaces.is_empty() {
+            return;
+        }
+        if let Err(e) = self.release() {
+            error!("{}:网络隔离模式关闭网卡失败:{}", self.task_name, e);
+        }
+    }
+}
+
+/// 关闭网卡并确认其已关闭
+fn set_down(control: &dyn LinkControl, name: &str) -> Result<(), Error> {
+    control.set_up(name, false)?;
+    if control.is_up(name)? {
+        return Err(Error::other(format!("网卡 {} 关闭后仍处于开启状态", name)));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+use linux::{link_is_up, set_link_up};
+
+#[cfg(not(target_os = "linux"))]
+fn link_is_up(_name: &str) -> Result<bool, Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "网络隔离模式仅支持 Linux",
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_link_up(_name: &str, _up: bool) -> Result<(), Error> {
+    Err(Error::new(
+        std::io::ErrorKind::Unsupported,
+        "网络隔离模式仅支持 Linux",
+    ))
+}
+
+/// 通过 NETLINK_ROUTE 查询及设置网卡的管理状态(IFF_UP)
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CString;
+    use std::io::{Error, ErrorKind};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// nlmsghdr 及 ifinfomsg 的长度
+    const NLMSG_HDRLEN: usize = 16;
+    const IFINFOMSG_LEN: usize = 16;
+
+    static SEQUENCE: AtomicU32 = AtomicU32::new(1);
+
+    /// 网卡是否处于开启状态
+    pub fn link_is_up(name: &str) -> Result<bool, Error> {
+        let index = interface_index(name)?;
+        let socket = open()?;
+        let reply = request(&socket, libc::RTM_GETLINK, 0, index, 0, 0)?;
+        let Some(info) = reply else {
+            return Err(Error::other(format!("查询网卡 {} 时没有收到回复", name)));
+        };
+        Ok(link_flags(&info)? & libc::IFF_UP as u32 != 0)
+    }
+
+    /// ifinfomsg 中的 ifi_flags
+    fn link_flags(info: &[u8]) -> Result<u32, Error> {
+        if info.len() < IFINFOMSG_LEN {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "netlink 回复的 ifinfomsg 不完整",
+            ));
+        }
+        Ok(u32::from_ne_bytes(info[8..12].try_into().unwrap()))
+    }
+
+    /// 开启或关闭网卡
+    pub fn set_link_up(name: &str, up: bool) -> Result<(), Error> {
+        let index = interface_index(name)?;
+        let socket = open()?;
+        let flags = if up { libc::IFF_UP as u32 } else { 0 };
+        request(
+            &socket,
+            libc::RTM_NEWLINK,
+            libc::NLM_F_ACK as u16,
+            index,
+            flags,
+            libc::IFF_UP as u32,
+        )
+        .map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "{}网卡 {} 失败:{}",
+                    if up { "开启" } else { "关闭" },
+                    name,
+                    e
+                ),
+            )
+        })?;
+        Ok(())
+    }
+
+    fn interface_index(name: &str) -> Result<i32, Error> {
+        let c_name = CString::new(name)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("网卡名称无效: {}", name)))?;
+        let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+        if index == 0 {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("网卡 {} 不存在", name),
+            ));
+        }
+        Ok(index as i32)
+    }
+
+    fn open() -> Result<OwnedFd, Error> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+        // 内核没有回复时不会一直等待
+        let timeout = libc::timeval {
+            tv_sec: 5,
+            tv_usec: 0,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// 发送一条只包含 ifinfomsg 的请求并等待对应序号的回复
+    /// 回复为 RTM_NEWLINK 时返回其中的 ifinfomsg 及属性, 为 ACK 时返回 None
+    fn request(
+        socket: &OwnedFd,
+        message_type: u16,
+        extra_flags: u16,
+        index: i32,
+        ifi_flags: u32,
+        ifi_change: u32,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let message = encode_request(
+            message_type,
+            extra_flags,
+            sequence,
+            index,
+            ifi_flags,
+            ifi_change,
+        );
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let sent = unsafe {
+            libc::sendto(
+                socket.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let mut buffer = vec![0u8; 32 * 1024];
+        loop {
+            let received = unsafe {
+                libc::recv(
+                    socket.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                )
+            };
+            if received < 0 {
+                return Err(Error::last_os_error());
+            }
+            match parse_reply(&buffer[..received as usize], sequence)? {
+                Some(Reply::Link(info)) => return Ok(Some(info)),
+                Some(Reply::Ack) => return Ok(None),
+                Some(Reply::Errno(errno)) => return Err(Error::from_raw_os_error(errno)),
+                None => {}
+            }
+        }
+    }
+
+    /// nlmsghdr 及 ifinfomsg, 按本机字节序
+    fn encode_request(
+        message_type: u16,
+        extra_flags: u16,
+        sequence: u32,
+        index: i32,
+        ifi_flags: u32,
+        ifi_change: u32,
+    ) -> Vec<u8> {
+        let length = NLMSG_HDRLEN + IFINFOMSG_LEN;
+        let mut message = Vec::with_capacity(length);
+        message.extend_from_slice(&(length as u32).to_ne_bytes());
+        message.extend_from_slice(&message_type.to_ne_bytes());
+        message.extend_from_slice(&(libc::NLM_F_REQUEST as u16 | extra_flags).to_ne_bytes());
+        message.extend_from_slice(&sequence.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.push(libc::AF_UNSPEC as u8);
+        message.push(0);
+        message.extend_from_slice(&0u16.to_ne_bytes());
+        message.extend_from_slice(&index.to_ne_bytes());
+        message.extend_from_slice(&ifi_flags.to_ne_bytes());
+        message.extend_from_slice(&ifi_change.to_ne_bytes());
+        message
+    }
+
+    /// 请求的回复
+    #[derive(Debug, PartialEq, Eq)]
+    enum Reply {
+        /// 错误码为 0 的 NLMSG_ERROR
+        Ack,
+        /// RTM_NEWLINK 中的 ifinfomsg 及属性
+        Link(Vec<u8>),
+        /// NLMSG_ERROR 中的错误码(取正值)
+        Errno(i32),
+    }
+
+    /// 在一次收到的数据中查找对应序号的回复, 没有时返回 None, 继续接收
+    fn parse_reply(mut data: &[u8], sequence: u32) -> Result<Option<Reply>, Error> {
+        while data.len() >= NLMSG_HDRLEN {
+            let length = u32::from_ne_bytes(data[0..4].try_into().unwrap()) as usize;
+            if length < NLMSG_HDRLEN || length > data.len() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "netlink 回复的消息长度无效",
+                ));
+            }
+            let reply_type = u16::from_ne_bytes(data[4..6].try_into().unwrap());
+            let reply_sequence = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+            let payload = &data[NLMSG_HDRLEN..length];
+            if reply_sequence == sequence {
+                if reply_type == libc::NLMSG_ERROR as u16 {
+                    if payload.len() < 4 {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "netlink 回复的错误消息不完整",
+                        ));
+                    }
+                    let errno = i32::from_ne_bytes(payload[0..4].try_into().unwrap());
+                    return Ok(Some(if errno == 0 {
+                        Reply::Ack
+                    } else {
+                        Reply::Errno(-errno)
+                    }));
+                }
+                if reply_type == libc::RTM_NEWLINK {
+                    return Ok(Some(Reply::Link(payload.to_vec())));
+                }
+            }
+            // 消息按 4 字节对齐
+            let aligned = (length + 3) & !3;
+            data = &data[aligned.min(data.len())..];
+        }
+        Ok(None)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// 构造一条回复消息, 长度不含对齐的填充
+        fn message(message_type: u16, sequence: u32, payload: &[u8]) -> Vec<u8> {
+            let mut data = Vec::new();
+            data.extend_from_slice(&((NLMSG_HDRLEN + payload.len()) as u32).to_ne_bytes());
+            data.extend_from_slice(&message_type.to_ne_bytes());
+            data.extend_from_slice(&0u16.to_ne_bytes());
+            data.extend_from_slice(&sequence.to_ne_bytes());
+            data.extend_from_slice(&0u32.to_ne_bytes());
+            data.extend_from_slice(payload);
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+            data
+        }
+
+        fn ifinfomsg(index: i32, flags: u32) -> Vec<u8> {
+            let mut info = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+            info.extend_from_slice(&index.to_ne_bytes());
+            info.extend_from_slice(&flags.to_ne_bytes());
+            info.extend_from_slice(&0u32.to_ne_bytes());
+            info
+        }
+
+        #[test]
+        fn request_layout() {
+            let message = encode_request(
+                libc::RTM_NEWLINK,
+                libc::NLM_F_ACK as u16,
+                7,
+                3,
+                libc::IFF_UP as u32,
+                libc::IFF_UP as u32,
+            );
+            assert_eq!(message.len(), 32);
+            assert_eq!(message[0..4], 32u32.to_ne_bytes());
+            assert_eq!(message[4..6], libc::RTM_NEWLINK.to_ne_bytes());
+            assert_eq!(
+                message[6..8],
+                ((libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16).to_ne_bytes()
+            );
+            assert_eq!(message[8..12], 7u32.to_ne_bytes());
+            assert_eq!(message[12..16], 0u32.to_ne_bytes());
+            assert_eq!(message[16..24], ifinfomsg(3, libc::IFF_UP as u32)[..8]);
+            assert_eq!(message[28..32], (libc::IFF_UP as u32).to_ne_bytes());
+        }
+
+        #[test]
+        fn replies_with_other_sequences_are_skipped() {
+            // 带 3 字节属性的消息需要对齐到 4 字节
+            let mut data = message(libc::RTM_NEWLINK, 1, &[1, 2, 3]);
+            data.extend(message(libc::RTM_NEWLINK, 2, &ifinfomsg(3, 1)));
+            assert_eq!(
+                parse_reply(&data, 2).unwrap(),
+                Some(Reply::Link(ifinfomsg(3, 1)))
+            );
+            assert_eq!(parse_reply(&data, 5).unwrap(), None);
+            assert_eq!(parse_reply(&data[..10], 2).unwrap(), None);
+        }
+
+        #[test]
+        fn acks_and_errors() {
+            let ack = message(libc::NLMSG_ERROR as u16, 4, &0i32.to_ne_bytes());
+            assert_eq!(parse_reply(&ack, 4).unwrap(), Some(Reply::Ack));
+            let denied = message(libc::NLMSG_ERROR as u16, 4, &(-libc::EPERM).to_ne_bytes());
+            assert_eq!(
+                parse_reply(&denied, 4).unwrap(),
+                Some(Reply::Errno(libc::EPERM))
+            );
+            let truncated = message(libc::NLMSG_ERROR as u16, 4, &[0, 0]);
+            assert!(parse_reply(&truncated, 4).is_err());
+        }
+
+        #[test]
+        fn invalid_length_is_rejected() {
+            let mut data = message(libc::RTM_NEWLINK, 1, &ifinfomsg(1, 0));
+            data[0..4].copy_from_slice(&64u32.to_ne_bytes());
+            assert!(parse_reply(&data, 1).is_err());
+            data[0..4].copy_from_slice(&8u32.to_ne_bytes());
+            assert!(parse_reply(&data, 1).is_err());
+        }
+
+        #[test]
+        fn flags_are_read_from_ifinfomsg() {
+            let info = ifinfomsg(2, libc::IFF_UP as u32 | libc::IFF_RUNNING as u32);
+            assert_eq!(
+                link_flags(&info).unwrap() & libc::IFF_UP as u32,
+                libc::IFF_UP as u32
+            );
+            assert!(link_flags(&info[..12]).is_err());
+        }
+
+        #[test]
+        fn loopback_can_be_queried() {
+            link_is_up("lo").unwrap();
+            let e = link_is_up("rsbk-missing0").unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::NotFound);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    /// 模拟的网卡, stuck 中的网卡无法关闭
+    #[derive(Default)]
+    struct FakeLinks {
+        up: Mutex<HashMap<String, bool>>,
+        stuck: Mutex<HashSet<String>>,
+    }
+
+    impl FakeLinks {
+        fn with(interfaces: &[(&str, bool)]) -> &'static FakeLinks {
+            let links = FakeLinks::default();
+            for (name, up) in interfaces {
+                links.up.lock().unwrap().insert(name.to_string(), *up);
+            }
+            Box::leak(Box::new(links))
+        }
+
+        fn is(&self, name: &str) -> bool {
+            self.up.lock().unwrap()[name]
+        }
+    }
+
+    impl LinkControl for FakeLinks {
+        fn is_up(&self, name: &str) -> Result<bool, Error> {
+            self.up
+                .lock()
+                .unwrap()
+                .get(name)
+                .copied()
+                .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, name.to_string()))
+        }
+
+        fn set_up(&self, name: &str, up: bool) -> Result<(), Error> {
+            let mut links = self.up.lock().unwrap();
+            let Some(state) = links.get_mut(name) else {
+                return Err(Error::new(std::io::ErrorKind::NotFound, name.to_string()));
+            };
+            if up || !self.stuck.lock().unwrap().contains(name) {
+                *state = up;
+            }
+            Ok(())
+        }
+    }
+
+    fn registry() -> &'static Mutex<HashMap<String, InterfaceState>> {
+        Box::leak(Box::new(Mutex::new(HashMap::new())))
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn interface_is_raised_and_lowered() {
+        let links = FakeLinks::with(&[("eth1", false)]);
+        let states = registry();
+        let mut guard =
+            AirGapGuard::acquire_with("a", &names(&["eth1", "eth1"]), links, states).unwrap();
+        assert!(links.is("eth1"));
+        assert_eq!(states.lock().unwrap()["eth1"].users, 1);
+        guard.release().unwrap();
+        assert!(!links.is("eth1"));
+    }
+
+    #[test]
+    fn shared_interface_stays_up_until_last_user() {
+        let links = FakeLinks::with(&[("eth1", false)]);
+        let states = registry();
+        let mut first = AirGapGuard::acquire_with("a", &names(&["eth1"]), links, states).unwrap();
+        let second = AirGapGuard::acquire_with("b", &names(&["eth1"]), links, states).unwrap();
+        first.release().unwrap();
+        assert!(links.is("eth1"));
+        drop(second);
+        assert!(!links.is("eth1"));
+        assert_eq!(states.lock().unwrap()["eth1"].users, 0);
+    }
+
+    #[test]
+    fn interface_up_before_backup_is_left_up() {
+        let links = FakeLinks::with(&[("eth1", true)]);
+        let mut guard =
+            AirGapGuard::acquire_with("a", &names(&["eth1"]), links, registry()).unwrap();
+        guard.release().unwrap();
+        assert!(links.is("eth1"));
+    }
+
+    #[test]
+    fn failed_acquire_lowers_raised_interfaces() {
+        let links = FakeLinks::with(&[("eth1", false)]);
+        let states = registry();
+        let result = AirGapGuard::acquire_with("a", &names(&["eth1", "eth9"]), links, states);
+        assert!(result.is_err());
+        assert!(!links.is("eth1"));
+        assert_eq!(states.lock().unwrap()["eth1"].users, 0);
+    }
+
+    #[test]
+    fn unrestored_interface_blocks_until_lowered() {
+        let links = FakeLinks::with(&[("eth1", false)]);
+        let states = registry();
+        links.stuck.lock().unwrap().insert("eth1".to_string());
+        let mut guard = AirGapGuard::acquire_with("a", &names(&["eth1"]), links, states).unwrap();
+        assert!(guard.release().is_err());
+        assert!(states.lock().unwrap()["eth1"].unrestored);
+
+        // 仍然无法关闭时拒绝运行
+        assert!(AirGapGuard::acquire_with("b", &names(&["eth1"]), links, states).is_err());
+        assert!(states.lock().unwrap()["eth1"].unrestored);
+
+        // 能够关闭后先恢复关闭状态, 再由本次备份开启
+        links.stuck.lock().unwrap().clear();
+        let mut guard = AirGapGuard::acquire_with("b", &names(&["eth1"]), links, states).unwrap();
+        assert!(links.is("eth1"));
+        assert!(!states.lock().unwrap()["eth1"].unrestored);
+        guard.release().unwrap();
+        assert!(!links.is("eth1"));
+    }
+}
@@ -0,0 +1,317 @@
+use super::global_config::GlobalConfig;
+use super::quota::ByteSize;
+use super::run_stats::{RunStats, RunStatus};
+use super::smtp;
+use super::webhook;
+use chrono::{DateTime, Duration, Local};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// 通知中最多列出的错误条数, 其余的见运行历史
+const MAX_ERRORS: usize = 50;
+
+lazy_static::lazy_static! {
+    /// 任务线程、监听线程及超时检查都会读写通知状态
+    static ref STATE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// 发送通知的事件
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyEvent {
+    /// 开始备份
+    Start,
+    /// 备份结束, 无论结果如何, 在 Failure、Partial 等结果通知之后发送
+    Finish,
+    /// 备份失败
+    Failure,
+    /// 备份完成, 但有部分文件或备份目的地失败
+    Partial,
+    /// 失败、部分失败或超时后再次备份成功
+    Recovery,
+    /// 备份成功(不包括无更新), 恢复正常时只发送 Recovery
+    Success,
+    /// 超过 stale_hours 小时没有成功的备份, 恢复前只发送一次
+    Stale,
+}
+
+impl NotifyEvent {
+    pub fn title(&self) -> &'static str {
+        match self {
+            NotifyEvent::Start => "开始备份",
+            NotifyEvent::Finish => "备份结束",
+            NotifyEvent::Failure => "备份失败",
+            NotifyEvent::Partial => "部分文件备份失败",
+            NotifyEvent::Recovery => "备份已恢复正常",
+            NotifyEvent::Success => "备份成功",
+            NotifyEvent::Stale => "长时间没有成功的备份",
+        }
+    }
+}
+
+/// 一条通知, 由各通知渠道按自己的格式发送
+pub struct Notification {
+    pub event: NotifyEvent,
+    pub task_name: String,
+    pub subject: String,
+    /// 纯文本的摘要, 包含运行统计及错误列表
+    pub body: String,
+    /// 触发通知的运行结果, Start 及 Stale 通知没有运行结果
+    pub stats: Option<RunStats>,
+}
+
+impl Notification {
+    fn run(event: NotifyEvent, stats: &RunStats) -> Notification {
+        let mut body = format!(
+            "任务: {}\n事件: {}\n开始时间: {}\n运行结果: {:?}\n扫描文件: {} 个\n复制: {} 个\n跳过: {} 个\n失败: {} 个\n读取: {}\n写入: {}\n删除过期文件: {} 个\n释放: {}\n耗时: {:.3} 秒\n",
+            stats.task_name,
+            event.title(),
+            stats.started_at.format("%Y-%m-%d %H:%M:%S"),
+            stats.status,
+            stats.files_scanned,
+            stats.files_copied,
+            stats.files_skipped,
+            stats.files_failed,
+            ByteSize(stats.bytes_read),
+            ByteSize(stats.bytes_written),
+            stats.files_deleted,
+            ByteSize(stats.bytes_freed),
+            stats.duration_secs
+        );
+        if !stats.destinations.is_empty() {
+            body.push_str("\n备份目的地:\n");
+            for destination in &stats.destinations {
+                body.push_str(&format!(
+                    "  {} [{:?}] 复制 {} 个, 失败 {} 个, 写入 {}\n",
+                    destination.path,
+                    destination.status,
+                    destination.files_copied,
+                    destination.files_failed,
+                    ByteSize(destination.bytes_written)
+                ));
+            }
+        }
+        if !stats.errors.is_empty() {
+            body.push_str(&format!("\n错误(共 {} 条):\n", stats.errors.len()));
+            for e in stats.errors.iter().take(MAX_ERRORS) {
+                body.push_str(&format!("  - {}\n", e));
+            }
+            if stats.errors.len() > MAX_ERRORS {
+                body.push_str(&format!(
+                    "  其余 {} 条见运行历史 {}\n",
+                    stats.errors.len() - MAX_ERRORS,
+                    RunStats::get_history_path(&stats.task_name).display()
+                ));
+            }
+        }
+        Notification {
+            event,
+            task_name: stats.task_name.clone(),
+            subject: format!("[rsbk] {}: {}", stats.task_name, event.title()),
+            body,
+            stats: Some(stats.clone()),
+        }
+    }
+
+    fn start(task_name: &str) -> Notification {
+        Notification {
+            event: NotifyEvent::Start,
+            task_name: task_name.to_string(),
+            subject: format!("[rsbk] {}: {}", task_name, NotifyEvent::Start.title()),
+            body: format!(
+                "任务: {}\n事件: {}\n开始时间: {}\n",
+                task_name,
+                NotifyEvent::Start.title(),
+                Local::now().format("%Y-%m-%d %H:%M:%S")
+            ),
+            stats: None,
+        }
+    }
+
+    fn stale(task_name: &str, stale_hours: u64, state: &NotifyState) -> Notification {
+        let last_success = match (&state.last_success, &state.since) {
+            (Some(time), _) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+            (None, Some(since)) => format!("从未成功(自 {} 起)", since.format("%Y-%m-%d %H:%M:%S")),
+            (None, None) => "从未成功".to_string(),
+        };
+        let last_status = state
+            .last_status
+            .map_or("未运行".to_string(), |status| format!("{:?}", status));
+        Notification {
+            event: NotifyEvent::Stale,
+            task_name: task_name.to_string(),
+            subject: format!("[rsbk] {}: 超过 {} 小时没有成功的备份", task_name, stale_hours),
+            body: format!(
+                "任务: {}\n事件: {}\n已超过 {} 小时没有成功的备份\n上次成功: {}\n上次运行结果: {}\n",
+                task_name,
+                NotifyEvent::Stale.title(),
+                stale_hours,
+                last_success,
+                last_status
+            ),
+            stats: None,
+        }
+    }
+}
+
+/// 每个任务的通知状态, 用于判断恢复正常及超时
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct NotifyState {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_status: Option<RunStatus>,
+    /// 上次成功(包括无更新)的时间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_success: Option<DateTime<Local>>,
+    /// 开始记录的时间, 从未成功时由此计算是否超时
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    since: Option<DateTime<Local>>,
+    /// 是否已发送过 Stale 通知
+    #[serde(default)]
+    stale_notified: bool,
+}
+
+/// 通知状态存放地址
+/// 取 BackupConfig/notify/{task_name}.yaml
+fn get_state_path(task_name: &str) -> PathBuf {
+    let mut state_path = PathBuf::from("BackupConfig");
+    state_path.push("notify");
+    state_path.push(task_name.to_owned() + ".yaml");
+    state_path
+}
+
+fn load_state(task_name: &str) -> NotifyState {
+    fs::read_to_string(get_state_path(task_name))
+        .ok()
+        .and_then(|buf| serde_yaml::from_str(&buf).ok())
+        .unwrap_or_default()
+}
+
+fn save_state(task_name: &str, state: &NotifyState) -> Result<(), Error> {
+    let state_path = get_state_path(task_name);
+    if let Some(parent) = state_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let yaml_str = serde_yaml::to_string(state).map_err(Error::other)?;
+    fs::write(state_path, yaml_str)
+}
+
+/// 备份开始时发送 Start 通知
+pub fn on_run_started(task_name: &str) {
+    send(&GlobalConfig::load(), &Notification::start(task_name));
+}
+
+/// 根据本次运行的结果发送通知, 之后再发送 Finish 通知
+/// 失败及部分失败每次运行都会通知; 之前失败、部分失败或已发送超时通知时, 本次成功或无更新发送 Recovery
+/// 通知失败只记录到日志, 不影响备份的结果
+pub fn on_run_finished(stats: &RunStats) {
+    let event = {
+        let _lock = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut state = load_state(&stats.task_name);
+        let had_problem = matches!(
+            state.last_status,
+            Some(RunStatus::Failed | RunStatus::Partial)
+        ) || state.stale_notified;
+        let event = match stats.status {
+            RunStatus::Failed => Some(NotifyEvent::Failure),
+            RunStatus::Partial => Some(NotifyEvent::Partial),
+            RunStatus::Success | RunStatus::NoChange => {
+                state.last_success = Some(Local::now());
+                state.stale_notified = false;
+                if had_problem {
+                    Some(NotifyEvent::Recovery)
+                } else if stats.status == RunStatus::Success {
+                    Some(NotifyEvent::Success)
+                } else {
+                    None
+                }
+            }
+        };
+        state.last_status = Some(stats.status);
+        state.since.get_or_insert(stats.started_at);
+        if let Err(e) = save_state(&stats.task_name, &state) {
+            error!("{}:保存通知状态时发生错误:{}", stats.task_name, e);
+        }
+        event
+    };
+    let global = GlobalConfig::load();
+    if let Some(event) = event {
+        send(&global, &Notification::run(event, stats));
+    }
+    send(&global, &Notification::run(NotifyEvent::Finish, stats));
+}
+
+/// 检查各任务是否超过全局配置 stale_hours 小时没有成功的备份, 超时的任务在恢复前只通知一次
+pub fn check_stale(task_names: &[String]) {
+    let global = GlobalConfig::load();
+    if global.stale_hours == 0 {
+        return;
+    }
+    let now = Local::now();
+    for task_name in task_names {
+        let notification = {
+            let _lock = STATE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let mut state = load_state(task_name);
+            let is_new = state.since.is_none();
+            let since = *state.since.get_or_insert(now);
+            let last_success = state.last_success.unwrap_or(since);
+            let stale = now - last_success >= Duration::hours(global.stale_hours as i64);
+            let notify = stale && !state.stale_notified;
+            if notify {
+                state.stale_notified = true;
+            }
+            if notify || is_new {
+                if let Err(e) = save_state(task_name, &state) {
+                    error!("{}:保存通知状态时发生错误:{}", task_name, e);
+                }
+            }
+            if !notify {
+                continue;
+            }
+            Notification::stale(task_name, global.stale_hours, &state)
+        };
+        send(&global, &notification);
+    }
+}
+
+/// 按全局配置将通知发送到各渠道, 发送失败只记录到日志
+fn send(global: &GlobalConfig, notification: &Notification) {
+    if let Some(smtp_config) = &global.smtp {
+        if smtp_config.events.contains(&notification.event) {
+            match smtp::send(smtp_config, notification) {
+                Ok(()) => info!(
+                    "{}:已发送{}邮件通知",
+                    notification.task_name,
+                    notification.event.title()
+                ),
+                Err(e) => error!(
+                    "{}:发送{}邮件通知时发生错误:{}",
+                    notification.task_name,
+                    notification.event.title(),
+                    e
+                ),
+            }
+        }
+    }
+    for webhook_config in &global.webhooks {
+        if webhook_config.events.contains(&notification.event) {
+            match webhook::send(webhook_config, notification) {
+                Ok(()) => info!(
+                    "{}:已发送{}通知到 {}",
+                    notification.task_name,
+                    notification.event.title(),
+                    webhook_config.label()
+                ),
+                Err(e) => error!(
+                    "{}:发送{}通知到 {} 时发生错误:{}",
+                    notification.task_name,
+                    notification.event.title(),
+                    webhook_config.label(),
+                    e
+                ),
+            }
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use super::{
+    base_bk_option::context,
+    bk_config::{BackupConfig, BackupMode},
+    incremental_mode::IncrementalMode,
+    quota::{dir_size, ByteSize},
+    remote,
+    replicate_mode::ReplicateMode,
+    storage::{self, StorageBackend},
+    trash::{Trash, TrashRecord},
+    version_mode::VersionMode,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Error;
+use std::path::Path;
+
+/// 删除所依据的规则
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PruneRule {
+    /// 版本控制模式的保留策略(preserve_version 或 retention)
+    Retention,
+    /// 空间限额
+    Quota,
+    /// 增量备份模式超过保存天数
+    Expired,
+    /// 回收站中超过保留天数
+    Purged,
+}
+
+/// 一个被删除(或预演中将被删除)的路径
+#[derive(Debug, Serialize, Clone)]
+pub struct PruneItem {
+    pub path: String,
+    pub rule: PruneRule,
+    /// 规则的具体说明
+    pub detail: String,
+    /// 删除或移入回收站的字节数
+    pub bytes: u64,
+    /// 是否移入回收站
+    pub trashed: bool,
+    /// 回收站中的条目ID, 预演时没有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trash_id: Option<String>,
+}
+
+/// 两种备份模式共用的删除记录
+/// 所有按保留策略、空间限额或保存天数进行的删除都经由此处, 每个路径都会写入日志;
+/// 预演(dry_run)时只记录不删除
+/// 任务配置了回收站时, 除空间限额外的删除改为移入回收站
+#[derive(Debug, Serialize)]
+pub struct PruneReport {
+    pub task_name: String,
+    pub dry_run: bool,
+    pub items: Vec<PruneItem>,
+    #[serde(skip)]
+    trash: Option<Trash>,
+}
+
+impl PruneReport {
+    pub fn new(task_name: &str, dry_run: bool) -> Self {
+        PruneReport {
+            task_name: task_name.to_string(),
+            dry_run,
+            items: Vec::new(),
+            trash: None,
+        }
+    }
+
+    /// 按任务配置创建, 配置了回收站时删除改为移入回收站
+    pub fn for_task(config: &BackupConfig, task_name: &str, dry_run: bool) -> Self {
+        let mut report = PruneReport::new(task_name, dry_run);
+        report.trash = config
+            .trash
+            .as_ref()
+            .map(|trash| Trash::open(&config.backup_destination_path, trash));
+        report
+    }
+
+    /// 删除一个文件或目录并记录, 预演时只记录
+    /// 配置了回收站时(空间限额除外)移入回收站
+    /// 返回删除或移入回收站的字节数
+    pub fn remove(
+        &mut self,
+        storage: &dyn StorageBackend,
+        path: &Path,
+        rule: PruneRule,
+        detail: String,
+    ) -> Result<u64, Error> {
+        let use_trash = rule != PruneRule::Quota;
+        self.delete(storage, path, rule, detail, use_trash, None)
+    }
+
+    /// 同 remove, 移入回收站时一并保存路径的备份记录, 还原时重新写入
+    pub fn remove_recorded(
+        &mut self,
+        storage: &dyn StorageBackend,
+        path: &Path,
+        rule: PruneRule,
+        detail: String,
+        record: TrashRecord,
+    ) -> Result<u64, Error> {
+        let use_trash = rule != PruneRule::Quota;
+        self.delete(storage, path, rule, detail, use_trash, Some(record))
+    }
+
+    /// 彻底删除回收站中超过保留天数的条目
+    pub fn purge(
+        &mut self,
+        storage: &dyn StorageBackend,
+        path: &Path,
+        detail: String,
+    ) -> Result<u64, Error> {
+        self.delete(storage, path, PruneRule::Purged, detail, false, None)
+    }
+
+    /// 清除本任务备份目的地回收站中超过保留天数的条目, 未配置回收站时不做任何事
+    pub fn purge_trash(&mut self, storage: &dyn StorageBackend) -> Result<(), Error> {
+        match self.trash.clone() {
+            Some(trash) => trash.purge(storage, self),
+            None => Ok(()),
+        }
+    }
+
+    fn delete(
+        &mut self,
+        storage: &dyn StorageBackend,
+        path: &Path,
+        rule: PruneRule,
+        detail: String,
+        use_trash: bool,
+        record: Option<TrashRecord>,
+    ) -> Result<u64, Error> {
+        let Some(meta) = storage.stat(path)? else {
+            return Ok(0);
+        };
+        let bytes = if meta.is_dir() {
+            dir_size(storage, path)?
+        } else {
+            meta.len
+        };
+        let trash = self.trash.as_ref().filter(|_| use_trash);
+        let action = match (self.dry_run, trash) {
+            (true, Some(_)) => "[预演]将移入回收站",
+            (true, None) => "[预演]将删除",
+            (false, Some(_)) => "已移入回收站",
+            (false, None) => "已删除",
+        };
+        let mut trash_id = None;
+        if !self.dry_run {
+            match trash {
+                Some(trash) => {
+                    trash_id = Some(trash.put(
+                        storage,
+                        &self.task_name,
+                        path,
+                        rule,
+                        &detail,
+                        bytes,
+                        record,
+                    )?);
+                }
+                None => storage.delete(path)?,
+            }
+        }
+        info!(
+            "{}:{} {} ({:?}:{}, {})",
+            self.task_name,
+            action,
+            path.display(),
+            rule,
+            detail,
+            ByteSize(bytes)
+        );
+        self.items.push(PruneItem {
+            path: path.to_string_lossy().to_string(),
+            rule,
+            detail,
+            bytes,
+            trashed: trash.is_some(),
+            trash_id,
+        });
+        Ok(bytes)
+    }
+
+    /// 彻底删除(未移入回收站)释放的字节数
+    pub fn bytes_freed(&self) -> u64 {
+        self.items
+            .iter()
+            .filter(|item| !item.trashed)
+            .map(|item| item.bytes)
+            .sum()
+    }
+
+    /// 移入回收站的字节数
+    pub fn bytes_trashed(&self) -> u64 {
+        self.items
+            .iter()
+            .filter(|item| item.trashed)
+            .map(|item| item.bytes)
+            .sum()
+    }
+
+    /// 预演中已"删除"的字节数, 用于在预演时继续计算空间限额
+    /// 实际删除时磁盘占用已经减少, 返回 0
+    pub fn simulated_freed(&self) -> u64 {
+        if self.dry_run {
+            self.bytes_freed()
+        } else {
+            0
+        }
+    }
+}
+
+impl fmt::Display for PruneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}处理[{}]个路径, 释放 {}, 移入回收站 {}",
+            self.task_name,
+            if self.dry_run { "[预演]" } else { "" },
+            self.items.len(),
+            ByteSize(self.bytes_freed()),
+            ByteSize(self.bytes_trashed())
+        )?;
+        for rule in [
+            PruneRule::Retention,
+            PruneRule::Quota,
+            PruneRule::Expired,
+            PruneRule::Purged,
+        ] {
+            let items: Vec<&PruneItem> = self.items.iter().filter(|i| i.rule == rule).collect();
+            if !items.is_empty() {
+                write!(
+                    f,
+                    ", 其中{:?}[{}]个({})",
+                    rule,
+                    items.len(),
+                    ByteSize(items.iter().map(|i| i.bytes).sum())
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 不进行备份, 只按任务的保留策略、空间限额或保存天数删除旧的备份
+/// 配置了回收站时同时清除回收站中超过保留天数的条目
+/// dry_run 为 true 时只生成报告, 不删除任何文件
+/// 备份目的地为远程地址时直接删除远程主机上的备份
+/// 有多个备份目的地时按各自的保留策略逐个处理, 返回各目的地的报告,
+/// 一个目的地出错时继续处理其他目的地, 最后返回第一个错误
+pub fn prune(task_name: &str, dry_run: bool) -> Result<Vec<PruneReport>, Error> {
+    let config = BackupConfig::create(&BackupConfig::get_hash_path(task_name))
+        .map_err(context("读取备份计划时发生错误"))?;
+    let mut reports = Vec::new();
+    let mut first_error = None;
+    for (index, config) in config.destination_configs()?.iter().enumerate() {
+        let key = BackupConfig::destination_key(task_name, index);
+        let mut report = PruneReport::for_task(config, task_name, dry_run);
+        let result = prune_task(config, &key, &mut report);
+        info!("{}", report);
+        match result {
+            Ok(()) => reports.push(report),
+            Err(e) => {
+                error!("{}({}):{}", task_name, config.backup_destination_path, e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(reports),
+    }
+}
+
+/// key 为目的地文件目录的名称, 见 BackupConfig::destination_key
+fn prune_task(config: &BackupConfig, key: &str, report: &mut PruneReport) -> Result<(), Error> {
+    let storage = remote::destination_storage(&storage::local(), config)
+        .map_err(context("打开备份目的地时发生错误"))?;
+    report
+        .purge_trash(storage.as_ref())
+        .map_err(context("清除回收站时发生错误"))?;
+    match &config.options {
+        BackupMode::IncrementalMode { .. } => {
+            IncrementalMode::create(config.clone()).prune(key, report)
+        }
+        BackupMode::VersionMode { .. } => VersionMode::create(config.clone()).prune(report),
+        BackupMode::ReplicateMode { .. } => ReplicateMode::create(config.clone()).prune(report),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::storage::MemoryStorage;
+    use crate::mods::trash;
+
+    /// /memory/data 下大小为 150 字节的版本目录 v1 及 30 字节的 v2
+    fn storage() -> MemoryStorage {
+        let storage = MemoryStorage::new();
+        storage.mkdir(Path::new("/memory/data/v1")).unwrap();
+        storage.mkdir(Path::new("/memory/data/v2")).unwrap();
+        storage
+            .write(Path::new("/memory/data/v1/a"), &mut &[0u8; 100][..])
+            .unwrap();
+        storage
+            .write(Path::new("/memory/data/v1/b"), &mut &[0u8; 50][..])
+            .unwrap();
+        storage
+            .write(Path::new("/memory/data/v2/a"), &mut &[0u8; 30][..])
+            .unwrap();
+        storage
+    }
+
+    fn config(trash: bool) -> BackupConfig {
+        let trash = if trash {
+            "trash:\n  grace_days: 7\n"
+        } else {
+            ""
+        };
+        serde_yaml::from_str(&format!(
+            "backup_source_path: /data\nbackup_destination_path: /memory\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\n{}options:\n  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 1\n",
+            trash
+        ))
+        .unwrap()
+    }
+
+    fn remove_both(storage: &MemoryStorage, report: &mut PruneReport) {
+        let v1 = report
+            .remove(
+                storage,
+                Path::new("/memory/data/v1"),
+                PruneRule::Retention,
+                "超出保留的版本数".to_string(),
+            )
+            .unwrap();
+        let v2 = report
+            .remove(
+                storage,
+                Path::new("/memory/data/v2"),
+                PruneRule::Quota,
+                "超出空间限额".to_string(),
+            )
+            .unwrap();
+        assert_eq!((v1, v2), (150, 30));
+    }
+
+    /// 预演时记录将释放的空间, 不删除任何文件, 也不移入回收站
+    #[test]
+    fn dry_run_reports_without_deleting() {
+        let storage = storage();
+        let mut report = PruneReport::for_task(&config(false), "task", true);
+        remove_both(&storage, &mut report);
+        assert_eq!(report.items.len(), 2);
+        assert_eq!(report.simulated_freed(), 180);
+        assert_eq!(report.bytes_freed(), 180);
+        assert!(report.to_string().contains("[预演]"));
+        for version in ["v1", "v2"] {
+            assert!(storage
+                .stat(&Path::new("/memory/data").join(version))
+                .unwrap()
+                .is_some());
+        }
+
+        let mut report = PruneReport::for_task(&config(true), "task", true);
+        remove_both(&storage, &mut report);
+        assert!(report.items.iter().all(|item| item.trash_id.is_none()));
+        assert!(report.items[0].trashed);
+        assert_eq!(report.simulated_freed(), 30);
+        assert!(trash::list(&storage, "/memory").unwrap().is_empty());
+        assert_eq!(
+            storage.read(Path::new("/memory/data/v1/a")).unwrap().len(),
+            100
+        );
+
+        // 实际删除后磁盘占用已经减少, 不再重复计算
+        let mut report = PruneReport::for_task(&config(false), "task", false);
+        remove_both(&storage, &mut report);
+        assert_eq!(report.simulated_freed(), 0);
+        assert!(storage
+            .stat(Path::new("/memory/data/v1"))
+            .unwrap()
+            .is_none());
+    }
+
+    /// 配置了回收站时按保留策略删除的版本移入回收站, 为满足空间限额的删除直接删除
+    #[test]
+    fn quota_deletions_bypass_trash() {
+        let storage = storage();
+        let mut report = PruneReport::for_task(&config(true), "task", false);
+        remove_both(&storage, &mut report);
+
+        assert!(report.items[0].trashed);
+        assert!(report.items[0].trash_id.is_some());
+        assert!(!report.items[1].trashed);
+        assert!(report.items[1].trash_id.is_none());
+        assert_eq!(report.bytes_trashed(), 150);
+        assert_eq!(report.bytes_freed(), 30);
+        let items = trash::list(&storage, "/memory").unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].original_path, "/memory/data/v1");
+        assert_eq!(items[0].rule, PruneRule::Retention);
+        for version in ["v1", "v2"] {
+            assert!(storage
+                .stat(&Path::new("/memory/data").join(version))
+                .unwrap()
+                .is_none());
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use super::storage::StorageBackend;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+/// 字节数, 配置文件中可以填写整数或带单位的字符串
+/// 支持 B、K/KB、M/MB、G/GB、T/TB(按1000进位)以及 KiB、MiB、GiB、TiB(按1024进位)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn parse(value: &str) -> Result<ByteSize, Error> {
+        let value = value.trim();
+        let split = value
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number: f64 = number.parse().map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("无法识别的大小: {}", value),
+            )
+        })?;
+        let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kb" => 1000,
+            "m" | "mb" => 1000_u64.pow(2),
+            "g" | "gb" => 1000_u64.pow(3),
+            "t" | "tb" => 1000_u64.pow(4),
+            "kib" => 1 << 10,
+            "mib" => 1 << 20,
+            "gib" => 1 << 30,
+            "tib" => 1 << 40,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("无法识别的大小单位: {}", value),
+                ))
+            }
+        };
+        Ok(ByteSize((number * multiplier as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = self.0 as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{}B", self.0)
+        } else {
+            write!(f, "{:.2}{}", size, UNITS[unit])
+        }
+    }
+}
+
+/// 以能整除的最大单位写回配置文件, 避免改写用户填写的大小
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        const UNITS: [(&str, u64); 8] = [
+            ("TiB", 1 << 40),
+            ("TB", 1000_u64.pow(4)),
+            ("GiB", 1 << 30),
+            ("GB", 1000_u64.pow(3)),
+            ("MiB", 1 << 20),
+            ("MB", 1000_u64.pow(2)),
+            ("KiB", 1 << 10),
+            ("KB", 1000),
+        ];
+        match UNITS
+            .iter()
+            .find(|(_, unit)| self.0 > 0 && self.0.is_multiple_of(*unit))
+        {
+            Some((name, unit)) => serializer.serialize_str(&format!("{}{}", self.0 / unit, name)),
+            None => serializer.serialize_u64(self.0),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(ByteSize(bytes)),
+            Raw::Text(text) => ByteSize::parse(&text).map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// 备份空间限额
+/// 备份前估算本次写入的大小, 超出限额时先删除最早的版本或过期文件,
+/// 删除后仍然不足时不开始备份
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct QuotaConfig {
+    /// 本任务的备份目录最多占用的空间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_task_size: Option<ByteSize>,
+    /// 备份目的地(所有使用该目的地的任务)最多占用的空间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_destination_size: Option<ByteSize>,
+    /// 备份目的地所在磁盘至少保留的可用空间
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_space: Option<ByteSize>,
+}
+
+/// 备份前的空间占用情况, 未配置对应限额的项不计算
+#[derive(Debug, Default)]
+pub struct QuotaUsage {
+    pub task_size: u64,
+    pub destination_size: u64,
+    pub free_space: u64,
+}
+
+impl QuotaConfig {
+    /// 读取本任务备份目录及备份目的地的空间占用情况
+    /// 存储无法提供可用空间时(如对象存储)不检查 min_free_space
+    pub fn usage(
+        &self,
+        storage: &dyn StorageBackend,
+        task_root: &Path,
+        destination_root: &Path,
+    ) -> Result<QuotaUsage, Error> {
+        Ok(QuotaUsage {
+            task_size: match self.max_task_size {
+                Some(_) => dir_size(storage, task_root)?,
+                None => 0,
+            },
+            destination_size: match self.max_destination_size {
+                Some(_) => dir_size(storage, destination_root)?,
+                None => 0,
+            },
+            free_space: match self.min_free_space {
+                Some(_) => match storage.available_space(destination_root)? {
+                    Some(space) => space,
+                    None => {
+                        warn!(
+                            "无法获取 {} 的可用空间, 不检查 min_free_space",
+                            destination_root.display()
+                        );
+                        u64::MAX
+                    }
+                },
+                None => u64::MAX,
+            },
+        })
+    }
+
+    /// 释放 freed 字节后写入 estimate 字节是否超出限额
+    /// 超出时返回原因
+    pub fn shortfall(&self, usage: &QuotaUsage, estimate: u64, freed: u64) -> Option<String> {
+        if let Some(max) = self.max_task_size {
+            let after = (usage.task_size + estimate).saturating_sub(freed);
+            if after > max.0 {
+                return Some(format!(
+                    "任务备份大小将达到 {}, 超出限额 {}",
+                    ByteSize(after),
+                    max
+                ));
+            }
+        }
+        if let Some(max) = self.max_destination_size {
+            let after = (usage.destination_size + estimate).saturating_sub(freed);
+            if after > max.0 {
+                return Some(format!(
+                    "备份目的地大小将达到 {}, 超出限额 {}",
+                    ByteSize(after),
+                    max
+                ));
+            }
+        }
+        if let Some(min) = self.min_free_space {
+            let after = usage
+                .free_space
+                .saturating_add(freed)
+                .saturating_sub(estimate);
+            if after < min.0 {
+                return Some(format!(
+                    "备份后可用空间仅剩 {}, 低于需保留的 {}",
+                    ByteSize(after),
+                    min
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// 目录内所有文件的大小之和, 不跟随符号链接, 目录不存在时为 0
+pub fn dir_size(storage: &dyn StorageBackend, path: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    let mut directories = vec![path.to_path_buf()];
+    while let Some(dir) = directories.pop() {
+        let entries = match storage.list(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            if entry.meta.is_dir() {
+                directories.push(dir.join(&entry.name));
+            } else {
+                size += entry.meta.len;
+            }
+        }
+    }
+    Ok(size)
+}
+
+/// 空间不足时的错误
+pub fn quota_error(reason: String) -> Error {
+    Error::new(
+        ErrorKind::StorageFull,
+        format!("空间限额不足, 未开始备份:{}", reason),
+    )
+}
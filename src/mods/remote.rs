@@ -0,0 +1,565 @@
+use super::{
+    base_bk_option::get_backup_base_path,
+    bk_config::BackupConfig,
+    command_source, database,
+    s3::{S3Storage, S3Url},
+    sftp::{SftpStorage, SftpUrl},
+    smb::{SmbStorage, SmbUrl},
+    storage::{Link, StorageBackend, StorageEntry, StorageKind, StorageMeta},
+    webdav::{WebDavStorage, WebDavUrl},
+};
+use log::info;
+use std::fs::{self, File};
+use std::io::{self, Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 是否为远程地址, 如 sftp://user@host:port/path、smb://domain;user@host/share/path 或 s3://bucket/prefix
+pub fn is_remote(path: &str) -> bool {
+    path.contains("://")
+}
+
+/// 数据库导出文件在本地的缓存目录, 位于程序的 BackupConfig/remote 目录下
+/// 数据库地址先导出到缓存目录再备份
+pub fn cache_path(url: &str) -> PathBuf {
+    let mut path = PathBuf::from("BackupConfig");
+    path.push("remote");
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    path.push(scheme);
+    for (index, segment) in rest.split('/').enumerate() {
+        if segment.is_empty() || segment == "." || segment == ".." {
+            continue;
+        }
+        if index == 0 {
+            // 地址中的密码不作为目录名称
+            let segment = match segment.rsplit_once('@') {
+                Some((user_info, host)) => {
+                    format!(
+                        "{}@{}",
+                        user_info.split(':').next().unwrap_or_default(),
+                        host
+                    )
+                }
+                None => segment.to_string(),
+            };
+            path.push(segment.replace(':', "_"));
+        } else {
+            path.push(segment);
+        }
+    }
+    path
+}
+
+/// 按地址打开远程存储, 返回存储及地址中的远程路径
+/// 远程存储实现 StorageBackend, 其中的路径均为远程主机上以 / 分隔的路径
+pub fn open(url: &str, config: &BackupConfig) -> Result<(Arc<dyn StorageBackend>, PathBuf), Error> {
+    match url.split_once("://").map(|(scheme, _)| scheme) {
+        Some("sftp") => {
+            let url = SftpUrl::parse(url)?;
+            let root = PathBuf::from(&url.path);
+            let storage = SftpStorage::create(url, config.sftp.clone().unwrap_or_default());
+            Ok((Arc::new(storage), root))
+        }
+        Some("s3") => {
+            let s3_config = config.s3.clone().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("使用 {} 时需要填写 s3 配置", url),
+                )
+            })?;
+            let url = S3Url::parse(url)?;
+            let root = PathBuf::from(&url.path);
+            Ok((Arc::new(S3Storage::create(url, s3_config)?), root))
+        }
+        Some("smb") => {
+            let smb_config = config.smb.clone().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("使用 {} 时需要填写 smb 配置", url),
+                )
+            })?;
+            let url = SmbUrl::parse(url)?;
+            let root = PathBuf::from(&url.path);
+            Ok((Arc::new(SmbStorage::create(url, smb_config)?), root))
+        }
+        Some("webdav" | "webdavs") => {
+            let url = WebDavUrl::parse(url)?;
+            let root = PathBuf::from(&url.path);
+            let storage = WebDavStorage::create(url, config.webdav.clone().unwrap_or_default())?;
+            Ok((Arc::new(storage), root))
+        }
+        _ => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("不支持的远程地址: {}", url),
+        )),
+    }
+}
+
+/// 备份目的地的存储
+/// 远程地址打开对应的远程存储, 备份直接写入远程主机, 本地不保留副本;
+/// 本地路径使用 storage
+pub fn destination_storage(
+    storage: &Arc<dyn StorageBackend>,
+    config: &BackupConfig,
+) -> Result<Arc<dyn StorageBackend>, Error> {
+    let url = &config.backup_destination_path;
+    if !is_remote(url) {
+        return Ok(storage.clone());
+    }
+    root_storage(url, config)
+}
+
+/// 远程源目录的存储, 备份时直接列出和读取远程主机上的文件, 本地不保留副本
+pub fn source_storage(url: &str, config: &BackupConfig) -> Result<Arc<dyn StorageBackend>, Error> {
+    root_storage(url, config)
+}
+
+fn root_storage(url: &str, config: &BackupConfig) -> Result<Arc<dyn StorageBackend>, Error> {
+    let (storage, root) = open(url, config)?;
+    Ok(Arc::new(RemoteRoot {
+        base: get_backup_base_path(url),
+        root,
+        storage,
+    }))
+}
+
+/// 远程地址下的存储, 用于远程备份目的地及远程源目录
+/// 备份模式中的路径以 get_backup_base_path 返回的地址开头, 访问时转换为远程存储中的路径
+#[derive(Debug)]
+struct RemoteRoot {
+    base: PathBuf,
+    root: PathBuf,
+    storage: Arc<dyn StorageBackend>,
+}
+
+impl RemoteRoot {
+    fn remote(&self, path: &Path) -> Result<PathBuf, Error> {
+        let relative = path.strip_prefix(&self.base).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} 不在备份目的地 {} 中",
+                    path.display(),
+                    self.base.display()
+                ),
+            )
+        })?;
+        if relative.as_os_str().is_empty() {
+            return Ok(self.root.clone());
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl StorageBackend for RemoteRoot {
+    fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+        self.storage.list(&self.remote(path)?)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+        self.storage.stat(&self.remote(path)?)
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        self.storage.read(&self.remote(path)?)
+    }
+
+    fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+        self.storage.write(&self.remote(path)?, reader)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<(), Error> {
+        self.storage.mkdir(&self.remote(path)?)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        self.storage.rename(&self.remote(from)?, &self.remote(to)?)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), Error> {
+        self.storage.delete(&self.remote(path)?)
+    }
+
+    fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+        let path = self.remote(path)?;
+        match link {
+            Link::Hard(target) => self.storage.link(Link::Hard(&self.remote(target)?), &path),
+            Link::Symbolic(_) => self.storage.link(link, &path),
+        }
+    }
+
+    fn read_link(&self, path: &Path) -> Result<String, Error> {
+        self.storage.read_link(&self.remote(path)?)
+    }
+
+    fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+        self.storage.copy_from(source, &self.remote(path)?)
+    }
+
+    fn copy_to(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        self.storage.copy_to(&self.remote(path)?, local)
+    }
+
+    fn digest(&self, path: &Path) -> Result<String, Error> {
+        self.storage.digest(&self.remote(path)?)
+    }
+
+    fn available_space(&self, path: &Path) -> Result<Option<u64>, Error> {
+        self.storage.available_space(&self.remote(path)?)
+    }
+
+    fn rename_copies_data(&self) -> bool {
+        self.storage.rename_copies_data()
+    }
+}
+
+/// 备份前准备源目录
+/// 数据库地址由 database::dump 导出到缓存目录, 命令源由 command_source::run 将命令的输出保存到缓存目录;
+/// 其他远程地址不需要准备, 备份时通过 source_storage 直接读取
+pub fn pull_source(task_name: &str, config: &BackupConfig) -> Result<(), Error> {
+    for source in config.sources() {
+        if source.is_command() {
+            command_source::run(task_name, &source)?;
+            continue;
+        }
+        let url = &source.path;
+        if !is_remote(url) {
+            continue;
+        }
+        if database::is_database(url) {
+            database::dump(task_name, url, config)?;
+            continue;
+        }
+        // 早期版本将远程源目录同步到缓存目录, 已不再使用
+        let cache = cache_path(url);
+        if cache.is_dir() {
+            fs::remove_dir_all(&cache)?;
+            info!(
+                "{}:已删除不再使用的远程源目录缓存 {}",
+                task_name,
+                cache.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// 远程存储中的路径, 以 / 分隔
+pub fn remote_path(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+/// 远程存储返回的 Unix 时间戳(秒)
+pub fn unix_time(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// 逐级创建远程目录, create 创建一级目录, 已存在的目录跳过
+pub fn mkdir_all(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    create: impl Fn(&Path) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut ancestors: Vec<&Path> = path
+        .ancestors()
+        .filter(|p| p.file_name().is_some())
+        .collect();
+    ancestors.reverse();
+    for ancestor in ancestors {
+        match storage.stat(ancestor)? {
+            Some(meta) if meta.is_dir() => {}
+            Some(_) => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("远程路径 {} 已存在且不是目录", ancestor.display()),
+                ))
+            }
+            None => create(ancestor)?,
+        }
+    }
+    Ok(())
+}
+
+/// 删除远程路径, 目录先逐个删除其中的内容
+/// remove 删除一个文件、符号链接或空目录
+pub fn delete_all(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    remove: &dyn Fn(&Path, StorageKind) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let meta = storage.stat(path)?.ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            format!("远程路径 {} 不存在", path.display()),
+        )
+    })?;
+    delete_tree(storage, path, meta.kind, remove)
+}
+
+fn delete_tree(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    kind: StorageKind,
+    remove: &dyn Fn(&Path, StorageKind) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if kind == StorageKind::Dir {
+        for entry in storage.list(path)? {
+            delete_tree(storage, &path.join(&entry.name), entry.meta.kind, remove)?;
+        }
+    }
+    remove(path, kind)
+}
+
+/// 下载到临时文件后计算 sha256, 不将整个文件读入内存
+pub fn download_digest(storage: &dyn StorageBackend, path: &Path) -> Result<String, Error> {
+    let spool = SpoolFile::create()?;
+    storage.copy_to(path, spool.path())?;
+    sha256::try_digest(spool.path())
+}
+
+/// 写入远程存储前暂存数据的本地临时文件, 上传失败重试时重新读取, 离开作用域时删除
+pub struct SpoolFile {
+    path: PathBuf,
+}
+
+impl SpoolFile {
+    /// 在系统临时目录中创建空文件
+    pub fn create() -> Result<SpoolFile, Error> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rsbk-spool-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        File::create(&path)?;
+        Ok(SpoolFile { path })
+    }
+
+    /// 将 reader 的全部内容写入临时文件
+    pub fn write(reader: &mut dyn Read) -> Result<SpoolFile, Error> {
+        let spool = SpoolFile::create()?;
+        let mut file = File::create(&spool.path)?;
+        io::copy(reader, &mut file)?;
+        Ok(spool)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::base_bk_option::{self, CopyTarget, SourceRoot, SourceSet};
+    use crate::mods::bk_config::{Compression, SymlinkPolicy};
+    use crate::mods::catalog::Catalog;
+    use crate::mods::file_metadata::Manifest;
+    use crate::mods::quota::{ByteSize, QuotaConfig};
+    use crate::mods::run_stats::RunStats;
+    use crate::mods::storage::MemoryStorage;
+
+    /// 统计读取次数的存储, 其余操作交给内存存储
+    #[derive(Debug, Default)]
+    struct CountingReads(MemoryStorage, AtomicU64);
+
+    impl StorageBackend for CountingReads {
+        fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+            self.0.list(path)
+        }
+        fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+            self.0.stat(path)
+        }
+        fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            self.0.read(path)
+        }
+        fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+            self.0.write(path, reader)
+        }
+        fn mkdir(&self, path: &Path) -> Result<(), Error> {
+            self.0.mkdir(path)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+            self.0.rename(from, to)
+        }
+        fn delete(&self, path: &Path) -> Result<(), Error> {
+            self.0.delete(path)
+        }
+        fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+            self.0.link(link, path)
+        }
+        fn read_link(&self, path: &Path) -> Result<String, Error> {
+            self.0.read_link(path)
+        }
+    }
+
+    #[test]
+    fn remote_source_reads_only_changed_files() {
+        let url = "sftp://user@host/srv/";
+        let remote = Arc::new(CountingReads::default());
+        remote.mkdir(Path::new("/srv/dir")).unwrap();
+        remote.write(Path::new("/srv/a"), &mut &b"one"[..]).unwrap();
+        remote
+            .write(Path::new("/srv/dir/b"), &mut &b"two"[..])
+            .unwrap();
+        let sources = SourceSet {
+            roots: vec![SourceRoot {
+                path: PathBuf::from(base_bk_option::get_source_path(url)),
+                folder: PathBuf::new(),
+                storage: Some(Arc::new(RemoteRoot {
+                    base: get_backup_base_path(url),
+                    root: PathBuf::from("/srv"),
+                    storage: remote.clone(),
+                })),
+            }],
+        };
+        let destination = MemoryStorage::new();
+        let backup_path = PathBuf::from("/backup");
+        destination.mkdir(&backup_path).unwrap();
+        let mut catalog = Catalog::default();
+        catalog.backup_root = "/backup".to_string();
+        let policy = SymlinkPolicy::Preserve;
+
+        // 返回本次备份的路径及从远程主机读取的文件数
+        let mut backup = || {
+            let reads = remote.1.load(Ordering::Relaxed);
+            let mut stats = RunStats::start("task");
+            let destination_storage: &dyn StorageBackend = &destination;
+            let changed = base_bk_option::get_changed_paths(
+                &sources,
+                policy,
+                &mut [(&mut catalog, 3, destination_storage)],
+                &mut stats,
+            )
+            .unwrap()
+            .remove(0);
+            base_bk_option::create_all_dir(
+                &destination,
+                &changed,
+                &backup_path,
+                &sources,
+                policy,
+                &mut stats,
+            )
+            .unwrap();
+            let mut targets = vec![CopyTarget::new(
+                &destination,
+                backup_path.clone(),
+                Compression::None,
+                Manifest::default(),
+                RunStats::start("task"),
+            )];
+            base_bk_option::copy_file(&changed, &mut targets, &sources, policy, &mut stats)
+                .unwrap();
+            assert_eq!(targets[0].stats.files_failed, 0);
+            catalog.commit(
+                targets[0]
+                    .copied
+                    .iter()
+                    .map(|p| sources.relative_key(Path::new(p))),
+            );
+            (changed, remote.1.load(Ordering::Relaxed) - reads)
+        };
+
+        let (changed, reads) = backup();
+        assert_eq!(
+            changed,
+            [
+                "sftp://user@host/srv/a",
+                "sftp://user@host/srv/dir",
+                "sftp://user@host/srv/dir/b"
+            ]
+        );
+        // 每个文件只下载一次, 不计算源文件的摘要
+        assert_eq!(reads, 2);
+        assert_eq!(destination.read(Path::new("/backup/a")).unwrap(), b"one");
+        assert_eq!(
+            destination.read(Path::new("/backup/dir/b")).unwrap(),
+            b"two"
+        );
+
+        // 远程文件未变动时只列出目录, 不读取任何文件
+        let (changed, reads) = backup();
+        assert!(changed.is_empty());
+        assert_eq!(reads, 0);
+
+        remote
+            .write(Path::new("/srv/dir/b"), &mut &b"three"[..])
+            .unwrap();
+        let (changed, reads) = backup();
+        assert_eq!(
+            changed,
+            ["sftp://user@host/srv/dir", "sftp://user@host/srv/dir/b"]
+        );
+        assert_eq!(reads, 1);
+        assert_eq!(
+            destination.read(Path::new("/backup/dir/b")).unwrap(),
+            b"three"
+        );
+        // 不在本地留下缓存
+        assert!(!cache_path(url).exists());
+    }
+
+    #[test]
+    fn remote_destination_writes_through() {
+        let url = "sftp://user@host/backups";
+        let remote = Arc::new(MemoryStorage::new());
+        let destination = RemoteRoot {
+            base: get_backup_base_path(url),
+            root: PathBuf::from("/backups"),
+            storage: remote.clone(),
+        };
+        let backup_path = base_bk_option::get_backup_path(&destination, url, "task").unwrap();
+        assert_eq!(backup_path, Path::new("sftp://user@host/backups/task"));
+        destination
+            .write(&backup_path.join("a"), &mut &b"one"[..])
+            .unwrap();
+        destination
+            .rename(&backup_path.join("a"), &backup_path.join("b"))
+            .unwrap();
+        assert_eq!(remote.read(Path::new("/backups/task/b")).unwrap(), b"one");
+        assert!(remote.stat(Path::new("/backups/task/a")).unwrap().is_none());
+
+        // 空间限额按远程主机上的占用计算
+        let quota = QuotaConfig {
+            max_task_size: Some(ByteSize(1)),
+            ..QuotaConfig::default()
+        };
+        let usage = quota
+            .usage(&destination, &backup_path, &get_backup_base_path(url))
+            .unwrap();
+        assert_eq!(usage.task_size, 3);
+        // 不在本地留下缓存
+        assert!(!cache_path(url).exists());
+        assert!(!backup_path.exists());
+    }
+
+    #[test]
+    fn remote_destination_maps_paths_under_base() {
+        let destination = RemoteRoot {
+            base: get_backup_base_path("s3://bucket/prefix/"),
+            root: PathBuf::from("prefix"),
+            storage: Arc::new(MemoryStorage::new()),
+        };
+        assert_eq!(
+            destination
+                .remote(Path::new("s3://bucket/prefix/task/a"))
+                .unwrap(),
+            Path::new("prefix/task/a")
+        );
+        assert_eq!(
+            destination.remote(Path::new("s3://bucket/prefix")).unwrap(),
+            Path::new("prefix")
+        );
+        assert!(destination.remote(Path::new("/tmp/task")).is_err());
+    }
+}
@@ -0,0 +1,622 @@
+use super::{
+    base_bk_option::{self, context},
+    bk_config::{BackupConfig, BackupMode},
+    prune::PruneReport,
+    remote::{self, SpoolFile},
+    retention::RetentionPolicy,
+    run_stats::{RunStats, RunStatus},
+    storage::{self, Link, StorageBackend, StorageKind},
+    version_index::{VersionEntry, VersionIndex},
+};
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+///复制任务，将另一个版本控制模式任务已完成的版本复制到本任务的备份目的地
+///不重新读取源目录, 版本ID、清单及文件内容与原版本一致, 本任务的备份目的地按自己的保留策略删除早期版本
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicateMode {
+    pub task_config: BackupConfig,
+    /// 备份目的地的存储, 默认为本地磁盘
+    #[serde(skip, default = "storage::local")]
+    pub storage: Arc<dyn StorageBackend>,
+}
+
+/// 被复制的版本库: 被复制任务的备份目的地的存储、其中的备份目录及版本索引
+struct Repository {
+    storage: Arc<dyn StorageBackend>,
+    root: PathBuf,
+    index: VersionIndex,
+}
+
+impl ReplicateMode {
+    /// 创建整个复制计划
+    /// 应当只使用这个create生成计划
+    pub fn create(task: BackupConfig) -> Self {
+        ReplicateMode {
+            task_config: task,
+            storage: storage::local(),
+        }
+    }
+
+    /// 复制到所有备份目的地
+    pub fn backup(&self, task_name: &str) -> RunStats {
+        let due: Vec<usize> = (0..self.task_config.destinations().len()).collect();
+        self.backup_to(task_name, &due)
+    }
+
+    /// 执行复制计划
+    /// due 为本次需要复制的目的地在 BackupConfig::destinations 中的序号。
+    /// 首先读取被复制任务的版本索引, 然后对每个目的地复制其中尚未有的完整版本,
+    /// 复制后逐个文件校验内容摘要, 校验通过才以原版本ID写入目的地的版本索引,
+    /// 最后按目的地的保留策略删除早期版本。
+    /// 一个目的地出错不影响其他目的地, 返回本次运行的统计信息, 其中包含各目的地的结果
+    pub fn backup_to(&self, task_name: &str, due: &[usize]) -> RunStats {
+        let mut stats = RunStats::start(task_name);
+        let result = self.open_repository().and_then(|repository| {
+            let configs = self.task_config.destination_configs()?;
+            let multiple = configs.len() > 1;
+            for config in due.iter().filter_map(|&i| configs.get(i)) {
+                let label = if multiple {
+                    format!("{}({})", task_name, config.backup_destination_path)
+                } else {
+                    task_name.to_string()
+                };
+                let mut destination_stats = RunStats::start(task_name);
+                let result = self.replicate_to(
+                    task_name,
+                    &label,
+                    config,
+                    &repository,
+                    &mut destination_stats,
+                    &mut stats,
+                );
+                if let Err(e) = result {
+                    error!("{}:{}", label, e);
+                    destination_stats.fail(&e);
+                }
+                stats.merge_destination(&config.backup_destination_path, destination_stats);
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            error!(
+                "{:#?}",
+                &(task_name.to_owned() + ":" + e.to_string().as_str())
+            );
+            stats.fail(&e);
+        }
+        stats.finish();
+        stats
+    }
+
+    /// 读取被复制任务的版本库, 其备份目的地为远程地址时直接从远程主机读取
+    /// 只读取版本索引, 不修改被复制任务的备份目录
+    fn open_repository(&self) -> Result<Repository, Error> {
+        let BackupMode::ReplicateMode {
+            task, destination, ..
+        } = &self.task_config.options
+        else {
+            return Err(Error::new(ErrorKind::InvalidInput, "不是复制任务"));
+        };
+        let config = BackupConfig::create(&BackupConfig::get_hash_path(task))
+            .map_err(context("读取被复制的备份计划时发生错误"))?;
+        if !matches!(config.options, BackupMode::VersionMode { .. }) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("任务 {} 不是版本控制模式, 无法复制", task),
+            ));
+        }
+        let config = config
+            .destination_configs()?
+            .into_iter()
+            .nth(*destination)
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("任务 {} 没有序号为 {} 的备份目的地", task, destination),
+                )
+            })?;
+        let storage = remote::destination_storage(&self.storage, &config)
+            .map_err(context("打开被复制的备份目的地时发生错误"))?;
+        let mut root = base_bk_option::get_backup_base_path(&config.backup_destination_path);
+        root.push(config.backup_title(task));
+        let index = VersionIndex::read(storage.as_ref(), &root)
+            .map_err(context("读取被复制任务的版本索引时发生错误"))?;
+        Ok(Repository {
+            storage,
+            root,
+            index,
+        })
+    }
+
+    /// 复制到一个备份目的地并按其保留策略删除早期版本
+    /// 单个版本复制或校验失败时记录到统计中并继续复制其余版本
+    fn replicate_to(
+        &self,
+        task_name: &str,
+        label: &str,
+        config: &BackupConfig,
+        repository: &Repository,
+        stats: &mut RunStats,
+        task_stats: &mut RunStats,
+    ) -> Result<(), Error> {
+        let BackupMode::ReplicateMode {
+            preserve_version,
+            retention,
+            ..
+        } = &config.options
+        else {
+            return Ok(());
+        };
+        let storage = remote::destination_storage(&self.storage, config)
+            .map_err(context("打开备份目的地时发生错误"))?;
+        let storage = storage.as_ref();
+        let backup_root = base_bk_option::get_backup_path(
+            storage,
+            &config.backup_destination_path,
+            &config.backup_title(task_name),
+        )
+        .map_err(context("获取备份路径时发生错误"))?;
+        let mut index = VersionIndex::load(storage, &backup_root, &[])
+            .map_err(context("读取版本索引时发生错误"))?;
+        let retention = retention
+            .clone()
+            .unwrap_or_else(|| RetentionPolicy::keep_last(*preserve_version));
+        let mut report = PruneReport::for_task(config, task_name, false);
+        if let Err(e) = report.purge_trash(storage) {
+            warn!("{}:清除回收站时发生错误:{}", label, e);
+        }
+
+        let missing = missing_versions(repository, &index, &retention, *preserve_version);
+        if missing.is_empty() {
+            info!(
+                "{:#?}",
+                &(label.to_owned() + ":没有需要复制的版本,等待下一个复制任务")
+            );
+            stats.status = RunStatus::NoChange;
+        }
+        for entry in missing {
+            let replicated = self.replicate_version(
+                storage,
+                repository,
+                &entry,
+                &backup_root,
+                &mut index,
+                stats,
+                task_stats,
+            );
+            match replicated {
+                Ok(version_path) => info!(
+                    "{:#?}",
+                    &(label.to_owned()
+                        + ":版本 "
+                        + &entry.id
+                        + " 复制并校验完成,备份目录为 "
+                        + &version_path.to_string_lossy())
+                ),
+                Err(e) => {
+                    error!("{}:复制版本 {} 时发生错误:{}", label, entry.id, e);
+                    stats.record_failure(&entry.id, &e);
+                }
+            }
+        }
+
+        let remaining = base_bk_option::prune_versions(
+            storage,
+            &backup_root,
+            &mut index,
+            &retention,
+            *preserve_version,
+            &mut report,
+        );
+        stats.bytes_freed = report.bytes_freed();
+        remaining.map_err(context("删除保留策略之外的版本时发生错误"))?;
+        Ok(())
+    }
+
+    /// 将一个版本复制到临时目录, 校验通过后以原版本ID写入索引, 返回版本目录
+    /// 复制或校验失败时删除临时目录
+    #[allow(clippy::too_many_arguments)]
+    fn replicate_version(
+        &self,
+        storage: &dyn StorageBackend,
+        repository: &Repository,
+        entry: &VersionEntry,
+        backup_root: &Path,
+        index: &mut VersionIndex,
+        stats: &mut RunStats,
+        task_stats: &mut RunStats,
+    ) -> Result<PathBuf, Error> {
+        let source = VersionIndex::version_path(&repository.root, entry);
+        let partial_path = VersionIndex::begin(storage, backup_root, &entry.id)
+            .map_err(context("创建版本目录时发生错误"))?;
+        let result = self
+            .copy_tree(
+                storage,
+                repository,
+                &source,
+                &partial_path,
+                stats,
+                task_stats,
+            )
+            .map_err(context("复制版本时发生错误"))
+            .and_then(|copied| verify(storage, &copied));
+        if let Err(e) = result {
+            let _ = VersionIndex::abort(storage, &partial_path);
+            return Err(e);
+        }
+        index
+            .import(storage, backup_root, &partial_path, entry.clone())
+            .map_err(context("写入版本索引时发生错误"))
+    }
+
+    /// 复制版本目录中的所有目录、文件(包括清单)及符号链接
+    /// 返回复制的文件及其源文件的摘要
+    fn copy_tree(
+        &self,
+        storage: &dyn StorageBackend,
+        repository: &Repository,
+        source: &Path,
+        target: &Path,
+        stats: &mut RunStats,
+        task_stats: &mut RunStats,
+    ) -> Result<Vec<(PathBuf, String)>, Error> {
+        let mut copied = Vec::new();
+        let mut directories = vec![(source.to_path_buf(), target.to_path_buf())];
+        while let Some((from, to)) = directories.pop() {
+            for entry in repository.storage.list(&from)? {
+                let path = from.join(&entry.name);
+                let target = to.join(&entry.name);
+                match entry.meta.kind {
+                    StorageKind::Dir => {
+                        storage.mkdir(&target)?;
+                        stats.dirs_created += 1;
+                        directories.push((path, target));
+                    }
+                    StorageKind::Symlink => {
+                        // 符号链接已记录在清单中, 无法读取或目标文件系统不支持时由还原按清单重建
+                        let linked = repository
+                            .storage
+                            .read_link(&path)
+                            .and_then(|link_to| storage.link(Link::Symbolic(&link_to), &target));
+                        if let Err(e) = linked {
+                            log::debug!("无法在备份目录中创建符号链接 {}: {}", target.display(), e);
+                        }
+                    }
+                    StorageKind::File => {
+                        let (digest, bytes) = copy_file(storage, repository, &path, &target)?;
+                        stats.files_copied += 1;
+                        stats.bytes_written += bytes;
+                        task_stats.bytes_read += bytes;
+                        copied.push((target, digest));
+                    }
+                }
+            }
+        }
+        Ok(copied)
+    }
+
+    /// 只按保留策略删除旧版本, 不进行复制, 供 prune 命令使用
+    /// task_config 应为单个备份目的地的任务配置(BackupConfig::for_destination)
+    pub fn prune(&self, report: &mut PruneReport) -> Result<(), Error> {
+        let BackupMode::ReplicateMode {
+            preserve_version,
+            retention,
+            ..
+        } = &self.task_config.options
+        else {
+            return Ok(());
+        };
+        let storage = remote::destination_storage(&self.storage, &self.task_config)?;
+        let storage = storage.as_ref();
+        let mut backup_root =
+            base_bk_option::get_backup_base_path(&self.task_config.backup_destination_path);
+        backup_root.push(self.task_config.backup_title(&report.task_name));
+        if !storage
+            .stat(&backup_root)?
+            .is_some_and(|meta| meta.is_dir())
+        {
+            return Ok(());
+        }
+        let mut index = VersionIndex::load(storage, &backup_root, &[])
+            .map_err(context("读取版本索引时发生错误"))?;
+        let retention = retention
+            .clone()
+            .unwrap_or_else(|| RetentionPolicy::keep_last(*preserve_version));
+        base_bk_option::prune_versions(
+            storage,
+            &backup_root,
+            &mut index,
+            &retention,
+            *preserve_version,
+            report,
+        )
+        .map_err(context("删除保留策略之外的版本时发生错误"))?;
+        Ok(())
+    }
+}
+
+/// 复制版本库中的一个文件, 返回源文件的摘要及复制的字节数
+/// 本地磁盘上的版本库直接复制, 其他存储中的版本库先下载到临时文件
+fn copy_file(
+    storage: &dyn StorageBackend,
+    repository: &Repository,
+    path: &Path,
+    target: &Path,
+) -> Result<(String, u64), Error> {
+    if let Some(local) = repository.storage.local_path(path) {
+        let digest = sha256::try_digest(&local)?;
+        return Ok((digest, storage.copy_from(&local, target)?));
+    }
+    let spool = SpoolFile::create()?;
+    repository.storage.copy_to(path, spool.path())?;
+    let digest = sha256::try_digest(spool.path())?;
+    Ok((digest, storage.copy_from(spool.path(), target)?))
+}
+
+/// 版本库中目的地尚未有的完整版本, 按创建时间从旧到新排列
+/// 与目的地已有的版本一起按保留策略计算, 只复制会被保留的版本,
+/// 避免复制后立即被删除、下一次运行时又再次复制
+fn missing_versions(
+    repository: &Repository,
+    index: &VersionIndex,
+    retention: &RetentionPolicy,
+    preserve_version: usize,
+) -> Vec<VersionEntry> {
+    let mut candidates: Vec<(DateTime<Local>, Option<&VersionEntry>)> = index
+        .versions
+        .iter()
+        .map(|entry| (entry.created_at, None))
+        .collect();
+    candidates.extend(
+        repository
+            .index
+            .versions
+            .iter()
+            .filter(|entry| entry.is_complete() && !index.versions.iter().any(|v| v.id == entry.id))
+            .map(|entry| (entry.created_at, Some(entry))),
+    );
+    candidates.sort_by_key(|(created_at, _)| *created_at);
+    let times: Vec<DateTime<Local>> = candidates.iter().map(|(time, _)| *time).collect();
+    retention
+        .evaluate(&times, preserve_version)
+        .into_iter()
+        .zip(candidates)
+        .filter(|(reasons, _)| !reasons.is_empty())
+        .filter_map(|(_, (_, entry))| entry.cloned())
+        .collect()
+}
+
+/// 校验复制的文件与源文件的摘要一致
+fn verify(storage: &dyn StorageBackend, copied: &[(PathBuf, String)]) -> Result<(), Error> {
+    for (path, digest) in copied {
+        if storage.digest(path)? != *digest {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("复制后校验失败, 内容与原版本不一致: {}", path.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::storage::{MemoryStorage, StorageEntry, StorageMeta};
+    use std::fs;
+    use std::io::Read;
+
+    /// 复制时写入与源文件不同的内容, 用于校验失败的情况, 其余操作交给内存存储
+    #[derive(Debug, Default)]
+    struct CorruptCopies(MemoryStorage);
+
+    impl StorageBackend for CorruptCopies {
+        fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+            self.0.list(path)
+        }
+        fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+            self.0.stat(path)
+        }
+        fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+            self.0.read(path)
+        }
+        fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+            self.0.write(path, reader)
+        }
+        fn mkdir(&self, path: &Path) -> Result<(), Error> {
+            self.0.mkdir(path)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+            self.0.rename(from, to)
+        }
+        fn delete(&self, path: &Path) -> Result<(), Error> {
+            self.0.delete(path)
+        }
+        fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+            self.0.link(link, path)
+        }
+        fn read_link(&self, path: &Path) -> Result<String, Error> {
+            self.0.read_link(path)
+        }
+        fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+            let mut content = fs::read(source)?;
+            content.push(b'!');
+            self.0.write(path, &mut content.as_slice())
+        }
+    }
+
+    /// 被复制的版本控制任务及复制任务, 被复制任务的配置文件在离开作用域时删除
+    struct Fixture {
+        primary: String,
+        primary_root: PathBuf,
+        mode: ReplicateMode,
+    }
+
+    impl Fixture {
+        fn create(name: &str, preserve_version: usize, storage: Arc<dyn StorageBackend>) -> Self {
+            let primary = format!("replicate_{}_primary_test", name);
+            fs::write(
+                BackupConfig::get_hash_path(&primary),
+                "backup_source_path: /src/data\nbackup_destination_path: /primary\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 10\n",
+            )
+            .unwrap();
+            let task_config = serde_yaml::from_str(&format!(
+                "backup_destination_path: /secondary\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: ReplicateMode\n  task: {}\n  preserve_version: {}\n",
+                primary, preserve_version
+            ))
+            .unwrap();
+            storage.mkdir(Path::new("/primary")).unwrap();
+            storage.mkdir(Path::new("/primary/data")).unwrap();
+            Fixture {
+                primary,
+                primary_root: PathBuf::from("/primary/data"),
+                mode: ReplicateMode {
+                    task_config,
+                    storage,
+                },
+            }
+        }
+
+        fn storage(&self) -> &dyn StorageBackend {
+            self.mode.storage.as_ref()
+        }
+
+        /// 在被复制任务中添加一个 days 天前创建的完整版本, 包含两个文件
+        fn add_version(&self, days: i64) -> VersionEntry {
+            let storage = self.storage();
+            let created_at = Local::now() - chrono::Duration::days(days);
+            let hash = format!("{:08}", days);
+            let entry = VersionEntry {
+                id: VersionIndex::version_id(&created_at, &hash),
+                created_at,
+                hash: Some(hash),
+                migrated_from: None,
+            };
+            let version_path = VersionIndex::version_path(&self.primary_root, &entry);
+            storage.mkdir(&version_path).unwrap();
+            storage.mkdir(&version_path.join("sub")).unwrap();
+            let content = format!("version {}", days);
+            storage
+                .write(&version_path.join("a"), &mut content.as_bytes())
+                .unwrap();
+            storage
+                .write(&version_path.join("sub/b"), &mut content.as_bytes())
+                .unwrap();
+            let mut index = VersionIndex::read(storage, &self.primary_root).unwrap();
+            index.versions.push(entry.clone());
+            index.save(storage, &self.primary_root).unwrap();
+            entry
+        }
+
+        fn secondary_root(&self) -> PathBuf {
+            Path::new("/secondary").join(self.mode.task_config.backup_title("replicate_test"))
+        }
+
+        fn secondary_ids(&self) -> Vec<String> {
+            VersionIndex::read(self.storage(), &self.secondary_root())
+                .unwrap()
+                .versions
+                .into_iter()
+                .map(|v| v.id)
+                .collect()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(BackupConfig::get_hash_path(&self.primary));
+        }
+    }
+
+    #[test]
+    fn copies_only_versions_missing_on_target() {
+        let fixture = Fixture::create("missing", 10, Arc::new(MemoryStorage::new()));
+        let old = fixture.add_version(3);
+        let newer = fixture.add_version(2);
+
+        let first = fixture.mode.backup("replicate_test");
+        assert_eq!(first.status, RunStatus::Success, "{:?}", first.errors);
+        assert_eq!(first.files_copied, 4);
+        assert_eq!(
+            fixture.secondary_ids(),
+            vec![old.id.clone(), newer.id.clone()]
+        );
+
+        let newest = fixture.add_version(1);
+        let second = fixture.mode.backup("replicate_test");
+        assert_eq!(second.status, RunStatus::Success);
+        assert_eq!(second.files_copied, 2);
+        assert_eq!(
+            fixture.secondary_ids(),
+            vec![old.id, newer.id, newest.id.clone()]
+        );
+
+        let third = fixture.mode.backup("replicate_test");
+        assert_eq!(third.status, RunStatus::NoChange);
+        assert_eq!(third.files_copied, 0);
+
+        // 以原版本ID及创建时间写入目的地的索引, 内容与原版本一致
+        let index = VersionIndex::read(fixture.storage(), &fixture.secondary_root()).unwrap();
+        let imported = index.find(&newest.id).unwrap();
+        assert_eq!(imported.created_at, newest.created_at);
+        assert_eq!(imported.hash, newest.hash);
+        let version_path = VersionIndex::version_path(&fixture.secondary_root(), imported);
+        assert_eq!(
+            fixture.storage().read(&version_path.join("sub/b")).unwrap(),
+            b"version 1"
+        );
+    }
+
+    #[test]
+    fn copies_only_versions_kept_by_target_retention() {
+        let fixture = Fixture::create("retention", 2, Arc::new(MemoryStorage::new()));
+        let versions: Vec<VersionEntry> = (1..=4)
+            .rev()
+            .map(|days| fixture.add_version(days))
+            .collect();
+
+        let stats = fixture.mode.backup("replicate_test");
+        assert_eq!(stats.status, RunStatus::Success);
+        // 只复制会被保留的两个最新版本, 不复制后再删除
+        assert_eq!(stats.files_copied, 4);
+        assert_eq!(stats.files_deleted, 0);
+        assert_eq!(
+            fixture.secondary_ids(),
+            vec![versions[2].id.clone(), versions[3].id.clone()]
+        );
+        assert_eq!(
+            fixture.mode.backup("replicate_test").status,
+            RunStatus::NoChange
+        );
+    }
+
+    #[test]
+    fn failed_verification_removes_partial_version() {
+        let fixture = Fixture::create("verify", 10, Arc::new(CorruptCopies::default()));
+        let entry = fixture.add_version(1);
+
+        let stats = fixture.mode.backup("replicate_test");
+        assert_eq!(stats.status, RunStatus::Partial);
+        assert_eq!(stats.files_failed, 1);
+        assert!(stats.errors[0].contains(&entry.id));
+        assert!(stats.errors[0].contains("校验失败"));
+        assert!(fixture.secondary_ids().is_empty());
+        let names: Vec<String> = fixture
+            .storage()
+            .list(&fixture.secondary_root())
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert!(
+            names.iter().all(|name| !name.ends_with(".partial")),
+            "{:?}",
+            names
+        );
+    }
+}
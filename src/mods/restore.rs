@@ -0,0 +1,303 @@
+use super::{
+    base_bk_option::{self, context},
+    bk_config::{BackupConfig, BackupMode, Compression},
+    file_metadata::{self, Manifest, MANIFEST_FILE_NAME},
+    remote::{self, SpoolFile},
+    run_stats::RunStats,
+    storage::{self, StorageBackend, StorageKind},
+    version_index::VersionIndex,
+};
+use log::info;
+use std::fs::{self, read_dir, File};
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 将任务的备份还原到指定目录
+/// version 为版本ID(如 20240101T120000-1a2b3c4d)或迁移前的版本目录名, 不填写时还原最新版本, 增量备份模式忽略此参数
+/// 还原目录必须不存在或为空, 不会覆盖已有文件
+/// 备份时保留了元数据的, 还原后按清单重新应用
+/// 符号链接、硬链接、命名管道和设备文件按清单重建
+/// 备份目的地为远程地址时直接从远程主机读取
+/// 有多个备份目的地时从第一个存在该备份的目的地还原
+/// 有多个源目录时, 各源目录还原到还原目录中以其别名命名的文件夹内
+pub fn restore(
+    task_name: &str,
+    version: Option<&str>,
+    restore_path: &Path,
+) -> Result<RunStats, Error> {
+    let config = BackupConfig::create(&BackupConfig::get_hash_path(task_name))
+        .map_err(context("读取备份计划时发生错误"))?;
+    restore_from(&storage::local(), task_name, &config, version, restore_path)
+}
+
+/// 同 restore, 本地的备份目的地通过 local 读取
+fn restore_from(
+    local: &Arc<dyn StorageBackend>,
+    task_name: &str,
+    config: &BackupConfig,
+    version: Option<&str>,
+    restore_path: &Path,
+) -> Result<RunStats, Error> {
+    let (storage, backup_path) = find_destination(local, task_name, config, version)?;
+    let storage = storage.as_ref();
+    let manifest = Manifest::load(storage, &backup_path).map_err(context("读取清单时发生错误"))?;
+
+    if restore_path.exists() && read_dir(restore_path)?.next().is_some() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("还原目录 {} 不为空", restore_path.display()),
+        ));
+    }
+    info!(
+        "{}:开始从 {} 还原到 {}",
+        task_name,
+        backup_path.display(),
+        restore_path.display()
+    );
+
+    let mut stats = RunStats::start(task_name);
+    fs::create_dir_all(restore_path)?;
+    let mut directories = vec![backup_path.clone()];
+    while let Some(path) = directories.pop() {
+        for entry in storage.list(&path)? {
+            if path == backup_path && entry.name == MANIFEST_FILE_NAME {
+                continue;
+            }
+            let entry_path = path.join(&entry.name);
+            let relative = entry_path.strip_prefix(&backup_path).unwrap().to_path_buf();
+            let target = restore_path.join(&relative);
+            match entry.meta.kind {
+                StorageKind::Dir => {
+                    fs::create_dir_all(&target)?;
+                    stats.dirs_created += 1;
+                    directories.push(entry_path);
+                }
+                StorageKind::File => {
+                    stats.files_scanned += 1;
+                    match restore_file(storage, &entry_path, &target, manifest.compression) {
+                        Ok((read, written)) => {
+                            stats.files_copied += 1;
+                            stats.bytes_read += read;
+                            stats.bytes_written += written;
+                        }
+                        Err(e) => stats.record_failure(&entry_path.to_string_lossy(), &e),
+                    }
+                }
+                StorageKind::Symlink => {
+                    let link_to = match storage.read_link(&entry_path) {
+                        Ok(link_to) => link_to,
+                        // 存储不支持读取符号链接时由清单重建
+                        Err(e) if e.kind() == ErrorKind::Unsupported => continue,
+                        Err(e) => return Err(e),
+                    };
+                    stats.files_scanned += 1;
+                    match file_metadata::create_symlink(&link_to, &target) {
+                        Ok(()) => stats.files_copied += 1,
+                        Err(e) => stats.record_failure(&entry_path.to_string_lossy(), &e),
+                    }
+                }
+            }
+        }
+    }
+
+    if !manifest.files.is_empty() {
+        stats.files_failed += manifest.restore_entries(task_name, restore_path) as u64;
+        let failed = manifest.apply_to(task_name, restore_path);
+        info!(
+            "{}:已按清单还原[{}]个路径的元数据, 失败[{}]个",
+            task_name,
+            manifest.files.len() - failed,
+            failed
+        );
+    }
+    stats.finish();
+    Ok(stats)
+}
+
+/// 复制一个备份文件到还原目录, 备份时压缩的文件在此解压
+/// 压缩的文件先复制到临时文件再解压
+/// 返回读取和写入的字节数
+fn restore_file(
+    storage: &dyn StorageBackend,
+    source: &Path,
+    target: &Path,
+    compression: Compression,
+) -> Result<(u64, u64), Error> {
+    if compression == Compression::None {
+        let bytes = storage.copy_to(source, target)?;
+        return Ok((bytes, bytes));
+    }
+    let spool = SpoolFile::create()?;
+    storage.copy_to(source, spool.path())?;
+    let file = File::open(spool.path())?;
+    let read = file.metadata()?.len();
+    let mut writer = File::create(target)?;
+    let written = io::copy(&mut compression.decoder(file), &mut writer)?;
+    Ok((read, written))
+}
+
+/// 在任务的备份目的地中按顺序查找第一个存在要还原的备份的目的地
+/// 返回该目的地的存储及备份目录, 都不存在时返回第一个目的地的错误
+fn find_destination(
+    local: &Arc<dyn StorageBackend>,
+    task_name: &str,
+    config: &BackupConfig,
+    version: Option<&str>,
+) -> Result<(Arc<dyn StorageBackend>, PathBuf), Error> {
+    let mut first_error = None;
+    for config in config.destination_configs()? {
+        let found = remote::destination_storage(local, &config)
+            .map_err(context("打开备份目的地时发生错误"))
+            .and_then(|storage| {
+                let backup_path = find_backup_path(storage.as_ref(), task_name, &config, version)?;
+                Ok((storage, backup_path))
+            });
+        match found {
+            Ok(found) => return Ok(found),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    Err(first_error.unwrap_or_else(|| Error::new(ErrorKind::NotFound, "未填写备份目的地")))
+}
+
+/// 查找要还原的备份目录
+fn find_backup_path(
+    storage: &dyn StorageBackend,
+    task_name: &str,
+    config: &BackupConfig,
+    version: Option<&str>,
+) -> Result<PathBuf, Error> {
+    let backup_title = config.backup_title(task_name);
+    let mut backup_path = base_bk_option::get_backup_base_path(&config.backup_destination_path);
+    backup_path.push(&backup_title);
+
+    let legacy_hashs = match &config.options {
+        BackupMode::VersionMode { backup_hashs, .. } => Some(backup_hashs.as_slice()),
+        BackupMode::ReplicateMode { .. } => Some(&[][..]),
+        BackupMode::IncrementalMode { .. } => None,
+    };
+    if let Some(legacy_hashs) = legacy_hashs {
+        let index = VersionIndex::load(storage, &backup_path, legacy_hashs)
+            .map_err(context("读取版本索引时发生错误"))?;
+        let entry = match version {
+            Some(version) => index.find(version),
+            None => index.versions.last(),
+        }
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("找不到要还原的版本: {}", version.unwrap_or("最新版本")),
+            )
+        })?;
+        backup_path = VersionIndex::version_path(&backup_path, entry);
+    }
+
+    if !storage
+        .stat(&backup_path)?
+        .is_some_and(|meta| meta.is_dir())
+    {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("备份目录 {} 不存在", backup_path.display()),
+        ));
+    }
+    Ok(backup_path)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::mods::run_stats::RunStatus;
+    use crate::mods::storage::MemoryStorage;
+    use crate::mods::version_mode::VersionMode;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn mtime(path: &Path) -> SystemTime {
+        fs::metadata(path).unwrap().modified().unwrap()
+    }
+
+    /// 经 MemoryStorage 备份后还原, 内容、权限及修改时间与源文件一致
+    #[test]
+    fn restores_versions_from_storage() {
+        let task_name = "restore_round_trip_test";
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a"), b"one").unwrap();
+        fs::write(source.join("sub/b"), b"two").unwrap();
+        fs::set_permissions(source.join("a"), fs::Permissions::from_mode(0o640)).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        File::options()
+            .write(true)
+            .open(source.join("a"))
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let yaml = format!(
+            "backup_source_path: {}\nbackup_destination_path: /memory\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\npreserve_metadata: true\noptions:\n  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 3\n",
+            source.display()
+        );
+        let config_path = BackupConfig::get_hash_path(task_name);
+        fs::write(&config_path, &yaml).unwrap();
+        let storage = Arc::new(MemoryStorage::new());
+        let mut mode = VersionMode {
+            task_config: serde_yaml::from_str(&yaml).unwrap(),
+            storage: storage.clone(),
+        };
+        let first = mode.backup(task_name);
+        fs::write(source.join("c"), b"three").unwrap();
+        let second = mode.backup(task_name);
+        let config = BackupConfig::create(&config_path).unwrap();
+        fs::remove_file(&config_path).unwrap();
+        assert_eq!(first.status, RunStatus::Success);
+        assert_eq!(second.status, RunStatus::Success);
+
+        let local: Arc<dyn StorageBackend> = storage.clone();
+        let latest = dir.path().join("latest");
+        let stats = restore_from(&local, task_name, &config, None, &latest).unwrap();
+        assert_eq!(stats.status, RunStatus::Success);
+        assert_eq!(stats.files_copied, 3);
+        assert_eq!(fs::read(latest.join("a")).unwrap(), b"one");
+        assert_eq!(fs::read(latest.join("sub/b")).unwrap(), b"two");
+        assert_eq!(fs::read(latest.join("c")).unwrap(), b"three");
+        assert_eq!(
+            fs::metadata(latest.join("a")).unwrap().permissions().mode() & 0o7777,
+            0o640
+        );
+        assert_eq!(mtime(&latest.join("a")), modified);
+        assert_eq!(mtime(&latest.join("c")), mtime(&source.join("c")));
+        assert!(!latest.join(MANIFEST_FILE_NAME).exists());
+
+        let index = VersionIndex::read(storage.as_ref(), Path::new("/memory/data")).unwrap();
+        let older = dir.path().join("older");
+        let stats = restore_from(
+            &local,
+            task_name,
+            &config,
+            Some(&index.versions[0].id),
+            &older,
+        )
+        .unwrap();
+        assert_eq!(stats.files_copied, 2);
+        assert_eq!(fs::read(older.join("a")).unwrap(), b"one");
+        assert!(!older.join("c").exists());
+        assert_eq!(mtime(&older.join("a")), modified);
+
+        // 还原目录不为空时不覆盖
+        let e = restore_from(&local, task_name, &config, None, &older).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+        let e = restore_from(
+            &local,
+            task_name,
+            &config,
+            Some("missing"),
+            &dir.path().join("none"),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+}
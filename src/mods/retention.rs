@@ -0,0 +1,248 @@
+use chrono::{DateTime, Datelike, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+
+/// 版本控制模式的祖父-父-子(GFS)保留策略
+/// 保留最近 keep_last 个版本, 另外按日、周、月、年各保留若干个时间段内最新的一个版本
+/// 各项均未填写时为 0, 即不按该时间段保留
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RetentionPolicy {
+    /// 保留最近几个版本, 不填写时使用 preserve_version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    /// 保留最近几天中每天最新的版本
+    #[serde(default)]
+    pub daily: usize,
+    /// 保留最近几周(ISO 周)中每周最新的版本
+    #[serde(default)]
+    pub weekly: usize,
+    /// 保留最近几个月中每月最新的版本
+    #[serde(default)]
+    pub monthly: usize,
+    /// 保留最近几年中每年最新的版本
+    #[serde(default)]
+    pub yearly: usize,
+}
+
+/// 取版本时间所在的时间段
+type BucketOf = fn(&DateTime<Local>) -> (i32, u32);
+
+/// 版本被保留的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum KeepReason {
+    Last,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl RetentionPolicy {
+    /// 只保留最近 preserve_version 个版本, 等同于未配置保留策略时的行为
+    pub fn keep_last(preserve_version: usize) -> Self {
+        RetentionPolicy {
+            keep_last: Some(preserve_version),
+            ..RetentionPolicy::default()
+        }
+    }
+
+    /// 按版本时间计算每个版本的保留原因, 返回的列表与 times 一一对应
+    /// 原因为空的版本应当删除; 最新的版本总是保留
+    pub fn evaluate(
+        &self,
+        times: &[DateTime<Local>],
+        preserve_version: usize,
+    ) -> Vec<BTreeSet<KeepReason>> {
+        let mut reasons = vec![BTreeSet::new(); times.len()];
+        // 从新到旧排列的下标
+        let mut order: Vec<usize> = (0..times.len()).collect();
+        order.sort_by(|a, b| times[*b].cmp(&times[*a]));
+
+        let keep_last = self.keep_last.unwrap_or(preserve_version).max(1);
+        for index in order.iter().take(keep_last) {
+            reasons[*index].insert(KeepReason::Last);
+        }
+
+        let buckets: [(usize, KeepReason, BucketOf); 4] = [
+            (self.daily, KeepReason::Daily, |t| (t.year(), t.ordinal())),
+            (self.weekly, KeepReason::Weekly, |t| {
+                (t.iso_week().year(), t.iso_week().week())
+            }),
+            (self.monthly, KeepReason::Monthly, |t| (t.year(), t.month())),
+            (self.yearly, KeepReason::Yearly, |t| (t.year(), 0)),
+        ];
+        for (count, reason, bucket_of) in buckets {
+            let mut seen = HashSet::new();
+            for index in order.iter() {
+                if seen.len() >= count {
+                    break;
+                }
+                // 每个时间段只保留其中最新的版本
+                if seen.insert(bucket_of(&times[*index])) {
+                    reasons[*index].insert(reason);
+                }
+            }
+        }
+        reasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDateTime, TimeZone};
+
+    fn at(time: &str) -> DateTime<Local> {
+        let naive = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&naive).unwrap()
+    }
+
+    /// 按策略保留的版本时间, 与输入顺序一致
+    fn kept(policy: &RetentionPolicy, preserve_version: usize, times: &[&str]) -> Vec<String> {
+        let parsed: Vec<DateTime<Local>> = times.iter().map(|t| at(t)).collect();
+        policy
+            .evaluate(&parsed, preserve_version)
+            .iter()
+            .zip(times)
+            .filter(|(reasons, _)| !reasons.is_empty())
+            .map(|(_, time)| time.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_versions_per_rule() {
+        let policy = |keep_last, daily, weekly, monthly, yearly| RetentionPolicy {
+            keep_last,
+            daily,
+            weekly,
+            monthly,
+            yearly,
+        };
+        // (说明, 策略, preserve_version, 版本时间, 保留的版本)
+        type Case = (
+            &'static str,
+            RetentionPolicy,
+            usize,
+            Vec<&'static str>,
+            Vec<&'static str>,
+        );
+        let cases: Vec<Case> = vec![
+            (
+                "keep_last 保留最近的版本, 与输入顺序无关",
+                policy(Some(2), 0, 0, 0, 0),
+                5,
+                vec![
+                    "2024-01-03 10:00",
+                    "2024-01-01 10:00",
+                    "2024-01-04 10:00",
+                    "2024-01-02 10:00",
+                ],
+                vec!["2024-01-03 10:00", "2024-01-04 10:00"],
+            ),
+            (
+                "未填写 keep_last 时使用 preserve_version",
+                policy(None, 0, 0, 0, 0),
+                3,
+                vec![
+                    "2024-01-01 10:00",
+                    "2024-01-02 10:00",
+                    "2024-01-03 10:00",
+                    "2024-01-04 10:00",
+                ],
+                vec!["2024-01-02 10:00", "2024-01-03 10:00", "2024-01-04 10:00"],
+            ),
+            (
+                "keep_last 为 0 时仍保留最新的版本",
+                policy(Some(0), 0, 0, 0, 0),
+                5,
+                vec!["2024-01-01 10:00", "2024-01-02 10:00"],
+                vec!["2024-01-02 10:00"],
+            ),
+            (
+                "每天保留当天最新的版本",
+                policy(Some(1), 3, 0, 0, 0),
+                1,
+                vec![
+                    "2024-01-01 23:00",
+                    "2024-01-02 08:00",
+                    "2024-01-02 20:00",
+                    "2024-01-03 08:00",
+                    "2024-01-03 09:00",
+                    "2024-01-04 07:00",
+                ],
+                vec!["2024-01-02 20:00", "2024-01-03 09:00", "2024-01-04 07:00"],
+            ),
+            (
+                // 2020-12-30 与 2021-01-01 同属 ISO 2020 年第 53 周, 2021-01-04 为 2021 年第 1 周
+                "每周按 ISO 周跨年计算",
+                policy(Some(1), 0, 3, 0, 0),
+                1,
+                vec!["2020-12-30 10:00", "2021-01-01 10:00", "2021-01-04 10:00"],
+                vec!["2021-01-01 10:00", "2021-01-04 10:00"],
+            ),
+            (
+                "每月保留当月最新的版本",
+                policy(Some(1), 0, 0, 2, 0),
+                1,
+                vec![
+                    "2024-01-05 10:00",
+                    "2024-01-31 10:00",
+                    "2024-02-10 10:00",
+                    "2024-02-29 23:00",
+                    "2024-03-01 00:30",
+                ],
+                vec!["2024-02-29 23:00", "2024-03-01 00:30"],
+            ),
+            (
+                "每年保留当年最新的版本",
+                policy(Some(1), 0, 0, 0, 2),
+                1,
+                vec![
+                    "2022-06-01 10:00",
+                    "2023-01-01 10:00",
+                    "2023-12-31 23:00",
+                    "2024-03-01 10:00",
+                ],
+                vec!["2023-12-31 23:00", "2024-03-01 10:00"],
+            ),
+        ];
+        for (name, policy, preserve_version, times, expected) in cases {
+            assert_eq!(
+                kept(&policy, preserve_version, &times),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    /// 多条规则保留同一个版本时记录所有原因, 任一规则保留的版本都不删除
+    #[test]
+    fn overlapping_rules_record_every_reason() {
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            daily: 2,
+            weekly: 0,
+            monthly: 2,
+            yearly: 1,
+        };
+        let times: Vec<DateTime<Local>> = [
+            "2023-12-20 10:00",
+            "2024-01-10 10:00",
+            "2024-01-11 08:00",
+            "2024-01-11 20:00",
+        ]
+        .iter()
+        .map(|t| at(t))
+        .collect();
+        let reasons = policy.evaluate(&times, 1);
+        use KeepReason::*;
+        let expected: Vec<BTreeSet<KeepReason>> = vec![
+            [Monthly].into(),
+            [Daily].into(),
+            [Last].into(),
+            [Last, Daily, Monthly, Yearly].into(),
+        ];
+        assert_eq!(reasons, expected);
+    }
+}
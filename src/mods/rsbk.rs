@@ -1,6 +1,8 @@
 use super::bk_config::{BackupConfig, BackupMode};
-// use super::network_interface_operate::{shutdown_all_interfaces, startup_all_interfaces};
-use super::{incremental_mode::IncrementalMode, version_mode::VersionMode};
+use super::{
+    hooks, incremental_mode::IncrementalMode, network_interface_operate, notify,
+    replicate_mode::ReplicateMode, version_mode::VersionMode, watch_mode,
+};
 use chrono::{DateTime, Duration, Local, Timelike};
 use chrono_tz::Asia::Shanghai;
 use chrono_tz::Tz;
@@ -9,15 +11,20 @@ use std::collections::HashMap;
 use std::fs::read_dir;
 use std::io::Error;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 
 lazy_static::lazy_static! {
     static ref NEXT_BACKUP_TIMES: Mutex<HashMap<String, DateTime<Tz>>> = Mutex::new(HashMap::new());
+    /// 定时备份及文件监听模式的备份依次进行, 同一时间只有一个任务在备份
+    /// 只在备份期间持有, 发送通知及读写下次备份时间时不持有, 通知渠道的超时不会阻塞其他任务
+    static ref BACKUP_LOCK: Mutex<()> = Mutex::new(());
 }
 
 pub struct RSBK {
     tasks: Vec<Arc<BackupModeWrapper>>,
+    /// 所有生效的任务, 包括由监听线程负责的任务, 用于检查是否长时间没有成功的备份
+    task_names: Vec<String>,
 }
 
 pub enum BackupModeWrapper {
@@ -29,6 +36,10 @@ pub enum BackupModeWrapper {
         task: Arc<Mutex<VersionMode>>,
         name: String,
     },
+    ReplicateMode {
+        task: Arc<Mutex<ReplicateMode>>,
+        name: String,
+    },
 }
 
 impl RSBK {
@@ -36,18 +47,34 @@ impl RSBK {
         let config_path = PathBuf::from("BackupConfig");
 
         let mut tasks: Vec<Arc<BackupModeWrapper>> = Vec::new();
+        let mut task_names = Vec::new();
+        let mut watched_count = 0;
         let mut next_backup_times = NEXT_BACKUP_TIMES.lock().unwrap();
 
         match Self::read_backup_configs(&config_path) {
             Ok(confs) => {
                 for (config, file_name) in confs.iter() {
                     if config.is_effect {
+                        task_names.push(file_name.clone());
+                        // 文件监听模式的任务由监听线程负责备份
+                        if config.watch.is_some() {
+                            if let Some(reason) = config.watch_unsupported() {
+                                warn!("{}:{}, 使用轮询模式备份", file_name, reason);
+                            } else if watch_mode::ensure_watcher(file_name) {
+                                watched_count += 1;
+                                continue;
+                            }
+                        }
+
                         let initial_time = parse_initial_backup_time(&config.initial_backup_time);
 
-                        //更新下一次备份时间表
-                        if !next_backup_times.contains_key(file_name) {
-                            next_backup_times.insert(file_name.clone(), initial_time);
-                        }
+                        //更新下一次备份时间表, 每个备份目的地按其推迟的分钟数单独记录
+                        schedule_destinations(
+                            &mut next_backup_times,
+                            file_name,
+                            config,
+                            initial_time,
+                        );
 
                         match &config.options {
                             BackupMode::IncrementalMode { .. } => {
@@ -64,13 +91,24 @@ impl RSBK {
                                     name: file_name.clone(),
                                 }));
                             }
+                            BackupMode::ReplicateMode { .. } => {
+                                tasks.push(Arc::new(BackupModeWrapper::ReplicateMode {
+                                    task: Arc::new(Mutex::new(ReplicateMode::create(
+                                        config.clone(),
+                                    ))),
+                                    name: file_name.clone(),
+                                }));
+                            }
                         }
                     }
                 }
-                next_backup_times.retain(|k, _| confs.iter().any(|(_, name)| name == k));
+                next_backup_times.retain(|k, _| {
+                    let task_name = k.split_once('@').map_or(k.as_str(), |(name, _)| name);
+                    confs.iter().any(|(_, name)| name == task_name)
+                });
 
-                if !tasks.is_empty() {
-                    RSBK { tasks }
+                if !tasks.is_empty() || watched_count > 0 {
+                    RSBK { tasks, task_names }
                 } else {
                     error!("所有备份计划模式错误或无效, 无法完成初始化");
                     panic!();
@@ -110,62 +148,62 @@ impl RSBK {
 
             let handle = thread::spawn(move || {
                 let task = task_clone.as_ref();
-                let name = match &*task {
+                let name = match task {
                     BackupModeWrapper::IncrementalMode { name, .. } => name,
                     BackupModeWrapper::VersionMode { name, .. } => name,
+                    BackupModeWrapper::ReplicateMode { name, .. } => name,
                 };
 
                 log::info!("检查任务的备份时间: {}", name);
 
-                let next_backup_times_lock = NEXT_BACKUP_TIMES.lock().unwrap();
-                if let Some(next_backup_time) = next_backup_times_lock.get(name) {
-                    log::info!("当前时间: {:?}. 下次备份时间: {:?}", &now, next_backup_time);
-                    if &now >= next_backup_time {
-                        log::info!("开始任务备份: {}", name);
-
-                        // // 尝试开启所有网卡，如果失败则停止当前线程执行
-                        // if let Err(err) = startup_all_interfaces() {
-                        //     log::error!("Failed to start up interfaces: {}", err);
-                        //     return; // 立即停止当前线程执行
-                        // }
-
-                        match &*task {
-                            BackupModeWrapper::IncrementalMode { task, name } => {
-                                let task_lock = task.lock().unwrap();
-                                task_lock.backup(name);
-                            }
-                            BackupModeWrapper::VersionMode { task, name } => {
-                                let mut task_lock = task.lock().unwrap();
-                                task_lock.backup(name);
-                            }
-                        }
-
-                        // // 在备份完成后关闭所有网卡
-                        // if let Err(err) = shutdown_all_interfaces() {
-                        //     log::error!("Failed to shut down interfaces: {}", err);
-                        // }
-
-                        let backup_interval_minutes = match &*task {
-                            BackupModeWrapper::IncrementalMode { task, .. } => {
-                                let task_lock = task.lock().unwrap();
-                                task_lock.task_config.backup_interval_minutes
-                            }
-                            BackupModeWrapper::VersionMode { task, .. } => {
-                                let task_lock = task.lock().unwrap();
-                                task_lock.task_config.backup_interval_minutes
-                            }
-                        };
-                        let next_time = now + Duration::minutes(backup_interval_minutes as i64);
-                        drop(next_backup_times_lock); // 释放锁
-                        let mut next_backup_times_mut = NEXT_BACKUP_TIMES.lock().unwrap();
-                        next_backup_times_mut.insert(name.clone(), next_time);
-                        log::info!("任务备份完成: {}. 下次备份时间: {:?}", name, next_time);
-                    } else {
-                        log::info!("当前任务无需备份: {}.", name);
+                let config = match task {
+                    BackupModeWrapper::IncrementalMode { task, .. } => {
+                        task.lock().unwrap().task_config.clone()
                     }
-                } else {
-                    log::warn!("找不到任务的备份时间: {}", name);
+                    BackupModeWrapper::VersionMode { task, .. } => {
+                        task.lock().unwrap().task_config.clone()
+                    }
+                    BackupModeWrapper::ReplicateMode { task, .. } => {
+                        task.lock().unwrap().task_config.clone()
+                    }
+                };
+                let due = due_destinations(&NEXT_BACKUP_TIMES.lock().unwrap(), name, &config, now);
+                if due.is_empty() {
+                    log::info!("当前任务无需备份: {}.", name);
+                    return;
                 }
+                log::info!("开始任务备份: {}", name);
+                notify::on_run_started(name);
+                let running = backup_lock();
+
+                // 网络隔离模式下网卡在备份前开启, 备份及钩子命令结束后关闭
+                let stats = network_interface_operate::run_with_air_gap(name, &config, || {
+                    hooks::run_with_hooks(name, &config, || match task {
+                        BackupModeWrapper::IncrementalMode { task, name } => {
+                            let task_lock = task.lock().unwrap();
+                            task_lock.backup_to(name, &due)
+                        }
+                        BackupModeWrapper::VersionMode { task, name } => {
+                            let mut task_lock = task.lock().unwrap();
+                            task_lock.backup_to(name, &due)
+                        }
+                        BackupModeWrapper::ReplicateMode { task, name } => {
+                            let task_lock = task.lock().unwrap();
+                            task_lock.backup_to(name, &due)
+                        }
+                    })
+                });
+                drop(running);
+                stats.record();
+
+                let next_time = now + Duration::minutes(config.backup_interval_minutes as i64);
+                reschedule(
+                    &mut NEXT_BACKUP_TIMES.lock().unwrap(),
+                    name,
+                    &due,
+                    next_time,
+                );
+                log::info!("任务备份完成: {}. 下次备份时间: {:?}", name, next_time);
             });
             handles.push(handle);
         }
@@ -175,8 +213,71 @@ impl RSBK {
                 error!("备份任务执行时发生错误: {:?}", e);
             }
         }
+        notify::check_stale(&self.task_names);
+    }
+}
+/// 获取备份锁, 在备份(包括钩子命令及网络隔离)期间持有
+/// 持有锁的线程 panic 后锁仍可使用
+pub fn backup_lock() -> MutexGuard<'static, ()> {
+    BACKUP_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// 记录任务各备份目的地的首次备份时间, 为 initial_time 加上目的地推迟的分钟数
+/// 已有记录的目的地保持原来的时间
+fn schedule_destinations(
+    times: &mut HashMap<String, DateTime<Tz>>,
+    task_name: &str,
+    config: &BackupConfig,
+    initial_time: DateTime<Tz>,
+) {
+    for (index, destination) in config.destinations().iter().enumerate() {
+        times
+            .entry(BackupConfig::destination_key(task_name, index))
+            .or_insert(
+                initial_time + Duration::minutes(destination.schedule_offset_minutes as i64),
+            );
+    }
+}
+
+/// 到达备份时间的目的地序号
+fn due_destinations(
+    times: &HashMap<String, DateTime<Tz>>,
+    task_name: &str,
+    config: &BackupConfig,
+    now: DateTime<Tz>,
+) -> Vec<usize> {
+    let mut due = Vec::new();
+    for index in 0..config.destinations().len() {
+        let key = BackupConfig::destination_key(task_name, index);
+        if let Some(next_backup_time) = times.get(&key) {
+            log::info!(
+                "当前时间: {:?}. {} 下次备份时间: {:?}",
+                &now,
+                key,
+                next_backup_time
+            );
+            if &now >= next_backup_time {
+                due.push(index);
+            }
+        } else {
+            log::warn!("找不到任务的备份时间: {}", key);
+        }
     }
+    due
 }
+
+/// 本次备份的目的地的下次备份时间, 其他目的地保持不变
+fn reschedule(
+    times: &mut HashMap<String, DateTime<Tz>>,
+    task_name: &str,
+    due: &[usize],
+    next_time: DateTime<Tz>,
+) {
+    for index in due {
+        times.insert(BackupConfig::destination_key(task_name, *index), next_time);
+    }
+}
+
 fn parse_initial_backup_time(time_str: &str) -> DateTime<Tz> {
     let now = Local::now().with_timezone(&Shanghai);
     // 解析时间字符串 "mm:ss" 并返回对应的 DateTime<Local>
@@ -185,3 +286,43 @@ fn parse_initial_backup_time(time_str: &str) -> DateTime<Tz> {
     let minutes: u32 = parts[1].parse().unwrap();
     now.with_hour(hours).unwrap().with_minute(minutes).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// 各目的地按推迟的分钟数单独到期, 备份后只更新已备份目的地的时间
+    #[test]
+    fn destination_offsets_are_scheduled_independently() {
+        let config: BackupConfig = serde_yaml::from_str(
+            "backup_source_path: /data\nbackup_destination_path: /backup/main\ndestinations:\n  - path: /backup/offsite\n    schedule_offset_minutes: 30\nbackup_interval_minutes: 60\ninitial_backup_time: \"2:00\"\nis_effect: true\noptions:\n  mode: IncrementalMode\n  save_days: 3\n",
+        )
+        .unwrap();
+        let initial = Shanghai.with_ymd_and_hms(2026, 1, 1, 2, 0, 0).unwrap();
+        let mut times = HashMap::new();
+        schedule_destinations(&mut times, "task", &config, initial);
+        assert_eq!(times["task"], initial);
+        assert_eq!(times["task@1"], initial + Duration::minutes(30));
+
+        assert!(
+            due_destinations(&times, "task", &config, initial - Duration::minutes(1)).is_empty()
+        );
+        let now = initial + Duration::minutes(10);
+        assert_eq!(due_destinations(&times, "task", &config, now), vec![0]);
+        reschedule(&mut times, "task", &[0], now + Duration::minutes(60));
+        assert_eq!(times["task@1"], initial + Duration::minutes(30));
+
+        let now = initial + Duration::minutes(30);
+        assert_eq!(due_destinations(&times, "task", &config, now), vec![1]);
+        reschedule(&mut times, "task", &[1], now + Duration::minutes(60));
+        let now = initial + Duration::minutes(70);
+        assert_eq!(due_destinations(&times, "task", &config, now), vec![0]);
+        let now = initial + Duration::minutes(90);
+        assert_eq!(due_destinations(&times, "task", &config, now), vec![0, 1]);
+
+        // 重新读取配置时已有的时间保持不变
+        schedule_destinations(&mut times, "task", &config, now);
+        assert_eq!(times["task"], initial + Duration::minutes(70));
+    }
+}
@@ -0,0 +1,342 @@
+use super::notify;
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{Error, Write};
+use std::path::PathBuf;
+
+/// 单次备份运行的结果
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    /// 所有文件均备份成功
+    Success,
+    /// 备份完成, 但有部分文件失败
+    Partial,
+    /// 备份中途出错
+    Failed,
+    /// 未检查到更新, 未进行备份
+    NoChange,
+}
+
+/// 单个备份目的地在一次运行中的结果
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DestinationStats {
+    pub path: String,
+    pub status: RunStatus,
+    pub files_copied: u64,
+    pub files_failed: u64,
+    pub bytes_written: u64,
+    pub bytes_freed: u64,
+    pub errors: Vec<String>,
+}
+
+/// 单次备份运行的统计信息
+/// 每次备份都会返回, 记录到日志和运行历史中, 并提供给通知使用
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RunStats {
+    pub task_name: String,
+    pub started_at: DateTime<Local>,
+    pub status: RunStatus,
+    /// 扫描的文件数
+    pub files_scanned: u64,
+    /// 复制的文件数
+    pub files_copied: u64,
+    /// 无需复制而跳过的文件数
+    pub files_skipped: u64,
+    /// 复制失败的文件数
+    pub files_failed: u64,
+    /// 从源目录读取的字节数
+    pub bytes_read: u64,
+    /// 写入备份目录的字节数
+    pub bytes_written: u64,
+    /// 新建的目录数
+    pub dirs_created: u64,
+    /// 按保留策略删除的文件数
+    pub files_deleted: u64,
+    /// 按保留策略或空间限额删除旧备份释放的字节数
+    #[serde(default)]
+    pub bytes_freed: u64,
+    /// 耗时(秒)
+    pub duration_secs: f64,
+    /// 本次运行的错误信息
+    pub errors: Vec<String>,
+    /// 各备份目的地的结果, 任务的结果由各目的地的结果汇总得到
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub destinations: Vec<DestinationStats>,
+}
+
+impl RunStats {
+    /// 在备份开始时创建
+    pub fn start(task_name: &str) -> Self {
+        RunStats {
+            task_name: task_name.to_string(),
+            started_at: Local::now(),
+            status: RunStatus::Success,
+            files_scanned: 0,
+            files_copied: 0,
+            files_skipped: 0,
+            files_failed: 0,
+            bytes_read: 0,
+            bytes_written: 0,
+            dirs_created: 0,
+            files_deleted: 0,
+            bytes_freed: 0,
+            duration_secs: 0.0,
+            errors: Vec::new(),
+            destinations: Vec::new(),
+        }
+    }
+
+    /// 记录单个文件的失败, 不中断本次备份
+    pub fn record_failure(&mut self, path: &str, e: &Error) {
+        warn!("{}:备份文件 {} 时发生错误:{}", self.task_name, path, e);
+        self.files_failed += 1;
+        self.errors.push(format!("{}: {}", path, e));
+    }
+
+    /// 记录导致本次备份中止的错误
+    pub fn fail(&mut self, e: &Error) {
+        self.status = RunStatus::Failed;
+        self.errors.push(e.to_string());
+    }
+
+    /// 汇总一个备份目的地的统计信息
+    /// destination 为该目的地单独记录的统计, 其状态按 finish 的规则确定
+    pub fn merge_destination(&mut self, path: &str, mut destination: RunStats) {
+        destination.finish();
+        self.files_copied += destination.files_copied;
+        self.files_failed += destination.files_failed;
+        self.bytes_written += destination.bytes_written;
+        self.dirs_created += destination.dirs_created;
+        self.files_deleted += destination.files_deleted;
+        self.bytes_freed += destination.bytes_freed;
+        self.errors.extend(
+            destination
+                .errors
+                .iter()
+                .map(|e| format!("{}: {}", path, e)),
+        );
+        self.destinations.push(DestinationStats {
+            path: path.to_string(),
+            status: destination.status,
+            files_copied: destination.files_copied,
+            files_failed: destination.files_failed,
+            bytes_written: destination.bytes_written,
+            bytes_freed: destination.bytes_freed,
+            errors: destination.errors,
+        });
+    }
+
+    /// 在备份结束时调用, 计算耗时并确定最终状态
+    /// 有多个目的地时, 全部失败为 Failed, 部分失败为 Partial, 全部无更新为 NoChange
+    pub fn finish(&mut self) {
+        self.duration_secs =
+            (Local::now() - self.started_at).num_milliseconds().max(0) as f64 / 1000.0;
+        if self.status == RunStatus::Success && !self.destinations.is_empty() {
+            let count = |status| {
+                self.destinations
+                    .iter()
+                    .filter(|d| d.status == status)
+                    .count()
+            };
+            if count(RunStatus::Failed) == self.destinations.len() {
+                self.status = RunStatus::Failed;
+            } else if count(RunStatus::Failed) > 0 || count(RunStatus::Partial) > 0 {
+                self.status = RunStatus::Partial;
+            } else if count(RunStatus::NoChange) == self.destinations.len() {
+                self.status = RunStatus::NoChange;
+            }
+        }
+        if self.status == RunStatus::Success && self.files_failed > 0 {
+            self.status = RunStatus::Partial;
+        }
+    }
+
+    /// 运行历史存放地址
+    /// 取 BackupConfig/history/{task_name}.yaml
+    pub fn get_history_path(task_name: &str) -> PathBuf {
+        let mut history_path = PathBuf::from("BackupConfig");
+        history_path.push("history");
+        history_path.push(task_name.to_owned() + ".yaml");
+        history_path
+    }
+
+    /// 以 YAML 文档的形式追加到运行历史中
+    pub fn append_history(&self) -> Result<(), Error> {
+        let history_path = RunStats::get_history_path(&self.task_name);
+        if let Some(parent) = history_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let yaml_str = serde_yaml::to_string(self)
+            .map_err(|e| Error::other(format!("Failed to serialize RunStats to YAML: {:?}", e)))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&history_path)?;
+        file.write_all(("---\n".to_string() + &yaml_str).as_bytes())
+    }
+
+    /// 写入日志, 保存到运行历史并发送通知
+    pub fn record(&self) {
+        match self.status {
+            RunStatus::Success | RunStatus::NoChange => info!("{}", self),
+            RunStatus::Partial | RunStatus::Failed => error!("{}", self),
+        }
+        if let Err(e) = self.append_history() {
+            error!("{}:写入运行历史时发生错误:{}", self.task_name, e);
+        }
+        notify::on_run_finished(self);
+    }
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:运行结果[{:?}], 扫描文件[{}]个, 复制[{}]个, 跳过[{}]个, 失败[{}]个, 读取[{}]字节, 写入[{}]字节, 新建目录[{}]个, 删除过期文件[{}]个, 释放[{}]字节, 耗时[{:.3}]秒",
+            self.task_name,
+            self.status,
+            self.files_scanned,
+            self.files_copied,
+            self.files_skipped,
+            self.files_failed,
+            self.bytes_read,
+            self.bytes_written,
+            self.dirs_created,
+            self.files_deleted,
+            self.bytes_freed,
+            self.duration_secs
+        )?;
+        if self.destinations.len() > 1 {
+            for destination in &self.destinations {
+                write!(
+                    f,
+                    ", 目的地 {}[{:?}]写入[{}]字节",
+                    destination.path, destination.status, destination.bytes_written
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_is_derived_from_failures() {
+        let mut success = RunStats::start("task");
+        success.files_copied = 1;
+        success.finish();
+        assert_eq!(success.status, RunStatus::Success);
+        assert!(success.errors.is_empty());
+
+        let mut partial = RunStats::start("task");
+        partial.files_copied = 1;
+        partial.record_failure("/data/a", &Error::other("权限不足"));
+        partial.finish();
+        assert_eq!(partial.status, RunStatus::Partial);
+        assert_eq!(partial.files_failed, 1);
+        assert_eq!(partial.errors, vec!["/data/a: 权限不足"]);
+
+        // 中止的备份即使有文件失败也是 Failed
+        let mut failed = RunStats::start("task");
+        failed.record_failure("/data/a", &Error::other("权限不足"));
+        failed.fail(&Error::other("目的地不可用"));
+        failed.finish();
+        assert_eq!(failed.status, RunStatus::Failed);
+        assert_eq!(failed.errors.len(), 2);
+    }
+
+    /// 任务结果由各目的地的结果汇总: 全部失败为 Failed, 全部无更新为 NoChange
+    #[test]
+    fn destination_statuses_are_combined() {
+        let run = |statuses: &[RunStatus]| {
+            let mut stats = RunStats::start("task");
+            for (i, status) in statuses.iter().enumerate() {
+                let mut destination = RunStats::start("task");
+                match status {
+                    RunStatus::Failed => destination.fail(&Error::other("失败")),
+                    RunStatus::Partial => destination.record_failure("a", &Error::other("失败")),
+                    RunStatus::NoChange => destination.status = RunStatus::NoChange,
+                    RunStatus::Success => {}
+                }
+                stats.merge_destination(&format!("/backup{}", i), destination);
+            }
+            stats.finish();
+            stats.status
+        };
+        use RunStatus::*;
+        assert_eq!(run(&[Success, Success]), Success);
+        assert_eq!(run(&[Success, NoChange]), Success);
+        assert_eq!(run(&[NoChange, NoChange]), NoChange);
+        assert_eq!(run(&[Success, Partial]), Partial);
+        assert_eq!(run(&[NoChange, Failed]), Partial);
+        assert_eq!(run(&[Failed, Failed]), Failed);
+    }
+
+    #[test]
+    fn destination_stats_are_summed() {
+        let mut stats = RunStats::start("task");
+        stats.files_scanned = 5;
+        for (path, copied, written, deleted, freed) in [("/a", 2, 20, 1, 100), ("/b", 3, 30, 0, 50)]
+        {
+            let mut destination = RunStats::start("task");
+            destination.files_copied = copied;
+            destination.bytes_written = written;
+            destination.dirs_created = 1;
+            destination.files_deleted = deleted;
+            destination.bytes_freed = freed;
+            stats.merge_destination(path, destination);
+        }
+        stats.finish();
+
+        assert_eq!(stats.files_scanned, 5);
+        assert_eq!(
+            (
+                stats.files_copied,
+                stats.bytes_written,
+                stats.dirs_created,
+                stats.files_deleted,
+                stats.bytes_freed
+            ),
+            (5, 50, 2, 1, 150)
+        );
+        let paths: Vec<&str> = stats.destinations.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a", "/b"]);
+        assert_eq!(stats.destinations[1].bytes_written, 30);
+        assert_eq!(stats.destinations[1].bytes_freed, 50);
+    }
+
+    /// 一个目的地失败时其他目的地的统计保持不变, 任务结果为 Partial
+    #[test]
+    fn failed_destination_leaves_others_intact() {
+        let mut stats = RunStats::start("task");
+        let mut main = RunStats::start("task");
+        main.files_copied = 2;
+        main.bytes_written = 10;
+        let mut offsite = RunStats::start("task");
+        offsite.fail(&Error::other("连接失败"));
+        stats.merge_destination("/backup", main);
+        stats.merge_destination("sftp://host/backup", offsite);
+        stats.finish();
+
+        assert_eq!(stats.status, RunStatus::Partial);
+        assert_eq!(stats.destinations[0].status, RunStatus::Success);
+        assert_eq!(
+            (
+                stats.destinations[0].files_copied,
+                stats.destinations[0].bytes_written
+            ),
+            (2, 10)
+        );
+        assert!(stats.destinations[0].errors.is_empty());
+        assert_eq!(stats.destinations[1].status, RunStatus::Failed);
+        assert_eq!(stats.destinations[1].errors, vec!["连接失败"]);
+        assert_eq!(stats.errors, vec!["sftp://host/backup: 连接失败"]);
+        assert_eq!((stats.files_copied, stats.bytes_written), (2, 10));
+    }
+}
@@ -0,0 +1,720 @@
+use super::remote::{self, SpoolFile};
+use super::storage::{Link, StorageBackend, StorageEntry, StorageKind, StorageMeta};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use ssh2::{CheckResult, ErrorCode, FileStat, KnownHostFileKind, Session, Sftp};
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+/// 未填写端口时使用的端口
+const DEFAULT_PORT: u16 = 22;
+/// 上传时先写入的临时文件后缀, 完成后重命名
+const UPLOAD_SUFFIX: &str = ".rsbk_upload";
+/// 读写时的缓冲区大小
+const BUFFER_SIZE: usize = 64 * 1024;
+/// libssh2 的超时错误码
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+
+/// sftp:// 地址的连接配置
+/// 只支持密钥认证, 未填写私钥时使用 ssh-agent
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SftpConfig {
+    /// 私钥文件, 不填写时使用 ssh-agent 中的密钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    /// 私钥的密码
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    /// 校验主机密钥的 known_hosts 文件, 不填写时使用 ~/.ssh/known_hosts
+    /// 主机密钥未记录或不一致时拒绝连接
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub known_hosts: Option<String>,
+    /// 连接及读写的超时秒数
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// 连接失败或中断时重新连接的次数
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+    /// 每次重新连接前等待的秒数
+    #[serde(default = "default_retry_delay_seconds")]
+    pub retry_delay_seconds: u64,
+}
+
+fn default_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_retries() -> usize {
+    3
+}
+
+fn default_retry_delay_seconds() -> u64 {
+    5
+}
+
+impl Default for SftpConfig {
+    fn default() -> Self {
+        SftpConfig {
+            private_key: None,
+            passphrase: None,
+            known_hosts: None,
+            timeout_seconds: default_timeout_seconds(),
+            retries: default_retries(),
+            retry_delay_seconds: default_retry_delay_seconds(),
+        }
+    }
+}
+
+/// 解析后的 sftp://user@host:port/path 地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpUrl {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    /// 远程主机上的绝对路径
+    pub path: String,
+}
+
+impl SftpUrl {
+    /// 未填写用户名时使用当前用户
+    pub fn parse(url: &str) -> Result<SftpUrl, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("无法识别的 sftp 地址: {}", url),
+            )
+        };
+        let rest = url.strip_prefix("sftp://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (user.to_string(), host_port),
+            None => (
+                std::env::var("USER").unwrap_or_else(|_| "root".to_string()),
+                authority,
+            ),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (host_port, DEFAULT_PORT),
+        };
+        if host.is_empty() || user.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SftpUrl {
+            user,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// 通过 SFTP 访问远程主机
+/// 连接在第一次使用时建立, 连接中断时按配置重新连接并重试当前操作
+/// 同一时间只有一个操作使用连接
+pub struct SftpStorage {
+    url: SftpUrl,
+    config: SftpConfig,
+    connection: Mutex<Option<(Session, Sftp)>>,
+}
+
+/// 不输出私钥的密码
+impl fmt::Debug for SftpStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpStorage")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SftpStorage {
+    pub fn create(url: SftpUrl, config: SftpConfig) -> Self {
+        SftpStorage {
+            url,
+            config,
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self) -> Result<(Session, Sftp), Error> {
+        let timeout = Duration::from_secs(self.config.timeout_seconds.max(1));
+        let mut last_error = Error::new(
+            ErrorKind::NotFound,
+            format!("无法解析主机地址 {}", self.url.host),
+        );
+        let mut tcp = None;
+        for addr in (self.url.host.as_str(), self.url.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let tcp = tcp.ok_or(last_error)?;
+
+        let mut session = Session::new().map_err(sftp_error)?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(timeout.as_millis() as u32);
+        session.handshake().map_err(sftp_error)?;
+        self.check_host_key(&session)?;
+
+        match &self.config.private_key {
+            Some(private_key) => session.userauth_pubkey_file(
+                &self.url.user,
+                None,
+                Path::new(private_key),
+                self.config.passphrase.as_deref(),
+            ),
+            None => session.userauth_agent(&self.url.user),
+        }
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("{}@{} 密钥认证失败:{}", self.url.user, self.url.host, e),
+            )
+        })?;
+        let sftp = session.sftp().map_err(sftp_error)?;
+        info!(
+            "已连接 sftp://{}@{}:{}",
+            self.url.user, self.url.host, self.url.port
+        );
+        Ok((session, sftp))
+    }
+
+    /// 按 known_hosts 校验主机密钥
+    fn check_host_key(&self, session: &Session) -> Result<(), Error> {
+        let known_hosts_path = match &self.config.known_hosts {
+            Some(path) => PathBuf::from(path),
+            None => PathBuf::from(std::env::var("HOME").unwrap_or_default())
+                .join(".ssh")
+                .join("known_hosts"),
+        };
+        let mut known_hosts = session.known_hosts().map_err(sftp_error)?;
+        known_hosts
+            .read_file(&known_hosts_path, KnownHostFileKind::OpenSSH)
+            .map_err(|e| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("读取 {} 时发生错误:{}", known_hosts_path.display(), e),
+                )
+            })?;
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| Error::other("无法获取主机密钥"))?;
+        match known_hosts.check_port(&self.url.host, self.url.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{} 的主机密钥与 {} 中的记录不一致, 拒绝连接",
+                    self.url.host,
+                    known_hosts_path.display()
+                ),
+            )),
+            CheckResult::NotFound | CheckResult::Failure => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "{} 的主机密钥未记录在 {} 中, 请先使用 ssh 连接一次确认主机密钥",
+                    self.url.host,
+                    known_hosts_path.display()
+                ),
+            )),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Option<(Session, Sftp)>>, Error> {
+        self.connection
+            .lock()
+            .map_err(|_| Error::other("sftp 连接的锁已失效"))
+    }
+
+    /// 执行一个操作, 连接失败或中断时重新连接并重试
+    fn call<T>(&self, mut operation: impl FnMut(&Sftp) -> Result<T, Error>) -> Result<T, Error> {
+        let mut connection = self.lock()?;
+        let mut attempt = 0;
+        loop {
+            let result = match connection.as_ref() {
+                Some((_, sftp)) => operation(sftp),
+                None => match self.connect() {
+                    Ok(connected) => {
+                        let result = operation(&connected.1);
+                        *connection = Some(connected);
+                        result
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Err(e) if is_retryable(&e) && attempt < self.config.retries => {
+                    attempt += 1;
+                    warn!(
+                        "sftp://{}@{}:{} 连接失败或中断:{}, {}秒后第[{}]次重试",
+                        self.url.user,
+                        self.url.host,
+                        self.url.port,
+                        e,
+                        self.config.retry_delay_seconds,
+                        attempt
+                    );
+                    *connection = None;
+                    thread::sleep(Duration::from_secs(self.config.retry_delay_seconds));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// 下载远程文件到本地, 返回下载的字节数
+    fn download(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        self.call(|sftp| {
+            let mut remote = sftp.open(path).map_err(sftp_error)?;
+            let mut file = File::create(local)?;
+            let mut buf = vec![0; BUFFER_SIZE];
+            let mut total = 0;
+            loop {
+                let read = remote.read(&mut buf).map_err(stream_error)?;
+                if read == 0 {
+                    break;
+                }
+                file.write_all(&buf[..read])?;
+                total += read as u64;
+            }
+            file.sync_all()?;
+            Ok(total)
+        })
+    }
+
+    /// 先上传到临时文件再重命名, 覆盖已存在的文件, 返回上传的字节数
+    fn upload(&self, local: &Path, path: &Path) -> Result<u64, Error> {
+        let upload_path = PathBuf::from(format!("{}{}", path.display(), UPLOAD_SUFFIX));
+        self.call(|sftp| {
+            let mut file = File::open(local)?;
+            let mut remote = sftp.create(&upload_path).map_err(sftp_error)?;
+            let mut buf = vec![0; BUFFER_SIZE];
+            let mut total = 0;
+            loop {
+                let read = file.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                remote.write_all(&buf[..read]).map_err(stream_error)?;
+                total += read as u64;
+            }
+            remote.fsync().or_else(ignore_unsupported)?;
+            drop(remote);
+            replace(sftp, &upload_path, path)?;
+            Ok(total)
+        })
+    }
+}
+
+impl StorageBackend for SftpStorage {
+    fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+        self.call(|sftp| {
+            let mut entries = Vec::new();
+            for (child, stat) in sftp.readdir(path).map_err(sftp_error)? {
+                // 命名管道、设备文件等不会出现在备份目的地中, 源目录中的也不同步
+                let Some(meta) = storage_meta(&stat) else {
+                    continue;
+                };
+                entries.push(StorageEntry {
+                    name: child
+                        .file_name()
+                        .map(|n| n.to_string_lossy().to_string())
+                        .unwrap_or_default(),
+                    meta,
+                });
+            }
+            Ok(entries)
+        })
+    }
+
+    fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+        self.call(|sftp| match sftp.lstat(path) {
+            Ok(stat) => Ok(Some(storage_meta(&stat).unwrap_or(StorageMeta {
+                kind: StorageKind::File,
+                len: stat.size.unwrap_or(0),
+                modified: remote::unix_time(stat.mtime.unwrap_or(0)),
+            }))),
+            Err(e) => match sftp_error(e) {
+                e if e.kind() == ErrorKind::NotFound => Ok(None),
+                e => Err(e),
+            },
+        })
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        self.call(|sftp| {
+            let mut data = Vec::new();
+            sftp.open(path)
+                .map_err(sftp_error)?
+                .read_to_end(&mut data)
+                .map_err(stream_error)?;
+            Ok(data)
+        })
+    }
+
+    fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+        let spool = SpoolFile::write(reader)?;
+        self.upload(spool.path(), path)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<(), Error> {
+        remote::mkdir_all(self, path, |dir| {
+            self.call(|sftp| sftp.mkdir(dir, 0o755).map_err(sftp_error))
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        self.call(|sftp| replace(sftp, from, to))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), Error> {
+        remote::delete_all(self, path, &|path, kind| {
+            self.call(|sftp| match kind {
+                StorageKind::Dir => sftp.rmdir(path).map_err(sftp_error),
+                StorageKind::File | StorageKind::Symlink => sftp.unlink(path).map_err(sftp_error),
+            })
+        })
+    }
+
+    fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+        match link {
+            Link::Hard(_) => Err(Error::new(ErrorKind::Unsupported, "SFTP 不支持创建硬链接")),
+            Link::Symbolic(target) => {
+                self.call(|sftp| sftp.symlink(Path::new(target), path).map_err(sftp_error))
+            }
+        }
+    }
+
+    fn read_link(&self, path: &Path) -> Result<String, Error> {
+        self.call(|sftp| {
+            let target = sftp.readlink(path).map_err(sftp_error)?;
+            Ok(target.to_string_lossy().to_string())
+        })
+    }
+
+    fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+        self.upload(source, path)
+    }
+
+    fn copy_to(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        self.download(path, local)
+    }
+
+    fn digest(&self, path: &Path) -> Result<String, Error> {
+        remote::download_digest(self, path)
+    }
+}
+
+/// 将 from 重命名为 to, 替换已存在的文件
+/// SFTP v3 的 rename 不会覆盖已存在的文件, 先删除目标
+fn replace(sftp: &Sftp, from: &Path, to: &Path) -> Result<(), Error> {
+    match sftp.unlink(to).map_err(sftp_error) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    sftp.rename(from, to, None).map_err(sftp_error)
+}
+
+/// 目录、文件及符号链接的信息, 其他类型返回 None
+fn storage_meta(stat: &FileStat) -> Option<StorageMeta> {
+    let file_type = stat.file_type();
+    let kind = if file_type.is_dir() {
+        StorageKind::Dir
+    } else if file_type.is_file() {
+        StorageKind::File
+    } else if file_type.is_symlink() {
+        StorageKind::Symlink
+    } else {
+        return None;
+    };
+    Some(StorageMeta {
+        kind,
+        len: if kind == StorageKind::Dir {
+            0
+        } else {
+            stat.size.unwrap_or(0)
+        },
+        modified: remote::unix_time(stat.mtime.unwrap_or(0)),
+    })
+}
+
+/// 将 ssh2 的错误转换为 io 错误
+/// 会话层的错误及连接断开视为连接中断, 可以重新连接后重试
+fn sftp_error(e: ssh2::Error) -> Error {
+    let kind = match e.code() {
+        ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT) => ErrorKind::TimedOut,
+        ErrorCode::Session(_) => ErrorKind::ConnectionAborted,
+        ErrorCode::SFTP(code) => match code {
+            2 | 10 => ErrorKind::NotFound,
+            3 => ErrorKind::PermissionDenied,
+            6 | 7 => ErrorKind::ConnectionAborted,
+            11 => ErrorKind::AlreadyExists,
+            14 | 15 => ErrorKind::StorageFull,
+            _ => ErrorKind::Other,
+        },
+    };
+    Error::new(kind, e.to_string())
+}
+
+/// 读写远程文件时的错误均来自会话层, 视为连接中断
+fn stream_error(e: Error) -> Error {
+    if e.kind() == ErrorKind::Other {
+        Error::new(ErrorKind::ConnectionAborted, e.to_string())
+    } else {
+        e
+    }
+}
+
+/// 部分服务器不支持 fsync 扩展
+fn ignore_unsupported(e: ssh2::Error) -> Result<(), Error> {
+    match e.code() {
+        // SSH_FX_OP_UNSUPPORTED
+        ErrorCode::SFTP(8) => Ok(()),
+        _ => Err(sftp_error(e)),
+    }
+}
+
+fn is_retryable(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// 集成测试使用的服务器, 由环境变量 RSBK_SFTP_TEST_URL 指定(如 sftp://rsbk@127.0.0.1:2222/tmp),
+    /// RSBK_SFTP_TEST_KEY 为私钥文件(不填写时使用 ssh-agent), RSBK_SFTP_TEST_KNOWN_HOSTS 为 known_hosts 文件
+    /// 未设置时跳过; 每个测试使用地址下以测试名称及进程号命名的目录, 结束时删除
+    fn test_server(name: &str) -> Option<(String, SftpConfig, PathBuf)> {
+        let Ok(url) = std::env::var("RSBK_SFTP_TEST_URL") else {
+            eprintln!("未设置 RSBK_SFTP_TEST_URL, 跳过 SFTP 集成测试");
+            return None;
+        };
+        let config = SftpConfig {
+            private_key: std::env::var("RSBK_SFTP_TEST_KEY").ok(),
+            known_hosts: std::env::var("RSBK_SFTP_TEST_KNOWN_HOSTS").ok(),
+            retries: 0,
+            ..SftpConfig::default()
+        };
+        let url = format!(
+            "{}/rsbk-{}-{}",
+            url.trim_end_matches('/'),
+            name,
+            std::process::id()
+        );
+        let root = PathBuf::from(SftpUrl::parse(&url).unwrap().path);
+        Some((url, config, root))
+    }
+
+    /// 离开作用域时删除测试目录
+    struct Cleanup<'a>(&'a SftpStorage, &'a Path);
+
+    impl Drop for Cleanup<'_> {
+        fn drop(&mut self) {
+            let _ = self.0.delete(self.1);
+        }
+    }
+
+    fn task_yaml(source: &str, destination: &str, options: &str) -> String {
+        format!(
+            "backup_source_path: {}\nbackup_destination_path: {}\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n{}",
+            source, destination, options
+        )
+    }
+
+    #[test]
+    fn storage_operations_against_server() {
+        let Some((url, config, root)) = test_server("storage") else {
+            return;
+        };
+        let storage = SftpStorage::create(SftpUrl::parse(&url).unwrap(), config);
+        let _cleanup = Cleanup(&storage, &root);
+        let dir = root.join("dir");
+        storage.mkdir(&dir.join("sub")).unwrap();
+        assert!(storage.stat(&dir).unwrap().is_some_and(|m| m.is_dir()));
+
+        storage.write(&dir.join("a"), &mut &b"one"[..]).unwrap();
+        storage.write(&dir.join("a"), &mut &b"changed"[..]).unwrap();
+        let meta = storage.stat(&dir.join("a")).unwrap().unwrap();
+        assert_eq!((meta.kind, meta.len), (StorageKind::File, 7));
+        assert_eq!(storage.read(&dir.join("a")).unwrap(), b"changed");
+        storage
+            .link(Link::Symbolic("../a"), &dir.join("sub/link"))
+            .unwrap();
+        assert_eq!(storage.read_link(&dir.join("sub/link")).unwrap(), "../a");
+        storage
+            .link(Link::Hard(&dir.join("a")), &dir.join("hard"))
+            .unwrap();
+        assert_eq!(storage.read(&dir.join("hard")).unwrap(), b"changed");
+
+        let mut entries: Vec<(String, StorageKind)> = storage
+            .list(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.meta.kind))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            vec![
+                ("a".to_string(), StorageKind::File),
+                ("hard".to_string(), StorageKind::File),
+                ("sub".to_string(), StorageKind::Dir)
+            ]
+        );
+
+        let moved = root.join("moved");
+        storage.rename(&dir, &moved).unwrap();
+        assert!(storage.stat(&dir).unwrap().is_none());
+        let local = tempfile::tempdir().unwrap();
+        storage
+            .copy_to(&moved.join("a"), &local.path().join("a"))
+            .unwrap();
+        assert_eq!(fs::read(local.path().join("a")).unwrap(), b"changed");
+        assert_eq!(
+            storage.digest(&moved.join("a")).unwrap(),
+            sha256::digest("changed")
+        );
+
+        storage.delete(&moved).unwrap();
+        assert!(storage.stat(&moved).unwrap().is_none());
+    }
+
+    /// 断开连接后下一次操作重新连接
+    #[test]
+    fn reconnects_after_disconnect() {
+        let Some((url, mut config, root)) = test_server("reconnect") else {
+            return;
+        };
+        config.retries = 1;
+        config.retry_delay_seconds = 0;
+        let storage = SftpStorage::create(SftpUrl::parse(&url).unwrap(), config);
+        let _cleanup = Cleanup(&storage, &root);
+        storage.mkdir(&root).unwrap();
+        if let Some((session, _)) = storage.lock().unwrap().as_ref() {
+            session.disconnect(None, "test", None).unwrap();
+        }
+        storage.write(&root.join("a"), &mut &b"one"[..]).unwrap();
+        assert_eq!(storage.read(&root.join("a")).unwrap(), b"one");
+    }
+
+    /// 从远程源目录增量备份到本地: 只读取变动的文件, 本地不保留源目录的缓存
+    #[test]
+    fn incremental_backup_from_server() {
+        use crate::mods::bk_config::BackupConfig;
+        use crate::mods::catalog::Catalog;
+        use crate::mods::incremental_mode::IncrementalMode;
+        use crate::mods::run_stats::RunStatus;
+
+        let Some((url, config, root)) = test_server("source") else {
+            return;
+        };
+        let storage = SftpStorage::create(SftpUrl::parse(&url).unwrap(), config.clone());
+        let _cleanup = Cleanup(&storage, &root);
+        storage.mkdir(&root.join("data/sub")).unwrap();
+        storage
+            .write(&root.join("data/a"), &mut &b"one"[..])
+            .unwrap();
+        storage
+            .write(&root.join("data/sub/b"), &mut &b"two"[..])
+            .unwrap();
+
+        let destination = tempfile::tempdir().unwrap();
+        let source_url = format!("{}/data", url);
+        let mut task_config: BackupConfig = serde_yaml::from_str(&task_yaml(
+            &source_url,
+            &destination.path().display().to_string(),
+            "  mode: IncrementalMode\n  save_days: 3\n",
+        ))
+        .unwrap();
+        task_config.sftp = Some(config);
+        let mode = IncrementalMode::create(task_config);
+        let task_name = "sftp_source_integration_test";
+        let first = mode.backup(task_name);
+        storage
+            .write(&root.join("data/c"), &mut &b"three"[..])
+            .unwrap();
+        let second = mode.backup(task_name);
+        let third = mode.backup(task_name);
+        let _ = fs::remove_file(Catalog::get_catalog_path(task_name));
+
+        assert_eq!(first.status, RunStatus::Success, "{:?}", first.errors);
+        assert_eq!(first.files_copied, 2);
+        assert_eq!(second.files_copied, 1);
+        assert_eq!(third.status, RunStatus::NoChange);
+        let backup = destination.path().join("data");
+        assert_eq!(fs::read(backup.join("a")).unwrap(), b"one");
+        assert_eq!(fs::read(backup.join("sub/b")).unwrap(), b"two");
+        assert_eq!(fs::read(backup.join("c")).unwrap(), b"three");
+        assert!(!remote::cache_path(&source_url).exists());
+    }
+
+    #[test]
+    fn version_backup_to_server() {
+        use crate::mods::bk_config::BackupConfig;
+        use crate::mods::run_stats::RunStatus;
+        use crate::mods::version_index::VersionIndex;
+        use crate::mods::version_mode::VersionMode;
+
+        let Some((url, config, root)) = test_server("backup") else {
+            return;
+        };
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = source_dir.path().join("data");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a"), b"one").unwrap();
+        fs::write(source.join("sub/b"), b"two").unwrap();
+        let task_name = "sftp_integration_test";
+        let yaml = task_yaml(
+            &source.display().to_string(),
+            &url,
+            "  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 2\n",
+        );
+        let config_path = BackupConfig::get_hash_path(task_name);
+        fs::write(&config_path, &yaml).unwrap();
+        let mut task_config: BackupConfig = serde_yaml::from_str(&yaml).unwrap();
+        task_config.sftp = Some(config.clone());
+        let storage = SftpStorage::create(SftpUrl::parse(&url).unwrap(), config);
+        let _cleanup = Cleanup(&storage, &root);
+
+        let mut mode = VersionMode::create(task_config);
+        let first = mode.backup(task_name);
+        fs::write(source.join("c"), b"three").unwrap();
+        let second = mode.backup(task_name);
+        fs::remove_file(&config_path).unwrap();
+
+        assert_eq!(first.status, RunStatus::Success, "{:?}", first.errors);
+        assert_eq!(second.status, RunStatus::Success, "{:?}", second.errors);
+        let backup_root = root.join("data");
+        let index = VersionIndex::read(&storage, &backup_root).unwrap();
+        assert_eq!(index.versions.len(), 2);
+        let latest = backup_root.join(&index.versions[1].id);
+        assert_eq!(storage.read(&latest.join("c")).unwrap(), b"three");
+        assert_eq!(storage.read(&latest.join("sub/b")).unwrap(), b"two");
+    }
+}
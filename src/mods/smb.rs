@@ -0,0 +1,1584 @@
+use super::remote::{self, SpoolFile};
+use super::storage::{Link, StorageBackend, StorageEntry, StorageKind, StorageMeta};
+use aes::Aes128;
+use cmac::Cmac;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use md4::{Digest, Md4};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 未填写端口时使用的端口
+const DEFAULT_PORT: u16 = 445;
+/// 上传时先写入的临时文件后缀, 完成后重命名
+const UPLOAD_SUFFIX: &str = ".rsbk_upload";
+/// 每次读写及列目录的最大字节数, 不超过 64KiB 时每个请求只消耗一个 credit
+const IO_SIZE: u32 = 64 * 1024;
+/// 每个请求向服务器申请的 credit 数
+const CREDIT_REQUEST: u16 = 64;
+/// FILETIME(1601-01-01 起的 100 纳秒数)与 Unix 时间戳相差的秒数
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+const HEADER_SIZE: usize = 64;
+
+// 支持的协议版本, 不支持需要加密的共享
+const SMB_2_0_2: u16 = 0x0202;
+const SMB_2_1: u16 = 0x0210;
+const SMB_3_0: u16 = 0x0300;
+const SMB_3_0_2: u16 = 0x0302;
+
+// 命令
+const NEGOTIATE: u16 = 0x00;
+const SESSION_SETUP: u16 = 0x01;
+const TREE_CONNECT: u16 = 0x03;
+const CREATE: u16 = 0x05;
+const CLOSE: u16 = 0x06;
+const READ: u16 = 0x08;
+const WRITE: u16 = 0x09;
+const QUERY_DIRECTORY: u16 = 0x0E;
+const SET_INFO: u16 = 0x11;
+
+// 消息头标志
+const FLAGS_ASYNC_COMMAND: u32 = 0x02;
+const FLAGS_SIGNED: u32 = 0x08;
+
+// 状态码
+const STATUS_SUCCESS: u32 = 0x0000_0000;
+const STATUS_PENDING: u32 = 0x0000_0103;
+const STATUS_NO_MORE_FILES: u32 = 0x8000_0006;
+const STATUS_NO_SUCH_FILE: u32 = 0xC000_000F;
+const STATUS_END_OF_FILE: u32 = 0xC000_0011;
+const STATUS_MORE_PROCESSING_REQUIRED: u32 = 0xC000_0016;
+const STATUS_ACCESS_DENIED: u32 = 0xC000_0022;
+const STATUS_OBJECT_NAME_NOT_FOUND: u32 = 0xC000_0034;
+const STATUS_OBJECT_NAME_COLLISION: u32 = 0xC000_0035;
+const STATUS_OBJECT_PATH_NOT_FOUND: u32 = 0xC000_003A;
+const STATUS_LOGON_FAILURE: u32 = 0xC000_006D;
+const STATUS_DISK_FULL: u32 = 0xC000_007F;
+const STATUS_FILE_IS_A_DIRECTORY: u32 = 0xC000_00BA;
+const STATUS_BAD_NETWORK_NAME: u32 = 0xC000_00CC;
+const STATUS_NETWORK_NAME_DELETED: u32 = 0xC000_00C9;
+const STATUS_DIRECTORY_NOT_EMPTY: u32 = 0xC000_0101;
+const STATUS_NOT_A_DIRECTORY: u32 = 0xC000_0103;
+const STATUS_USER_SESSION_DELETED: u32 = 0xC000_0203;
+const STATUS_NETWORK_SESSION_EXPIRED: u32 = 0xC000_035C;
+
+// 会话及共享标志
+const SESSION_FLAG_IS_GUEST: u16 = 0x01;
+const SESSION_FLAG_IS_NULL: u16 = 0x02;
+const SESSION_FLAG_ENCRYPT_DATA: u16 = 0x04;
+const SHAREFLAG_ENCRYPT_DATA: u32 = 0x08;
+/// 服务器要求加密传输时的处理方法
+const ENCRYPTION_HINT: &str =
+    "请在服务器上关闭强制加密, Samba: smb encrypt = desired, Windows: Set-SmbServerConfiguration -RejectUnencryptedAccess $false";
+
+// 打开文件时的访问权限、创建方式及选项
+const FILE_READ_DATA: u32 = 0x0000_0001;
+const FILE_WRITE_DATA: u32 = 0x0000_0002;
+const FILE_READ_ATTRIBUTES: u32 = 0x0000_0080;
+const DELETE: u32 = 0x0001_0000;
+const SYNCHRONIZE: u32 = 0x0010_0000;
+const FILE_SHARE_ALL: u32 = 0x07;
+const FILE_OPEN: u32 = 1;
+const FILE_CREATE: u32 = 2;
+const FILE_OVERWRITE_IF: u32 = 5;
+const FILE_DIRECTORY_FILE: u32 = 0x0000_0001;
+const FILE_NON_DIRECTORY_FILE: u32 = 0x0000_0040;
+const FILE_DELETE_ON_CLOSE: u32 = 0x0000_1000;
+const FILE_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
+const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+const FILE_ATTRIBUTE_REPARSE_POINT: u32 = 0x400;
+
+// 文件信息类别
+const INFO_FILE: u8 = 0x01;
+const FILE_DIRECTORY_INFORMATION: u8 = 0x01;
+const FILE_RENAME_INFORMATION: u8 = 0x0A;
+
+/// NTLMSSP 协商标志: UNICODE、REQUEST_TARGET、SIGN、NTLM、ALWAYS_SIGN、
+/// EXTENDED_SESSIONSECURITY、TARGET_INFO、128、56
+const NTLM_FLAGS: u32 = 0xA088_8215;
+/// challenge 消息 target info 中的时间戳
+const MSV_AV_TIMESTAMP: u16 = 0x0007;
+const NTLMSSP_SIGNATURE: &[u8] = b"NTLMSSP\0";
+/// SPNEGO 及 NTLMSSP 的 OID
+const SPNEGO_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+const NTLMSSP_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0x37, 0x02, 0x02, 0x0a];
+
+/// smb:// 地址的连接配置
+/// 使用 NTLMv2 认证, 支持 SMB 2.0.2 至 3.0.2, 会话建立后对所有请求签名
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmbConfig {
+    /// 凭据文件, 格式与 mount.cifs 的 credentials 文件相同:
+    /// username=、password=、domain= 每行一项
+    /// 地址中填写了用户名或域时以地址为准
+    pub credentials: String,
+    /// 连接及读写的超时秒数
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// 连接失败或中断时重新连接的次数
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+    /// 每次重新连接前等待的秒数
+    #[serde(default = "default_retry_delay_seconds")]
+    pub retry_delay_seconds: u64,
+}
+
+fn default_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_retries() -> usize {
+    3
+}
+
+fn default_retry_delay_seconds() -> u64 {
+    5
+}
+
+/// 解析后的 smb://domain;user@host:port/share/path 地址
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmbUrl {
+    pub domain: Option<String>,
+    pub user: Option<String>,
+    pub host: String,
+    pub port: u16,
+    pub share: String,
+    /// 共享中的路径, 以 / 开头
+    pub path: String,
+}
+
+impl SmbUrl {
+    pub fn parse(url: &str) -> Result<SmbUrl, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("无法识别的 smb 地址: {}", url),
+            )
+        };
+        let rest = url.strip_prefix("smb://").ok_or_else(invalid)?;
+        let (authority, share_path) = rest.split_once('/').ok_or_else(invalid)?;
+        let (user_info, host_port) = match authority.rsplit_once('@') {
+            Some((user_info, host_port)) => (Some(user_info.replace("%3B", ";")), host_port),
+            None => (None, authority),
+        };
+        let (domain, user) = match user_info {
+            Some(user_info) => match user_info.split_once(';') {
+                Some((domain, user)) => (Some(domain.to_string()), Some(user.to_string())),
+                None => (None, Some(user_info)),
+            },
+            None => (None, None),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (host_port, DEFAULT_PORT),
+        };
+        let (share, path) = match share_path.find('/') {
+            Some(index) => share_path.split_at(index),
+            None => (share_path, "/"),
+        };
+        if host.is_empty() || share.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SmbUrl {
+            domain: domain.filter(|d| !d.is_empty()),
+            user: user.filter(|u| !u.is_empty()),
+            host: host.to_string(),
+            port,
+            share: share.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// 登录使用的凭据
+#[derive(Debug, Clone, Default)]
+struct Credentials {
+    user: String,
+    password: String,
+    domain: String,
+}
+
+impl Credentials {
+    /// 读取凭据文件, 地址中的用户名和域优先
+    fn load(path: &str, url: &SmbUrl) -> Result<Credentials, Error> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::new(e.kind(), format!("读取凭据文件 {} 时发生错误:{}", path, e)))?;
+        let mut credentials = Credentials::default();
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim_end_matches(['\r', '\n']).to_string();
+            match key.trim() {
+                "username" | "user" => credentials.user = value,
+                "password" | "pass" => credentials.password = value,
+                "domain" | "workgroup" | "dom" => credentials.domain = value,
+                _ => {}
+            }
+        }
+        if let Some(user) = &url.user {
+            credentials.user = user.clone();
+        }
+        if let Some(domain) = &url.domain {
+            credentials.domain = domain.clone();
+        }
+        if credentials.user.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("凭据文件 {} 及地址中都没有用户名", path),
+            ));
+        }
+        Ok(credentials)
+    }
+}
+
+/// 打开的文件或目录
+struct Opened {
+    file_id: [u8; 16],
+    last_write_time: u64,
+    end_of_file: u64,
+    attributes: u32,
+}
+
+/// 一个已登录并连接到共享的 SMB2 连接
+/// 请求逐个发送, 每个请求只消耗一个 credit
+struct Connection {
+    stream: TcpStream,
+    dialect: u16,
+    message_id: u64,
+    session_id: u64,
+    tree_id: u32,
+    signing_key: Option<Vec<u8>>,
+    max_read: u32,
+    max_write: u32,
+    max_transact: u32,
+}
+
+impl Connection {
+    fn connect(url: &SmbUrl, credentials: &Credentials, timeout: Duration) -> Result<Self, Error> {
+        let mut last_error = Error::new(
+            ErrorKind::NotFound,
+            format!("无法解析主机地址 {}", url.host),
+        );
+        let mut stream = None;
+        for addr in (url.host.as_str(), url.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(tcp) => {
+                    stream = Some(tcp);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let stream = stream.ok_or(last_error)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+
+        let mut connection = Connection {
+            stream,
+            dialect: 0,
+            message_id: 0,
+            session_id: 0,
+            tree_id: 0,
+            signing_key: None,
+            max_read: IO_SIZE,
+            max_write: IO_SIZE,
+            max_transact: IO_SIZE,
+        };
+        connection.negotiate()?;
+        connection.session_setup(credentials)?;
+        connection.tree_connect(&format!("\\\\{}\\{}", url.host, url.share))?;
+        Ok(connection)
+    }
+
+    fn negotiate(&mut self) -> Result<(), Error> {
+        let dialects = [SMB_2_0_2, SMB_2_1, SMB_3_0, SMB_3_0_2];
+        let mut body = Vec::with_capacity(36 + dialects.len() * 2);
+        put_u16(&mut body, 36);
+        put_u16(&mut body, dialects.len() as u16);
+        // SecurityMode: 启用签名
+        put_u16(&mut body, 0x01);
+        put_u16(&mut body, 0);
+        // Capabilities
+        put_u32(&mut body, 0);
+        body.extend_from_slice(&random_bytes::<16>()?);
+        // ClientStartTime
+        put_u64(&mut body, 0);
+        for dialect in dialects {
+            put_u16(&mut body, dialect);
+        }
+        let response = self.request_ok(NEGOTIATE, &body, "negotiate")?;
+        let dialect = u16_at(&response, HEADER_SIZE + 4)?;
+        if !dialects.contains(&dialect) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("服务器选择了不支持的协议版本 0x{:04X}", dialect),
+            ));
+        }
+        self.dialect = dialect;
+        self.max_transact = u32_at(&response, HEADER_SIZE + 28)?.min(IO_SIZE);
+        self.max_read = u32_at(&response, HEADER_SIZE + 32)?.min(IO_SIZE);
+        self.max_write = u32_at(&response, HEADER_SIZE + 36)?.min(IO_SIZE);
+        Ok(())
+    }
+
+    /// 通过 SPNEGO 包装的 NTLMSSP 进行 NTLMv2 认证
+    fn session_setup(&mut self, credentials: &Credentials) -> Result<(), Error> {
+        let negotiate = spnego_init(&ntlm_negotiate());
+        let (status, response) = self.request(SESSION_SETUP, &session_setup_body(&negotiate))?;
+        if status != STATUS_MORE_PROCESSING_REQUIRED {
+            return Err(status_error(status, "session setup"));
+        }
+        self.session_id = u64_at(&response, 40)?;
+        let security = security_buffer(&response)?;
+        let challenge = security
+            .windows(NTLMSSP_SIGNATURE.len())
+            .position(|w| w == NTLMSSP_SIGNATURE)
+            .map(|index| &security[index..])
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "服务器未返回 NTLM challenge"))?;
+        let (authenticate, session_key) = ntlm_authenticate(challenge, credentials)?;
+
+        let (status, response) = self.request(
+            SESSION_SETUP,
+            &session_setup_body(&spnego_response(&authenticate)),
+        )?;
+        if status != STATUS_SUCCESS {
+            return Err(match status {
+                STATUS_LOGON_FAILURE => Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "{}\\{} 认证失败, 请检查凭据文件",
+                        credentials.domain, credentials.user
+                    ),
+                ),
+                // 要求加密而客户端不支持加密时, 服务器以拒绝访问结束会话
+                STATUS_ACCESS_DENIED => encryption_required("服务器拒绝了会话, 可能要求加密传输"),
+                status => status_error(status, "session setup"),
+            });
+        }
+        let session_flags = u16_at(&response, HEADER_SIZE + 2)?;
+        if session_flags & SESSION_FLAG_ENCRYPT_DATA != 0 {
+            return Err(encryption_required("服务器要求加密传输"));
+        }
+        if session_flags & (SESSION_FLAG_IS_GUEST | SESSION_FLAG_IS_NULL) == 0 {
+            let signing_key = if self.dialect >= SMB_3_0 {
+                smb3_signing_key(&session_key)
+            } else {
+                session_key.to_vec()
+            };
+            // 最后一个 session setup 响应使用新的签名密钥签名
+            if u32_at(&response, 16)? & FLAGS_SIGNED != 0 {
+                verify_signature(self.dialect, &signing_key, &response)?;
+            }
+            self.signing_key = Some(signing_key);
+        }
+        Ok(())
+    }
+
+    fn tree_connect(&mut self, unc: &str) -> Result<(), Error> {
+        let path = utf16(unc);
+        let mut body = Vec::with_capacity(8 + path.len());
+        put_u16(&mut body, 9);
+        put_u16(&mut body, 0);
+        put_u16(&mut body, (HEADER_SIZE + 8) as u16);
+        put_u16(&mut body, path.len() as u16);
+        body.extend_from_slice(&path);
+        let response = match self.request(TREE_CONNECT, &body)? {
+            (STATUS_SUCCESS, response) => response,
+            (STATUS_BAD_NETWORK_NAME, _) => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("共享 {} 不存在", unc),
+                ))
+            }
+            (STATUS_ACCESS_DENIED, _) => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "无权访问共享 {}, 或共享要求加密传输(暂不支持, {})",
+                        unc, ENCRYPTION_HINT
+                    ),
+                ))
+            }
+            (status, _) => return Err(status_error(status, unc)),
+        };
+        self.tree_id = u32_at(&response, 36)?;
+        if u32_at(&response, HEADER_SIZE + 4)? & SHAREFLAG_ENCRYPT_DATA != 0 {
+            return Err(encryption_required(&format!("共享 {} 要求加密传输", unc)));
+        }
+        Ok(())
+    }
+
+    fn create(
+        &mut self,
+        path: &str,
+        access: u32,
+        disposition: u32,
+        options: u32,
+    ) -> Result<Opened, Error> {
+        let name = utf16(path);
+        let mut body = Vec::with_capacity(57 + name.len());
+        put_u16(&mut body, 57);
+        // SecurityFlags、RequestedOplockLevel(不使用 oplock)
+        body.extend_from_slice(&[0, 0]);
+        // ImpersonationLevel: Impersonation
+        put_u32(&mut body, 2);
+        put_u64(&mut body, 0);
+        put_u64(&mut body, 0);
+        put_u32(&mut body, access);
+        put_u32(&mut body, FILE_ATTRIBUTE_NORMAL);
+        put_u32(&mut body, FILE_SHARE_ALL);
+        put_u32(&mut body, disposition);
+        put_u32(&mut body, options);
+        put_u16(&mut body, (HEADER_SIZE + 56) as u16);
+        put_u16(&mut body, name.len() as u16);
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        body.extend_from_slice(&name);
+        if name.is_empty() {
+            body.push(0);
+        }
+        let response = match self.request(CREATE, &body)? {
+            (STATUS_SUCCESS, response) => response,
+            (status, _) => return Err(status_error(status, path)),
+        };
+        let mut file_id = [0; 16];
+        file_id.copy_from_slice(slice_at(&response, HEADER_SIZE + 64, 16)?);
+        Ok(Opened {
+            file_id,
+            last_write_time: u64_at(&response, HEADER_SIZE + 24)?,
+            end_of_file: u64_at(&response, HEADER_SIZE + 48)?,
+            attributes: u32_at(&response, HEADER_SIZE + 56)?,
+        })
+    }
+
+    fn close(&mut self, file_id: &[u8; 16]) -> Result<(), Error> {
+        let mut body = Vec::with_capacity(24);
+        put_u16(&mut body, 24);
+        put_u16(&mut body, 0);
+        put_u32(&mut body, 0);
+        body.extend_from_slice(file_id);
+        self.request_ok(CLOSE, &body, "close").map(|_| ())
+    }
+
+    /// 读取一段数据, 已到文件末尾时返回空
+    fn read(&mut self, file_id: &[u8; 16], offset: u64) -> Result<Vec<u8>, Error> {
+        let mut body = Vec::with_capacity(49);
+        put_u16(&mut body, 49);
+        body.extend_from_slice(&[(HEADER_SIZE + 16) as u8, 0]);
+        put_u32(&mut body, self.max_read);
+        put_u64(&mut body, offset);
+        body.extend_from_slice(file_id);
+        // MinimumCount、Channel、RemainingBytes、ReadChannelInfo
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        body.push(0);
+        let response = match self.request(READ, &body)? {
+            (STATUS_SUCCESS, response) => response,
+            (STATUS_END_OF_FILE, _) => return Ok(Vec::new()),
+            (status, _) => return Err(status_error(status, "read")),
+        };
+        let data_offset = response
+            .get(HEADER_SIZE + 2)
+            .copied()
+            .ok_or_else(truncated)? as usize;
+        let length = u32_at(&response, HEADER_SIZE + 4)? as usize;
+        Ok(slice_at(&response, data_offset, length)?.to_vec())
+    }
+
+    fn write(&mut self, file_id: &[u8; 16], offset: u64, data: &[u8]) -> Result<u32, Error> {
+        let mut body = Vec::with_capacity(48 + data.len());
+        put_u16(&mut body, 49);
+        put_u16(&mut body, (HEADER_SIZE + 48) as u16);
+        put_u32(&mut body, data.len() as u32);
+        put_u64(&mut body, offset);
+        body.extend_from_slice(file_id);
+        // Channel、RemainingBytes、WriteChannelInfo、Flags
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        put_u32(&mut body, 0);
+        body.extend_from_slice(data);
+        let response = self.request_ok(WRITE, &body, "write")?;
+        u32_at(&response, HEADER_SIZE + 4)
+    }
+
+    /// 读取目录中的下一批条目, 没有更多条目时返回 None
+    fn query_directory(
+        &mut self,
+        file_id: &[u8; 16],
+        restart: bool,
+    ) -> Result<Option<Vec<StorageEntry>>, Error> {
+        let pattern = utf16("*");
+        let mut body = Vec::with_capacity(32 + pattern.len());
+        put_u16(&mut body, 33);
+        body.push(FILE_DIRECTORY_INFORMATION);
+        // Flags: RESTART_SCANS
+        body.push(if restart { 0x01 } else { 0 });
+        put_u32(&mut body, 0);
+        body.extend_from_slice(file_id);
+        put_u16(&mut body, (HEADER_SIZE + 32) as u16);
+        put_u16(&mut body, pattern.len() as u16);
+        put_u32(&mut body, self.max_transact);
+        body.extend_from_slice(&pattern);
+        let response = match self.request(QUERY_DIRECTORY, &body)? {
+            (STATUS_SUCCESS, response) => response,
+            (STATUS_NO_MORE_FILES, _) => return Ok(None),
+            (status, _) => return Err(status_error(status, "query directory")),
+        };
+        let offset = u16_at(&response, HEADER_SIZE + 2)? as usize;
+        let length = u32_at(&response, HEADER_SIZE + 4)? as usize;
+        let buffer = slice_at(&response, offset, length)?;
+
+        let mut entries = Vec::new();
+        let mut position = 0;
+        loop {
+            let next = u32_at(buffer, position)? as usize;
+            let name_length = u32_at(buffer, position + 60)? as usize;
+            let name = from_utf16(slice_at(buffer, position + 64, name_length)?);
+            if name != "." && name != ".." {
+                entries.push(StorageEntry {
+                    name,
+                    meta: storage_meta(
+                        u32_at(buffer, position + 56)?,
+                        u64_at(buffer, position + 40)?,
+                        u64_at(buffer, position + 24)?,
+                    ),
+                });
+            }
+            if next == 0 {
+                break;
+            }
+            position += next;
+        }
+        Ok(Some(entries))
+    }
+
+    fn set_info(&mut self, file_id: &[u8; 16], class: u8, info: &[u8]) -> Result<(), Error> {
+        let mut body = Vec::with_capacity(32 + info.len());
+        put_u16(&mut body, 33);
+        body.push(INFO_FILE);
+        body.push(class);
+        put_u32(&mut body, info.len() as u32);
+        put_u16(&mut body, (HEADER_SIZE + 32) as u16);
+        put_u16(&mut body, 0);
+        put_u32(&mut body, 0);
+        body.extend_from_slice(file_id);
+        body.extend_from_slice(info);
+        self.request_ok(SET_INFO, &body, "set info").map(|_| ())
+    }
+
+    /// 发送请求, 状态不是成功时返回错误
+    fn request_ok(&mut self, command: u16, body: &[u8], what: &str) -> Result<Vec<u8>, Error> {
+        match self.request(command, body)? {
+            (STATUS_SUCCESS, response) => Ok(response),
+            (status, _) => Err(status_error(status, what)),
+        }
+    }
+
+    /// 发送请求并等待对应的响应, 返回状态码及包含消息头的完整响应
+    fn request(&mut self, command: u16, body: &[u8]) -> Result<(u32, Vec<u8>), Error> {
+        let message_id = self.message_id;
+        self.message_id += 1;
+        // SMB 2.0.2 不使用 CreditCharge
+        let credit_charge: u16 = if self.dialect > SMB_2_0_2 { 1 } else { 0 };
+        let sign = self.signing_key.is_some() && command != SESSION_SETUP;
+
+        let mut message = Vec::with_capacity(4 + HEADER_SIZE + body.len());
+        message.extend_from_slice(&[0; 4]);
+        message.extend_from_slice(b"\xfeSMB");
+        put_u16(&mut message, HEADER_SIZE as u16);
+        put_u16(&mut message, credit_charge);
+        put_u32(&mut message, 0);
+        put_u16(&mut message, command);
+        put_u16(&mut message, CREDIT_REQUEST);
+        put_u32(&mut message, if sign { FLAGS_SIGNED } else { 0 });
+        put_u32(&mut message, 0);
+        put_u64(&mut message, message_id);
+        put_u32(&mut message, 0);
+        put_u32(&mut message, self.tree_id);
+        put_u64(&mut message, self.session_id);
+        message.extend_from_slice(&[0; 16]);
+        message.extend_from_slice(body);
+        let length = (message.len() - 4) as u32;
+        message[..4].copy_from_slice(&length.to_be_bytes());
+        if let (true, Some(key)) = (sign, &self.signing_key) {
+            let signature = sign_message(self.dialect, key, &message[4..])?;
+            message[4 + 48..4 + HEADER_SIZE].copy_from_slice(&signature);
+        }
+        self.stream.write_all(&message)?;
+
+        loop {
+            let response = self.receive()?;
+            if response.starts_with(b"\xfdSMB") {
+                return Err(encryption_required("服务器返回了加密的响应"));
+            }
+            if response.len() < HEADER_SIZE || &response[..4] != b"\xfeSMB" {
+                return Err(Error::new(ErrorKind::InvalidData, "无法识别的 SMB2 响应"));
+            }
+            let status = u32_at(&response, 8)?;
+            let flags = u32_at(&response, 16)?;
+            // 服务器未响应的通知或处理中的临时响应
+            if u64_at(&response, 24)? != message_id
+                || (status == STATUS_PENDING && flags & FLAGS_ASYNC_COMMAND != 0)
+            {
+                continue;
+            }
+            if let (true, Some(key)) = (flags & FLAGS_SIGNED != 0, &self.signing_key) {
+                verify_signature(self.dialect, key, &response)?;
+            }
+            return Ok((status, response));
+        }
+    }
+
+    /// 读取一个 Direct TCP 帧
+    fn receive(&mut self) -> Result<Vec<u8>, Error> {
+        let mut frame_header = [0; 4];
+        self.stream.read_exact(&mut frame_header)?;
+        frame_header[0] = 0;
+        let length = u32::from_be_bytes(frame_header) as usize;
+        let mut response = vec![0; length];
+        self.stream.read_exact(&mut response)?;
+        Ok(response)
+    }
+}
+
+/// 计算消息签名, 消息头中的签名字段需为 0
+/// SMB 2.x 使用 HMAC-SHA256, SMB 3.x 使用 AES-128-CMAC
+fn sign_message(dialect: u16, key: &[u8], message: &[u8]) -> Result<[u8; 16], Error> {
+    let mut signature = [0; 16];
+    if dialect >= SMB_3_0 {
+        let mut mac = <Cmac<Aes128> as Mac>::new_from_slice(key).map_err(Error::other)?;
+        mac.update(message);
+        signature.copy_from_slice(&mac.finalize().into_bytes());
+    } else {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).map_err(Error::other)?;
+        mac.update(message);
+        signature.copy_from_slice(&mac.finalize().into_bytes()[..16]);
+    }
+    Ok(signature)
+}
+
+/// 校验带 SMB2_FLAGS_SIGNED 的响应, 签名不符时视为被篡改
+fn verify_signature(dialect: u16, key: &[u8], response: &[u8]) -> Result<(), Error> {
+    let mut message = response.to_vec();
+    let signature = slice_at(response, 48, 16)?;
+    message[48..HEADER_SIZE].fill(0);
+    if sign_message(dialect, key, &message)?[..] != *signature {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "SMB 响应 (message id {}) 的签名校验失败, 响应可能被篡改",
+                u64_at(response, 24)?
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// 通过 SMB2/3 协议访问 Windows 或 Samba 共享, 不需要挂载
+/// 连接在第一次使用时建立, 连接中断时按配置重新连接并重试当前操作
+/// 同一时间只有一个操作使用连接
+pub struct SmbStorage {
+    url: SmbUrl,
+    config: SmbConfig,
+    credentials: Credentials,
+    connection: Mutex<Option<Connection>>,
+}
+
+/// 不输出登录凭据
+impl fmt::Debug for SmbStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmbStorage")
+            .field("host", &self.url.host)
+            .field("port", &self.url.port)
+            .field("share", &self.url.share)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SmbStorage {
+    pub fn create(url: SmbUrl, config: SmbConfig) -> Result<Self, Error> {
+        let credentials = Credentials::load(&config.credentials, &url)?;
+        Ok(SmbStorage {
+            url,
+            config,
+            credentials,
+            connection: Mutex::new(None),
+        })
+    }
+
+    fn connect(&self) -> Result<Connection, Error> {
+        let timeout = Duration::from_secs(self.config.timeout_seconds.max(1));
+        let connection = Connection::connect(&self.url, &self.credentials, timeout)?;
+        info!(
+            "已连接 smb://{}:{}/{}(SMB {})",
+            self.url.host,
+            self.url.port,
+            self.url.share,
+            dialect_name(connection.dialect)
+        );
+        Ok(connection)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Option<Connection>>, Error> {
+        self.connection
+            .lock()
+            .map_err(|_| Error::other("smb 连接的锁已失效"))
+    }
+
+    /// 执行一个操作, 连接失败或中断时重新连接并重试
+    fn call<T>(
+        &self,
+        mut operation: impl FnMut(&mut Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut connection = self.lock()?;
+        let mut attempt = 0;
+        loop {
+            let result = match connection.as_mut() {
+                Some(connection) => operation(connection),
+                None => match self.connect() {
+                    Ok(mut connected) => {
+                        let result = operation(&mut connected);
+                        *connection = Some(connected);
+                        result
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match result {
+                Err(e) if is_retryable(&e) && attempt < self.config.retries => {
+                    attempt += 1;
+                    warn!(
+                        "smb://{}:{}/{} 连接失败或中断:{}, {}秒后第[{}]次重试",
+                        self.url.host,
+                        self.url.port,
+                        self.url.share,
+                        e,
+                        self.config.retry_delay_seconds,
+                        attempt
+                    );
+                    *connection = None;
+                    thread::sleep(Duration::from_secs(self.config.retry_delay_seconds));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// 打开后删除, 关闭时由服务器删除
+    fn delete_on_close(&self, path: &Path, options: u32) -> Result<(), Error> {
+        let name = share_path(path);
+        self.call(|connection| {
+            let opened = connection.create(
+                &name,
+                DELETE | SYNCHRONIZE,
+                FILE_OPEN,
+                options | FILE_DELETE_ON_CLOSE | FILE_OPEN_REPARSE_POINT,
+            )?;
+            connection.close(&opened.file_id)
+        })
+    }
+
+    /// 下载远程文件到本地, 返回下载的字节数
+    fn download(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        let name = share_path(path);
+        self.call(|connection| {
+            let opened = connection.create(
+                &name,
+                FILE_READ_DATA | FILE_READ_ATTRIBUTES | SYNCHRONIZE,
+                FILE_OPEN,
+                FILE_NON_DIRECTORY_FILE,
+            )?;
+            let result = (|| {
+                let mut file = File::create(local)?;
+                let mut total = 0;
+                loop {
+                    let data = connection.read(&opened.file_id, total)?;
+                    if data.is_empty() {
+                        break;
+                    }
+                    file.write_all(&data)?;
+                    total += data.len() as u64;
+                }
+                file.sync_all()?;
+                Ok(total)
+            })();
+            connection.close(&opened.file_id)?;
+            result
+        })
+    }
+
+    /// 先上传到临时文件再重命名, 覆盖已存在的文件, 返回上传的字节数
+    fn upload(&self, local: &Path, path: &Path) -> Result<u64, Error> {
+        let name = share_path(path);
+        let upload_name = format!("{}{}", name, UPLOAD_SUFFIX);
+        self.call(|connection| {
+            let opened = connection.create(
+                &upload_name,
+                FILE_WRITE_DATA | DELETE | SYNCHRONIZE,
+                FILE_OVERWRITE_IF,
+                FILE_NON_DIRECTORY_FILE,
+            )?;
+            let result = (|| {
+                let mut file = File::open(local)?;
+                let mut buf = vec![0; connection.max_write as usize];
+                let mut total = 0;
+                loop {
+                    let read = file.read(&mut buf)?;
+                    if read == 0 {
+                        break;
+                    }
+                    let mut written = 0;
+                    while written < read {
+                        let count = connection.write(
+                            &opened.file_id,
+                            total + written as u64,
+                            &buf[written..read],
+                        )?;
+                        if count == 0 {
+                            return Err(Error::new(ErrorKind::WriteZero, "服务器未写入任何数据"));
+                        }
+                        written += count as usize;
+                    }
+                    total += read as u64;
+                }
+                connection.set_info(
+                    &opened.file_id,
+                    FILE_RENAME_INFORMATION,
+                    &rename_info(&name),
+                )?;
+                Ok(total)
+            })();
+            connection.close(&opened.file_id)?;
+            result
+        })
+    }
+}
+
+impl StorageBackend for SmbStorage {
+    fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+        let name = share_path(path);
+        self.call(|connection| {
+            let opened = connection.create(
+                &name,
+                FILE_READ_DATA | FILE_READ_ATTRIBUTES | SYNCHRONIZE,
+                FILE_OPEN,
+                FILE_DIRECTORY_FILE,
+            )?;
+            let mut entries = Vec::new();
+            let mut result = Ok(());
+            let mut restart = true;
+            loop {
+                match connection.query_directory(&opened.file_id, restart) {
+                    Ok(Some(batch)) => entries.extend(batch),
+                    Ok(None) => break,
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
+                }
+                restart = false;
+            }
+            connection.close(&opened.file_id)?;
+            result.map(|_| entries)
+        })
+    }
+
+    fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+        let name = share_path(path);
+        self.call(|connection| {
+            let opened = match connection.create(
+                &name,
+                FILE_READ_ATTRIBUTES | SYNCHRONIZE,
+                FILE_OPEN,
+                FILE_OPEN_REPARSE_POINT,
+            ) {
+                Ok(opened) => opened,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            connection.close(&opened.file_id)?;
+            Ok(Some(storage_meta(
+                opened.attributes,
+                opened.end_of_file,
+                opened.last_write_time,
+            )))
+        })
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let spool = SpoolFile::create()?;
+        self.download(path, spool.path())?;
+        fs::read(spool.path())
+    }
+
+    fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+        let spool = SpoolFile::write(reader)?;
+        self.upload(spool.path(), path)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<(), Error> {
+        remote::mkdir_all(self, path, |dir| {
+            let name = share_path(dir);
+            self.call(|connection| {
+                let opened = connection.create(
+                    &name,
+                    FILE_READ_ATTRIBUTES | SYNCHRONIZE,
+                    FILE_CREATE,
+                    FILE_DIRECTORY_FILE,
+                )?;
+                connection.close(&opened.file_id)
+            })
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let name = share_path(from);
+        let target = rename_info(&share_path(to));
+        self.call(|connection| {
+            let opened = connection.create(
+                &name,
+                DELETE | SYNCHRONIZE,
+                FILE_OPEN,
+                FILE_OPEN_REPARSE_POINT,
+            )?;
+            let result = connection.set_info(&opened.file_id, FILE_RENAME_INFORMATION, &target);
+            connection.close(&opened.file_id)?;
+            result
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), Error> {
+        remote::delete_all(self, path, &|path, kind| {
+            let options = match kind {
+                StorageKind::Dir => FILE_DIRECTORY_FILE,
+                StorageKind::File => FILE_NON_DIRECTORY_FILE,
+                StorageKind::Symlink => 0,
+            };
+            self.delete_on_close(path, options)
+        })
+    }
+
+    fn link(&self, _link: Link, path: &Path) -> Result<(), Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("SMB 共享不支持创建链接: {}", path.display()),
+        ))
+    }
+
+    /// 重解析点(符号链接、联接点等)的目标需要 FSCTL_GET_REPARSE_POINT 读取, 不支持
+    fn read_link(&self, path: &Path) -> Result<String, Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("不读取 SMB 共享中的重解析点: {}", path.display()),
+        ))
+    }
+
+    fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+        self.upload(source, path)
+    }
+
+    fn copy_to(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        self.download(path, local)
+    }
+
+    fn digest(&self, path: &Path) -> Result<String, Error> {
+        remote::download_digest(self, path)
+    }
+}
+
+fn dialect_name(dialect: u16) -> &'static str {
+    match dialect {
+        SMB_2_0_2 => "2.0.2",
+        SMB_2_1 => "2.1",
+        SMB_3_0 => "3.0",
+        _ => "3.0.2",
+    }
+}
+
+/// 将 /dir/file 形式的路径转换为共享中的 dir\file
+fn share_path(path: &Path) -> String {
+    remote::remote_path(path)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+/// 重解析点视为符号链接, 不跟随
+fn storage_meta(attributes: u32, end_of_file: u64, last_write_time: u64) -> StorageMeta {
+    let kind = if attributes & FILE_ATTRIBUTE_REPARSE_POINT != 0 {
+        StorageKind::Symlink
+    } else if attributes & FILE_ATTRIBUTE_DIRECTORY != 0 {
+        StorageKind::Dir
+    } else {
+        StorageKind::File
+    };
+    StorageMeta {
+        kind,
+        len: if kind == StorageKind::Dir {
+            0
+        } else {
+            end_of_file
+        },
+        modified: remote::unix_time(filetime_to_unix(last_write_time)),
+    }
+}
+
+/// FILE_RENAME_INFORMATION, 替换已存在的文件
+fn rename_info(target: &str) -> Vec<u8> {
+    let target = utf16(target);
+    let mut rename = Vec::with_capacity(20 + target.len());
+    // ReplaceIfExists
+    rename.push(1);
+    rename.extend_from_slice(&[0; 7]);
+    put_u64(&mut rename, 0);
+    put_u32(&mut rename, target.len() as u32);
+    rename.extend_from_slice(&target);
+    rename
+}
+
+fn session_setup_body(security: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(24 + security.len());
+    put_u16(&mut body, 25);
+    // Flags、SecurityMode: 启用签名
+    body.extend_from_slice(&[0, 0x01]);
+    // Capabilities、Channel
+    put_u32(&mut body, 0);
+    put_u32(&mut body, 0);
+    put_u16(&mut body, (HEADER_SIZE + 24) as u16);
+    put_u16(&mut body, security.len() as u16);
+    // PreviousSessionId
+    put_u64(&mut body, 0);
+    body.extend_from_slice(security);
+    body
+}
+
+fn security_buffer(response: &[u8]) -> Result<&[u8], Error> {
+    let offset = u16_at(response, HEADER_SIZE + 4)? as usize;
+    let length = u16_at(response, HEADER_SIZE + 6)? as usize;
+    slice_at(response, offset, length)
+}
+
+/// SPNEGO NegTokenInit, 只提供 NTLMSSP
+fn spnego_init(token: &[u8]) -> Vec<u8> {
+    let mech_types = der(0xa0, &der(0x30, &der(0x06, NTLMSSP_OID)));
+    let mech_token = der(0xa2, &der(0x04, token));
+    let init = der(0xa0, &der(0x30, &[mech_types, mech_token].concat()));
+    der(0x60, &[der(0x06, SPNEGO_OID), init].concat())
+}
+
+/// SPNEGO NegTokenResp
+fn spnego_response(token: &[u8]) -> Vec<u8> {
+    der(0xa1, &der(0x30, &der(0xa2, &der(0x04, token))))
+}
+
+/// DER 编码的一个 TLV
+fn der(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let length = value.len();
+    if length < 0x80 {
+        out.push(length as u8);
+    } else if length < 0x100 {
+        out.extend_from_slice(&[0x81, length as u8]);
+    } else {
+        out.push(0x82);
+        out.extend_from_slice(&(length as u16).to_be_bytes());
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+fn ntlm_negotiate() -> Vec<u8> {
+    let mut message = NTLMSSP_SIGNATURE.to_vec();
+    put_u32(&mut message, 1);
+    put_u32(&mut message, NTLM_FLAGS);
+    // DomainNameFields、WorkstationFields
+    message.extend_from_slice(&[0; 16]);
+    message
+}
+
+/// 根据服务器的 challenge 生成 NTLMv2 authenticate 消息, 同时返回会话密钥
+fn ntlm_authenticate(
+    challenge: &[u8],
+    credentials: &Credentials,
+) -> Result<(Vec<u8>, [u8; 16]), Error> {
+    if u32_at(challenge, 8)? != 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "无法识别的 NTLM challenge",
+        ));
+    }
+    let flags = u32_at(challenge, 20)? & NTLM_FLAGS;
+    let server_challenge = slice_at(challenge, 24, 8)?;
+    let target_info_length = u16_at(challenge, 40)? as usize;
+    let target_info_offset = u32_at(challenge, 44)? as usize;
+    let target_info = slice_at(challenge, target_info_offset, target_info_length)?;
+    let server_timestamp = av_timestamp(target_info);
+    let timestamp = server_timestamp.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (now.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000 + now.subsec_nanos() as u64 / 100
+    });
+    let client_challenge = random_bytes::<8>()?;
+
+    let response_key = ntowf_v2(
+        &credentials.password,
+        &credentials.user,
+        &credentials.domain,
+    );
+    let (nt_response, session_key) = ntlm_v2_response(
+        &response_key,
+        server_challenge,
+        &client_challenge,
+        timestamp,
+        target_info,
+    );
+    // target info 中有时间戳时不发送 LMv2 响应
+    let lm_response = match server_timestamp {
+        Some(_) => vec![0; 24],
+        None => lm_v2_response(&response_key, server_challenge, &client_challenge),
+    };
+
+    let fields = [
+        lm_response,
+        nt_response,
+        utf16(&credentials.domain),
+        utf16(&credentials.user),
+        Vec::new(),
+        Vec::new(),
+    ];
+    let mut message = NTLMSSP_SIGNATURE.to_vec();
+    put_u32(&mut message, 3);
+    let mut offset = 64;
+    for field in &fields {
+        put_u16(&mut message, field.len() as u16);
+        put_u16(&mut message, field.len() as u16);
+        put_u32(&mut message, offset as u32);
+        offset += field.len();
+    }
+    put_u32(&mut message, flags);
+    for field in &fields {
+        message.extend_from_slice(field);
+    }
+    Ok((message, session_key))
+}
+
+/// NTOWFv2 = HMAC-MD5(MD4(UTF16(password)), UTF16(UPPER(user) + domain))
+fn ntowf_v2(password: &str, user: &str, domain: &str) -> [u8; 16] {
+    let hash = Md4::digest(utf16(password));
+    hmac_md5(&hash, &utf16(&format!("{}{}", user.to_uppercase(), domain)))
+}
+
+/// 返回 NTLMv2 响应及会话密钥(未协商密钥交换, 即 SessionBaseKey)
+fn ntlm_v2_response(
+    response_key: &[u8; 16],
+    server_challenge: &[u8],
+    client_challenge: &[u8],
+    timestamp: u64,
+    target_info: &[u8],
+) -> (Vec<u8>, [u8; 16]) {
+    let mut temp = vec![0x01, 0x01, 0, 0, 0, 0, 0, 0];
+    put_u64(&mut temp, timestamp);
+    temp.extend_from_slice(client_challenge);
+    temp.extend_from_slice(&[0; 4]);
+    temp.extend_from_slice(target_info);
+    temp.extend_from_slice(&[0; 4]);
+    let nt_proof = hmac_md5(response_key, &[server_challenge, &temp].concat());
+    let session_key = hmac_md5(response_key, &nt_proof);
+    ([&nt_proof[..], &temp].concat(), session_key)
+}
+
+fn lm_v2_response(
+    response_key: &[u8; 16],
+    server_challenge: &[u8],
+    client_challenge: &[u8],
+) -> Vec<u8> {
+    let proof = hmac_md5(response_key, &[server_challenge, client_challenge].concat());
+    [&proof[..], client_challenge].concat()
+}
+
+/// target info 中的 MsvAvTimestamp
+fn av_timestamp(target_info: &[u8]) -> Option<u64> {
+    let mut position = 0;
+    while let (Ok(id), Ok(length)) = (
+        u16_at(target_info, position),
+        u16_at(target_info, position + 2),
+    ) {
+        if id == 0 {
+            break;
+        }
+        if id == MSV_AV_TIMESTAMP && length == 8 {
+            return u64_at(target_info, position + 4).ok();
+        }
+        position += 4 + length as usize;
+    }
+    None
+}
+
+/// SMB 3.x 的签名密钥: SP800-108 计数器模式的 KDF
+fn smb3_signing_key(session_key: &[u8]) -> Vec<u8> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(session_key).expect("HMAC 可以使用任意长度的密钥");
+    mac.update(&1u32.to_be_bytes());
+    mac.update(b"SMB2AESCMAC\0");
+    mac.update(&[0]);
+    mac.update(b"SmbSign\0");
+    mac.update(&128u32.to_be_bytes());
+    mac.finalize().into_bytes()[..16].to_vec()
+}
+
+fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; 16] {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).expect("HMAC 可以使用任意长度的密钥");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N], Error> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| Error::other(e.to_string()))?;
+    Ok(bytes)
+}
+
+fn filetime_to_unix(filetime: u64) -> u64 {
+    (filetime / 10_000_000).saturating_sub(FILETIME_UNIX_OFFSET)
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn from_utf16(bytes: &[u8]) -> String {
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn truncated() -> Error {
+    Error::new(ErrorKind::InvalidData, "SMB2 响应不完整")
+}
+
+fn slice_at(buf: &[u8], offset: usize, length: usize) -> Result<&[u8], Error> {
+    buf.get(offset..offset + length).ok_or_else(truncated)
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> Result<u16, Error> {
+    let bytes = slice_at(buf, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> Result<u32, Error> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(slice_at(buf, offset, 4)?);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> Result<u64, Error> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(slice_at(buf, offset, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// 将 NT 状态码转换为 io 错误
+/// 会话过期或共享断开视为连接中断, 可以重新连接后重试
+fn status_error(status: u32, path: &str) -> Error {
+    let kind = match status {
+        STATUS_NO_SUCH_FILE | STATUS_OBJECT_NAME_NOT_FOUND | STATUS_OBJECT_PATH_NOT_FOUND => {
+            ErrorKind::NotFound
+        }
+        STATUS_OBJECT_NAME_COLLISION => ErrorKind::AlreadyExists,
+        STATUS_ACCESS_DENIED | STATUS_LOGON_FAILURE => ErrorKind::PermissionDenied,
+        STATUS_DISK_FULL => ErrorKind::StorageFull,
+        STATUS_DIRECTORY_NOT_EMPTY => ErrorKind::DirectoryNotEmpty,
+        STATUS_NOT_A_DIRECTORY => ErrorKind::NotADirectory,
+        STATUS_FILE_IS_A_DIRECTORY => ErrorKind::IsADirectory,
+        STATUS_NETWORK_NAME_DELETED
+        | STATUS_USER_SESSION_DELETED
+        | STATUS_NETWORK_SESSION_EXPIRED => ErrorKind::ConnectionAborted,
+        _ => ErrorKind::Other,
+    };
+    Error::new(
+        kind,
+        format!("SMB 操作 {} 失败: 状态 0x{:08X}", path, status),
+    )
+}
+
+/// 服务器或共享要求加密传输, 暂不支持
+fn encryption_required(what: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("{}, 暂不支持加密的 SMB3 连接; {}", what, ENCRYPTION_HINT),
+    )
+}
+
+fn is_retryable(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::NotConnected
+            | ErrorKind::BrokenPipe
+            | ErrorKind::TimedOut
+            | ErrorKind::WouldBlock
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// 集成测试使用的共享, 由环境变量 RSBK_SMB_TEST_URL 指定(如 smb://rsbk@127.0.0.1:4450/share),
+    /// RSBK_SMB_TEST_CREDENTIALS 为凭据文件; 未设置时跳过
+    /// 每个测试使用地址下以测试名称及进程号命名的目录, 结束时删除
+    fn test_server(name: &str) -> Option<(SmbStorage, PathBuf)> {
+        let (Ok(url), Ok(credentials)) = (
+            std::env::var("RSBK_SMB_TEST_URL"),
+            std::env::var("RSBK_SMB_TEST_CREDENTIALS"),
+        ) else {
+            eprintln!("未设置 RSBK_SMB_TEST_URL 或 RSBK_SMB_TEST_CREDENTIALS, 跳过 SMB 集成测试");
+            return None;
+        };
+        let url = format!(
+            "{}/rsbk-{}-{}",
+            url.trim_end_matches('/'),
+            name,
+            std::process::id()
+        );
+        let url = SmbUrl::parse(&url).unwrap();
+        let root = PathBuf::from(&url.path);
+        let config = SmbConfig {
+            credentials,
+            timeout_seconds: default_timeout_seconds(),
+            retries: 0,
+            retry_delay_seconds: 0,
+        };
+        Some((SmbStorage::create(url, config).unwrap(), root))
+    }
+
+    /// 离开作用域时删除测试目录
+    struct Cleanup<'a>(&'a SmbStorage, &'a Path);
+
+    impl Drop for Cleanup<'_> {
+        fn drop(&mut self) {
+            let _ = self.0.delete(self.1);
+        }
+    }
+
+    #[test]
+    fn storage_operations_against_server() {
+        let Some((storage, root)) = test_server("storage") else {
+            return;
+        };
+        let _cleanup = Cleanup(&storage, &root);
+        let dir = root.join("dir");
+        storage.mkdir(&dir.join("sub")).unwrap();
+        assert!(storage.stat(&dir).unwrap().is_some_and(|m| m.is_dir()));
+
+        // 超过单次读写大小的文件分多次请求
+        let large: Vec<u8> = (0..IO_SIZE as usize * 3 + 17).map(|i| i as u8).collect();
+        storage.write(&dir.join("a"), &mut &b"one"[..]).unwrap();
+        storage.write(&dir.join("a"), &mut &large[..]).unwrap();
+        let meta = storage.stat(&dir.join("a")).unwrap().unwrap();
+        assert_eq!(
+            (meta.kind, meta.len),
+            (StorageKind::File, large.len() as u64)
+        );
+        assert_eq!(storage.read(&dir.join("a")).unwrap(), large);
+        storage.write(&dir.join("sub/b"), &mut &b"two"[..]).unwrap();
+        assert_eq!(
+            storage
+                .link(Link::Hard(&dir.join("a")), &dir.join("hard"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Unsupported
+        );
+
+        let mut entries: Vec<(String, StorageKind)> = storage
+            .list(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.meta.kind))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            vec![
+                ("a".to_string(), StorageKind::File),
+                ("sub".to_string(), StorageKind::Dir)
+            ]
+        );
+
+        let moved = root.join("moved");
+        storage.rename(&dir, &moved).unwrap();
+        assert!(storage.stat(&dir).unwrap().is_none());
+        storage
+            .rename(&moved.join("sub/b"), &moved.join("c"))
+            .unwrap();
+        assert_eq!(storage.read(&moved.join("c")).unwrap(), b"two");
+        let local = tempfile::tempdir().unwrap();
+        storage
+            .copy_to(&moved.join("a"), &local.path().join("a"))
+            .unwrap();
+        assert_eq!(fs::read(local.path().join("a")).unwrap(), large);
+        assert_eq!(
+            storage.digest(&moved.join("c")).unwrap(),
+            sha256::digest("two")
+        );
+
+        storage.delete(&moved.join("c")).unwrap();
+        assert!(storage.stat(&moved.join("c")).unwrap().is_none());
+        assert_eq!(
+            storage.read(&moved.join("c")).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        storage.delete(&moved).unwrap();
+        assert!(storage.stat(&moved).unwrap().is_none());
+    }
+
+    /// 要求加密传输的共享由 RSBK_SMB_TEST_ENCRYPTED_URL 指定(Samba: smb encrypt = required),
+    /// 凭据同样使用 RSBK_SMB_TEST_CREDENTIALS; 连接时报错并提示关闭强制加密
+    #[test]
+    fn encrypted_share_is_rejected() {
+        let (Ok(url), Ok(credentials)) = (
+            std::env::var("RSBK_SMB_TEST_ENCRYPTED_URL"),
+            std::env::var("RSBK_SMB_TEST_CREDENTIALS"),
+        ) else {
+            eprintln!("未设置 RSBK_SMB_TEST_ENCRYPTED_URL 或 RSBK_SMB_TEST_CREDENTIALS, 跳过 SMB 加密测试");
+            return;
+        };
+        let config = SmbConfig {
+            credentials,
+            timeout_seconds: default_timeout_seconds(),
+            retries: 0,
+            retry_delay_seconds: 0,
+        };
+        let storage = SmbStorage::create(SmbUrl::parse(&url).unwrap(), config).unwrap();
+        let e = storage.stat(Path::new("/")).unwrap_err();
+        // SMB 3 会话返回加密标志, SMB 2 会话被拒绝
+        assert!(
+            matches!(
+                e.kind(),
+                ErrorKind::Unsupported | ErrorKind::PermissionDenied
+            ),
+            "{}",
+            e
+        );
+        assert!(e.to_string().contains(ENCRYPTION_HINT), "{}", e);
+        assert!(storage.lock().unwrap().is_none());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// MS-NLMP 4.2.4 NTLMv2 Authentication 中的示例
+    #[test]
+    fn ntlm_v2_matches_ms_nlmp_vectors() {
+        let response_key = ntowf_v2("Password", "User", "Domain");
+        assert_eq!(
+            response_key.to_vec(),
+            hex("0c868a403bfd7a93a3001ef22ef02e3f")
+        );
+
+        let server_challenge = hex("0123456789abcdef");
+        let client_challenge = [0xaa; 8];
+        // MsvAvNbDomainName "Domain", MsvAvNbComputerName "Server", MsvAvEOL
+        let target_info =
+            hex("02000c0044006f006d00610069006e0001000c0053006500720076006500720000000000");
+        assert_eq!(
+            lm_v2_response(&response_key, &server_challenge, &client_challenge),
+            hex("86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa")
+        );
+        let (nt_response, session_key) = ntlm_v2_response(
+            &response_key,
+            &server_challenge,
+            &client_challenge,
+            0,
+            &target_info,
+        );
+        assert_eq!(nt_response[..16], hex("68cd0ab851e51c96aabc927bebef6a1c"));
+        assert_eq!(
+            nt_response[16..],
+            [
+                &hex("01010000000000000000000000000000aaaaaaaaaaaaaaaa00000000")[..],
+                &target_info,
+                &[0; 4],
+            ]
+            .concat()
+        );
+        assert_eq!(
+            session_key.to_vec(),
+            hex("8de40ccadbc14a82f15cb0ad0de95ca3")
+        );
+    }
+
+    /// Microsoft 开放规范博客 "Encryption in SMB 3.0: A protocol perspective" 中
+    /// 按 MS-SMB2 3.2.5.3.1 由会话密钥派生签名密钥的示例
+    #[test]
+    fn smb3_signing_key_matches_ms_smb2_vector() {
+        assert_eq!(
+            smb3_signing_key(&hex("b4546771b515f766a86735532dd6c4f0")),
+            hex("f773cd23c18fd1e08ee510cada7cf852")
+        );
+    }
+
+    #[test]
+    fn verify_signature_rejects_tampered_response() {
+        for dialect in [SMB_2_1, SMB_3_0_2] {
+            let key = hex("f773cd23c18fd1e08ee510cada7cf852");
+            let mut response = b"\xfeSMB".to_vec();
+            response.resize(HEADER_SIZE, 0);
+            response[16] = FLAGS_SIGNED as u8;
+            response.extend_from_slice(b"body");
+            let signature = sign_message(dialect, &key, &response).unwrap();
+            response[48..HEADER_SIZE].copy_from_slice(&signature);
+            verify_signature(dialect, &key, &response).unwrap();
+
+            let last = response.len() - 1;
+            response[last] ^= 1;
+            assert_eq!(
+                verify_signature(dialect, &key, &response)
+                    .unwrap_err()
+                    .kind(),
+                ErrorKind::InvalidData
+            );
+        }
+    }
+}
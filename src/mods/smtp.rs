@@ -0,0 +1,451 @@
+use super::notify::{Notification, NotifyEvent};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Local;
+use log::warn;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 连接 SMTP 服务器的加密方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SmtpSecurity {
+    /// 明文连接后使用 STARTTLS 升级为加密连接, 服务器不支持时拒绝发送, 默认端口 587
+    #[default]
+    StartTls,
+    /// 直接使用 TLS 连接, 默认端口 465
+    Tls,
+    /// 不加密, 默认端口 25, 仅用于本机或内网的邮件中继
+    None,
+}
+
+/// 邮件通知的配置
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmtpConfig {
+    /// SMTP 服务器地址
+    pub host: String,
+    /// SMTP 服务器端口, 不填写时按 security 取默认端口
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    /// 登录用户名, 不填写时不认证
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// 校验服务器证书的 CA 证书(PEM), 不填写时使用内置的公共根证书
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<String>,
+    /// 发件人地址
+    pub from: String,
+    /// 收件人地址
+    pub to: Vec<String>,
+    /// 发送邮件的事件
+    #[serde(default = "default_events")]
+    pub events: Vec<NotifyEvent>,
+    /// 连接及读写的超时秒数
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+}
+
+fn default_events() -> Vec<NotifyEvent> {
+    vec![
+        NotifyEvent::Failure,
+        NotifyEvent::Partial,
+        NotifyEvent::Recovery,
+        NotifyEvent::Stale,
+    ]
+}
+
+fn default_timeout_seconds() -> u64 {
+    30
+}
+
+/// SMTP 连接, STARTTLS 前为明文连接
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.read(buf),
+            Stream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(s) => s.write(buf),
+            Stream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(s) => s.flush(),
+            Stream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+struct Session {
+    reader: BufReader<Stream>,
+}
+
+impl Session {
+    fn response(&mut self, expect: u16) -> Result<Vec<String>, Error> {
+        read_response(&mut self.reader, expect)
+    }
+
+    fn command(&mut self, command: &str, expect: u16) -> Result<Vec<String>, Error> {
+        let stream = self.reader.get_mut();
+        stream.write_all(command.as_bytes())?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        self.response(expect)
+    }
+
+    /// 发送 EHLO, 返回服务器支持的扩展(大写)
+    fn ehlo(&mut self) -> Result<Vec<String>, Error> {
+        let lines = self.command("EHLO rsbk", 250)?;
+        Ok(lines
+            .iter()
+            .skip(1)
+            .map(|line| line.get(4..).unwrap_or_default().to_uppercase())
+            .collect())
+    }
+}
+
+/// 读取一条(可能多行的)应答, 应答码与 expect 不符时返回错误
+/// 多行应答除最后一行外, 应答码之后为 '-'
+fn read_response(reader: &mut impl BufRead, expect: u16) -> Result<Vec<String>, Error> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "SMTP 服务器关闭了连接",
+            ));
+        }
+        let line = line.trim_end().to_string();
+        let code = line.get(..3).and_then(|c| c.parse::<u16>().ok());
+        let last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line);
+        if last {
+            if code != Some(expect) {
+                return Err(Error::other(format!(
+                    "SMTP 服务器返回错误: {}",
+                    lines.join(" | ")
+                )));
+            }
+            return Ok(lines);
+        }
+    }
+}
+
+/// 地址中不能包含换行, 否则可以在邮件头或 SMTP 命令中注入内容
+fn check_address(address: &str) -> Result<(), Error> {
+    if address.is_empty() || address.contains(['\r', '\n']) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("无效的邮件地址: {:?}", address),
+        ));
+    }
+    Ok(())
+}
+
+/// 发送一封通知邮件
+pub fn send(config: &SmtpConfig, notification: &Notification) -> Result<(), Error> {
+    if config.to.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "未填写收件人地址"));
+    }
+    check_address(&config.from)?;
+    for to in &config.to {
+        check_address(to)?;
+    }
+    let timeout = Duration::from_secs(config.timeout_seconds.max(1));
+    let port = config.port.unwrap_or(match config.security {
+        SmtpSecurity::StartTls => 587,
+        SmtpSecurity::Tls => 465,
+        SmtpSecurity::None => 25,
+    });
+    let address = (config.host.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("无法解析 SMTP 服务器地址 {}", config.host),
+            )
+        })?;
+    let tcp = TcpStream::connect_timeout(&address, timeout).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("无法连接 SMTP 服务器 {}:{}:{}", config.host, port, e),
+        )
+    })?;
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+
+    let stream = match config.security {
+        SmtpSecurity::Tls => Stream::Tls(Box::new(tls(config, tcp)?)),
+        _ => Stream::Plain(tcp),
+    };
+    let mut session = Session {
+        reader: BufReader::new(stream),
+    };
+    session.response(220)?;
+    let mut extensions = session.ehlo()?;
+    if config.security == SmtpSecurity::StartTls {
+        if !extensions.iter().any(|e| e == "STARTTLS") {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("SMTP 服务器 {} 不支持 STARTTLS", config.host),
+            ));
+        }
+        session.command("STARTTLS", 220)?;
+        let Stream::Plain(tcp) = session.reader.into_inner() else {
+            unreachable!("STARTTLS 前为明文连接");
+        };
+        session = Session {
+            reader: BufReader::new(Stream::Tls(Box::new(tls(config, tcp)?))),
+        };
+        extensions = session.ehlo()?;
+    }
+
+    if let Some(username) = &config.username {
+        if config.security == SmtpSecurity::None {
+            warn!("SMTP 服务器 {} 未加密, 登录密码以明文发送", config.host);
+        }
+        let password = config.password.clone().unwrap_or_default();
+        let mechanisms: Vec<&str> = extensions
+            .iter()
+            .filter_map(|e| e.strip_prefix("AUTH "))
+            .flat_map(|m| m.split_whitespace())
+            .collect();
+        if mechanisms.contains(&"PLAIN") || !mechanisms.contains(&"LOGIN") {
+            let credentials = STANDARD.encode(format!("\0{}\0{}", username, password));
+            session.command(&format!("AUTH PLAIN {}", credentials), 235)?;
+        } else {
+            session.command("AUTH LOGIN", 334)?;
+            session.command(&STANDARD.encode(username), 334)?;
+            session.command(&STANDARD.encode(password), 235)?;
+        }
+    }
+
+    session.command(&format!("MAIL FROM:<{}>", config.from), 250)?;
+    for to in &config.to {
+        session.command(&format!("RCPT TO:<{}>", to), 250)?;
+    }
+    session.command("DATA", 354)?;
+    session.command(
+        &format!("{}\r\n.", dot_stuff(&message(config, notification))),
+        250,
+    )?;
+    let _ = session.command("QUIT", 221);
+    Ok(())
+}
+
+/// 建立 TLS 连接, 校验服务器证书及主机名
+fn tls(
+    config: &SmtpConfig,
+    tcp: TcpStream,
+) -> Result<StreamOwned<ClientConnection, TcpStream>, Error> {
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(ca_file) => {
+            for cert in CertificateDer::pem_file_iter(ca_file)
+                .map_err(|e| Error::other(format!("无法读取 CA 证书 {}:{}", ca_file, e)))?
+            {
+                let cert =
+                    cert.map_err(|e| Error::other(format!("无法读取 CA 证书 {}:{}", ca_file, e)))?;
+                roots
+                    .add(cert)
+                    .map_err(|e| Error::other(format!("无效的 CA 证书 {}:{}", ca_file, e)))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let tls_config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(Error::other)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let server_name = ServerName::try_from(config.host.clone()).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("无效的 SMTP 服务器地址 {}:{}", config.host, e),
+        )
+    })?;
+    let connection =
+        ClientConnection::new(Arc::new(tls_config), server_name).map_err(Error::other)?;
+    let mut stream = StreamOwned::new(connection, tcp);
+    // 立即完成握手, 证书错误在此处报告
+    while stream.conn.is_handshaking() {
+        stream
+            .conn
+            .complete_io(&mut stream.sock)
+            .map_err(|e| Error::new(e.kind(), format!("TLS 握手失败:{}", e)))?;
+    }
+    Ok(stream)
+}
+
+/// 以 '.' 开头的行前再加一个 '.', 避免被当作 DATA 的结束标记(RFC 5321 4.5.2)
+fn dot_stuff(message: &str) -> String {
+    message
+        .split("\r\n")
+        .map(|line| {
+            if line.starts_with('.') {
+                format!(".{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+/// 组装邮件, 标题按 RFC 2047 编码, 正文为 base64 编码的 UTF-8 纯文本
+fn message(config: &SmtpConfig, notification: &Notification) -> String {
+    let body = STANDARD.encode(notification.body.replace('\n', "\r\n"));
+    let lines: Vec<&str> = body
+        .as_bytes()
+        .chunks(76)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect();
+    let now = Local::now();
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: =?UTF-8?B?{}?=\r\nDate: {}\r\nMessage-ID: <rsbk.{}.{}@{}>\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=UTF-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}",
+        config.from,
+        config.to.join(", "),
+        STANDARD.encode(&notification.subject),
+        now.to_rfc2822(),
+        now.timestamp_nanos_opt().unwrap_or_default(),
+        &sha256::digest(notification.task_name.as_str())[..8],
+        config.from.rsplit('@').next().unwrap_or("localhost"),
+        lines.join("\r\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn config() -> SmtpConfig {
+        SmtpConfig {
+            host: "smtp.example.com".to_string(),
+            port: None,
+            security: SmtpSecurity::StartTls,
+            username: None,
+            password: None,
+            ca_file: None,
+            from: "rsbk@example.com".to_string(),
+            to: vec!["ops@example.com".to_string(), "dev@example.com".to_string()],
+            events: default_events(),
+            timeout_seconds: 1,
+        }
+    }
+
+    fn notification(body: &str) -> Notification {
+        Notification {
+            event: NotifyEvent::Failure,
+            task_name: "任务".to_string(),
+            subject: "[rsbk] 任务: 备份失败".to_string(),
+            body: body.to_string(),
+            stats: None,
+        }
+    }
+
+    #[test]
+    fn message_encodes_subject_and_body() {
+        let body = "第一行\n第二行\n".repeat(20);
+        let message = message(&config(), &notification(&body));
+        let (headers, encoded) = message.split_once("\r\n\r\n").unwrap();
+        let headers: Vec<&str> = headers.split("\r\n").collect();
+        assert!(headers.contains(&"From: rsbk@example.com"));
+        assert!(headers.contains(&"To: ops@example.com, dev@example.com"));
+        assert!(headers.contains(&"Content-Transfer-Encoding: base64"));
+        let subject = headers
+            .iter()
+            .find_map(|h| h.strip_prefix("Subject: =?UTF-8?B?"))
+            .and_then(|s| s.strip_suffix("?="))
+            .unwrap();
+        assert_eq!(
+            STANDARD.decode(subject).unwrap(),
+            "[rsbk] 任务: 备份失败".as_bytes()
+        );
+        assert!(encoded.split("\r\n").all(|line| line.len() <= 76));
+        let decoded = STANDARD.decode(encoded.replace("\r\n", "")).unwrap();
+        assert_eq!(
+            String::from_utf8(decoded).unwrap(),
+            body.replace('\n', "\r\n")
+        );
+    }
+
+    #[test]
+    fn dot_stuffing_escapes_leading_dots() {
+        assert_eq!(dot_stuff("a\r\n.\r\n..b\r\nc.d"), "a\r\n..\r\n...b\r\nc.d");
+        assert_eq!(dot_stuff(".start"), "..start");
+    }
+
+    #[test]
+    fn addresses_with_line_breaks_are_rejected() {
+        let mut injected = config();
+        injected.from = "rsbk@example.com>\r\nRCPT TO:<victim@example.com".to_string();
+        let e = send(&injected, &notification("")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        let mut injected = config();
+        injected
+            .to
+            .push("ops@example.com\nBcc: victim@example.com".to_string());
+        let e = send(&injected, &notification("")).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn multi_line_response_is_read_to_the_last_line() {
+        let mut reader = Cursor::new(
+            b"250-smtp.example.com\r\n250-STARTTLS\r\n250-AUTH PLAIN LOGIN\r\n250 SIZE 1000\r\n220 next\r\n"
+                .to_vec(),
+        );
+        let lines = read_response(&mut reader, 250).unwrap();
+        assert_eq!(
+            lines,
+            [
+                "250-smtp.example.com",
+                "250-STARTTLS",
+                "250-AUTH PLAIN LOGIN",
+                "250 SIZE 1000"
+            ]
+        );
+        // 下一条应答不受影响
+        assert_eq!(read_response(&mut reader, 220).unwrap(), ["220 next"]);
+    }
+
+    #[test]
+    fn unexpected_response_code_is_an_error() {
+        let mut reader = Cursor::new(b"535-5.7.8 Bad\r\n535 5.7.8 credentials\r\n".to_vec());
+        let e = read_response(&mut reader, 235).unwrap_err();
+        assert!(e
+            .to_string()
+            .contains("535-5.7.8 Bad | 535 5.7.8 credentials"));
+
+        let mut reader = Cursor::new(b"250-partial\r\n".to_vec());
+        let e = read_response(&mut reader, 250).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,856 @@
+use super::base_bk_option::{SourceRoot, SourceSet};
+use super::bk_config::BackupConfig;
+use super::quota::ByteSize;
+use super::remote;
+use chrono::Local;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// 快照的类型
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SnapshotKind {
+    /// 按源目录所在的文件系统自动选择: btrfs 及 zfs 使用其自身的快照, 其他文件系统位于 LVM 逻辑卷上时使用 LVM 快照
+    #[default]
+    Auto,
+    Btrfs,
+    Lvm,
+    Zfs,
+}
+
+/// 文件系统快照配置
+/// 备份前为源目录所在的文件系统创建快照, 从快照中读取源文件, 备份结束后(包括出错)删除快照
+/// 仅支持 Linux, 需要 root 权限及对应的 btrfs、lvcreate/lvremove 或 zfs 命令
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnapshotConfig {
+    #[serde(default)]
+    pub kind: SnapshotKind,
+    /// LVM 快照的写时复制空间, 快照存在期间源逻辑卷的写入超过该大小时快照失效
+    #[serde(default = "default_lvm_size")]
+    pub lvm_size: ByteSize,
+    /// 存放快照的目录, 不能位于源目录内, 否则监听模式会监听到创建快照产生的变动
+    /// 不填写时位于程序目录的 BackupConfig/snapshot 下; btrfs 快照只能创建在同一文件系统中,
+    /// BackupConfig 不在源目录所在的 btrfs 文件系统上时改为该文件系统挂载点下的 .rsbk-snapshots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub directory: Option<String>,
+}
+
+fn default_lvm_size() -> ByteSize {
+    ByteSize(1 << 30)
+}
+
+/// 本次备份创建的一个快照
+enum Snapshot {
+    /// 只读的 btrfs 子卷快照
+    Btrfs { path: PathBuf },
+    /// LVM 快照卷(vg/lv)及其只读挂载点
+    Lvm {
+        volume: String,
+        mount: Option<PathBuf>,
+    },
+    /// zfs 快照(dataset@name)
+    Zfs { name: String },
+}
+
+/// 本次备份使用的快照, drop 时删除所有快照
+pub struct SnapshotSet {
+    task_name: String,
+    /// 读取源文件的目录, 本地源目录替换为快照中的对应目录, 远程地址、数据库及命令源保持不变
+    pub sources: SourceSet,
+    /// 原源目录及其在快照中的对应目录
+    mapping: Vec<(PathBuf, PathBuf)>,
+    snapshots: Vec<Snapshot>,
+}
+
+impl SnapshotSet {
+    /// 按任务的 snapshot 配置为本地源目录创建快照, 没有配置时原样使用源目录
+    /// 位于同一子卷、逻辑卷或数据集上的源目录共用一个快照
+    /// 任一快照创建失败时删除已创建的快照并返回错误
+    pub fn create(
+        task_name: &str,
+        config: &BackupConfig,
+        sources: SourceSet,
+    ) -> Result<SnapshotSet, Error> {
+        let mut set = SnapshotSet {
+            task_name: task_name.to_string(),
+            sources: SourceSet { roots: Vec::new() },
+            mapping: Vec::new(),
+            snapshots: Vec::new(),
+        };
+        let Some(snapshot_config) = &config.snapshot else {
+            set.sources = sources;
+            return Ok(set);
+        };
+        // 本地源目录的实际路径, 存放快照的目录不能位于其中
+        let mut lives = Vec::new();
+        for (root, source) in sources.roots.iter().zip(config.sources()) {
+            if source.is_command() || remote::is_remote(&source.path) {
+                lives.push(None);
+                continue;
+            }
+            let live = fs::canonicalize(&root.path).map_err(|e| {
+                Error::new(
+                    e.kind(),
+                    format!("无法读取源目录 {}:{}", root.path.display(), e),
+                )
+            })?;
+            lives.push(Some(live));
+        }
+        let live_sources: Vec<PathBuf> = lives.iter().flatten().cloned().collect();
+        // 快照的来源(子卷、设备或数据集) -> (来源在原文件系统中的目录, 快照中的对应目录)
+        let mut created: HashMap<String, (PathBuf, PathBuf)> = HashMap::new();
+        for (root, live) in sources.roots.into_iter().zip(lives) {
+            let Some(live) = live else {
+                set.sources.roots.push(root);
+                continue;
+            };
+            let origin = linux::locate(snapshot_config.kind, &live)?;
+            let (origin_dir, snapshot_dir) = match created.get(&origin.key) {
+                Some(created) => created.clone(),
+                None => {
+                    let snapshot_dir = set.snapshot(snapshot_config, &origin, &live_sources)?;
+                    created.insert(
+                        origin.key.clone(),
+                        (origin.dir.clone(), snapshot_dir.clone()),
+                    );
+                    (origin.dir, snapshot_dir)
+                }
+            };
+            let path = snapshot_dir.join(live.strip_prefix(&origin_dir).unwrap_or(Path::new("")));
+            info!(
+                "{}:从快照 {} 读取源目录 {}",
+                task_name,
+                path.display(),
+                root.path.display()
+            );
+            set.mapping.push((root.path.clone(), path.clone()));
+            set.sources.roots.push(SourceRoot {
+                path,
+                folder: root.folder,
+                storage: None,
+            });
+        }
+        Ok(set)
+    }
+
+    /// 将源目录中的路径转换为快照中的对应路径, 不在快照的源目录内时原样返回
+    pub fn translate(&self, path: &str) -> String {
+        let path = Path::new(path);
+        self.mapping
+            .iter()
+            .filter(|(live, _)| path.starts_with(live))
+            .max_by_key(|(live, _)| live.components().count())
+            .map(|(live, snapshot)| {
+                snapshot
+                    .join(path.strip_prefix(live).unwrap_or(Path::new("")))
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .unwrap_or_else(|| path.to_string_lossy().to_string())
+    }
+
+    /// 创建一个快照, 返回快照中与 origin.dir 对应的目录
+    fn snapshot(
+        &mut self,
+        config: &SnapshotConfig,
+        origin: &Origin,
+        live_sources: &[PathBuf],
+    ) -> Result<PathBuf, Error> {
+        let name = format!(
+            "rsbk-{}-{}-{}",
+            &sha256::digest(self.task_name.as_str())[..8],
+            Local::now().format("%Y%m%d%H%M%S"),
+            self.snapshots.len()
+        );
+        let snapshot_dir = match origin.kind {
+            SnapshotKind::Btrfs => {
+                let dir = btrfs_directory(config, origin, live_sources)?;
+                let path = dir.join(&name);
+                run(
+                    "btrfs",
+                    &[
+                        "subvolume".as_ref(),
+                        "snapshot".as_ref(),
+                        "-r".as_ref(),
+                        origin.dir.as_os_str(),
+                        path.as_os_str(),
+                    ],
+                )?;
+                self.snapshots.push(Snapshot::Btrfs { path: path.clone() });
+                path
+            }
+            SnapshotKind::Lvm => {
+                let (vg, _) = origin.key.split_once('/').unwrap_or_default();
+                let volume = format!("{}/{}", vg, name);
+                let size = format!("{}k", config.lvm_size.0.div_ceil(1024).max(1));
+                run(
+                    "lvcreate",
+                    &[
+                        "--snapshot".as_ref(),
+                        "--size".as_ref(),
+                        size.as_ref(),
+                        "--name".as_ref(),
+                        name.as_ref(),
+                        origin.key.as_ref(),
+                    ],
+                )?;
+                self.snapshots.push(Snapshot::Lvm {
+                    volume: volume.clone(),
+                    mount: None,
+                });
+                let dir = snapshot_directory(config, live_sources)?;
+                let mount = dir.join(&name);
+                fs::create_dir(&mount)?;
+                // xfs 拒绝挂载与已挂载的文件系统 UUID 相同的快照
+                let options = if origin.fstype == "xfs" {
+                    "ro,nouuid"
+                } else {
+                    "ro"
+                };
+                let device = format!("/dev/{}", volume);
+                let mounted = run(
+                    "mount",
+                    &[
+                        "-o".as_ref(),
+                        options.as_ref(),
+                        device.as_ref(),
+                        mount.as_os_str(),
+                    ],
+                );
+                if let Err(e) = mounted {
+                    let _ = fs::remove_dir(&mount);
+                    return Err(e);
+                }
+                if let Some(Snapshot::Lvm { mount: m, .. }) = self.snapshots.last_mut() {
+                    *m = Some(mount.clone());
+                }
+                // 挂载点不是文件系统根目录(如绑定挂载)时, 快照中的对应目录为其根目录下的相同位置
+                mount.join(origin.root.strip_prefix("/").unwrap_or(&origin.root))
+            }
+            SnapshotKind::Zfs => {
+                let snapshot = format!("{}@{}", origin.key, name);
+                run("zfs", &["snapshot".as_ref(), snapshot.as_ref()])?;
+                self.snapshots.push(Snapshot::Zfs { name: snapshot });
+                origin.dir.join(".zfs").join("snapshot").join(&name)
+            }
+            SnapshotKind::Auto => unreachable!("快照类型已由 locate 确定"),
+        };
+        info!(
+            "{}:已创建{:?}快照 {}",
+            self.task_name,
+            origin.kind,
+            snapshot_dir.display()
+        );
+        Ok(snapshot_dir)
+    }
+}
+
+impl Drop for SnapshotSet {
+    fn drop(&mut self) {
+        for snapshot in self.snapshots.drain(..).rev() {
+            let (label, result) = match &snapshot {
+                Snapshot::Btrfs { path } => (
+                    path.to_string_lossy().to_string(),
+                    run(
+                        "btrfs",
+                        &["subvolume".as_ref(), "delete".as_ref(), path.as_os_str()],
+                    ),
+                ),
+                Snapshot::Lvm { volume, mount } => {
+                    let unmounted = match mount {
+                        Some(mount) => run("umount", &[mount.as_os_str()])
+                            .and_then(|_| fs::remove_dir(mount).map(|_| String::new())),
+                        None => Ok(String::new()),
+                    };
+                    (
+                        volume.clone(),
+                        unmounted.and_then(|_| run("lvremove", &["-f".as_ref(), volume.as_ref()])),
+                    )
+                }
+                Snapshot::Zfs { name } => (
+                    name.clone(),
+                    run("zfs", &["destroy".as_ref(), name.as_ref()]),
+                ),
+            };
+            match result {
+                Ok(_) => info!("{}:已删除快照 {}", self.task_name, label),
+                Err(e) => error!(
+                    "{}:删除快照 {} 时发生错误, 请手动删除:{}",
+                    self.task_name, label, e
+                ),
+            }
+        }
+    }
+}
+
+/// 存放快照的目录(已创建, 为实际路径), 位于源目录内时返回错误
+fn snapshot_directory(config: &SnapshotConfig, live_sources: &[PathBuf]) -> Result<PathBuf, Error> {
+    let dir = match &config.directory {
+        Some(directory) => PathBuf::from(directory),
+        None => default_directory(),
+    };
+    fs::create_dir_all(&dir)?;
+    let dir = fs::canonicalize(&dir)?;
+    check_outside(&dir, live_sources)?;
+    Ok(dir)
+}
+
+fn default_directory() -> PathBuf {
+    PathBuf::from("BackupConfig").join("snapshot")
+}
+
+fn check_outside(dir: &Path, live_sources: &[PathBuf]) -> Result<(), Error> {
+    match live_sources.iter().find(|source| dir.starts_with(source)) {
+        Some(source) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "存放快照的目录 {} 位于源目录 {} 内, 请在 snapshot.directory 中填写源目录之外的目录",
+                dir.display(),
+                source.display()
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// 存放 btrfs 快照的目录
+/// 快照只能创建在同一文件系统中: 未填写 directory 且 BackupConfig/snapshot 不在该文件系统上时,
+/// 改用文件系统挂载点下的 .rsbk-snapshots
+fn btrfs_directory(
+    config: &SnapshotConfig,
+    origin: &Origin,
+    live_sources: &[PathBuf],
+) -> Result<PathBuf, Error> {
+    if config.directory.is_some() {
+        return snapshot_directory(config, live_sources);
+    }
+    let default = default_directory();
+    fs::create_dir_all(&default)?;
+    let default = fs::canonicalize(&default)?;
+    let dir = if linux::same_filesystem(&default, origin)? {
+        default
+    } else {
+        let dir = origin.mount_point.join(".rsbk-snapshots");
+        fs::create_dir_all(&dir)?;
+        dir
+    };
+    check_outside(&dir, live_sources)?;
+    Ok(dir)
+}
+
+/// 源目录所在的快照来源
+struct Origin {
+    kind: SnapshotKind,
+    /// 同一来源的源目录共用一个快照: btrfs 为子卷目录, LVM 为 vg/lv, zfs 为数据集
+    key: String,
+    /// 来源在当前文件系统中的目录: btrfs 为子卷目录, LVM 及 zfs 为挂载点
+    dir: PathBuf,
+    /// 挂载点对应的文件系统内的目录
+    root: PathBuf,
+    /// 源目录所在的挂载点
+    mount_point: PathBuf,
+    fstype: String,
+    /// 文件系统的来源(设备或数据集)
+    device: String,
+}
+
+/// 执行快照相关的命令, 返回标准输出; 命令不存在或退出码不为 0 时返回包含错误输出的错误
+fn run(program: &str, args: &[&std::ffi::OsStr]) -> Result<String, Error> {
+    let output = Command::new(program).args(args).output().map_err(|e| {
+        if e.kind() == ErrorKind::NotFound {
+            Error::new(e.kind(), format!("找不到命令 {}, 请先安装", program))
+        } else {
+            Error::new(e.kind(), format!("无法执行 {}:{}", program, e))
+        }
+    })?;
+    if !output.status.success() {
+        let args: Vec<String> = args
+            .iter()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect();
+        return Err(Error::other(format!(
+            "{} {} 退出状态为 {}: {}",
+            program,
+            args.join(" "),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+mod linux {
+    use super::{Origin, SnapshotKind};
+    use std::io::{Error, ErrorKind};
+    use std::path::Path;
+
+    pub fn locate(_kind: SnapshotKind, _path: &Path) -> Result<Origin, Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "文件系统快照仅支持 Linux",
+        ))
+    }
+
+    pub fn same_filesystem(_path: &Path, _origin: &Origin) -> Result<bool, Error> {
+        Ok(false)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{run, Origin, SnapshotKind};
+    use std::fs;
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+
+    /// btrfs 子卷根目录的 inode 号
+    const BTRFS_SUBVOLUME_INODE: u64 = 256;
+
+    /// /proc/self/mountinfo 中的一个挂载点
+    struct Mount {
+        root: PathBuf,
+        mount_point: PathBuf,
+        fstype: String,
+        source: String,
+    }
+
+    /// 确定源目录所在的快照来源, 文件系统不支持所需的快照类型时返回错误
+    pub fn locate(kind: SnapshotKind, path: &Path) -> Result<Origin, Error> {
+        let mount = find_mount(path)?;
+        let unsupported = |kind: &str| {
+            Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "源目录 {} 位于 {} 上的 {} 文件系统({}), 不支持{}快照",
+                    path.display(),
+                    mount.mount_point.display(),
+                    mount.fstype,
+                    mount.source,
+                    kind
+                ),
+            )
+        };
+        let kind = match kind {
+            SnapshotKind::Auto => match mount.fstype.as_str() {
+                "btrfs" => SnapshotKind::Btrfs,
+                "zfs" => SnapshotKind::Zfs,
+                _ if lvm_volume(&mount.source).is_ok() => SnapshotKind::Lvm,
+                _ => return Err(unsupported(" btrfs、LVM 或 zfs ")),
+            },
+            kind => kind,
+        };
+        match kind {
+            SnapshotKind::Btrfs => {
+                if mount.fstype != "btrfs" {
+                    return Err(unsupported(" btrfs "));
+                }
+                let subvolume = subvolume_root(path, &mount.mount_point)?;
+                Ok(Origin {
+                    kind,
+                    key: subvolume.to_string_lossy().to_string(),
+                    dir: subvolume,
+                    root: mount.root,
+                    mount_point: mount.mount_point,
+                    fstype: mount.fstype,
+                    device: mount.source,
+                })
+            }
+            SnapshotKind::Lvm => {
+                let volume = lvm_volume(&mount.source).map_err(|e| {
+                    Error::new(
+                        ErrorKind::Unsupported,
+                        format!("{}, {}", unsupported(" LVM "), e),
+                    )
+                })?;
+                Ok(Origin {
+                    kind,
+                    key: volume,
+                    dir: mount.mount_point.clone(),
+                    root: mount.root,
+                    mount_point: mount.mount_point,
+                    fstype: mount.fstype,
+                    device: mount.source,
+                })
+            }
+            SnapshotKind::Zfs => {
+                if mount.fstype != "zfs" {
+                    return Err(unsupported(" zfs "));
+                }
+                Ok(Origin {
+                    kind,
+                    key: mount.source.clone(),
+                    dir: mount.mount_point.clone(),
+                    root: mount.root,
+                    mount_point: mount.mount_point,
+                    fstype: mount.fstype,
+                    device: mount.source,
+                })
+            }
+            SnapshotKind::Auto => unreachable!(),
+        }
+    }
+
+    /// path 是否与 origin 位于同一文件系统(类型及来源设备相同)
+    pub fn same_filesystem(path: &Path, origin: &Origin) -> Result<bool, Error> {
+        let mount = find_mount(path)?;
+        Ok(mount.fstype == origin.fstype && mount.source == origin.device)
+    }
+
+    /// 路径所在的挂载点, 取最深的挂载点, 同一位置多次挂载时取最后一次
+    fn find_mount(path: &Path) -> Result<Mount, Error> {
+        let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+        let mut best: Option<Mount> = None;
+        for line in mountinfo.lines() {
+            let Some(mount) = parse_mountinfo(line) else {
+                continue;
+            };
+            if !path.starts_with(&mount.mount_point) {
+                continue;
+            }
+            let depth = mount.mount_point.components().count();
+            if best
+                .as_ref()
+                .is_none_or(|b| depth >= b.mount_point.components().count())
+            {
+                best = Some(mount);
+            }
+        }
+        best.ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("找不到源目录 {} 所在的挂载点", path.display()),
+            )
+        })
+    }
+
+    /// 格式: id parent major:minor root mount_point options [optional...] - fstype source super_options
+    fn parse_mountinfo(line: &str) -> Option<Mount> {
+        let (left, right) = line.split_once(" - ")?;
+        let fields: Vec<&str> = left.split(' ').collect();
+        let mut right = right.split(' ');
+        Some(Mount {
+            root: PathBuf::from(unescape(fields.get(3)?)),
+            mount_point: PathBuf::from(unescape(fields.get(4)?)),
+            fstype: right.next()?.to_string(),
+            source: unescape(right.next()?),
+        })
+    }
+
+    /// mountinfo 中的空格、制表符、换行及反斜杠以 \ooo 八进制转义
+    fn unescape(field: &str) -> String {
+        let bytes = field.as_bytes();
+        let mut result = Vec::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+                let digits = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or_default();
+                if let Ok(value) = u8::from_str_radix(digits, 8) {
+                    result.push(value);
+                    i += 4;
+                    continue;
+                }
+            }
+            result.push(bytes[i]);
+            i += 1;
+        }
+        String::from_utf8_lossy(&result).to_string()
+    }
+
+    /// 路径所在的 btrfs 子卷的根目录
+    fn subvolume_root(path: &Path, mount_point: &Path) -> Result<PathBuf, Error> {
+        for ancestor in path.ancestors() {
+            if fs::metadata(ancestor)?.ino() == BTRFS_SUBVOLUME_INODE {
+                return Ok(ancestor.to_path_buf());
+            }
+            if ancestor == mount_point {
+                break;
+            }
+        }
+        Err(Error::new(
+            ErrorKind::NotFound,
+            format!("找不到源目录 {} 所在的 btrfs 子卷", path.display()),
+        ))
+    }
+
+    /// 块设备对应的 LVM 逻辑卷(vg/lv), 不是逻辑卷时返回错误
+    fn lvm_volume(device: &str) -> Result<String, Error> {
+        if !device.starts_with("/dev/") {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} 不是块设备", device),
+            ));
+        }
+        let output = run(
+            "lvs",
+            &[
+                "--noheadings".as_ref(),
+                "--separator".as_ref(),
+                "/".as_ref(),
+                "-o".as_ref(),
+                "vg_name,lv_name".as_ref(),
+                device.as_ref(),
+            ],
+        )?;
+        let volume = output.trim();
+        if volume.is_empty() || !volume.contains('/') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} 不是 LVM 逻辑卷", device),
+            ));
+        }
+        Ok(volume.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mountinfo_line_is_parsed() {
+            let mount = parse_mountinfo(
+                "36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue",
+            )
+            .unwrap();
+            assert_eq!(mount.root, Path::new("/mnt1"));
+            assert_eq!(mount.mount_point, Path::new("/mnt2"));
+            assert_eq!(mount.fstype, "ext3");
+            assert_eq!(mount.source, "/dev/root");
+        }
+
+        #[test]
+        fn mountinfo_without_optional_fields() {
+            let mount = parse_mountinfo(
+                "29 1 0:26 /@home /home rw,relatime - btrfs /dev/nvme0n1p2 rw,ssd,subvol=/@home",
+            )
+            .unwrap();
+            assert_eq!(mount.root, Path::new("/@home"));
+            assert_eq!(mount.mount_point, Path::new("/home"));
+            assert_eq!(mount.fstype, "btrfs");
+            assert_eq!(mount.source, "/dev/nvme0n1p2");
+        }
+
+        #[test]
+        fn mountinfo_escapes_are_decoded() {
+            let mount = parse_mountinfo(
+                "40 29 0:45 / /media/usb\\040disk\\011x rw shared:7 - vfat /dev/sdb\\1341 rw",
+            )
+            .unwrap();
+            assert_eq!(mount.mount_point, Path::new("/media/usb disk\tx"));
+            assert_eq!(mount.source, "/dev/sdb\\1");
+            // 不完整或非八进制的转义原样保留
+            assert_eq!(unescape("a\\09"), "a\\09");
+            assert_eq!(unescape("a\\"), "a\\");
+        }
+
+        #[test]
+        fn malformed_mountinfo_is_skipped() {
+            assert!(parse_mountinfo("").is_none());
+            assert!(parse_mountinfo("36 35 98:0 /mnt1 /mnt2 rw").is_none());
+            assert!(parse_mountinfo("36 35 98:0 / - ext4").is_none());
+        }
+
+        #[test]
+        fn root_filesystem_is_found() {
+            let mount = find_mount(Path::new("/")).unwrap();
+            assert_eq!(mount.mount_point, Path::new("/"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_directory_must_be_outside_sources() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = fs::canonicalize(dir.path()).unwrap().join("source");
+        fs::create_dir(&source).unwrap();
+        let inside = SnapshotConfig {
+            kind: SnapshotKind::Btrfs,
+            lvm_size: default_lvm_size(),
+            directory: Some(source.join(".rsbk-snapshots").to_string_lossy().to_string()),
+        };
+        let e = snapshot_directory(&inside, std::slice::from_ref(&source)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+
+        let outside = SnapshotConfig {
+            directory: Some(dir.path().join("snapshots").to_string_lossy().to_string()),
+            ..inside
+        };
+        let created = snapshot_directory(&outside, &[source]).unwrap();
+        assert!(created.is_dir());
+    }
+
+    #[cfg(target_os = "linux")]
+    mod btrfs {
+        use super::*;
+        use crate::mods::catalog::Catalog;
+        use crate::mods::incremental_mode::IncrementalMode;
+        use crate::mods::run_stats::RunStatus;
+        use crate::mods::storage::{
+            Link, MemoryStorage, StorageBackend, StorageEntry, StorageMeta,
+        };
+        use std::fs::File;
+        use std::io::Read;
+        use std::sync::{Arc, Mutex};
+
+        /// 挂载在临时目录上的 btrfs 镜像, drop 时卸载
+        struct Loopback {
+            mount: PathBuf,
+            _dir: tempfile::TempDir,
+        }
+
+        impl Loopback {
+            /// 需要 root 权限及 mkfs.btrfs, 由环境变量 RSBK_BTRFS_TEST 开启, 未设置时跳过
+            fn create() -> Option<Loopback> {
+                if std::env::var_os("RSBK_BTRFS_TEST").is_none() {
+                    eprintln!("未设置 RSBK_BTRFS_TEST, 跳过 btrfs 快照测试");
+                    return None;
+                }
+                if unsafe { libc::geteuid() } != 0 {
+                    eprintln!("btrfs 快照测试需要 root 权限, 跳过");
+                    return None;
+                }
+                let dir = tempfile::tempdir().unwrap();
+                let image = dir.path().join("btrfs.img");
+                File::create(&image).unwrap().set_len(256 << 20).unwrap();
+                run("mkfs.btrfs", &["-q".as_ref(), image.as_os_str()]).unwrap();
+                let mount = dir.path().join("mnt");
+                fs::create_dir(&mount).unwrap();
+                run(
+                    "mount",
+                    &[
+                        "-o".as_ref(),
+                        "loop".as_ref(),
+                        image.as_os_str(),
+                        mount.as_os_str(),
+                    ],
+                )
+                .unwrap();
+                Some(Loopback { mount, _dir: dir })
+            }
+        }
+
+        impl Drop for Loopback {
+            fn drop(&mut self) {
+                let _ = run("umount", &[self.mount.as_os_str()]);
+            }
+        }
+
+        /// 记录复制时读取的本地路径, 并在读取前修改源目录中的文件;
+        /// fail 为 true 时复制失败
+        #[derive(Debug)]
+        struct Observed {
+            inner: MemoryStorage,
+            live: PathBuf,
+            fail: bool,
+            sources: Mutex<Vec<PathBuf>>,
+        }
+
+        impl StorageBackend for Observed {
+            fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+                self.inner.list(path)
+            }
+            fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+                self.inner.stat(path)
+            }
+            fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+                self.inner.read(path)
+            }
+            fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+                self.inner.write(path, reader)
+            }
+            fn mkdir(&self, path: &Path) -> Result<(), Error> {
+                self.inner.mkdir(path)
+            }
+            fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+                self.inner.rename(from, to)
+            }
+            fn delete(&self, path: &Path) -> Result<(), Error> {
+                self.inner.delete(path)
+            }
+            fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+                self.inner.link(link, path)
+            }
+            fn read_link(&self, path: &Path) -> Result<String, Error> {
+                self.inner.read_link(path)
+            }
+            fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+                self.sources.lock().unwrap().push(source.to_path_buf());
+                fs::write(&self.live, b"changed")?;
+                if self.fail {
+                    return Err(Error::other("复制失败"));
+                }
+                self.write(path, &mut File::open(source)?)
+            }
+        }
+
+        fn backup(mount: &Path, fail: bool) -> (RunStatus, Vec<PathBuf>, Arc<Observed>) {
+            let source = mount.join("data");
+            fs::create_dir_all(&source).unwrap();
+            fs::write(source.join("a"), b"one").unwrap();
+            let task_config: BackupConfig = serde_yaml::from_str(&format!(
+                "backup_source_path: {}\nbackup_destination_path: /memory\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\nsnapshot:\n  kind: Btrfs\noptions:\n  mode: IncrementalMode\n  save_days: 3\n",
+                source.display()
+            ))
+            .unwrap();
+            let storage = Arc::new(Observed {
+                inner: MemoryStorage::new(),
+                live: source.join("a"),
+                fail,
+                sources: Mutex::new(Vec::new()),
+            });
+            let mode = IncrementalMode {
+                task_config,
+                storage: storage.clone(),
+            };
+            let task_name = "btrfs_snapshot_test";
+            let stats = mode.backup(task_name);
+            let _ = fs::remove_file(Catalog::get_catalog_path(task_name));
+            let sources = storage.sources.lock().unwrap().clone();
+            (stats.status, sources, storage)
+        }
+
+        /// BackupConfig 不在镜像的文件系统上, 快照位于挂载点下的 .rsbk-snapshots
+        fn remaining_snapshots(mount: &Path) -> usize {
+            fs::read_dir(mount.join(".rsbk-snapshots")).unwrap().count()
+        }
+
+        #[test]
+        fn backup_reads_from_snapshot() {
+            let Some(loopback) = Loopback::create() else {
+                return;
+            };
+            let (status, sources, storage) = backup(&loopback.mount, false);
+            assert_eq!(status, RunStatus::Success);
+            assert_eq!(sources.len(), 1);
+            assert!(
+                sources[0].starts_with(loopback.mount.join(".rsbk-snapshots")),
+                "{}",
+                sources[0].display()
+            );
+            assert!(sources[0].ends_with("data/a"));
+            // 创建快照后对源目录的修改不影响本次备份
+            assert_eq!(storage.read(Path::new("/memory/data/a")).unwrap(), b"one");
+            assert_eq!(remaining_snapshots(&loopback.mount), 0);
+        }
+
+        #[test]
+        fn snapshot_is_removed_when_backup_fails() {
+            let Some(loopback) = Loopback::create() else {
+                return;
+            };
+            let (status, sources, _) = backup(&loopback.mount, true);
+            assert_eq!(status, RunStatus::Failed);
+            assert!(sources[0].starts_with(loopback.mount.join(".rsbk-snapshots")));
+            assert_eq!(remaining_snapshots(&loopback.mount), 0);
+        }
+    }
+}
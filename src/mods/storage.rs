@@ -0,0 +1,448 @@
+use super::file_metadata;
+use std::fmt::Debug;
+use std::fs::{self, read_dir, symlink_metadata, File, Metadata};
+use std::io::{self, Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// 备份目的地中路径的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Dir,
+    File,
+    Symlink,
+}
+
+/// 备份目的地中路径的信息, 不跟随符号链接
+#[derive(Debug, Clone)]
+pub struct StorageMeta {
+    pub kind: StorageKind,
+    /// 文件大小(字节), 符号链接为指向路径的长度, 目录为 0
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+impl StorageMeta {
+    pub fn is_dir(&self) -> bool {
+        self.kind == StorageKind::Dir
+    }
+}
+
+/// 目录中的一项
+#[derive(Debug, Clone)]
+pub struct StorageEntry {
+    /// 文件名, 不包括上级目录
+    pub name: String,
+    pub meta: StorageMeta,
+}
+
+/// 创建的链接
+#[derive(Debug, Clone, Copy)]
+pub enum Link<'a> {
+    /// 指向备份目的地中已存在文件的硬链接
+    Hard(&'a Path),
+    /// 符号链接, 内容原样保存
+    Symbolic(&'a str),
+}
+
+/// 备份目的地的存储
+/// 备份模式对目的地的所有读写都经过此接口; 本地源目录直接从本地文件系统读取,
+/// 远程源目录由 remote::source_storage 打开, 同样通过此接口读取
+/// 本地磁盘为 LocalStorage, SFTP、S3、SMB 及 WebDAV 由各自的模块实现
+pub trait StorageBackend: Debug + Send + Sync {
+    /// 列出目录中的所有路径, 目录不存在时返回 NotFound
+    fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error>;
+    /// 读取路径的信息, 不存在时返回 None
+    fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error>;
+    /// 读取文件的全部内容, 用于清单、版本索引等小文件
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error>;
+    /// 写入文件, 已存在时覆盖, 返回写入的字节数
+    fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error>;
+    /// 创建目录及所有上级目录, 已存在时不报错
+    fn mkdir(&self, path: &Path) -> Result<(), Error>;
+    /// 重命名文件或目录, 目标为已存在的文件时被替换
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error>;
+    /// 删除文件、符号链接或整个目录
+    fn delete(&self, path: &Path) -> Result<(), Error>;
+    /// 在 path 创建链接, 目标文件系统不支持时返回错误, 由调用者决定是否退回为复制
+    fn link(&self, link: Link, path: &Path) -> Result<(), Error>;
+    /// 读取符号链接指向的路径, 不支持符号链接时返回 Unsupported
+    fn read_link(&self, path: &Path) -> Result<String, Error>;
+
+    /// 将本地源文件复制到 path, 返回写入的字节数
+    fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+        self.write(path, &mut File::open(source)?)
+    }
+
+    /// 将 path 复制到本地文件 local, 返回读取的字节数
+    fn copy_to(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        let data = self.read(path)?;
+        fs::write(local, &data)?;
+        Ok(data.len() as u64)
+    }
+
+    /// 文件内容的 sha256, 用于复制后的校验
+    fn digest(&self, path: &Path) -> Result<String, Error> {
+        Ok(sha256::digest(self.read(path)?.as_slice()))
+    }
+
+    /// path 所在存储的可用空间, 无法获取时返回 None
+    fn available_space(&self, _path: &Path) -> Result<Option<u64>, Error> {
+        Ok(None)
+    }
+
+    /// path 可以直接在本地文件系统中读取时返回其本地路径, 远程存储返回 None
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// 重命名目录时是否需要复制其中的全部数据(如对象存储)
+    /// 为 true 时新版本直接写入版本目录, 以写入版本索引作为完成的标志, 不再重命名
+    fn rename_copies_data(&self) -> bool {
+        false
+    }
+}
+
+/// 默认使用的本地磁盘存储
+pub fn local() -> Arc<dyn StorageBackend> {
+    Arc::new(LocalStorage)
+}
+
+/// 本地磁盘, 包括已挂载的网络文件系统
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalStorage;
+
+impl LocalStorage {
+    fn meta(meta: &Metadata) -> Result<StorageMeta, Error> {
+        let file_type = meta.file_type();
+        let kind = if file_type.is_dir() {
+            StorageKind::Dir
+        } else if file_type.is_symlink() {
+            StorageKind::Symlink
+        } else {
+            StorageKind::File
+        };
+        Ok(StorageMeta {
+            kind,
+            len: if kind == StorageKind::Dir {
+                0
+            } else {
+                meta.len()
+            },
+            modified: meta.modified()?,
+        })
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+        let mut entries = Vec::new();
+        for entry in read_dir(path)? {
+            let entry = entry?;
+            entries.push(StorageEntry {
+                name: entry.file_name().to_string_lossy().to_string(),
+                meta: LocalStorage::meta(&symlink_metadata(entry.path())?)?,
+            });
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+        match symlink_metadata(path) {
+            Ok(meta) => LocalStorage::meta(&meta).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+        let mut file = File::create(path)?;
+        let bytes = io::copy(reader, &mut file)?;
+        file.sync_all()?;
+        Ok(bytes)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<(), Error> {
+        fs::create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        fs::rename(from, to)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), Error> {
+        if symlink_metadata(path)?.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+
+    fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+        match link {
+            Link::Hard(target) => fs::hard_link(target, path),
+            Link::Symbolic(target) => file_metadata::create_symlink(target, path),
+        }
+    }
+
+    fn read_link(&self, path: &Path) -> Result<String, Error> {
+        Ok(fs::read_link(path)?.to_string_lossy().to_string())
+    }
+
+    /// 使用 fs::copy, 同时复制文件的权限位
+    fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+        fs::copy(source, path)
+    }
+
+    fn copy_to(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        fs::copy(path, local)
+    }
+
+    /// 按块读取计算, 不将整个文件读入内存
+    fn digest(&self, path: &Path) -> Result<String, Error> {
+        sha256::try_digest(path)
+    }
+
+    fn available_space(&self, path: &Path) -> Result<Option<u64>, Error> {
+        fs2::available_space(path).map(Some)
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_path_buf())
+    }
+}
+
+#[cfg(test)]
+pub use memory::MemoryStorage;
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::sync::{Mutex, MutexGuard};
+
+    #[derive(Debug, Clone)]
+    enum MemoryNode {
+        Dir,
+        File(Vec<u8>),
+        Symlink(String),
+    }
+
+    type MemoryNodes = BTreeMap<PathBuf, (MemoryNode, SystemTime)>;
+
+    /// 保存在内存中的存储, 供单元测试使用, 不需要临时目录
+    /// 硬链接以复制内容代替
+    #[derive(Debug, Default)]
+    pub struct MemoryStorage {
+        nodes: Mutex<MemoryNodes>,
+        copying_renames: bool,
+    }
+
+    impl MemoryStorage {
+        pub fn new() -> Self {
+            MemoryStorage::default()
+        }
+
+        /// 模拟重命名需要复制数据的对象存储
+        pub fn with_copying_renames() -> Self {
+            MemoryStorage {
+                copying_renames: true,
+                ..MemoryStorage::default()
+            }
+        }
+
+        /// 上级目录不存在时返回 NotFound, 没有上级目录的路径视为位于根目录
+        fn check_parent(nodes: &MemoryNodes, path: &Path) -> Result<(), Error> {
+            match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => match nodes.get(parent) {
+                    Some((MemoryNode::Dir, _)) => Ok(()),
+                    _ => Err(not_found(parent)),
+                },
+                _ => Ok(()),
+            }
+        }
+
+        fn lock(&self) -> Result<MutexGuard<'_, MemoryNodes>, Error> {
+            self.nodes
+                .lock()
+                .map_err(|_| Error::other("内存存储的锁已失效"))
+        }
+
+        fn insert(&self, path: &Path, node: MemoryNode) -> Result<(), Error> {
+            let mut nodes = self.lock()?;
+            MemoryStorage::check_parent(&nodes, path)?;
+            if let Some((MemoryNode::Dir, _)) = nodes.get(path) {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} 是目录", path.display()),
+                ));
+            }
+            nodes.insert(path.to_path_buf(), (node, SystemTime::now()));
+            Ok(())
+        }
+    }
+
+    fn not_found(path: &Path) -> Error {
+        Error::new(ErrorKind::NotFound, format!("{} 不存在", path.display()))
+    }
+
+    impl StorageBackend for MemoryStorage {
+        fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+            let nodes = self.lock()?;
+            if !matches!(nodes.get(path), Some((MemoryNode::Dir, _))) {
+                return Err(not_found(path));
+            }
+            let entries = nodes
+                .iter()
+                .filter(|(child, _)| child.parent() == Some(path))
+                .map(|(child, (node, modified))| StorageEntry {
+                    name: child
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .to_string(),
+                    meta: memory_meta(node, *modified),
+                })
+                .collect();
+            Ok(entries)
+        }
+
+        fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+            Ok(self
+                .lock()?
+                .get(path)
+                .map(|(node, modified)| memory_meta(node, *modified)))
+        }
+
+        fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+            match self.lock()?.get(path) {
+                Some((MemoryNode::File(data), _)) => Ok(data.clone()),
+                Some(_) => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} 不是文件", path.display()),
+                )),
+                None => Err(not_found(path)),
+            }
+        }
+
+        fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            let bytes = data.len() as u64;
+            self.insert(path, MemoryNode::File(data))?;
+            Ok(bytes)
+        }
+
+        fn mkdir(&self, path: &Path) -> Result<(), Error> {
+            let mut nodes = self.lock()?;
+            let mut ancestors: Vec<&Path> = path
+                .ancestors()
+                .filter(|p| !p.as_os_str().is_empty())
+                .collect();
+            ancestors.reverse();
+            for ancestor in ancestors {
+                match nodes.get(ancestor) {
+                    Some((MemoryNode::Dir, _)) => {}
+                    Some(_) => {
+                        return Err(Error::new(
+                            ErrorKind::AlreadyExists,
+                            format!("{} 已存在且不是目录", ancestor.display()),
+                        ))
+                    }
+                    None => {
+                        nodes.insert(ancestor.to_path_buf(), (MemoryNode::Dir, SystemTime::now()));
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+            let mut nodes = self.lock()?;
+            if !nodes.contains_key(from) {
+                return Err(not_found(from));
+            }
+            MemoryStorage::check_parent(&nodes, to)?;
+            if let Some((MemoryNode::Dir, _)) = nodes.get(to) {
+                if nodes.keys().any(|p| p.parent() == Some(to)) {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("{} 不是空目录", to.display()),
+                    ));
+                }
+            }
+            nodes.remove(to);
+            let moved: Vec<PathBuf> = nodes
+                .keys()
+                .filter(|p| p.starts_with(from))
+                .cloned()
+                .collect();
+            for path in moved {
+                if let Some(node) = nodes.remove(&path) {
+                    let relative = path.strip_prefix(from).unwrap_or(Path::new(""));
+                    nodes.insert(to.join(relative), node);
+                }
+            }
+            Ok(())
+        }
+
+        fn delete(&self, path: &Path) -> Result<(), Error> {
+            let mut nodes = self.lock()?;
+            if !nodes.contains_key(path) {
+                return Err(not_found(path));
+            }
+            nodes.retain(|p, _| !p.starts_with(path));
+            Ok(())
+        }
+
+        fn link(&self, link: Link, path: &Path) -> Result<(), Error> {
+            let node = match link {
+                Link::Hard(target) => match self.lock()?.get(target) {
+                    Some((MemoryNode::File(data), _)) => MemoryNode::File(data.clone()),
+                    _ => return Err(not_found(target)),
+                },
+                Link::Symbolic(target) => MemoryNode::Symlink(target.to_string()),
+            };
+            if self.stat(path)?.is_some() {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("{} 已存在", path.display()),
+                ));
+            }
+            self.insert(path, node)
+        }
+
+        fn read_link(&self, path: &Path) -> Result<String, Error> {
+            match self.lock()?.get(path) {
+                Some((MemoryNode::Symlink(target), _)) => Ok(target.clone()),
+                Some(_) => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} 不是符号链接", path.display()),
+                )),
+                None => Err(not_found(path)),
+            }
+        }
+
+        fn rename_copies_data(&self) -> bool {
+            self.copying_renames
+        }
+    }
+
+    fn memory_meta(node: &MemoryNode, modified: SystemTime) -> StorageMeta {
+        let (kind, len) = match node {
+            MemoryNode::Dir => (StorageKind::Dir, 0),
+            MemoryNode::File(data) => (StorageKind::File, data.len() as u64),
+            MemoryNode::Symlink(target) => (StorageKind::Symlink, target.len() as u64),
+        };
+        StorageMeta {
+            kind,
+            len,
+            modified,
+        }
+    }
+}
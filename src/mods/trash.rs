@@ -0,0 +1,449 @@
+use super::{
+    base_bk_option::get_backup_base_path,
+    catalog::{Catalog, CatalogEntry},
+    file_metadata::{FileMetadata, Manifest},
+    prune::{PruneReport, PruneRule},
+    storage::StorageBackend,
+    version_index::{VersionEntry, VersionIndex},
+};
+use chrono::{DateTime, Duration, Local};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// 回收站目录名, 位于备份目的地的根目录中, 由使用该目的地的所有任务共用
+pub const TRASH_DIR_NAME: &str = ".rsbk_trash";
+/// 回收站中每个条目的说明文件及被删除的数据
+const ITEM_FILE_NAME: &str = "item.yaml";
+const ITEM_DATA_NAME: &str = "data";
+
+/// 回收站配置
+/// 配置后按保留策略或保存天数删除的文件和版本会先移入回收站, 超过保留天数后才彻底删除
+/// 为满足空间限额的删除不经过回收站
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashConfig {
+    /// 回收站中的条目保留几天
+    pub grace_days: usize,
+}
+
+/// 被删除的路径在备份记录中的条目, 从回收站还原时重新写入, 使其重新由备份任务管理
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum TrashRecord {
+    /// 版本控制模式的版本, 写回备份目录的版本索引
+    Version(VersionEntry),
+    /// 增量备份模式的文件, 写回文件目录及清单
+    File {
+        /// 文件目录的名称, 见 BackupConfig::destination_key
+        catalog: String,
+        /// 文件在备份目录中的相对路径
+        relative: String,
+        entry: CatalogEntry,
+        /// 文件及其上级目录在清单中的记录
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        metadata: BTreeMap<String, FileMetadata>,
+    },
+}
+
+/// 回收站中的一个条目
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrashItem {
+    pub id: String,
+    pub task_name: String,
+    /// 删除前的路径
+    pub original_path: String,
+    pub rule: PruneRule,
+    pub detail: String,
+    pub bytes: u64,
+    pub deleted_at: DateTime<Local>,
+    /// 超过此时间后彻底删除
+    pub purge_after: DateTime<Local>,
+    /// 删除前的备份记录, 还原时重新写入
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<TrashRecord>,
+}
+
+/// 一个备份目的地的回收站
+#[derive(Debug, Clone)]
+pub struct Trash {
+    root: PathBuf,
+    grace_days: usize,
+}
+
+impl Trash {
+    /// 回收站存放地址, 取备份目的地下的 .rsbk_trash
+    pub fn get_trash_path(destination_path: &str) -> PathBuf {
+        get_backup_base_path(destination_path).join(TRASH_DIR_NAME)
+    }
+
+    pub fn open(destination_path: &str, config: &TrashConfig) -> Trash {
+        Trash {
+            root: Trash::get_trash_path(destination_path),
+            grace_days: config.grace_days,
+        }
+    }
+
+    /// 将路径移入回收站, 返回条目ID
+    #[allow(clippy::too_many_arguments)]
+    pub fn put(
+        &self,
+        storage: &dyn StorageBackend,
+        task_name: &str,
+        path: &Path,
+        rule: PruneRule,
+        detail: &str,
+        bytes: u64,
+        record: Option<TrashRecord>,
+    ) -> Result<String, Error> {
+        let deleted_at = Local::now();
+        let prefix = deleted_at.format("%Y%m%dT%H%M%S").to_string();
+        let mut index = 0;
+        let (id, item_path) = loop {
+            let id = format!("{}-{}", prefix, index);
+            let item_path = self.root.join(&id);
+            if storage.stat(&item_path)?.is_none() {
+                storage.mkdir(&item_path)?;
+                break (id, item_path);
+            }
+            index += 1;
+        };
+
+        let item = TrashItem {
+            id: id.clone(),
+            task_name: task_name.to_string(),
+            original_path: path.to_string_lossy().to_string(),
+            rule,
+            detail: detail.to_string(),
+            bytes,
+            deleted_at,
+            purge_after: deleted_at + Duration::days(self.grace_days as i64),
+            record,
+        };
+        let moved = write_item(storage, &item_path, &item)
+            .and_then(|_| storage.rename(path, &item_path.join(ITEM_DATA_NAME)));
+        if let Err(e) = moved {
+            let _ = storage.delete(&item_path);
+            return Err(e);
+        }
+        Ok(id)
+    }
+
+    /// 彻底删除超过保留天数的条目, 记录到报告中
+    pub fn purge(
+        &self,
+        storage: &dyn StorageBackend,
+        report: &mut PruneReport,
+    ) -> Result<(), Error> {
+        let now = Local::now();
+        for item in list_items(storage, &self.root)? {
+            if item.purge_after > now {
+                continue;
+            }
+            report.purge(
+                storage,
+                &self.root.join(&item.id),
+                format!(
+                    "回收站条目 {}({}) 于 {} 删除, 已超过保留天数",
+                    item.id,
+                    item.original_path,
+                    item.deleted_at.format("%Y-%m-%d %T")
+                ),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// 列出备份目的地回收站中的所有条目, 按删除时间排列
+pub fn list(storage: &dyn StorageBackend, destination_path: &str) -> Result<Vec<TrashItem>, Error> {
+    list_items(storage, &Trash::get_trash_path(destination_path))
+}
+
+/// 将条目移回原来的位置, 并将删除前的备份记录写回版本索引或文件目录
+/// 原位置已存在文件时不会覆盖
+pub fn undelete(
+    storage: &dyn StorageBackend,
+    destination_path: &str,
+    id: &str,
+) -> Result<TrashItem, Error> {
+    let item_path = Trash::get_trash_path(destination_path).join(id);
+    let item = read_item(storage, &item_path)?;
+    let original_path = Path::new(&item.original_path);
+    if storage.stat(original_path)?.is_some() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("原位置 {} 已存在, 无法还原", item.original_path),
+        ));
+    }
+    if let Some(parent) = original_path.parent() {
+        storage.mkdir(parent)?;
+    }
+    storage.rename(&item_path.join(ITEM_DATA_NAME), original_path)?;
+    if let Some(record) = &item.record {
+        if let Err(e) = register(storage, record, original_path) {
+            // 记录写入失败时放回回收站, 以便之后再次还原
+            storage.rename(original_path, &item_path.join(ITEM_DATA_NAME))?;
+            return Err(e);
+        }
+    }
+    storage.delete(&item_path)?;
+    info!("{}:已从回收站还原 {}", item.task_name, item.original_path);
+    Ok(item)
+}
+
+/// 将还原的路径重新写入版本索引, 或文件目录及清单
+/// 文件的备份时间改为还原的时间, 否则下一次运行时会因超过保存天数再次删除
+fn register(storage: &dyn StorageBackend, record: &TrashRecord, path: &Path) -> Result<(), Error> {
+    match record {
+        TrashRecord::Version(entry) => {
+            let backup_root = path
+                .parent()
+                .ok_or_else(|| Error::other(format!("{:?} 没有上级目录", path)))?;
+            let mut index = VersionIndex::read(storage, backup_root)?;
+            index.restore(storage, backup_root, entry.clone())
+        }
+        TrashRecord::File {
+            catalog: key,
+            relative,
+            entry,
+            metadata,
+        } => {
+            let depth = Path::new(relative).components().count();
+            let backup_root = path
+                .ancestors()
+                .nth(depth)
+                .ok_or_else(|| Error::other(format!("{:?} 不在备份目录 {} 中", path, relative)))?;
+            let mut catalog = Catalog::load(key, backup_root)?;
+            catalog.files.insert(
+                relative.clone(),
+                CatalogEntry {
+                    backed_up_at: Local::now(),
+                    ..entry.clone()
+                },
+            );
+            catalog.save(key)?;
+            if !metadata.is_empty() {
+                let mut manifest = Manifest::load(storage, backup_root)?;
+                for (relative, meta) in metadata {
+                    manifest
+                        .files
+                        .entry(relative.clone())
+                        .or_insert_with(|| meta.clone());
+                }
+                manifest.save(storage, backup_root)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn list_items(storage: &dyn StorageBackend, trash_path: &Path) -> Result<Vec<TrashItem>, Error> {
+    let entries = match storage.list(trash_path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut items = Vec::new();
+    for entry in entries {
+        if !entry.meta.is_dir() {
+            continue;
+        }
+        let item_path = trash_path.join(&entry.name);
+        match read_item(storage, &item_path) {
+            Ok(item) => items.push(item),
+            Err(e) => log::warn!("读取回收站条目 {} 时发生错误:{}", item_path.display(), e),
+        }
+    }
+    items.sort_by(|a, b| a.deleted_at.cmp(&b.deleted_at).then(a.id.cmp(&b.id)));
+    Ok(items)
+}
+
+fn read_item(storage: &dyn StorageBackend, item_path: &Path) -> Result<TrashItem, Error> {
+    let buf = storage.read(&item_path.join(ITEM_FILE_NAME))?;
+    serde_yaml::from_slice(&buf).map_err(|e| {
+        Error::other(format!(
+            "读取回收站条目 {:?} 时发生错误: {:?}",
+            item_path, e
+        ))
+    })
+}
+
+fn write_item(
+    storage: &dyn StorageBackend,
+    item_path: &Path,
+    item: &TrashItem,
+) -> Result<(), Error> {
+    let yaml_str = serde_yaml::to_string(item)
+        .map_err(|e| Error::other(format!("Failed to serialize TrashItem to YAML: {:?}", e)))?;
+    storage
+        .write(&item_path.join(ITEM_FILE_NAME), &mut yaml_str.as_bytes())
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::storage;
+    use std::fs;
+
+    fn trash(destination: &Path) -> Trash {
+        Trash::open(
+            &destination.to_string_lossy(),
+            &TrashConfig { grace_days: 7 },
+        )
+    }
+
+    fn version(backup_root: &Path, hour: u32) -> VersionEntry {
+        let created_at = Local::now()
+            .date_naive()
+            .and_hms_opt(hour, 0, 0)
+            .unwrap()
+            .and_local_timezone(Local)
+            .unwrap();
+        let entry = VersionEntry {
+            id: VersionIndex::version_id(&created_at, &format!("hash{}", hour)),
+            created_at,
+            hash: Some(format!("hash{}", hour)),
+            migrated_from: None,
+        };
+        fs::create_dir_all(backup_root.join(&entry.id)).unwrap();
+        fs::write(backup_root.join(&entry.id).join("a.txt"), "a").unwrap();
+        entry
+    }
+
+    #[test]
+    fn undeleted_version_returns_to_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("task");
+        let storage = storage::local();
+        let versions: Vec<VersionEntry> = (1..4).map(|h| version(&backup_root, h)).collect();
+
+        // 保留策略删除中间的版本: 移入回收站后从索引中移除
+        let removed = versions[1].clone();
+        let id = trash(dir.path())
+            .put(
+                storage::local().as_ref(),
+                "task",
+                &backup_root.join(&removed.id),
+                PruneRule::Retention,
+                "",
+                1,
+                Some(TrashRecord::Version(removed.clone())),
+            )
+            .unwrap();
+        let index = VersionIndex {
+            versions: vec![versions[0].clone(), versions[2].clone()],
+        };
+        index.save(storage.as_ref(), &backup_root).unwrap();
+
+        undelete(
+            storage::local().as_ref(),
+            &dir.path().to_string_lossy(),
+            &id,
+        )
+        .unwrap();
+        assert!(backup_root.join(&removed.id).join("a.txt").exists());
+        let index = VersionIndex::read(storage.as_ref(), &backup_root).unwrap();
+        let ids: Vec<&str> = index.versions.iter().map(|v| v.id.as_str()).collect();
+        let expected: Vec<&str> = versions.iter().map(|v| v.id.as_str()).collect();
+        assert_eq!(ids, expected);
+        assert_eq!(index.versions[1].hash, removed.hash);
+        assert!(
+            list(storage::local().as_ref(), &dir.path().to_string_lossy())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn undeleted_file_returns_to_catalog_and_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("task");
+        let key = format!("trash-test-{}", std::process::id());
+        let file = backup_root.join("docs").join("a.txt");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "a").unwrap();
+        let backed_up_at = Local::now() - Duration::days(30);
+        let entry = CatalogEntry {
+            size: 1,
+            mtime: Some(backed_up_at),
+            digest: Some(sha256::digest("a")),
+            backed_up_at,
+        };
+        let mut metadata = BTreeMap::new();
+        metadata.insert(
+            "docs".to_string(),
+            FileMetadata {
+                mode: Some(0o700),
+                ..FileMetadata::default()
+            },
+        );
+
+        let id = trash(dir.path())
+            .put(
+                storage::local().as_ref(),
+                "task",
+                &file,
+                PruneRule::Expired,
+                "",
+                1,
+                Some(TrashRecord::File {
+                    catalog: key.clone(),
+                    relative: "docs/a.txt".to_string(),
+                    entry,
+                    metadata,
+                }),
+            )
+            .unwrap();
+        let result = undelete(
+            storage::local().as_ref(),
+            &dir.path().to_string_lossy(),
+            &id,
+        );
+        let catalog = Catalog::load(&key, &backup_root);
+        let _ = fs::remove_file(Catalog::get_catalog_path(&key));
+        result.unwrap();
+
+        assert!(file.exists());
+        let record = &catalog.unwrap().files["docs/a.txt"];
+        assert_eq!(record.digest.as_deref(), Some(sha256::digest("a").as_str()));
+        // 还原后重新计算保存天数
+        assert!(record.backed_up_at > backed_up_at + Duration::days(29));
+        let manifest = Manifest::load(storage::local().as_ref(), &backup_root).unwrap();
+        assert_eq!(manifest.files["docs"].mode, Some(0o700));
+    }
+
+    #[test]
+    fn failed_registration_keeps_item_in_trash() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("task");
+        let removed = version(&backup_root, 1);
+        let id = trash(dir.path())
+            .put(
+                storage::local().as_ref(),
+                "task",
+                &backup_root.join(&removed.id),
+                PruneRule::Retention,
+                "",
+                1,
+                Some(TrashRecord::Version(removed.clone())),
+            )
+            .unwrap();
+        fs::write(VersionIndex::get_index_path(&backup_root), "versions: [").unwrap();
+
+        assert!(undelete(
+            storage::local().as_ref(),
+            &dir.path().to_string_lossy(),
+            &id
+        )
+        .is_err());
+        assert!(!backup_root.join(&removed.id).exists());
+        assert_eq!(
+            list(storage::local().as_ref(), &dir.path().to_string_lossy())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,495 @@
+use super::storage::StorageBackend;
+use chrono::{DateTime, Duration, Local, NaiveDateTime};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// 版本索引文件名, 保存在任务的备份目录(各版本目录的上级目录)中
+pub const VERSION_INDEX_FILE_NAME: &str = ".rsbk_versions.yaml";
+
+/// 版本ID中的时间格式(ISO 8601 基本格式)
+const VERSION_TIME_FORMAT: &str = "%Y%m%dT%H%M%S";
+/// 版本ID中hash的长度
+const SHORT_HASH_LEN: usize = 8;
+/// 正在写入的版本目录的后缀, 写入完成后重命名为版本ID
+/// 重命名需要复制数据的存储直接写入版本目录, 同名的空文件标记该版本尚未完成
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// 一个版本的记录
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VersionEntry {
+    /// 版本ID, 也是版本目录名, 如 20240101T120000-1a2b3c4d
+    /// 版本创建后不再改变
+    pub id: String,
+    pub created_at: DateTime<Local>,
+    /// 创建版本时源目录的hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// 由旧版本目录迁移而来时, 旧的目录名
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migrated_from: Option<String>,
+}
+
+impl VersionEntry {
+    /// 是否为完整的版本: 有文件备份失败的版本不记录hash, 迁移而来的旧版本视为完整
+    pub fn is_complete(&self) -> bool {
+        self.hash.is_some() || self.migrated_from.is_some()
+    }
+}
+
+/// 版本控制模式的版本索引, 按创建时间从旧到新排列
+/// 版本目录以不变的版本ID命名, 删除早期版本时不需要重命名其余版本
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct VersionIndex {
+    pub versions: Vec<VersionEntry>,
+}
+
+impl VersionIndex {
+    pub fn get_index_path(backup_root: &Path) -> PathBuf {
+        backup_root.join(VERSION_INDEX_FILE_NAME)
+    }
+
+    /// 由创建时间和hash生成版本ID
+    pub fn version_id(created_at: &DateTime<Local>, hash: &str) -> String {
+        let short_hash: String = hash.chars().take(SHORT_HASH_LEN).collect();
+        format!("{}-{}", created_at.format(VERSION_TIME_FORMAT), short_hash)
+    }
+
+    /// 读取版本索引, 并迁移旧的目录结构
+    /// bk_version_N 及 bk_{时间} 目录会被重命名为版本ID并加入索引,
+    /// legacy_hashs 为配置中记录的hash(从旧到新), 按顺序对应最新的几个旧版本
+    /// 索引中目录已不存在的版本会被移除
+    pub fn load(
+        storage: &dyn StorageBackend,
+        backup_root: &Path,
+        legacy_hashs: &[String],
+    ) -> Result<VersionIndex, Error> {
+        let index_path = VersionIndex::get_index_path(backup_root);
+        let mut index = match storage.read(&index_path) {
+            Ok(buf) => serde_yaml::from_slice(&buf).map_err(|e| {
+                Error::other(format!("读取版本索引 {:?} 时发生错误: {:?}", index_path, e))
+            })?,
+            Err(e) if e.kind() == ErrorKind::NotFound => VersionIndex::default(),
+            Err(e) => return Err(e),
+        };
+        if !storage.stat(backup_root)?.is_some_and(|meta| meta.is_dir()) {
+            return Ok(index);
+        }
+
+        let mut changed = false;
+        let recorded = index.versions.len();
+        index.versions.retain(|entry| {
+            let exists = storage
+                .stat(&backup_root.join(&entry.id))
+                .is_ok_and(|meta| meta.is_some_and(|meta| meta.is_dir()));
+            if !exists {
+                warn!("版本目录 {} 已不存在, 从版本索引中移除", entry.id);
+            }
+            exists
+        });
+        changed |= index.versions.len() != recorded;
+        changed |= index.migrate(storage, backup_root, legacy_hashs)?;
+        if changed {
+            index.save(storage, backup_root)?;
+        }
+        Ok(index)
+    }
+
+    /// 只读取版本索引, 不迁移旧的目录结构也不写入, 不存在时返回空索引
+    /// 用于读取其他任务的版本索引
+    pub fn read(storage: &dyn StorageBackend, backup_root: &Path) -> Result<VersionIndex, Error> {
+        let index_path = VersionIndex::get_index_path(backup_root);
+        match storage.read(&index_path) {
+            Ok(buf) => serde_yaml::from_slice(&buf).map_err(|e| {
+                Error::other(format!("读取版本索引 {:?} 时发生错误: {:?}", index_path, e))
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(VersionIndex::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 将索引中没有的版本目录加入索引, 返回是否有变动
+    fn migrate(
+        &mut self,
+        storage: &dyn StorageBackend,
+        backup_root: &Path,
+        legacy_hashs: &[String],
+    ) -> Result<bool, Error> {
+        // bk_version_N: (旧版本序号, 目录的修改时间, 目录名)
+        let mut numbered = Vec::new();
+        // bk_{时间}: (版本时间, 目录名)
+        let mut timed = Vec::new();
+        let mut adopted = Vec::new();
+        let entries = storage.list(backup_root)?;
+        // 有未完成标记的版本目录由下一次 begin 删除, 不加入索引
+        let unfinished: Vec<String> = entries
+            .iter()
+            .filter(|entry| !entry.meta.is_dir())
+            .filter_map(|entry| unfinished_version(&entry.name))
+            .collect();
+        for entry in entries {
+            let name = entry.name;
+            if !entry.meta.is_dir()
+                || name.starts_with('.')
+                || self.versions.iter().any(|v| v.id == name)
+                || unfinished.contains(&name)
+            {
+                continue;
+            }
+            if let Some(index) = name
+                .strip_prefix("bk_version_")
+                .and_then(|n| n.parse::<usize>().ok())
+            {
+                // 旧版本目录没有记录时间, 取目录的修改时间
+                let time: DateTime<Local> = entry.meta.modified.into();
+                numbered.push((index, time, name));
+            } else if let Some(time) = name.strip_prefix("bk_").and_then(parse_version_time) {
+                timed.push((time, name));
+            } else if let Some(time) = parse_version_time(&name) {
+                // 已重命名为版本ID但未写入索引的目录
+                adopted.push(VersionEntry {
+                    id: name,
+                    created_at: time,
+                    hash: None,
+                    migrated_from: None,
+                });
+            }
+        }
+        if numbered.is_empty() && timed.is_empty() && adopted.is_empty() {
+            return Ok(false);
+        }
+
+        // bk_version_N 中 N 越大越新, 修改时间只用于区分序号相同的目录;
+        // 修改时间与序号的顺序不一致时顺延, 使迁移后的版本按创建时间排列时顺序不变
+        // bk_{时间} 是之后的目录结构, 排在 bk_version_N 之后
+        numbered.sort();
+        let mut legacy: Vec<(DateTime<Local>, String)> = Vec::new();
+        for (_, time, name) in numbered {
+            let time = match legacy.last() {
+                Some((previous, _)) if time <= *previous => *previous + Duration::seconds(1),
+                _ => time,
+            };
+            legacy.push((time, name));
+        }
+        timed.sort();
+        legacy.extend(timed);
+        let offset = legacy.len() as isize - legacy_hashs.len() as isize;
+        for (position, (time, name)) in legacy.into_iter().enumerate() {
+            let hash = usize::try_from(position as isize - offset)
+                .ok()
+                .and_then(|i| legacy_hashs.get(i).cloned());
+            let mut id = VersionIndex::version_id(
+                &time,
+                hash.as_deref().unwrap_or(&sha256::digest(name.as_str())),
+            );
+            while storage.stat(&backup_root.join(&id))?.is_some() {
+                id.push('_');
+            }
+            storage.rename(&backup_root.join(&name), &backup_root.join(&id))?;
+            info!(
+                "已将旧版本目录 {} 迁移为 {}",
+                backup_root.join(&name).display(),
+                id
+            );
+            self.versions.push(VersionEntry {
+                id,
+                created_at: time,
+                hash,
+                migrated_from: Some(name),
+            });
+        }
+        self.versions.extend(adopted);
+        self.versions
+            .sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+        Ok(true)
+    }
+
+    /// 写入临时文件后替换, 避免中途出错时损坏索引
+    pub fn save(&self, storage: &dyn StorageBackend, backup_root: &Path) -> Result<(), Error> {
+        let yaml_str = serde_yaml::to_string(self).map_err(|e| {
+            Error::other(format!("Failed to serialize VersionIndex to YAML: {:?}", e))
+        })?;
+        let index_path = VersionIndex::get_index_path(backup_root);
+        let tmp_path = index_path.with_extension("yaml.tmp");
+        storage.write(&tmp_path, &mut yaml_str.as_bytes())?;
+        storage.rename(&tmp_path, &index_path)
+    }
+
+    /// 版本目录的路径
+    pub fn version_path(backup_root: &Path, entry: &VersionEntry) -> PathBuf {
+        backup_root.join(&entry.id)
+    }
+
+    /// 是否已有以此hash完整备份的版本
+    pub fn contains_hash(&self, hash: &str) -> bool {
+        self.versions
+            .iter()
+            .any(|v| v.hash.as_deref() == Some(hash))
+    }
+
+    /// 查找版本, version 可以是版本ID或迁移前的目录名
+    pub fn find(&self, version: &str) -> Option<&VersionEntry> {
+        self.versions
+            .iter()
+            .find(|v| v.id == version || v.migrated_from.as_deref() == Some(version))
+    }
+
+    /// 创建用于写入新版本的临时目录, 并清除上次中断时遗留的临时目录
+    /// 重命名需要复制数据的存储直接创建版本目录, 同时写入未完成的标记
+    pub fn begin(
+        storage: &dyn StorageBackend,
+        backup_root: &Path,
+        id: &str,
+    ) -> Result<PathBuf, Error> {
+        for entry in storage.list(backup_root)? {
+            if entry.name.starts_with('.') && entry.name.ends_with(PARTIAL_SUFFIX) {
+                let path = backup_root.join(&entry.name);
+                if let Some(version) =
+                    unfinished_version(&entry.name).filter(|_| !entry.meta.is_dir())
+                {
+                    let version_path = backup_root.join(version);
+                    if storage.stat(&version_path)?.is_some() {
+                        warn!("删除上次未完成的版本目录 {}", version_path.display());
+                        storage.delete(&version_path)?;
+                    }
+                } else {
+                    warn!("删除上次未完成的版本目录 {}", path.display());
+                }
+                storage.delete(&path)?;
+            }
+        }
+        let partial_path = backup_root.join(format!(".{}{}", id, PARTIAL_SUFFIX));
+        if !storage.rename_copies_data() {
+            storage.mkdir(&partial_path)?;
+            return Ok(partial_path);
+        }
+        let version_path = backup_root.join(id);
+        if storage.stat(&version_path)?.is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("版本目录 {} 已存在", version_path.display()),
+            ));
+        }
+        storage.write(&partial_path, &mut &b""[..])?;
+        storage.mkdir(&version_path)?;
+        Ok(version_path)
+    }
+
+    /// 删除未完成的版本目录及其标记
+    pub fn abort(storage: &dyn StorageBackend, partial_path: &Path) -> Result<(), Error> {
+        if storage.rename_copies_data() {
+            storage.delete(&unfinished_marker(partial_path))?;
+        }
+        storage.delete(partial_path)
+    }
+
+    /// 将写入完成的临时目录重命名为版本ID并写入索引, 返回版本目录
+    /// 有文件备份失败的版本(complete 为 false)不记录hash, 下一次运行时重新备份
+    pub fn commit(
+        &mut self,
+        storage: &dyn StorageBackend,
+        backup_root: &Path,
+        partial_path: &Path,
+        created_at: DateTime<Local>,
+        hash: &str,
+        complete: bool,
+    ) -> Result<PathBuf, Error> {
+        let version_path = if storage.rename_copies_data() {
+            // 先删除标记再写入索引: 中途出错时版本目录作为不完整的版本重新加入索引
+            storage.delete(&unfinished_marker(partial_path))?;
+            partial_path.to_path_buf()
+        } else {
+            let mut id = VersionIndex::version_id(&created_at, hash);
+            while storage.stat(&backup_root.join(&id))?.is_some() {
+                id.push('_');
+            }
+            let version_path = backup_root.join(&id);
+            storage.rename(partial_path, &version_path)?;
+            version_path
+        };
+        let id = version_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.versions.push(VersionEntry {
+            id,
+            created_at,
+            hash: complete.then(|| hash.to_string()),
+            migrated_from: None,
+        });
+        self.save(storage, backup_root)?;
+        Ok(version_path)
+    }
+
+    /// 将复制完成的临时目录重命名为原版本的ID并写入索引, 供复制任务使用
+    pub fn import(
+        &mut self,
+        storage: &dyn StorageBackend,
+        backup_root: &Path,
+        partial_path: &Path,
+        entry: VersionEntry,
+    ) -> Result<PathBuf, Error> {
+        let version_path = VersionIndex::version_path(backup_root, &entry);
+        if storage.rename_copies_data() {
+            storage.delete(&unfinished_marker(partial_path))?;
+        } else {
+            storage.rename(partial_path, &version_path)?;
+        }
+        self.insert(entry);
+        self.save(storage, backup_root)?;
+        Ok(version_path)
+    }
+
+    /// 将从回收站还原的版本重新写入索引, 已在索引中时不做任何事
+    pub fn restore(
+        &mut self,
+        storage: &dyn StorageBackend,
+        backup_root: &Path,
+        entry: VersionEntry,
+    ) -> Result<(), Error> {
+        if self.versions.iter().any(|v| v.id == entry.id) {
+            return Ok(());
+        }
+        self.insert(entry);
+        self.save(storage, backup_root)
+    }
+
+    /// 按创建时间插入, 保持索引从旧到新排列
+    fn insert(&mut self, entry: VersionEntry) {
+        let position = self
+            .versions
+            .partition_point(|v| v.created_at <= entry.created_at);
+        self.versions.insert(position, entry);
+    }
+}
+
+/// 解析版本ID或目录名开头的时间
+fn parse_version_time(name: &str) -> Option<DateTime<Local>> {
+    let time = NaiveDateTime::parse_from_str(name.get(..15)?, VERSION_TIME_FORMAT).ok()?;
+    time.and_local_timezone(Local).earliest()
+}
+
+/// 直接写入的版本目录的未完成标记
+fn unfinished_marker(version_path: &Path) -> PathBuf {
+    let name = version_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    version_path.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX))
+}
+
+/// 未完成标记对应的版本目录名
+fn unfinished_version(name: &str) -> Option<String> {
+    name.strip_prefix('.')
+        .and_then(|name| name.strip_suffix(PARTIAL_SUFFIX))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::storage::{self, MemoryStorage};
+    use std::fs::{self, File};
+    use std::time::{Duration as StdDuration, SystemTime};
+
+    #[test]
+    fn legacy_versions_migrate_in_numeric_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path();
+        let now = SystemTime::now();
+        for n in 0..12 {
+            let path = backup_root.join(format!("bk_version_{}", n));
+            fs::create_dir(&path).unwrap();
+            // 修改时间与序号的顺序相反, 迁移时应以序号为准
+            File::open(&path)
+                .unwrap()
+                .set_modified(now - StdDuration::from_secs(n * 60))
+                .unwrap();
+        }
+        let legacy_hashs = vec!["hash_of_10".to_string(), "hash_of_11".to_string()];
+        let index =
+            VersionIndex::load(storage::local().as_ref(), backup_root, &legacy_hashs).unwrap();
+
+        let migrated: Vec<&str> = index
+            .versions
+            .iter()
+            .map(|v| v.migrated_from.as_deref().unwrap())
+            .collect();
+        let expected: Vec<String> = (0..12).map(|n| format!("bk_version_{}", n)).collect();
+        assert_eq!(migrated, expected);
+        assert!(index
+            .versions
+            .windows(2)
+            .all(|w| w[0].created_at < w[1].created_at));
+        assert_eq!(index.versions[10].hash.as_deref(), Some("hash_of_10"));
+        assert_eq!(index.versions[11].hash.as_deref(), Some("hash_of_11"));
+        assert!(index.versions[9].hash.is_none());
+        assert!(index
+            .versions
+            .iter()
+            .all(|v| backup_root.join(&v.id).is_dir()));
+    }
+
+    #[test]
+    fn timestamped_versions_follow_numbered_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path();
+        fs::create_dir(backup_root.join("bk_20200101T000000")).unwrap();
+        fs::create_dir(backup_root.join("bk_version_0")).unwrap();
+        File::open(backup_root.join("bk_version_0"))
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + StdDuration::from_secs(86400))
+            .unwrap();
+        let index = VersionIndex::load(storage::local().as_ref(), backup_root, &[]).unwrap();
+        let migrated: Vec<&str> = index
+            .versions
+            .iter()
+            .map(|v| v.migrated_from.as_deref().unwrap())
+            .collect();
+        assert_eq!(migrated, ["bk_version_0", "bk_20200101T000000"]);
+    }
+
+    #[test]
+    fn copying_storage_writes_versions_in_place() {
+        let storage = MemoryStorage::with_copying_renames();
+        let backup_root = Path::new("/backup/task");
+        storage.mkdir(backup_root).unwrap();
+        let created_at = Local::now();
+        let id = VersionIndex::version_id(&created_at, "1a2b3c4d5e");
+
+        // 直接写入版本目录, 标记存在期间不加入索引
+        let path = VersionIndex::begin(&storage, backup_root, &id).unwrap();
+        assert_eq!(path, backup_root.join(&id));
+        storage.write(&path.join("a"), &mut &b"one"[..]).unwrap();
+        let index = VersionIndex::load(&storage, backup_root, &[]).unwrap();
+        assert!(index.versions.is_empty());
+
+        // 中断后的下一次 begin 删除未完成的版本目录
+        let retry_id = VersionIndex::version_id(&(created_at + Duration::seconds(1)), "1a2b3c4d5e");
+        let path = VersionIndex::begin(&storage, backup_root, &retry_id).unwrap();
+        assert!(storage.stat(&backup_root.join(&id)).unwrap().is_none());
+        storage.write(&path.join("a"), &mut &b"one"[..]).unwrap();
+        let mut index = VersionIndex::load(&storage, backup_root, &[]).unwrap();
+        let committed = index
+            .commit(&storage, backup_root, &path, created_at, "1a2b3c4d5e", true)
+            .unwrap();
+        assert_eq!(committed, path);
+        assert_eq!(index.versions[0].id, retry_id);
+
+        let names: Vec<String> = storage
+            .list(backup_root)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert!(
+            names.iter().all(|name| !name.ends_with(PARTIAL_SUFFIX)),
+            "{:?}",
+            names
+        );
+        let index = VersionIndex::load(&storage, backup_root, &[]).unwrap();
+        assert_eq!(index.versions.len(), 1);
+        assert!(index.contains_hash("1a2b3c4d5e"));
+    }
+}
@@ -1,225 +1,776 @@
 use super::{
-    base_bk_option,
-    bk_config::{BackupConfig, BackupMode},
+    base_bk_option::{self, context, CopyTarget, SourceSet},
+    bk_config::{BackupConfig, BackupMode, Compression},
+    file_metadata::Manifest,
+    prune::{PruneReport, PruneRule},
+    quota::{dir_size, quota_error, QuotaConfig},
+    remote,
+    retention::RetentionPolicy,
+    run_stats::{RunStats, RunStatus},
+    snapshot::SnapshotSet,
+    storage::{self, StorageBackend},
+    version_index::{VersionEntry, VersionIndex},
 };
+use chrono::Local;
 use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 ///基于版本控制的备份模式，根据文件的哈希值判断是否需要备份，并保留指定数量的历史备份版本
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VersionMode {
     pub task_config: BackupConfig,
+    /// 备份目的地的存储, 默认为本地磁盘
+    #[serde(skip, default = "storage::local")]
+    pub storage: Arc<dyn StorageBackend>,
 }
+
+/// 一个备份目的地在本次备份中的状态
+struct Destination {
+    /// 日志中使用的名称, 有多个目的地时附带目的地
+    label: String,
+    /// 该目的地的任务配置
+    config: BackupConfig,
+    /// 该目的地的存储, 检查目的地时确定
+    storage: Arc<dyn StorageBackend>,
+    compression: Compression,
+    stats: RunStats,
+    result: Result<(), Error>,
+    /// 需要写入新版本的目的地的备份目录、版本索引及删除记录
+    pending: Option<(PathBuf, VersionIndex, PruneReport)>,
+}
+
+impl Destination {
+    fn fail(&mut self, e: Error) {
+        error!("{}:{}", self.label, e);
+        self.pending = None;
+        self.result = Err(e);
+    }
+}
+
 impl VersionMode {
     /// 创建整个备份计划
     /// 应当只使用这个create生成计划
     pub fn create(task: BackupConfig) -> Self {
-        VersionMode { task_config: task }
+        VersionMode {
+            task_config: task,
+            storage: storage::local(),
+        }
     }
+
+    /// 备份到所有备份目的地
+    pub fn backup(&mut self, task_name: &str) -> RunStats {
+        let due: Vec<usize> = (0..self.task_config.destinations().len()).collect();
+        self.backup_to(task_name, &due)
+    }
+
     /// 用于执行备份计划，根据配置信息进行备份操作。
-    /// 在backup方法中，首先获取备份目录的路径，并根据文件的哈希值判断是否需要进行备份。
-    /// 如果需要备份，则根据指定的版本保留数，删除早期备份。
-    /// 接着根据备份路径获取备份目录，如果获取失败，则跳过备份等待下一个任务。
-    /// 获取成功后，获取所有需要备份的文件路径，并根据路径创建相应的文件夹。
-    /// 然后将文件复制到备份目录中，并记录备份大小。
-    /// 最后将哈希值写入配置文件中，表示备份完成。
-    pub fn backup(&mut self, task_name: &str) {
-        // 获取hash
-        match BackupConfig::get_hash(&self.task_config.backup_source_path) {
-            Ok(hash) => {
-                let mode = &self.task_config.options;
-                if let BackupMode::VersionMode { backup_hashs, .. } = mode {
-                    if !backup_hashs.contains(&hash) {
-                        self.backup_files(task_name, &hash);
-                    } else {
-                        info!(
-                            "{:#?}",
-                            &(task_name.to_owned() + ":检查到无更新,等待下一个备份任务")
-                        );
-                    }
+    /// due 为本次需要备份的目的地在 BackupConfig::destinations 中的序号。
+    /// 在backup方法中，首先同步源目录并计算hash, 各目的地已有相同hash的完整版本时跳过。
+    /// 需要备份时，获取所有需要备份的文件路径，并在各目的地创建临时目录及相应的文件夹。
+    /// 然后读取一次源文件, 同时复制到所有目的地的临时目录中，并记录备份大小。
+    /// 备份完成后将临时目录重命名为版本ID(时间及hash)并写入版本索引，
+    /// 再按各目的地的保留策略删除早期版本，最后将哈希值写入配置文件中，表示备份完成。
+    /// 一个目的地出错不影响其他目的地, 返回本次运行的统计信息, 其中包含各目的地的结果
+    pub fn backup_to(&mut self, task_name: &str, due: &[usize]) -> RunStats {
+        let mut stats = RunStats::start(task_name);
+        if let Err(e) = self.check_and_backup(task_name, due, &mut stats) {
+            error!(
+                "{:#?}",
+                &(task_name.to_owned() + ":" + e.to_string().as_str())
+            );
+            stats.fail(&e);
+        }
+        stats.finish();
+        stats
+    }
+
+    fn check_and_backup(
+        &mut self,
+        task_name: &str,
+        due: &[usize],
+        stats: &mut RunStats,
+    ) -> Result<(), Error> {
+        let configs = self.task_config.destination_configs()?;
+        let compressions: Vec<Compression> = self
+            .task_config
+            .destinations()
+            .iter()
+            .map(|d| d.compression)
+            .collect();
+        let multiple = configs.len() > 1;
+        let mut destinations: Vec<Destination> = due
+            .iter()
+            .filter_map(|&i| Some((configs.get(i)?.clone(), compressions[i])))
+            .map(|(config, compression)| Destination {
+                label: if multiple {
+                    format!("{}({})", task_name, config.backup_destination_path)
                 } else {
-                    self.backup_files(task_name, &hash);
+                    task_name.to_string()
+                },
+                config,
+                storage: self.storage.clone(),
+                compression,
+                stats: RunStats::start(task_name),
+                result: Ok(()),
+                pending: None,
+            })
+            .collect();
+
+        let result = self.backup_files_to(task_name, &mut destinations, stats);
+        for mut destination in destinations {
+            // 源目录出错时错误只记录在任务中
+            if result.is_err() {
+                destination.stats.status = RunStatus::Failed;
+            }
+            if let Err(e) = std::mem::replace(&mut destination.result, Ok(())) {
+                destination.stats.fail(&e);
+            }
+            stats.merge_destination(
+                &destination.config.backup_destination_path,
+                destination.stats,
+            );
+        }
+        result
+    }
+
+    fn backup_files_to(
+        &mut self,
+        task_name: &str,
+        destinations: &mut [Destination],
+        stats: &mut RunStats,
+    ) -> Result<(), Error> {
+        let sources = self.task_config.source_set()?;
+        remote::pull_source(task_name, &self.task_config)
+            .map_err(context("准备源目录时发生错误"))?;
+        // 获取hash
+        let hash = BackupConfig::get_hash(&sources, self.task_config.symlink_policy)
+            .map_err(context("计算hash时发生错误"))?;
+
+        let backup_title = self.task_config.backup_title(task_name);
+        for destination in destinations.iter_mut() {
+            let checked = remote::destination_storage(&self.storage, &destination.config)
+                .map_err(context("打开备份目的地时发生错误"))
+                .and_then(|storage| {
+                    destination.storage = storage;
+                    self.check_destination(&backup_title, &hash, destination)
+                });
+            match checked {
+                Ok(Some(pending)) => destination.pending = Some(pending),
+                Ok(None) => {
+                    info!(
+                        "{:#?}",
+                        &(destination.label.clone() + ":检查到无更新,等待下一个备份任务")
+                    );
+                    destination.stats.status = RunStatus::NoChange;
+                }
+                Err(e) => destination.fail(e),
+            }
+        }
+        if destinations.iter().all(|d| d.pending.is_none()) {
+            return Ok(());
+        }
+        // 确认需要备份后再创建快照, 快照在本次备份结束后删除
+        let snapshots = SnapshotSet::create(task_name, &self.task_config, sources)
+            .map_err(context("创建快照时发生错误"))?;
+        self.backup_files(task_name, &snapshots.sources, &hash, destinations, stats)
+    }
+
+    /// 读取目的地的版本索引, 已有相同hash的完整版本时返回 None
+    fn check_destination(
+        &self,
+        backup_title: &str,
+        hash: &str,
+        destination: &Destination,
+    ) -> Result<Option<(PathBuf, VersionIndex, PruneReport)>, Error> {
+        let config = &destination.config;
+        let BackupMode::VersionMode { backup_hashs, .. } = &config.options else {
+            return Ok(None);
+        };
+        let storage = destination.storage.as_ref();
+        let backup_root =
+            base_bk_option::get_backup_path(storage, &config.backup_destination_path, backup_title)
+                .map_err(context("获取备份路径时发生错误"))?;
+        let index = VersionIndex::load(storage, &backup_root, backup_hashs)
+            .map_err(context("读取版本索引时发生错误"))?;
+        if index.contains_hash(hash) {
+            return Ok(None);
+        }
+        Ok(Some((
+            backup_root,
+            index,
+            PruneReport::for_task(config, &destination.stats.task_name, false),
+        )))
+    }
+
+    fn backup_files(
+        &mut self,
+        task_name: &str,
+        sources: &SourceSet,
+        hash: &str,
+        destinations: &mut [Destination],
+        stats: &mut RunStats,
+    ) -> Result<(), Error> {
+        info!(
+            "{:#?}",
+            &(task_name.to_owned() + ":当前任务使用版本控制模式,检查到有更新,开始备份")
+        );
+        let policy = self.task_config.symlink_policy;
+        let path_list = base_bk_option::get_all_path(sources, policy, stats)
+            .map_err(context("读取需备份文件时发生错误"))?;
+        let estimate = base_bk_option::estimate_size(&path_list, sources, policy);
+
+        // 先写入临时目录, 完成后再以版本ID命名, 中途出错不会留下不完整的版本
+        let created_at = Local::now();
+        let version_id = VersionIndex::version_id(&created_at, hash);
+        let storages: Vec<Arc<dyn StorageBackend>> =
+            destinations.iter().map(|d| d.storage.clone()).collect();
+        let mut targets = Vec::new();
+        let mut active = Vec::new();
+        for (i, destination) in destinations.iter_mut().enumerate() {
+            let Some((backup_root, index, report)) = &mut destination.pending else {
+                continue;
+            };
+            let storage = storages[i].as_ref();
+            if let Err(e) = report.purge_trash(storage) {
+                warn!("{}:清除回收站时发生错误:{}", destination.label, e);
+            }
+            let mut begin = || {
+                if let Some(quota) = &destination.config.quota {
+                    let result = make_room(
+                        storage,
+                        &destination.config.backup_destination_path,
+                        quota,
+                        backup_root,
+                        index,
+                        estimate,
+                        report,
+                    );
+                    destination.stats.bytes_freed = report.bytes_freed();
+                    result?;
                 }
+                let backup_path = VersionIndex::begin(storage, backup_root, &version_id)
+                    .map_err(context("创建版本目录时发生错误"))?;
+                base_bk_option::create_all_dir(
+                    storage,
+                    &path_list,
+                    &backup_path,
+                    sources,
+                    policy,
+                    &mut destination.stats,
+                )
+                .map_err(context("创建备份文件夹时发生错误"))?;
+                Ok::<PathBuf, Error>(backup_path)
+            };
+            match begin() {
+                Ok(backup_path) => {
+                    let manifest = Manifest {
+                        compression: destination.compression,
+                        ..Manifest::default()
+                    };
+                    let stats =
+                        std::mem::replace(&mut destination.stats, RunStats::start(task_name));
+                    targets.push(CopyTarget::new(
+                        storage,
+                        backup_path,
+                        destination.compression,
+                        manifest,
+                        stats,
+                    ));
+                    active.push(i);
+                }
+                Err(e) => destination.fail(e),
             }
-            Err(e) => {
-                error!(
+        }
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        base_bk_option::copy_file(&path_list, &mut targets, sources, policy, stats)
+            .map_err(context("备份文件时发生错误"))?;
+
+        let mut remaining = Vec::new();
+        for (target, i) in targets.into_iter().zip(active) {
+            let destination = &mut destinations[i];
+            destination.stats = target.stats;
+            let mut manifest = target.manifest;
+            let result = self.finish_version(
+                destination,
+                &path_list,
+                sources,
+                &target.backup_path,
+                &mut manifest,
+                created_at,
+                hash,
+            );
+            match result {
+                Ok(kept) if destination.stats.files_failed == 0 => remaining.push(kept),
+                Ok(_) => {}
+                Err(e) => destination.fail(e),
+            }
+        }
+        // 没有任何目的地完整备份时不写入hash, 下一次运行时重新备份
+        if remaining.is_empty() {
+            if destinations.iter().any(|d| d.stats.files_failed > 0) {
+                warn!(
                     "{:#?}",
-                    &(task_name.to_owned() + "计算hash时发生错误:" + e.to_string().as_str()),
+                    &(task_name.to_owned() + ":部分文件备份失败,不写入hash,等待下一个备份任务重试"),
                 );
-                return;
+            }
+            return Ok(());
+        }
+        if let BackupMode::VersionMode { backup_hashs, .. } = &self.task_config.options {
+            if backup_hashs.iter().any(|h| h == hash) {
+                return Ok(());
             }
         }
+        self.task_config
+            .set_hash(task_name, hash, remaining.into_iter().max().unwrap_or(1))
+            .map_err(context("写入hash时发生错误"))?;
+        info!(
+            "{:#?}",
+            &(task_name.to_owned() + ":hash写入完成,等待下一个备份任务")
+        );
+        Ok(())
     }
 
-    fn backup_files(&mut self, task_name: &str, hash: &str) {
+    /// 保存清单, 将临时目录提交为版本并按保留策略删除早期版本, 返回剩余的版本数
+    #[allow(clippy::too_many_arguments)]
+    fn finish_version(
+        &self,
+        destination: &mut Destination,
+        path_list: &[String],
+        sources: &SourceSet,
+        backup_path: &Path,
+        manifest: &mut Manifest,
+        created_at: chrono::DateTime<Local>,
+        hash: &str,
+    ) -> Result<usize, Error> {
+        let storage = destination.storage.clone();
+        let storage = storage.as_ref();
+        let Some((backup_root, index, report)) = &mut destination.pending else {
+            return Ok(0);
+        };
+        let BackupMode::VersionMode {
+            preserve_version,
+            retention,
+            ..
+        } = &destination.config.options
+        else {
+            return Ok(0);
+        };
+        if destination.config.preserve_metadata {
+            manifest.record_and_apply(&destination.label, path_list, sources, backup_path);
+        }
+        // 清单中记录了符号链接等无法直接保存的路径或压缩方式时, 即使未开启 preserve_metadata 也需要保存
+        if manifest.needs_saving() {
+            manifest
+                .save(storage, backup_path)
+                .map_err(context("保存清单时发生错误"))?;
+        }
+        let complete = destination.stats.files_failed == 0;
+        let version_path = index
+            .commit(
+                storage,
+                backup_root,
+                backup_path,
+                created_at,
+                hash,
+                complete,
+            )
+            .map_err(context("写入版本索引时发生错误"))?;
         info!(
             "{:#?}",
-            &(task_name.to_owned() + ":当前任务使用版本控制模式,检查到有更新,开始备份")
+            &(destination.label.clone()
+                + ":备份完成，备份大小为["
+                + &destination.stats.bytes_written.to_string()
+                + "]字节,备份目录为 "
+                + &version_path.to_string_lossy()),
         );
 
-        let mut backup_path: PathBuf = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let retention = retention
+            .clone()
+            .unwrap_or_else(|| RetentionPolicy::keep_last(*preserve_version));
+        let remaining = base_bk_option::prune_versions(
+            storage,
+            backup_root,
+            index,
+            &retention,
+            *preserve_version,
+            report,
+        );
+        destination.stats.bytes_freed = report.bytes_freed();
+        remaining.map_err(context("删除保留策略之外的版本时发生错误"))
+    }
 
-        if let BackupMode::VersionMode {
+    /// 只按保留策略及空间限额删除旧版本, 不进行备份, 供 prune 命令使用
+    /// task_config 应为单个备份目的地的任务配置(BackupConfig::for_destination)
+    pub fn prune(&self, report: &mut PruneReport) -> Result<(), Error> {
+        let BackupMode::VersionMode {
             backup_hashs,
             preserve_version,
+            retention,
         } = &self.task_config.options
+        else {
+            return Ok(());
+        };
+        let storage = remote::destination_storage(&self.storage, &self.task_config)?;
+        let storage = storage.as_ref();
+        let mut backup_root =
+            base_bk_option::get_backup_base_path(&self.task_config.backup_destination_path);
+        backup_root.push(self.task_config.backup_title(&report.task_name));
+        if !storage
+            .stat(&backup_root)?
+            .is_some_and(|meta| meta.is_dir())
         {
-            let hash_len = backup_hashs.len() + 1;
-            let total_len = *preserve_version + 1;
-            if hash_len >= total_len {
-                match base_bk_option::remove_first_version(
-                    &self.task_config.backup_destination_path,
-                    &self.task_config.detect_path_title().unwrap_or_default(),
-                    preserve_version,
-                ) {
-                    Ok(s) => {
-                        backup_path = s;
-                        info!(
-                            "{:#?}",
-                            &(task_name.to_owned() + ":检查到历史版本过多,已删除早期备份")
-                        );
-                    }
-                    Err(e) => {
-                        error!(
-                            "{:#?}",
-                            &(task_name.to_owned()
-                                + "删除早期备份时发生错误:"
-                                + e.to_string().as_str()),
-                        );
-                        return;
-                    }
-                }
-            }
+            return Ok(());
+        }
+        let mut index = VersionIndex::load(storage, &backup_root, backup_hashs)
+            .map_err(context("读取版本索引时发生错误"))?;
+        let retention = retention
+            .clone()
+            .unwrap_or_else(|| RetentionPolicy::keep_last(*preserve_version));
+        base_bk_option::prune_versions(
+            storage,
+            &backup_root,
+            &mut index,
+            &retention,
+            *preserve_version,
+            report,
+        )
+        .map_err(context("删除保留策略之外的版本时发生错误"))?;
+        if let Some(quota) = &self.task_config.quota {
+            make_room(
+                storage,
+                &self.task_config.backup_destination_path,
+                quota,
+                &backup_root,
+                &mut index,
+                0,
+                report,
+            )?;
+        }
+        Ok(())
+    }
+}
 
-            if backup_path == PathBuf::from(env!("CARGO_MANIFEST_DIR")) {
-                match base_bk_option::get_backup_path_by_version(
-                    &self.task_config.backup_destination_path,
-                    &self.task_config.detect_path_title().unwrap_or_default(),
-                    &backup_hashs.len(),
-                    preserve_version,
-                ) {
-                    Ok(s) => backup_path = s,
-                    Err(e) => {
-                        error!(
-                            "{:#?}",
-                            &(task_name.to_owned()
-                                + "获取备份路径时发生错误:"
-                                + e.to_string().as_str()),
-                        );
-                        return;
-                    }
-                }
-            }
-        } else {
-            info!(
-                "{:#?}",
-                &(task_name.to_owned() + ":当前任务使用动态目录模式,检查到有更新,开始备份")
-            );
+/// 空间限额不足时从最早的版本开始删除, 最新的版本总是保留
+/// 删除所有可删除的版本后仍然不足时不删除任何版本, 返回错误, 不开始备份
+fn make_room(
+    storage: &dyn StorageBackend,
+    destination_path: &str,
+    quota: &QuotaConfig,
+    backup_root: &Path,
+    index: &mut VersionIndex,
+    estimate: u64,
+    report: &mut PruneReport,
+) -> Result<(), Error> {
+    let usage = quota
+        .usage(
+            storage,
+            backup_root,
+            &base_bk_option::get_backup_base_path(destination_path),
+        )
+        .map_err(context("读取备份空间占用时发生错误"))?;
 
-            if backup_path == PathBuf::from(env!("CARGO_MANIFEST_DIR")) {
-                match base_bk_option::get_backup_path(
-                    &self.task_config.backup_destination_path,
-                    &self.task_config.detect_path_title().unwrap_or_default(),
-                ) {
-                    Ok(s) => backup_path = s,
-                    Err(e) => {
-                        error!(
-                            "{:#?}",
-                            &(task_name.to_owned()
-                                + "获取备份路径时发生错误:"
-                                + e.to_string().as_str()),
-                        );
-                        return;
-                    }
-                }
-            }
+    let mut freed = report.simulated_freed();
+    let mut reasons = Vec::new();
+    let candidates = index.versions.len().saturating_sub(1);
+    while let Some(reason) = quota.shortfall(&usage, estimate, freed) {
+        if reasons.len() >= candidates {
+            return Err(quota_error(reason));
         }
+        let entry = &index.versions[reasons.len()];
+        freed += dir_size(storage, &VersionIndex::version_path(backup_root, entry))?;
+        reasons.push(reason);
+    }
+    if reasons.is_empty() {
+        return Ok(());
+    }
 
-        if backup_path == PathBuf::from(env!("CARGO_MANIFEST_DIR")) {
-            warn!(
-                "{:#?}",
-                &(task_name.to_owned() + "检查hash有更新,但未获取备份路径,跳过等待下一个备份任务"),
-            );
-            return;
+    // 逐个删除版本目录, 删除成功后才从索引中移除并保存, 中途出错时索引与磁盘保持一致
+    let pruned: Vec<VersionEntry> = index.versions[..reasons.len()].to_vec();
+    for (entry, reason) in pruned.iter().zip(reasons) {
+        report.remove(
+            storage,
+            &VersionIndex::version_path(backup_root, entry),
+            PruneRule::Quota,
+            format!(
+                "版本时间 {}, {}",
+                entry.created_at.format("%Y-%m-%d %T"),
+                reason
+            ),
+        )?;
+        if report.dry_run {
+            continue;
         }
+        index.versions.retain(|v| v.id != entry.id);
+        index
+            .save(storage, backup_root)
+            .map_err(context("写入版本索引时发生错误"))?;
+    }
+    Ok(())
+}
 
-        match base_bk_option::get_all_path(&self.task_config.backup_source_path) {
-            Ok(path_list) => {
-                match base_bk_option::create_all_dir(
-                    &path_list,
-                    &backup_path,
-                    &self.task_config.detect_path_title().unwrap_or_default(),
-                ) {
-                    Ok(()) => {
-                        match base_bk_option::copy_file(
-                            &path_list,
-                            &backup_path,
-                            &self.task_config.detect_path_title().unwrap_or_default(),
-                        ) {
-                            Ok(size) => {
-                                info!(
-                                    "{:#?}",
-                                    &(task_name.to_owned()
-                                        + ":备份完成，备份大小为["
-                                        + &size.to_string()
-                                        + "]MB"),
-                                );
-
-                                if let BackupMode::VersionMode { .. } =
-                                    &mut self.task_config.options
-                                {
-                                    match self.task_config.set_hash(task_name, hash) {
-                                        Ok(_) => {
-                                            info!(
-                                                "{:#?}",
-                                                &(task_name.to_owned()
-                                                    + ":hash写入完成,备份目录为 "
-                                                    + &backup_path.as_os_str().to_str().unwrap()
-                                                    + ",等待下一个备份任务"),
-                                            );
-                                        }
-                                        Err(e) => {
-                                            error!(
-                                                "{:#?}",
-                                                &(task_name.to_owned()
-                                                    + "写入hash时发生错误:"
-                                                    + e.to_string().as_str()),
-                                            );
-                                            return;
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!(
-                                    "{:#?}",
-                                    &(task_name.to_owned()
-                                        + "备份文件时发生错误:"
-                                        + e.to_string().as_str()),
-                                );
-                                return;
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!(
-                            "{:#?}",
-                            &(task_name.to_owned()
-                                + "创建备份文件夹时发生错误:"
-                                + e.to_string().as_str()),
-                        );
-                        return;
-                    }
-                }
-            }
-            Err(e) => {
-                error!(
-                    "{:#?}",
-                    &(task_name.to_owned() + "读取需备份文件时发生错误:" + e.to_string().as_str()),
-                );
-                return;
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::quota::ByteSize;
+    use crate::mods::storage::{MemoryStorage, StorageKind};
+    use std::fs;
+
+    /// 在 backup_root 下创建 count 个各含 size 字节文件的版本
+    fn versions(backup_root: &Path, count: usize, size: usize) -> VersionIndex {
+        let mut index = VersionIndex::default();
+        for i in 0..count {
+            let created_at = Local::now() - chrono::Duration::days((count - i) as i64);
+            let id = VersionIndex::version_id(&created_at, &format!("{:08}", i));
+            fs::create_dir_all(backup_root.join(&id)).unwrap();
+            fs::write(backup_root.join(&id).join("file"), vec![0u8; size]).unwrap();
+            index.versions.push(VersionEntry {
+                id,
+                created_at,
+                hash: Some(i.to_string()),
+                migrated_from: None,
+            });
         }
+        index
+    }
+
+    fn task_quota(max: u64) -> QuotaConfig {
+        QuotaConfig {
+            max_task_size: Some(ByteSize(max)),
+            ..QuotaConfig::default()
+        }
+    }
+
+    #[test]
+    fn make_room_saves_index_after_each_removal() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("task");
+        let mut index = versions(&backup_root, 3, 100);
+        let storage = storage::local();
+        let mut report = PruneReport::new("task", false);
+        make_room(
+            storage.as_ref(),
+            &dir.path().to_string_lossy(),
+            &task_quota(250),
+            &backup_root,
+            &mut index,
+            100,
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(report.items.len(), 2);
+        assert_eq!(index.versions.len(), 1);
+        let saved = VersionIndex::read(storage.as_ref(), &backup_root).unwrap();
+        assert_eq!(saved.versions.len(), 1);
+        assert_eq!(saved.versions[0].id, index.versions[0].id);
+        assert!(backup_root.join(&index.versions[0].id).is_dir());
+    }
+
+    #[test]
+    fn make_room_keeps_everything_when_quota_cannot_be_met() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("task");
+        let mut index = versions(&backup_root, 3, 100);
+        let mut report = PruneReport::new("task", false);
+        let result = make_room(
+            storage::local().as_ref(),
+            &dir.path().to_string_lossy(),
+            &task_quota(50),
+            &backup_root,
+            &mut index,
+            100,
+            &mut report,
+        );
+        assert!(result.is_err());
+        assert!(report.items.is_empty());
+        assert_eq!(index.versions.len(), 3);
+    }
+
+    #[test]
+    fn make_room_dry_run_does_not_touch_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_root = dir.path().join("task");
+        let mut index = versions(&backup_root, 3, 100);
+        let mut report = PruneReport::new("task", true);
+        make_room(
+            storage::local().as_ref(),
+            &dir.path().to_string_lossy(),
+            &task_quota(250),
+            &backup_root,
+            &mut index,
+            100,
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(report.items.len(), 2);
+        assert_eq!(index.versions.len(), 3);
+        assert!(index
+            .versions
+            .iter()
+            .all(|v| backup_root.join(&v.id).is_dir()));
+    }
+
+    #[test]
+    fn backs_up_to_storage_backend() {
+        backs_up_versions(MemoryStorage::new(), "version_memory_storage_test");
+    }
+
+    /// 重命名需要复制数据的存储直接写入版本目录, 结果与其他存储相同
+    #[test]
+    fn backs_up_to_storage_with_copying_renames() {
+        backs_up_versions(
+            MemoryStorage::with_copying_renames(),
+            "version_copying_storage_test",
+        );
+    }
+
+    /// 估算的大小超出某个目的地的限额时, 该目的地在写入前中止, 不留下版本目录, 其他目的地照常备份
+    #[test]
+    fn destination_over_quota_aborts_before_writing() {
+        let task_name = "version_destination_quota_test";
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a"), b"one").unwrap();
+        let yaml = format!(
+            "backup_source_path: {}\nbackup_destination_path: /memory/main\ndestinations:\n  - path: /memory/small\n    quota:\n      max_task_size: 2\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 2\n",
+            source.display()
+        );
+        let config_path = BackupConfig::get_hash_path(task_name);
+        fs::write(&config_path, &yaml).unwrap();
+        let storage = Arc::new(MemoryStorage::new());
+        let mut mode = VersionMode {
+            task_config: serde_yaml::from_str(&yaml).unwrap(),
+            storage: storage.clone(),
+        };
+        let stats = mode.backup(task_name);
+        fs::remove_file(&config_path).unwrap();
+
+        assert_eq!(stats.status, RunStatus::Partial);
+        assert_eq!(stats.destinations[0].status, RunStatus::Success);
+        assert_eq!(stats.destinations[1].status, RunStatus::Failed);
+        assert!(
+            stats.destinations[1].errors[0].contains("空间限额不足"),
+            "{:?}",
+            stats.destinations[1].errors
+        );
+        let main = VersionIndex::read(storage.as_ref(), Path::new("/memory/main/data")).unwrap();
+        assert_eq!(main.versions.len(), 1);
+        let small = Path::new("/memory/small/data");
+        assert!(VersionIndex::read(storage.as_ref(), small)
+            .unwrap()
+            .versions
+            .is_empty());
+        assert!(storage
+            .list(small)
+            .unwrap_or_default()
+            .iter()
+            .all(|e| !e.meta.is_dir() && !e.name.ends_with(".partial")));
+    }
+
+    /// 多个源目录共同组成一个版本, 任一源目录变动时新版本包含所有源目录
+    #[test]
+    fn sources_share_one_version() {
+        let task_name = "version_multi_source_test";
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let conf = dir.path().join("etc/conf");
+        fs::create_dir_all(&data).unwrap();
+        fs::create_dir_all(&conf).unwrap();
+        fs::write(data.join("a"), b"one").unwrap();
+        fs::write(conf.join("b"), b"two").unwrap();
+        let yaml = format!(
+            "sources:\n  - path: {}\n  - path: {}\n    alias: settings\nbackup_destination_path: /memory\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 3\n",
+            data.display(),
+            conf.display()
+        );
+        let config_path = BackupConfig::get_hash_path(task_name);
+        fs::write(&config_path, &yaml).unwrap();
+        let storage = Arc::new(MemoryStorage::new());
+        let mut mode = VersionMode {
+            task_config: serde_yaml::from_str(&yaml).unwrap(),
+            storage: storage.clone(),
+        };
+        let first = mode.backup(task_name);
+        fs::write(conf.join("c"), b"three").unwrap();
+        let second = mode.backup(task_name);
+        fs::remove_file(&config_path).unwrap();
+
+        assert_eq!(first.status, RunStatus::Success);
+        assert_eq!(second.status, RunStatus::Success);
+        // 有多个源目录时备份目录取任务名
+        let backup_root = Path::new("/memory").join(task_name);
+        let index = VersionIndex::read(storage.as_ref(), &backup_root).unwrap();
+        assert_eq!(index.versions.len(), 2);
+        let read = |id: &str, relative: &str| storage.read(&backup_root.join(id).join(relative));
+        for entry in &index.versions {
+            assert_eq!(read(&entry.id, "data/a").unwrap(), b"one");
+            assert_eq!(read(&entry.id, "settings/b").unwrap(), b"two");
+        }
+        assert!(read(&index.versions[0].id, "settings/c").is_err());
+        assert_eq!(read(&index.versions[1].id, "settings/c").unwrap(), b"three");
+        let folders: Vec<String> = storage
+            .list(&backup_root.join(&index.versions[1].id))
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(folders.len(), 2, "{:?}", folders);
+    }
+
+    fn backs_up_versions(storage: MemoryStorage, task_name: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a"), b"one").unwrap();
+        fs::write(source.join("sub/b"), b"two").unwrap();
+        let yaml = format!(
+            "backup_source_path: {}\nbackup_destination_path: /memory\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: VersionMode\n  backup_hashs: []\n  preserve_version: 2\n",
+            source.display()
+        );
+        // 写入hash时需要任务的配置文件已存在
+        let config_path = BackupConfig::get_hash_path(task_name);
+        fs::write(&config_path, &yaml).unwrap();
+        let storage = Arc::new(storage);
+        let mut mode = VersionMode {
+            task_config: serde_yaml::from_str(&yaml).unwrap(),
+            storage: storage.clone(),
+        };
+        let first = mode.backup(task_name);
+        let unchanged = mode.backup(task_name);
+        // hash按修改时间计算, 精确到秒, 新增文件才能保证产生新的版本
+        fs::write(source.join("c"), b"three").unwrap();
+        let second = mode.backup(task_name);
+        fs::remove_file(&config_path).unwrap();
+
+        assert_eq!(first.status, RunStatus::Success);
+        assert_eq!(unchanged.status, RunStatus::NoChange);
+        assert_eq!(second.status, RunStatus::Success);
+        let backup_root = Path::new("/memory/data");
+        let index = VersionIndex::read(storage.as_ref(), backup_root).unwrap();
+        assert_eq!(index.versions.len(), 2);
+        let read = |id: &str, relative: &str| storage.read(&backup_root.join(id).join(relative));
+        assert_eq!(read(&index.versions[0].id, "a").unwrap(), b"one");
+        assert!(read(&index.versions[0].id, "c").is_err());
+        assert_eq!(read(&index.versions[1].id, "c").unwrap(), b"three");
+        assert_eq!(read(&index.versions[1].id, "sub/b").unwrap(), b"two");
+        let versions = storage
+            .list(backup_root)
+            .unwrap()
+            .into_iter()
+            .filter(|e| e.meta.kind == StorageKind::Dir)
+            .count();
+        assert_eq!(versions, 2);
+        assert!(storage
+            .list(backup_root)
+            .unwrap()
+            .iter()
+            .all(|e| !e.name.ends_with(".partial")));
+        assert!(!Path::new("/memory").exists());
     }
 }
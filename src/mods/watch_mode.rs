@@ -0,0 +1,318 @@
+use log::{error, warn};
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    /// 监听线程正在运行的任务
+    static ref WATCHERS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// 确保任务的监听线程正在运行
+/// 返回 true 表示该任务由监听线程负责备份, 调度器应当跳过该任务
+/// 监听线程启动失败或退出后, 调度器下一轮会重新尝试启动, 期间按轮询模式备份
+pub fn ensure_watcher(task_name: &str) -> bool {
+    let mut watchers = WATCHERS.lock().unwrap();
+    if watchers.contains(task_name) {
+        return true;
+    }
+    if !cfg!(target_os = "linux") {
+        warn!("{}:文件监听模式仅支持 Linux, 使用轮询模式备份", task_name);
+        return false;
+    }
+    watchers.insert(task_name.to_string());
+
+    let name = task_name.to_string();
+    let spawn_result = std::thread::Builder::new()
+        .name(format!("watch-{}", task_name))
+        .spawn(move || {
+            let _guard = WatcherGuard(name.clone());
+            #[cfg(target_os = "linux")]
+            if let Err(e) = linux::watch_loop(&name) {
+                error!("{}:文件监听发生错误, 退回轮询模式:{}", name, e);
+            }
+        });
+    if let Err(e) = spawn_result {
+        error!("{}:启动文件监听线程时发生错误:{}", task_name, e);
+        watchers.remove(task_name);
+        return false;
+    }
+    true
+}
+
+/// 监听线程退出(包括 panic)时从运行列表中移除
+struct WatcherGuard(String);
+
+impl Drop for WatcherGuard {
+    fn drop(&mut self) {
+        if let Ok(mut watchers) = WATCHERS.lock() {
+            watchers.remove(&self.0);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::super::bk_config::BackupConfig;
+    use super::super::{
+        hooks, incremental_mode::IncrementalMode, network_interface_operate, notify, rsbk,
+    };
+    use chrono::{Duration, Local};
+    use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+    use log::{info, warn};
+    use std::collections::{BTreeSet, HashMap};
+    use std::fs::read_dir;
+    use std::io::{Error, ErrorKind};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Instant;
+
+    /// 读取事件的间隔
+    const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+    /// 监听一个任务的源目录, 在变动平息后进行备份
+    /// 启动时及事件队列溢出后进行完整扫描
+    /// 按 backup_interval_minutes 定期完整扫描, 用于执行保留策略和补漏
+    /// 任务被删除、失效或关闭监听后正常退出
+    pub fn watch_loop(task_name: &str) -> Result<(), Error> {
+        let config_path = BackupConfig::get_hash_path(task_name);
+        let mut config = BackupConfig::create(&config_path)?;
+        if let Some(reason) = config.watch_unsupported() {
+            info!("{}:{}, 停止文件监听", task_name, reason);
+            return Ok(());
+        }
+        let sources = config.sources();
+        let roots: Vec<PathBuf> = sources.iter().map(|s| PathBuf::from(&s.path)).collect();
+
+        let mut watcher = TreeWatcher::new(&roots)?;
+        info!(
+            "{}:文件监听已启动, 共监听[{}]个目录",
+            task_name,
+            watcher.dirs.len()
+        );
+        full_backup(task_name, &config);
+        let mut next_full_scan =
+            Local::now() + Duration::minutes(config.backup_interval_minutes.max(1) as i64);
+
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            watcher.poll()?;
+
+            let Some(debounce_seconds) = config.watch.as_ref().map(|w| w.debounce_seconds) else {
+                return Ok(());
+            };
+            let settled = watcher
+                .last_event
+                .map(|t| t.elapsed().as_secs() >= debounce_seconds)
+                .unwrap_or(false);
+            let full_scan_due = Local::now() >= next_full_scan;
+            if !(watcher.overflow || full_scan_due || (settled && !watcher.pending.is_empty())) {
+                continue;
+            }
+
+            // 每次备份前重新读取配置, 获取最新的hash并响应配置变动
+            config = match BackupConfig::create(&config_path) {
+                Ok(c) => c,
+                Err(_) => {
+                    info!("{}:备份计划已删除, 停止文件监听", task_name);
+                    return Ok(());
+                }
+            };
+            if !config.is_effect || config.watch.is_none() {
+                info!("{}:备份计划已失效或关闭文件监听, 停止文件监听", task_name);
+                return Ok(());
+            }
+            if let Some(reason) = config.watch_unsupported() {
+                info!("{}:{}, 停止文件监听", task_name, reason);
+                return Ok(());
+            }
+            if config.sources() != sources {
+                info!("{}:源目录已变更, 重新启动文件监听", task_name);
+                return Ok(());
+            }
+
+            let (paths, overflow) = watcher.take();
+            if overflow || full_scan_due {
+                if overflow {
+                    warn!("{}:文件监听事件队列溢出, 改为完整扫描", task_name);
+                }
+                full_backup(task_name, &config);
+                next_full_scan =
+                    Local::now() + Duration::minutes(config.backup_interval_minutes.max(1) as i64);
+            } else {
+                changed_backup(task_name, &config, &paths);
+            }
+        }
+    }
+
+    /// 完整扫描一次源目录并备份
+    fn full_backup(task_name: &str, config: &BackupConfig) {
+        info!("{}:文件监听模式执行完整扫描", task_name);
+        notify::on_run_started(task_name);
+        let running = rsbk::backup_lock();
+        let stats = network_interface_operate::run_with_air_gap(task_name, config, || {
+            hooks::run_with_hooks(task_name, config, || {
+                IncrementalMode::create(config.clone()).backup(task_name)
+            })
+        });
+        drop(running);
+        stats.record();
+    }
+
+    /// 仅备份发生变动的路径
+    fn changed_backup(task_name: &str, config: &BackupConfig, paths: &[String]) {
+        info!(
+            "{}:文件监听模式检查到[{}]个路径变动,开始备份",
+            task_name,
+            paths.len()
+        );
+        notify::on_run_started(task_name);
+        // 与定时备份共用备份锁, 避免与其他任务同时备份
+        let running = rsbk::backup_lock();
+        let stats = network_interface_operate::run_with_air_gap(task_name, config, || {
+            hooks::run_with_hooks(task_name, config, || {
+                IncrementalMode::create(config.clone()).backup_paths(task_name, paths)
+            })
+        });
+        drop(running);
+        stats.record();
+    }
+
+    /// 递归监听源目录树
+    struct TreeWatcher {
+        inotify: Inotify,
+        roots: Vec<PathBuf>,
+        dirs: HashMap<WatchDescriptor, PathBuf>,
+        buffer: Vec<u8>,
+        /// 自上次备份以来变动的路径
+        pending: BTreeSet<PathBuf>,
+        /// 事件队列是否溢出, 溢出后变动的路径不再可信
+        overflow: bool,
+        last_event: Option<Instant>,
+    }
+
+    impl TreeWatcher {
+        fn new(roots: &[PathBuf]) -> Result<Self, Error> {
+            let mut watcher = TreeWatcher {
+                inotify: Inotify::init()?,
+                roots: roots.to_vec(),
+                dirs: HashMap::new(),
+                buffer: vec![0; 64 * 1024],
+                pending: BTreeSet::new(),
+                overflow: false,
+                last_event: None,
+            };
+            for root in roots {
+                watcher.add_tree(root, false)?;
+            }
+            Ok(watcher)
+        }
+
+        /// 为目录及其所有子目录添加监听
+        /// is_new 为 true 时, 目录中已有的文件也记为变动(监听建立前写入的文件不会产生事件)
+        fn add_tree(&mut self, dir: &Path, is_new: bool) -> Result<(), Error> {
+            let mut directories = vec![dir.to_path_buf()];
+            while let Some(path) = directories.pop() {
+                let mask = WatchMask::CLOSE_WRITE
+                    | WatchMask::CREATE
+                    | WatchMask::DELETE
+                    | WatchMask::MOVED_FROM
+                    | WatchMask::MOVED_TO
+                    | WatchMask::ATTRIB
+                    | WatchMask::DELETE_SELF
+                    | WatchMask::MOVE_SELF
+                    | WatchMask::DONT_FOLLOW
+                    | WatchMask::ONLYDIR;
+                let wd = match self.inotify.watches().add(&path, mask) {
+                    Ok(wd) => wd,
+                    // 目录在添加监听前已被删除
+                    Err(e) if e.kind() == ErrorKind::NotFound && !self.roots.contains(&path) => {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.dirs.insert(wd, path.clone());
+
+                let entries = match read_dir(&path) {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound && !self.roots.contains(&path) => {
+                        continue
+                    }
+                    Err(e) => return Err(e),
+                };
+                for entry in entries {
+                    let entry = entry?;
+                    if entry.file_type()?.is_dir() {
+                        directories.push(entry.path());
+                    } else if is_new {
+                        self.pending.insert(entry.path());
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// 读取所有已到达的事件
+        fn poll(&mut self) -> Result<(), Error> {
+            loop {
+                let mut new_dirs = Vec::new();
+                let events = match self.inotify.read_events(&mut self.buffer) {
+                    Ok(events) => events,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                let mut received = false;
+                for event in events {
+                    received = true;
+                    if event.mask.contains(EventMask::Q_OVERFLOW) {
+                        self.overflow = true;
+                        continue;
+                    }
+                    let Some(dir) = self.dirs.get(&event.wd).cloned() else {
+                        continue;
+                    };
+                    if event.mask.contains(EventMask::IGNORED) {
+                        self.dirs.remove(&event.wd);
+                        if self.roots.contains(&dir) {
+                            return Err(Error::new(
+                                ErrorKind::NotFound,
+                                format!("源目录 {} 已被删除或移动", dir.display()),
+                            ));
+                        }
+                        continue;
+                    }
+                    let Some(name) = event.name else {
+                        continue;
+                    };
+                    let path = dir.join(name);
+                    if event.mask.contains(EventMask::ISDIR)
+                        && event
+                            .mask
+                            .intersects(EventMask::CREATE | EventMask::MOVED_TO)
+                    {
+                        new_dirs.push(path.clone());
+                    }
+                    self.pending.insert(path);
+                }
+                for dir in new_dirs {
+                    self.add_tree(&dir, true)?;
+                }
+                if !received {
+                    return Ok(());
+                }
+                self.last_event = Some(Instant::now());
+            }
+        }
+
+        /// 取出变动的路径及溢出标记, 并重置状态
+        fn take(&mut self) -> (Vec<String>, bool) {
+            let paths = std::mem::take(&mut self.pending)
+                .into_iter()
+                .map(|p| p.to_string_lossy().to_string())
+                .collect();
+            let overflow = self.overflow;
+            self.overflow = false;
+            self.last_event = None;
+            (paths, overflow)
+        }
+    }
+}
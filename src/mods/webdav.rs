@@ -0,0 +1,832 @@
+use super::remote::{self, SpoolFile};
+use super::storage::{Link, StorageBackend, StorageEntry, StorageKind, StorageMeta};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::DateTime;
+use log::warn;
+use md5::Md5;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// 上传时先写入的临时文件后缀, 完成后移动到目标位置
+const UPLOAD_SUFFIX: &str = ".rsbk_upload";
+/// 读写时的缓冲区大小
+const BUFFER_SIZE: usize = 64 * 1024;
+/// PROPFIND 请求的属性
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><D:propfind xmlns:D="DAV:"><D:prop><D:resourcetype/><D:getcontentlength/><D:getlastmodified/></D:prop></D:propfind>"#;
+
+/// webdav:// 及 webdavs:// 地址的连接配置
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebDavConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// 认证方式, 默认 Basic
+    #[serde(default)]
+    pub auth: WebDavAuth,
+    /// 上传时使用分块传输编码(Transfer-Encoding: chunked)
+    /// 服务器不支持时关闭, 改为带 Content-Length 上传
+    #[serde(default = "default_chunked")]
+    pub chunked: bool,
+    /// 连接及读写的超时秒数
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// 请求失败(网络错误或服务端 5xx)时重试的次数
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+    /// 每次重试前等待的秒数
+    #[serde(default = "default_retry_delay_seconds")]
+    pub retry_delay_seconds: u64,
+}
+
+/// HTTP 认证方式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WebDavAuth {
+    /// 每个请求都附带用户名和密码, 请使用 webdavs://
+    #[default]
+    Basic,
+    /// 按服务器返回的 challenge 计算摘要, 支持 MD5、MD5-sess、SHA-256 及 SHA-256-sess
+    Digest,
+}
+
+fn default_chunked() -> bool {
+    true
+}
+
+fn default_timeout_seconds() -> u64 {
+    60
+}
+
+fn default_retries() -> usize {
+    3
+}
+
+fn default_retry_delay_seconds() -> u64 {
+    5
+}
+
+impl Default for WebDavConfig {
+    fn default() -> Self {
+        WebDavConfig {
+            username: None,
+            password: None,
+            auth: WebDavAuth::default(),
+            chunked: default_chunked(),
+            timeout_seconds: default_timeout_seconds(),
+            retries: default_retries(),
+            retry_delay_seconds: default_retry_delay_seconds(),
+        }
+    }
+}
+
+/// 解析后的 webdav://host:port/path 地址
+/// webdav:// 使用 http, webdavs:// 使用 https
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebDavUrl {
+    /// 服务地址, 如 https://host:port
+    pub base: String,
+    /// 服务器上的路径, 以 / 开头
+    pub path: String,
+}
+
+impl WebDavUrl {
+    pub fn parse(url: &str) -> Result<WebDavUrl, Error> {
+        let (scheme, rest) = match url.split_once("://") {
+            Some(("webdav", rest)) => ("http", rest),
+            Some(("webdavs", rest)) => ("https", rest),
+            _ => ("", ""),
+        };
+        let (host, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "无法识别的 webdav 地址: {}(用户名和密码请填写在 webdav 配置中)",
+                    url
+                ),
+            ));
+        }
+        Ok(WebDavUrl {
+            base: format!("{}://{}", scheme, host),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// 服务器返回的 Digest challenge
+#[derive(Debug, Clone)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: String,
+    /// 服务器支持 qop=auth
+    qop_auth: bool,
+    /// 使用同一个 nonce 的请求数
+    nc: u32,
+}
+
+/// 请求体
+enum Body<'a> {
+    Empty,
+    Text(&'a str),
+    /// 每次发送(包括重试)时重新打开文件
+    File(&'a Path),
+}
+
+/// 通过 WebDAV 访问 NAS 等只提供 WebDAV 的存储
+/// 目录由 MKCOL 创建, 列目录使用 PROPFIND, 上传先 PUT 到临时文件再 MOVE 到目标位置
+pub struct WebDavStorage {
+    url: WebDavUrl,
+    config: WebDavConfig,
+    agent: ureq::Agent,
+    /// 最近一次收到的 Digest challenge, 各请求共用
+    digest: Mutex<Option<DigestChallenge>>,
+}
+
+/// 不输出用户名和密码
+impl fmt::Debug for WebDavStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebDavStorage")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WebDavStorage {
+    pub fn create(url: WebDavUrl, config: WebDavConfig) -> Result<Self, Error> {
+        if config.auth == WebDavAuth::Digest
+            && (config.username.is_none() || config.password.is_none())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "webdav 配置使用 Digest 认证时需要填写 username 和 password",
+            ));
+        }
+        let timeout = Duration::from_secs(config.timeout_seconds.max(1));
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(timeout)
+            .timeout_read(timeout)
+            .timeout_write(timeout)
+            .redirects(0)
+            .build();
+        Ok(WebDavStorage {
+            url,
+            config,
+            agent,
+            digest: Mutex::new(None),
+        })
+    }
+
+    /// 发送请求, 网络错误及服务端错误时重试, 收到 Digest challenge 时认证后重发
+    /// 返回状态码小于 300 的响应, 其他状态码转换为错误
+    fn send(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: Body,
+    ) -> Result<ureq::Response, Error> {
+        let mut attempt = 0;
+        let mut authenticated = false;
+        loop {
+            match self.send_once(method, path, headers, &body)? {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(401, response))
+                    if self.config.auth == WebDavAuth::Digest && !authenticated =>
+                {
+                    let challenge = parse_challenge(&response).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::PermissionDenied,
+                            format!("{} 未返回 Digest 认证的 challenge", self.url.base),
+                        )
+                    })?;
+                    *self.lock_digest()? = Some(challenge);
+                    authenticated = true;
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let error = status_error(path, status, response);
+                    if !(status >= 500 || status == 429) || attempt >= self.config.retries {
+                        return Err(error);
+                    }
+                    self.wait_retry(&error, &mut attempt);
+                }
+                Err(ureq::Error::Transport(transport)) => {
+                    let error = Error::new(ErrorKind::ConnectionAborted, transport.to_string());
+                    if attempt >= self.config.retries {
+                        return Err(error);
+                    }
+                    self.wait_retry(&error, &mut attempt);
+                }
+            }
+        }
+    }
+
+    fn wait_retry(&self, error: &Error, attempt: &mut usize) {
+        *attempt += 1;
+        warn!(
+            "{} 请求失败:{}, {}秒后第[{}]次重试",
+            self.url.base, error, self.config.retry_delay_seconds, attempt
+        );
+        thread::sleep(Duration::from_secs(self.config.retry_delay_seconds));
+    }
+
+    /// 附带认证信息后发送, 本地文件读取失败时返回外层的错误
+    fn send_once(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, String)],
+        body: &Body,
+    ) -> Result<Result<ureq::Response, ureq::Error>, Error> {
+        let uri = uri_encode(path);
+        let mut request = self
+            .agent
+            .request(method, &format!("{}{}", self.url.base, uri));
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        if let Some(authorization) = self.authorization(method, &uri)? {
+            request = request.set("Authorization", &authorization);
+        }
+        Ok(match body {
+            Body::Empty => request.call(),
+            Body::Text(text) => request
+                .set("Content-Type", "application/xml; charset=utf-8")
+                .send_string(text),
+            Body::File(local) => {
+                let file = File::open(local)?;
+                if !self.config.chunked {
+                    request = request.set("Content-Length", &file.metadata()?.len().to_string());
+                }
+                request.send(file)
+            }
+        })
+    }
+
+    fn lock_digest(&self) -> Result<std::sync::MutexGuard<'_, Option<DigestChallenge>>, Error> {
+        self.digest
+            .lock()
+            .map_err(|_| Error::other("WebDAV 认证信息的锁已失效"))
+    }
+
+    fn authorization(&self, method: &str, uri: &str) -> Result<Option<String>, Error> {
+        let Some(username) = self.config.username.as_deref() else {
+            return Ok(None);
+        };
+        let password = self.config.password.as_deref().unwrap_or_default();
+        Ok(match self.config.auth {
+            WebDavAuth::Basic => Some(format!(
+                "Basic {}",
+                BASE64.encode(format!("{}:{}", username, password))
+            )),
+            WebDavAuth::Digest => self.lock_digest()?.as_mut().map(|challenge| {
+                challenge.nc += 1;
+                digest_authorization(challenge, username, password, method, uri)
+            }),
+        })
+    }
+
+    fn propfind(&self, path: &str, depth: &str) -> Result<Vec<(String, StorageEntry)>, Error> {
+        let response = self.send(
+            "PROPFIND",
+            path,
+            &[("Depth", depth.to_string())],
+            Body::Text(PROPFIND_BODY),
+        )?;
+        parse_multistatus(&response.into_string()?)
+    }
+
+    /// 下载远程文件到本地, 返回下载的字节数
+    fn download(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        let mut reader = self
+            .send("GET", &remote::remote_path(path), &[], Body::Empty)?
+            .into_reader();
+        let mut file = File::create(local)?;
+        let mut buf = vec![0; BUFFER_SIZE];
+        let mut total = 0;
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buf[..read])?;
+            total += read as u64;
+        }
+        file.sync_all()?;
+        Ok(total)
+    }
+
+    /// 先上传到临时文件再移动到目标位置, 覆盖已存在的文件, 返回上传的字节数
+    fn upload(&self, local: &Path, path: &Path) -> Result<u64, Error> {
+        let path = remote::remote_path(path);
+        let upload_path = format!("{}{}", path, UPLOAD_SUFFIX);
+        self.send("PUT", &upload_path, &[], Body::File(local))?;
+        self.move_to(&upload_path, &path)?;
+        Ok(std::fs::metadata(local)?.len())
+    }
+
+    /// MOVE, 目标已存在时覆盖
+    fn move_to(&self, from: &str, to: &str) -> Result<(), Error> {
+        let destination = format!("{}{}", self.url.base, uri_encode(to));
+        self.send(
+            "MOVE",
+            from,
+            &[("Destination", destination), ("Overwrite", "T".to_string())],
+            Body::Empty,
+        )
+        .map(|_| ())
+    }
+}
+
+impl StorageBackend for WebDavStorage {
+    fn list(&self, path: &Path) -> Result<Vec<StorageEntry>, Error> {
+        let dir = remote::remote_path(path);
+        let dir = dir.trim_end_matches('/');
+        let entries = self.propfind(&format!("{}/", dir), "1")?;
+        Ok(entries
+            .into_iter()
+            .filter(|(href, _)| href.trim_end_matches('/') != dir)
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> Result<Option<StorageMeta>, Error> {
+        match self.propfind(&remote::remote_path(path), "0") {
+            Ok(entries) => Ok(entries.into_iter().next().map(|(_, entry)| entry.meta)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::new();
+        self.send("GET", &remote::remote_path(path), &[], Body::Empty)?
+            .into_reader()
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn write(&self, path: &Path, reader: &mut dyn Read) -> Result<u64, Error> {
+        let spool = SpoolFile::write(reader)?;
+        self.upload(spool.path(), path)
+    }
+
+    fn mkdir(&self, path: &Path) -> Result<(), Error> {
+        remote::mkdir_all(self, path, |dir| {
+            let dir_path = format!("{}/", remote::remote_path(dir).trim_end_matches('/'));
+            self.send("MKCOL", &dir_path, &[], Body::Empty).map(|_| ())
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let from = remote::remote_path(from);
+        let to = remote::remote_path(to);
+        self.move_to(&from, &to)
+    }
+
+    /// DELETE 集合时服务器一并删除其中的内容
+    fn delete(&self, path: &Path) -> Result<(), Error> {
+        let mut path = remote::remote_path(path);
+        if self
+            .stat(Path::new(&path))?
+            .is_some_and(|meta| meta.is_dir())
+        {
+            path = format!("{}/", path.trim_end_matches('/'));
+        }
+        self.send("DELETE", &path, &[], Body::Empty).map(|_| ())
+    }
+
+    fn link(&self, _link: Link, path: &Path) -> Result<(), Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("WebDAV 不支持创建链接: {}", path.display()),
+        ))
+    }
+
+    fn read_link(&self, path: &Path) -> Result<String, Error> {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("WebDAV 不支持符号链接: {}", path.display()),
+        ))
+    }
+
+    fn copy_from(&self, source: &Path, path: &Path) -> Result<u64, Error> {
+        self.upload(source, path)
+    }
+
+    fn copy_to(&self, path: &Path, local: &Path) -> Result<u64, Error> {
+        self.download(path, local)
+    }
+
+    fn digest(&self, path: &Path) -> Result<String, Error> {
+        remote::download_digest(self, path)
+    }
+}
+
+/// 解析 PROPFIND 返回的 multistatus, 返回每个条目解码后的路径及信息
+fn parse_multistatus(body: &str) -> Result<Vec<(String, StorageEntry)>, Error> {
+    let invalid = |e: String| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("无法解析 PROPFIND 的响应: {}", e),
+        )
+    };
+    let mut reader = Reader::from_str(body);
+    let mut entries = Vec::new();
+    let mut element = String::new();
+    let mut href = String::new();
+    let mut is_dir = false;
+    let mut size = 0;
+    let mut mtime = 0;
+    loop {
+        match reader.read_event().map_err(|e| invalid(e.to_string()))? {
+            Event::Start(start) | Event::Empty(start) => {
+                element = String::from_utf8_lossy(start.local_name().as_ref()).to_string();
+                match element.as_str() {
+                    "response" => {
+                        href.clear();
+                        is_dir = false;
+                        size = 0;
+                        mtime = 0;
+                    }
+                    "collection" => is_dir = true,
+                    _ => {}
+                }
+            }
+            Event::Text(text) => {
+                let text = text.unescape().map_err(|e| invalid(e.to_string()))?;
+                let text = text.trim();
+                match element.as_str() {
+                    "href" => href.push_str(text),
+                    "getcontentlength" => size = text.parse().unwrap_or(0),
+                    "getlastmodified" => {
+                        mtime = DateTime::parse_from_rfc2822(text)
+                            .map(|t| t.timestamp().max(0) as u64)
+                            .unwrap_or(0)
+                    }
+                    _ => {}
+                }
+            }
+            Event::End(end) => {
+                element.clear();
+                if end.local_name().as_ref() == b"response" {
+                    let path = href_path(&href);
+                    let name = path
+                        .trim_end_matches('/')
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default()
+                        .to_string();
+                    entries.push((
+                        path,
+                        StorageEntry {
+                            name,
+                            meta: StorageMeta {
+                                kind: if is_dir {
+                                    StorageKind::Dir
+                                } else {
+                                    StorageKind::File
+                                },
+                                len: if is_dir { 0 } else { size },
+                                modified: remote::unix_time(mtime),
+                            },
+                        },
+                    ));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+/// href 可能是完整的地址, 只取路径部分并解码
+fn href_path(href: &str) -> String {
+    let path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|index| &rest[index..]).unwrap_or("/"),
+        None => href,
+    };
+    uri_decode(path)
+}
+
+/// 解析 WWW-Authenticate 中的 Digest challenge
+fn parse_challenge(response: &ureq::Response) -> Option<DigestChallenge> {
+    let header = response
+        .all("WWW-Authenticate")
+        .into_iter()
+        .find(|h| h.len() > 7 && h[..7].eq_ignore_ascii_case("digest "))?;
+    let params = auth_params(&header[7..]);
+    let get = |name: &str| {
+        params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+    };
+    Some(DigestChallenge {
+        realm: get("realm").unwrap_or_default(),
+        nonce: get("nonce")?,
+        opaque: get("opaque"),
+        algorithm: get("algorithm").unwrap_or_else(|| "MD5".to_string()),
+        qop_auth: get("qop").is_some_and(|qop| qop.split(',').any(|q| q.trim() == "auth")),
+        nc: 0,
+    })
+}
+
+/// 解析 name=value 或 name="value" 形式的参数列表
+fn auth_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = value.trim();
+    while let Some((name, after)) = rest.split_once('=') {
+        let name = name.trim().trim_start_matches(',').trim().to_string();
+        let after = after.trim_start();
+        let (param, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut param = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '\\' => param.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        c => param.push(c),
+                    }
+                }
+                (param, &quoted[end..])
+            }
+            None => match after.find(',') {
+                Some(index) => (after[..index].trim().to_string(), &after[index..]),
+                None => (after.trim().to_string(), ""),
+            },
+        };
+        params.push((name, param));
+        rest = remaining.trim_start().trim_start_matches(',');
+    }
+    params
+}
+
+/// 按 RFC 7616 计算 Authorization 头
+fn digest_authorization(
+    challenge: &DigestChallenge,
+    username: &str,
+    password: &str,
+    method: &str,
+    uri: &str,
+) -> String {
+    let algorithm = challenge.algorithm.to_ascii_uppercase();
+    let hash = |data: String| -> String {
+        if algorithm.starts_with("SHA-256") {
+            hex::encode(Sha256::digest(data))
+        } else {
+            hex::encode(Md5::digest(data))
+        }
+    };
+    let mut cnonce = [0; 16];
+    let _ = getrandom::getrandom(&mut cnonce);
+    let cnonce = hex::encode(cnonce);
+    let nc = format!("{:08x}", challenge.nc);
+
+    let mut ha1 = hash(format!("{}:{}:{}", username, challenge.realm, password));
+    if algorithm.ends_with("-SESS") {
+        ha1 = hash(format!("{}:{}:{}", ha1, challenge.nonce, cnonce));
+    }
+    let ha2 = hash(format!("{}:{}", method, uri));
+    let response = if challenge.qop_auth {
+        hash(format!(
+            "{}:{}:{}:{}:auth:{}",
+            ha1, challenge.nonce, nc, cnonce, ha2
+        ))
+    } else {
+        hash(format!("{}:{}:{}", ha1, challenge.nonce, ha2))
+    };
+
+    let mut authorization = format!(
+        r#"Digest username="{}", realm="{}", nonce="{}", uri="{}", algorithm={}, response="{}""#,
+        quote(username),
+        quote(&challenge.realm),
+        quote(&challenge.nonce),
+        uri,
+        challenge.algorithm,
+        response
+    );
+    if challenge.qop_auth {
+        authorization.push_str(&format!(r#", qop=auth, nc={}, cnonce="{}""#, nc, cnonce));
+    }
+    if let Some(opaque) = &challenge.opaque {
+        authorization.push_str(&format!(r#", opaque="{}""#, quote(opaque)));
+    }
+    authorization
+}
+
+/// 转义 quoted-string 中的 \ 及 "
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// 编码路径, / 不编码
+fn uri_encode(path: &str) -> String {
+    let mut encoded = String::new();
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+pub fn uri_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            if let Some(byte) = value
+                .get(index + 1..index + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                index += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[index]);
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// 将状态码不小于 300 的响应转换为错误
+fn status_error(path: &str, status: u16, response: ureq::Response) -> Error {
+    let kind = match status {
+        // 409: 上级目录不存在
+        404 | 409 => ErrorKind::NotFound,
+        401 | 403 => ErrorKind::PermissionDenied,
+        405 => ErrorKind::Unsupported,
+        507 => ErrorKind::StorageFull,
+        _ => ErrorKind::Other,
+    };
+    Error::new(
+        kind,
+        format!(
+            "WebDAV 请求 {} 返回 {} {}",
+            path,
+            status,
+            response.status_text()
+        ),
+    )
+}
+
+/// 需要 WebDAV 服务器的集成测试
+/// 在 RSBK_WEBDAV_TEST_URL 中填写测试用的地址(如 webdav://127.0.0.1:8080/rsbk),
+/// 需要认证时在 RSBK_WEBDAV_TEST_USERNAME 和 RSBK_WEBDAV_TEST_PASSWORD 中填写用户名和密码;
+/// 未设置时跳过。测试在该路径下创建以进程号命名的目录, 结束后删除
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::bk_config::BackupConfig;
+    use crate::mods::catalog::Catalog;
+    use crate::mods::incremental_mode::IncrementalMode;
+    use crate::mods::run_stats::RunStatus;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// 测试用的服务器地址、配置及测试目录, 未设置地址时返回 None
+    fn test_server(name: &str) -> Option<(String, WebDavConfig, PathBuf)> {
+        let Ok(url) = env::var("RSBK_WEBDAV_TEST_URL") else {
+            eprintln!("未设置 RSBK_WEBDAV_TEST_URL, 跳过 WebDAV 集成测试");
+            return None;
+        };
+        let config = WebDavConfig {
+            username: env::var("RSBK_WEBDAV_TEST_USERNAME").ok(),
+            password: env::var("RSBK_WEBDAV_TEST_PASSWORD").ok(),
+            ..WebDavConfig::default()
+        };
+        let url = format!(
+            "{}/rsbk-{}-{}",
+            url.trim_end_matches('/'),
+            name,
+            std::process::id()
+        );
+        let root = PathBuf::from(WebDavUrl::parse(&url).unwrap().path);
+        Some((url, config, root))
+    }
+
+    /// 离开作用域时删除测试目录
+    struct Cleanup<'a>(&'a WebDavStorage, &'a Path);
+
+    impl Drop for Cleanup<'_> {
+        fn drop(&mut self) {
+            let _ = self.0.delete(self.1);
+        }
+    }
+
+    #[test]
+    fn storage_operations_against_server() {
+        let Some((url, config, root)) = test_server("storage") else {
+            return;
+        };
+        let storage = WebDavStorage::create(WebDavUrl::parse(&url).unwrap(), config).unwrap();
+        let _cleanup = Cleanup(&storage, &root);
+        let dir = root.join("dir");
+        storage.mkdir(&dir.join("sub")).unwrap();
+        assert!(storage.stat(&dir).unwrap().is_some_and(|m| m.is_dir()));
+
+        storage.write(&dir.join("a"), &mut &b"one"[..]).unwrap();
+        // 覆盖已存在的文件
+        storage.write(&dir.join("a"), &mut &b"changed"[..]).unwrap();
+        let meta = storage.stat(&dir.join("a")).unwrap().unwrap();
+        assert_eq!((meta.kind, meta.len), (StorageKind::File, 7));
+        assert_eq!(storage.read(&dir.join("a")).unwrap(), b"changed");
+
+        let mut entries: Vec<(String, StorageKind)> = storage
+            .list(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name, e.meta.kind))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            vec![
+                ("a".to_string(), StorageKind::File),
+                ("sub".to_string(), StorageKind::Dir)
+            ]
+        );
+
+        storage.rename(&dir.join("a"), &dir.join("b")).unwrap();
+        assert!(storage.stat(&dir.join("a")).unwrap().is_none());
+        let local = tempfile::tempdir().unwrap();
+        storage
+            .copy_to(&dir.join("b"), &local.path().join("b"))
+            .unwrap();
+        assert_eq!(fs::read(local.path().join("b")).unwrap(), b"changed");
+        assert_eq!(
+            storage.digest(&dir.join("b")).unwrap(),
+            sha256::digest("changed")
+        );
+        assert_eq!(
+            storage
+                .link(Link::Symbolic("b"), &dir.join("c"))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Unsupported
+        );
+
+        storage.delete(&dir).unwrap();
+        assert!(storage.stat(&dir).unwrap().is_none());
+        assert_eq!(storage.list(&dir).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn incremental_backup_to_server() {
+        let Some((url, config, root)) = test_server("backup") else {
+            return;
+        };
+        let source_dir = tempfile::tempdir().unwrap();
+        let source = source_dir.path().join("data");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a"), b"one").unwrap();
+        fs::write(source.join("sub/b"), b"two").unwrap();
+        let mut task_config: BackupConfig = serde_yaml::from_str(&format!(
+            "backup_source_path: {}\nbackup_destination_path: {}\nbackup_interval_minutes: 60\ninitial_backup_time: \"0:00\"\nis_effect: true\noptions:\n  mode: IncrementalMode\n  save_days: 3\n",
+            source.display(),
+            url
+        ))
+        .unwrap();
+        task_config.webdav = Some(config.clone());
+        let storage = WebDavStorage::create(WebDavUrl::parse(&url).unwrap(), config).unwrap();
+        let _cleanup = Cleanup(&storage, &root);
+
+        let task_name = "webdav_integration_test";
+        let mode = IncrementalMode::create(task_config);
+        let first = mode.backup(task_name);
+        let second = mode.backup(task_name);
+        fs::remove_file(Catalog::get_catalog_path(task_name)).unwrap();
+
+        assert_eq!(first.status, RunStatus::Success);
+        assert_eq!(first.files_copied, 2);
+        assert_eq!(second.status, RunStatus::NoChange);
+        assert_eq!(storage.read(&root.join("data/a")).unwrap(), b"one");
+        assert_eq!(storage.read(&root.join("data/sub/b")).unwrap(), b"two");
+    }
+}
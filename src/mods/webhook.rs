@@ -0,0 +1,410 @@
+use super::notify::{Notification, NotifyEvent};
+use super::s3::uri_encode;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Local;
+use hmac::{Hmac, Mac};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::thread;
+use std::time::Duration;
+
+/// 请求体的格式
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WebhookFormat {
+    /// 通用 JSON, 包含事件、任务名、摘要及完整的运行统计
+    #[default]
+    Json,
+    /// Slack incoming webhook
+    Slack,
+    /// Microsoft Teams 工作流(Workflows) webhook, 发送 Adaptive Card
+    Teams,
+    /// 钉钉自定义机器人, 填写 secret 时使用加签
+    DingTalk,
+    /// 企业微信群机器人
+    WeCom,
+    /// 使用 template 作为请求体
+    Custom,
+}
+
+/// webhook 通知的配置
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookConfig {
+    /// 接收通知的地址
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// Custom 格式的请求体模板, {{task}}、{{event}}、{{body}} 等占位符替换为 JSON 转义后的内容
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// 附加的请求头, 未填写 Content-Type 时为 application/json
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// 钉钉机器人的加签密钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// 发送通知的事件
+    #[serde(default = "default_events")]
+    pub events: Vec<NotifyEvent>,
+    /// 请求的超时秒数
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    /// 网络错误或服务端错误时重试的次数
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+    /// 第一次重试前等待的秒数, 之后每次重试等待的时间翻倍
+    #[serde(default = "default_retry_delay_seconds")]
+    pub retry_delay_seconds: u64,
+}
+
+fn default_events() -> Vec<NotifyEvent> {
+    vec![
+        NotifyEvent::Failure,
+        NotifyEvent::Partial,
+        NotifyEvent::Recovery,
+        NotifyEvent::Stale,
+    ]
+}
+
+fn default_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_retries() -> usize {
+    3
+}
+
+fn default_retry_delay_seconds() -> u64 {
+    2
+}
+
+impl WebhookConfig {
+    /// 日志中显示的地址, 只保留协议及主机名, 避免泄露地址中的令牌
+    pub fn label(&self) -> String {
+        match self.url.split_once("://") {
+            Some((scheme, rest)) => format!(
+                "{}://{}",
+                scheme,
+                rest.split(['/', '?']).next().unwrap_or_default()
+            ),
+            None => self.url.clone(),
+        }
+    }
+}
+
+/// 发送一条 webhook 通知, 网络错误、5xx、429 及钉钉/企业微信返回的错误码按指数退避重试
+pub fn send(config: &WebhookConfig, notification: &Notification) -> Result<(), Error> {
+    let body = payload(config, notification)?;
+    let timeout = Duration::from_secs(config.timeout_seconds.max(1));
+    let agent = ureq::AgentBuilder::new()
+        .timeout_connect(timeout)
+        .timeout_read(timeout)
+        .timeout_write(timeout)
+        .build();
+    let mut attempt = 0;
+    loop {
+        let error = match send_once(&agent, config, &body) {
+            Ok(()) => return Ok(()),
+            Err((error, false)) => return Err(error),
+            Err((error, true)) => error,
+        };
+        if attempt >= config.retries {
+            return Err(error);
+        }
+        let delay = config.retry_delay_seconds << attempt.min(16);
+        attempt += 1;
+        warn!(
+            "{} 请求失败:{}, {}秒后第[{}]次重试",
+            config.label(),
+            error,
+            delay,
+            attempt
+        );
+        thread::sleep(Duration::from_secs(delay));
+    }
+}
+
+/// 发送一次请求, 失败时同时返回是否可以重试
+fn send_once(agent: &ureq::Agent, config: &WebhookConfig, body: &str) -> Result<(), (Error, bool)> {
+    let mut request = agent.post(&signed_url(config));
+    if !config
+        .headers
+        .keys()
+        .any(|name| name.eq_ignore_ascii_case("Content-Type"))
+    {
+        request = request.set("Content-Type", "application/json; charset=utf-8");
+    }
+    for (name, value) in &config.headers {
+        request = request.set(name, value);
+    }
+    let response = match request.send_string(body) {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, response)) => {
+            let status_text = response.status_text().to_string();
+            let error = Error::other(format!(
+                "{} 返回 {} {}: {}",
+                config.label(),
+                status,
+                status_text,
+                response.into_string().unwrap_or_default().trim()
+            ));
+            return Err((error, status >= 500 || status == 429));
+        }
+        Err(ureq::Error::Transport(transport)) => {
+            return Err((
+                Error::new(ErrorKind::ConnectionAborted, transport.to_string()),
+                true,
+            ))
+        }
+    };
+    // 钉钉及企业微信出错时仍返回 200, 错误码在响应中
+    if matches!(
+        config.format,
+        WebhookFormat::DingTalk | WebhookFormat::WeCom
+    ) {
+        let text = response
+            .into_string()
+            .map_err(|e| (Error::new(e.kind(), e.to_string()), true))?;
+        let result: Value = serde_json::from_str(&text).unwrap_or_default();
+        let errcode = result.get("errcode").and_then(Value::as_i64).unwrap_or(0);
+        if errcode != 0 {
+            return Err((
+                Error::other(format!(
+                    "{} 返回错误码 {}: {}",
+                    config.label(),
+                    errcode,
+                    result
+                        .get("errmsg")
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                )),
+                true,
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// 钉钉加签: 在地址后附加毫秒时间戳及签名
+fn signed_url(config: &WebhookConfig) -> String {
+    match (&config.secret, config.format) {
+        (Some(secret), WebhookFormat::DingTalk) => {
+            let timestamp = Local::now().timestamp_millis();
+            let separator = if config.url.contains('?') { '&' } else { '?' };
+            format!(
+                "{}{}timestamp={}&sign={}",
+                config.url,
+                separator,
+                timestamp,
+                uri_encode(&dingtalk_sign(secret, timestamp), true)
+            )
+        }
+        _ => config.url.clone(),
+    }
+}
+
+/// 钉钉签名: HmacSHA256("{timestamp}\n{secret}") 的 base64
+fn dingtalk_sign(secret: &str, timestamp: i64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC 可以使用任意长度的密钥");
+    mac.update(format!("{}\n{}", timestamp, secret).as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+/// 按格式生成请求体
+fn payload(config: &WebhookConfig, notification: &Notification) -> Result<String, Error> {
+    let text = format!(
+        "{}\n\n{}",
+        notification.subject,
+        notification.body.trim_end()
+    );
+    let value = match config.format {
+        WebhookFormat::Json => json!({
+            "event": notification.event,
+            "task": notification.task_name,
+            "title": notification.event.title(),
+            "subject": notification.subject,
+            "text": notification.body,
+            "status": notification.stats.as_ref().map(|s| s.status),
+            "stats": notification.stats,
+            "timestamp": Local::now().to_rfc3339(),
+        }),
+        WebhookFormat::Slack => json!({
+            "text": format!("*{}*\n```{}```", notification.subject, notification.body.trim_end()),
+        }),
+        WebhookFormat::Teams => json!({
+            "type": "message",
+            "attachments": [{
+                "contentType": "application/vnd.microsoft.card.adaptive",
+                "content": {
+                    "$schema": "http://adaptivecards.io/schemas/adaptive-card.json",
+                    "type": "AdaptiveCard",
+                    "version": "1.4",
+                    "body": [
+                        {
+                            "type": "TextBlock",
+                            "text": notification.subject,
+                            "weight": "Bolder",
+                            "size": "Medium",
+                            "wrap": true,
+                        },
+                        {
+                            "type": "TextBlock",
+                            "text": notification.body.trim_end().replace('\n', "\n\n"),
+                            "wrap": true,
+                        },
+                    ],
+                },
+            }],
+        }),
+        WebhookFormat::DingTalk | WebhookFormat::WeCom => json!({
+            "msgtype": "text",
+            "text": { "content": text },
+        }),
+        WebhookFormat::Custom => {
+            let template = config.template.as_ref().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    "Custom 格式的 webhook 需要填写 template",
+                )
+            })?;
+            return Ok(render(template, notification));
+        }
+    };
+    Ok(value.to_string())
+}
+
+/// 替换模板中的占位符, 内容按 JSON 字符串转义(不含两侧的引号)
+/// 可用的占位符: event、title、task、subject、body、status、files_copied、files_failed、
+/// bytes_written、duration_seconds、errors(以换行分隔)、timestamp
+fn render(template: &str, notification: &Notification) -> String {
+    let stats = notification.stats.as_ref();
+    let values = [
+        ("event", format!("{:?}", notification.event)),
+        ("title", notification.event.title().to_string()),
+        ("task", notification.task_name.clone()),
+        ("subject", notification.subject.clone()),
+        ("body", notification.body.clone()),
+        (
+            "status",
+            stats.map_or(String::new(), |s| format!("{:?}", s.status)),
+        ),
+        (
+            "files_copied",
+            stats.map_or(0, |s| s.files_copied).to_string(),
+        ),
+        (
+            "files_failed",
+            stats.map_or(0, |s| s.files_failed).to_string(),
+        ),
+        (
+            "bytes_written",
+            stats.map_or(0, |s| s.bytes_written).to_string(),
+        ),
+        (
+            "duration_seconds",
+            format!("{:.3}", stats.map_or(0.0, |s| s.duration_secs)),
+        ),
+        (
+            "errors",
+            stats.map_or(String::new(), |s| s.errors.join("\n")),
+        ),
+        ("timestamp", Local::now().to_rfc3339()),
+    ];
+    let mut rendered = template.to_string();
+    for (name, value) in values {
+        let escaped = Value::String(value).to_string();
+        rendered = rendered.replace(&format!("{{{{{}}}}}", name), &escaped[1..escaped.len() - 1]);
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mods::run_stats::{RunStats, RunStatus};
+
+    fn notification() -> Notification {
+        let mut stats = RunStats::start("docs");
+        stats.status = RunStatus::Partial;
+        stats.files_copied = 3;
+        stats.files_failed = 1;
+        stats.bytes_written = 2048;
+        stats.errors = vec!["a.txt: \"denied\"".to_string(), "b.txt: gone".to_string()];
+        Notification {
+            event: NotifyEvent::Partial,
+            task_name: "docs".to_string(),
+            subject: "[rsbk] docs: 部分文件备份失败".to_string(),
+            body: "第一行\n第二行".to_string(),
+            stats: Some(stats),
+        }
+    }
+
+    fn config(format: WebhookFormat) -> WebhookConfig {
+        WebhookConfig {
+            url: "https://oapi.dingtalk.com/robot/send?access_token=token".to_string(),
+            format,
+            template: None,
+            headers: BTreeMap::new(),
+            secret: None,
+            events: default_events(),
+            timeout_seconds: 1,
+            retries: 0,
+            retry_delay_seconds: 0,
+        }
+    }
+
+    #[test]
+    fn custom_template_is_rendered_as_valid_json() {
+        let template = r#"{"task":"{{task}}","event":"{{event}}","status":"{{status}}","copied":{{files_copied}},"failed":{{files_failed}},"bytes":{{bytes_written}},"body":"{{body}}","errors":"{{errors}}","unknown":"{{missing}}"}"#;
+        let rendered = render(template, &notification());
+        let value: Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(value["task"], "docs");
+        assert_eq!(value["event"], "Partial");
+        assert_eq!(value["status"], "Partial");
+        assert_eq!(value["copied"], 3);
+        assert_eq!(value["failed"], 1);
+        assert_eq!(value["bytes"], 2048);
+        assert_eq!(value["body"], "第一行\n第二行");
+        assert_eq!(value["errors"], "a.txt: \"denied\"\nb.txt: gone");
+        // 未知的占位符原样保留
+        assert_eq!(value["unknown"], "{{missing}}");
+    }
+
+    #[test]
+    fn custom_format_requires_template() {
+        let e = payload(&config(WebhookFormat::Custom), &notification()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn dingtalk_signature() {
+        assert_eq!(
+            dingtalk_sign("SEC0123456789abcdef", 1700000000000),
+            "TSZbRFUuvaSQaRKUpF970OPCb2/LcQAP3wOvwZIzBZk="
+        );
+    }
+
+    #[test]
+    fn dingtalk_url_is_signed_only_with_secret() {
+        let mut dingtalk = config(WebhookFormat::DingTalk);
+        assert_eq!(signed_url(&dingtalk), dingtalk.url);
+        dingtalk.secret = Some("SEC0123456789abcdef".to_string());
+        let url = signed_url(&dingtalk);
+        let query = url
+            .strip_prefix(&(dingtalk.url.clone() + "&timestamp="))
+            .unwrap();
+        let (timestamp, sign) = query.split_once("&sign=").unwrap();
+        let expected = dingtalk_sign("SEC0123456789abcdef", timestamp.parse().unwrap());
+        assert_eq!(sign, uri_encode(&expected, true));
+        assert!(!sign.contains(['+', '/', '=']));
+
+        let mut slack = config(WebhookFormat::Slack);
+        slack.secret = Some("SEC0123456789abcdef".to_string());
+        assert_eq!(signed_url(&slack), slack.url);
+    }
+}